@@ -1,43 +1,266 @@
 use crate::redis_server::DB;
 use crate::redis_server::Operation;
 use crate::redis_server::DBError;
+use crate::redis_server::{SetOptions, SetOp, FieldValue, ScoredMember, ZAddOptions, KeyType, now_millis, random_u64};
+use crate::redis_server::{NewStreamId, StreamEntry, StreamId, StreamTrim, GroupCommand, ClaimOptions, PendingEntry, PendingRange};
+use crate::protocol::Reply;
+use crate::pubsub::PubSub;
+use crate::glob::{glob_match, literal_prefix};
 use std::sync::mpsc::{channel, Sender, Receiver};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::ops::Bound;
+use std::thread;
+use std::time::Duration;
+
+// value and expiry (unix time in milliseconds) of a string key
+type StringEntry = (Vec<u8>, Option<u64>);
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+// keys read per call while KEYS or DBSIZE walk the whole keyspace
+const KEYS_BATCH: usize = 1024;
+// runs of a command conflicting with other transactions before giving up
+const MAX_TXN_ATTEMPTS: usize = 5;
+const DEFAULT_AUTOCLAIM_COUNT: usize = 100;
+// pending entries XAUTOCLAIM looks at for each one it may claim
+const AUTOCLAIM_ATTEMPTS: usize = 10;
+// pause after a lost compare-and-swap, doubled up to the max on every loss
+const CAS_RETRY_BASE_DELAY: Duration = Duration::from_millis(1);
+const CAS_RETRY_MAX_DELAY: Duration = Duration::from_millis(100);
+// proto-max-bulk-len, the largest string SETRANGE may grow a value to
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+// [MATCH pattern] [COUNT count] [TYPE type] of the SCAN family
+struct ScanOptions{
+    pattern: Option<Vec<u8>>,
+    count: usize,
+    key_type: Option<String>,
+}
+
 pub struct Executor<E: DB>{
     db: Arc<RwLock<E>>,
-    raw_command: Vec<u8>,
+    args: Vec<Vec<u8>>,
     op: Operation,
-    result_sender: Sender<String>,
+    result_sender: Sender<Reply>,
+    // lists the command pushed to, clients blocked on them are woken
+    ready_keys: Vec<Vec<u8>>,
+    // subscriptions PUBLISH sends to, set by the server
+    pubsub: Option<Arc<Mutex<PubSub>>>,
+    // messages of PUBLISH, sent once the command committed
+    published: Vec<(Vec<u8>, Vec<u8>)>,
 }
 
 impl<E: DB> Executor<E>{
-    pub fn new(db: Arc<RwLock<E>>, raw_command: Vec<u8>, tx: Sender<String>) -> Self{
+    pub fn new(db: Arc<RwLock<E>>, args: Vec<Vec<u8>>, tx: Sender<Reply>) -> Self{
         Executor{
             db,
-            raw_command,
+            args,
             result_sender: tx,
             op: Operation::NotParsed,
+            ready_keys: Vec::new(),
+            pubsub: None,
+            published: Vec::new(),
         }
     }
 
-    fn response(&self, res: String){
+    pub fn set_pubsub(&mut self, pubsub: Arc<Mutex<PubSub>>){
+        self.pubsub = Some(pubsub);
+    }
+
+    // EXEC of the commands queued by MULTI, parsed here. `watched` holds the
+    // keys under WATCH with the version they had.
+    pub fn new_exec(db: Arc<RwLock<E>>, queued: Vec<Vec<Vec<u8>>>, watched: Vec<(Vec<u8>, u64)>, tx: Sender<Reply>)
+        -> Self{
+        let mut executor = Executor::new(db, vec![b"EXEC".to_vec()], tx);
+        let ops = queued.iter().map(|args| executor.get_op(args)).collect();
+        executor.op = Operation::Exec(ops, watched);
+        executor
+    }
+
+    fn response(&self, res: Reply){
         self.result_sender.send(res).unwrap();
     }
 
+    // The reply to a command that did not parse, MULTI does not queue those.
+    pub fn parse_error(&self) -> Option<Reply>{
+        match self.op{
+            Operation::Other => Some(Reply::Error("ERR not supported".to_string())),
+            Operation::NotParsed =>{
+                let name = self.args.first().map(|name| String::from_utf8_lossy(name).to_string());
+                Some(Reply::Error(format!("ERR unknown command '{}'", name.unwrap_or_default())))
+            }
+            Operation::Error(ref msg) => Some(Reply::Error(msg.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn is_exec(&self) -> bool{
+        matches!(self.op, Operation::Exec(..))
+    }
+
+    // The keys a blocking command waits on and its timeout in milliseconds,
+    // 0 waiting forever. Each run only tries once, the connection waits.
+    pub fn blocking(&self) -> Option<(Vec<Vec<u8>>, u64)>{
+        match self.op{
+            Operation::BPop(ref keys, _, timeout) => Some((keys.clone(), timeout)),
+            Operation::LMove(ref source, _, _, _, Some(timeout)) => Some((vec![source.clone()], timeout)),
+            Operation::XRead(ref keys, _, _, Some(timeout)) => Some((keys.clone(), timeout)),
+            Operation::XReadGroup(_, _, ref keys, _, _, Some(timeout), _) => Some((keys.clone(), timeout)),
+            _ => None,
+        }
+    }
+
+    // The command a blocked client tries again: XREAD reads after the last
+    // entries of the streams when it started blocking instead of `$`.
+    pub fn blocking_args(&self) -> Result<Vec<Vec<u8>>, Reply>{
+        let mut args = self.args.clone();
+        if let Operation::XRead(ref keys, ref ids, _, _) = self.op{
+            let first_id = args.len() - ids.len();
+            for (i, (key, id)) in keys.iter().zip(ids).enumerate(){
+                if id.is_some(){
+                    continue;
+                }
+                match self.db.read().unwrap().stream_last_id(key.clone()){
+                    Ok(last_id) => args[first_id + i] = last_id.unwrap_or(StreamId::MIN).to_string().into_bytes(),
+                    Err(e) => return Err(error_reply(e)),
+                }
+            }
+        }
+        Ok(args)
+    }
+
+    // `args` of `blocking_args` trying only `keys`, the ones the client is
+    // first in line on.
+    pub fn blocking_args_on(&self, args: &[Vec<u8>], keys: &[Vec<u8>]) -> Vec<Vec<u8>>{
+        match self.op{
+            Operation::BPop(ref all, _, _) =>{
+                let mut on = vec![args[0].clone()];
+                on.extend(all.iter().filter(|key| keys.contains(key)).cloned());
+                on.push(args[args.len() - 1].clone());
+                on
+            }
+            Operation::XReadGroup(_, _, ref all, ..) =>{
+                // STREAMS comes last, the keys and then their ids
+                let first_key = args.len() - 2 * all.len();
+                let kept: Vec<usize> = (0..all.len()).filter(|i| keys.contains(&all[*i])).collect();
+                let mut on = args[..first_key].to_vec();
+                on.extend(kept.iter().map(|i| args[first_key + i].clone()));
+                on.extend(kept.iter().map(|i| args[first_key + all.len() + i].clone()));
+                on
+            }
+            _ => args.to_vec(),
+        }
+    }
+
+    // Whether a blocking command takes what it gets, so blocked clients
+    // take turns on each key. XREAD only reads.
+    pub fn consumes(&self) -> bool{
+        !matches!(self.op, Operation::XRead(..))
+    }
+
+    // the reply of a blocking command that timed out
+    pub fn timeout_reply(&self) -> Reply{
+        match self.op{
+            Operation::LMove(..) => Reply::Nil,
+            _ => Reply::NilArray,
+        }
+    }
+
+    pub fn ready_keys(&self) -> &[Vec<u8>]{
+        &self.ready_keys
+    }
+
+    // Runs the command in a storage transaction, so its reads and writes
+    // take effect together. A command losing a conflict to another one is
+    // run again from the start, its replies are held back until it commits.
+    // EXEC after WATCH fails instead once a watched key has changed.
     pub fn exec_command(&mut self){
-        let op = self.op.clone();
+        let watched = match self.op{
+            Operation::Exec(_, ref watched) => watched.clone(),
+            _ => Vec::new(),
+        };
+        let sender = self.result_sender.clone();
+        for _ in 0..MAX_TXN_ATTEMPTS{
+            self.ready_keys.clear();
+            self.published.clear();
+            let begun = if watched.is_empty(){
+                self.db.read().unwrap().begin_transaction().map(|_| true)
+            }else{
+                self.db.read().unwrap().begin_watched_transaction(watched.clone())
+            };
+            match begun{
+                Ok(true) => {},
+                Ok(false) =>{
+                    self.response(Reply::NilArray);
+                    return;
+                }
+                Err(e) =>{
+                    self.response(error_reply(e));
+                    return;
+                }
+            }
+            let (tx, rx) = channel();
+            self.result_sender = tx;
+            self.run_command(self.op.clone());
+            self.result_sender = sender.clone();
+            match self.db.read().unwrap().commit_transaction(){
+                Ok(true) =>{
+                    if let Some(ref pubsub) = self.pubsub{
+                        let pubsub = pubsub.lock().unwrap();
+                        for (channel, message) in &self.published{
+                            pubsub.publish(channel, message);
+                        }
+                    }
+                    for res in rx.try_iter(){
+                        self.response(res);
+                    }
+                    return;
+                }
+                Ok(false) => continue,
+                Err(e) =>{
+                    self.response(error_reply(e));
+                    return;
+                }
+            }
+        }
+        self.response(Reply::Error("ERR too many conflicts, try again".to_string()));
+    }
+
+    // Runs a command queued by MULTI and returns its reply.
+    fn run_queued(&mut self, op: Operation) -> Reply{
+        let (tx, rx) = channel();
+        let sender = mem::replace(&mut self.result_sender, tx);
+        self.run_command(op);
+        self.result_sender = sender;
+        rx.try_recv().unwrap_or(Reply::Nil)
+    }
+
+    fn run_command(&mut self, op: Operation){
         match op{
-            Operation::Other =>{
-                self.response("-NOT SURPPORTED\r\n".to_string());
+            Operation::Other | Operation::NotParsed | Operation::Error(_) =>{
+                if let Some(res) = self.parse_error(){
+                    self.response(res);
+                }
             }
 
-            Operation::NotParsed =>{
-                self.response("-UNKNWO COMMAND\r\n".to_string());
+            Operation::Exec(ops, _) =>{
+                let replies = ops.into_iter().map(|op| self.run_queued(op)).collect();
+                self.response(Reply::Array(replies));
+            }
+
+            Operation::Set(key, value, options) =>{
+                let res = self.set_with_options(key, value, options);
+                self.response(res);
             }
 
-            Operation::Set(key, value, nx) =>{
-                ///TODO: surport setnx
-                let res = self.set(vec![(key.clone(), value.clone())]);
+            Operation::SetNx(key, value) =>{
+                let options = SetOptions{ nx: true, ..Default::default() };
+                let res = match self.set_with_options(key, value, options){
+                    Reply::Status(_) => Reply::Integer(1),
+                    Reply::Nil => Reply::Integer(0),
+                    res => res,
+                };
                 self.response(res);
             }
 
@@ -47,11 +270,11 @@ impl<E: DB> Executor<E>{
             }
 
             Operation::Get(key) =>{
-                self.response(self.get(vec![key.clone()]));
+                self.response(self.get(key.clone()));
             }
 
             Operation::Mget(keys) =>{
-                self.response(self.get(keys.to_vec()));
+                self.response(self.mget(keys.to_vec()));
             }
 
             Operation::GetSet(key, value) =>{
@@ -77,432 +300,3782 @@ impl<E: DB> Executor<E>{
                 let res = self.set_range(key.clone(), off, data.clone());
                 self.response(res);
             }
-        }
-    }
-
-    pub fn parse(&mut self){
-        let mut command_string = String::from_utf8(self.raw_command.clone()).unwrap();
-        command_string = command_string.trim().to_string();
-        //command_string = command_string.to_uppercase();
-        let args: Vec<&str> = command_string.split("\r\n").collect();
-        /*for arg in &args{
-            println!("{:?}", arg);
-        }*/
-        //println!("raw command {:?}", args);
-        assert!(args.len() > 2);
-        self.op = self.get_op(&args);
-    }
 
-    fn get_op(&self, args: &Vec<&str>) -> Operation{
-        let arg_num: u16 = args[0].trim_start_matches("*").parse().unwrap();
-        println!("arg_num is {}", arg_num);
-        /*for arg in args{
-            println!("{:?}", arg);
-        }*/
-        let op_str = args[2].to_uppercase();
-        println!("OP[{}]", op_str);
-        match op_str.as_ref(){
-            "GET" => Operation::Get(args[4].to_string()),
-            "STRLEN" => Operation::StrLen(args[4].to_string()),
-            "SET" => Operation::Set(args[4].to_string(), args[6].to_string(), false),
-            "SETNX" => Operation::Set(args[4].to_string(), args[6].to_string(), true),
-            "GETSET" => Operation::GetSet(args[4].to_string(), args[6].to_string()),
-            "APPEND" => Operation::Append(args[4].to_string(), args[6].to_string()),
-            "SETRANGE" => {
-                let off: usize = args[6].parse().unwrap();
-                Operation::SetRange(args[4].to_string(), off, args[8].to_string())
-            },
-            "GETRANGE" => {
-                let start_off: i32 = args[6].parse().unwrap();
-                let end_off: i32 = args[8].parse().unwrap();
-                Operation::GetRange(args[4].to_string(), start_off, end_off)
-            },
-            "MSET" =>{
-                let mut pos: usize = 4;
-                let mut args_kv: Vec<(String, String)> = Vec::new();
-                while pos < args.len(){
-                    args_kv.push((args[pos].to_string(), args[pos + 2].to_string()));
-                    pos += 4;
-                }
-                Operation::Mset(args_kv)
-            },
-            "MGET" =>{
-                let mut pos: usize = 4;
-                let mut args_kv: Vec<String> = Vec::new();
-                while pos < args.len(){
-                    args_kv.push(args[pos].to_string());
-                    pos += 2;
-                }
-                Operation::Mget(args_kv)
-            },
+            Operation::Expire(key, expire_at) =>{
+                let res = self.expire(key, expire_at);
+                self.response(res);
+            }
 
-            _ => Operation::NotParsed,
+            Operation::Ttl(key, in_millis) =>{
+                self.response(self.ttl(key, in_millis));
+            }
 
-        }
-    }
+            Operation::Persist(key) =>{
+                let res = self.persist(key);
+                self.response(res);
+            }
 
-    fn gen_multi_reply(&self, reply: Vec<String>) -> String{
-        let field_num = reply.len();
-        let iter = reply.iter().map(|x| format!("${}\r\n{}\r\n", x.len(), x));
-        let mut res = format!("*{}\r\n", field_num);
-        for item in iter{
-            res += &item;
-        }
-        res
-    }
+            Operation::Del(keys) =>{
+                let res = self.del(keys);
+                self.response(res);
+            }
 
-    fn set(&mut self, kvs: Vec<(String, String)>) -> String{
-        let mut succ_count = 0;
-        for (key, value) in &kvs{
-            self.db.write().unwrap().raw_put(key.clone(), value.clone()).unwrap();
-            succ_count += 1;
-        }
-        if succ_count != kvs.len(){
-            return "-FAILED\r\n".to_string();
-        }else{
-            return "+OK\r\n".to_string();
-        }
-    }
+            Operation::Exists(keys) =>{
+                self.response(self.exists(keys));
+            }
 
-    fn get(&self, keys: Vec<String>) -> String{
-        let mut values = Vec::new();
-        let mut all_done = true;
-        for key in keys{
-            match self.db.read().unwrap().raw_get(key){
-                Ok(value) => {
-                    values.push(value);
-                },
-                Err(e) => {
-                    match e {
-                        DBError::NotFound => {
-                            all_done = false;
-                            break;
-                        }
+            Operation::Type(key) =>{
+                self.response(self.key_type(key));
+            }
 
-                        _ => (),
-                    }
+            Operation::Rename(key, new_key, nx) =>{
+                let res = self.rename(key, new_key.clone(), nx);
+                if res == Reply::ok() || res == Reply::Integer(1){
+                    self.ready_keys.push(new_key);
                 }
+                self.response(res);
             }
-        }
-        if all_done{
-            self.gen_multi_reply(values)
-        }else{
-            "-FAILED\r\n".to_string()
-        }
-    }
-
-    fn getset(&mut self, key: String, value: String) -> String{
-        let res;
-        {
-            res = self.db.read().unwrap().raw_get(key.clone())
-        }
 
-        {
-            match res{
-                Ok(old_value) =>{
-                    self.db.write().unwrap().raw_put(key, value).unwrap();
-                    return format!("+{}\r\n", old_value);
-                },
+            Operation::Keys(pattern) =>{
+                self.response(self.keys(pattern));
+            }
 
-                Err(e) =>{
-                    match e {
-                        DBError::NotFound => {
-                            self.db.write().unwrap().raw_put(key, value.clone()).unwrap();
-                            return format!("+{}\r\n", value);
-                        },
-                        _ => return "-FAILED BY ERROR\r\n".to_string(),
-                    }
-                }
+            Operation::Scan(after, pattern, count, key_type) =>{
+                self.response(self.scan(after, pattern, count, key_type));
             }
-        }
 
-    }
+            Operation::DBSize =>{
+                self.response(self.dbsize());
+            }
 
-    fn strlen(&self, key: String) -> String{
-        match self.db.read().unwrap().raw_get(key){
-            Ok(value) =>{
-                return format!("+{}\r\n", value.len());
-            },
+            // counted now, sent after the commit so a rerun does not send twice
+            Operation::Publish(channel, message) =>{
+                let received = self.pubsub.as_ref().map_or(0, |pubsub| pubsub.lock().unwrap().receivers(&channel));
+                self.published.push((channel, message));
+                self.response(Reply::Integer(received as i64));
+            }
 
-            Err(e) =>{
-                match e {
-                    DBError::NotFound => return "-FAILED NOT FOUND\r\n".to_string(),
-                    _ => return "-FAILED BY ERROR\r\n".to_string(),
-                }
+            Operation::IncrBy(key, delta) =>{
+                let res = self.incr_by(key, delta);
+                self.response(res);
             }
-        }
-    }
 
-    fn append(&mut self, key: String, value: String) -> String{
-        let res;
-        {
-            res = self.db.read().unwrap().raw_get(key.clone());
-        }
-        {
-            match res{
-                Ok(old_value) =>{
-                    self.db.write().unwrap().raw_put(key, old_value + &value).unwrap();
-                    return "+OK\r\n".to_string();
-                },
+            Operation::IncrByFloat(key, delta) =>{
+                let res = self.incr_by_float(key, delta);
+                self.response(res);
+            }
 
-                Err(e) =>{
-                    match e {
-                        DBError::NotFound => return "-FAILED NOT FOUND\r\n".to_string(),
-                        _ => return "-FAILED BY ERROR\r\n".to_string(),
+            Operation::Push(key, values, left, only_existing) =>{
+                let res = self.push(key.clone(), values, left, only_existing);
+                if let Reply::Integer(len) = res{
+                    if len > 0{
+                        self.ready_keys.push(key);
                     }
                 }
+                self.response(res);
             }
-        }
 
-    }
+            Operation::Pop(key, left, count) =>{
+                let res = self.pop(key, left, count);
+                self.response(res);
+            }
 
-    fn get_range(&self, key: String, start: i32, end: i32) -> String{
-        match self.db.read().unwrap().raw_get(key.clone()){
-            Ok(value) =>{
-                let start_abs = if start < 0{
-                    value.len() as i32 + start
-                }else{
-                    start
-                };
-                let mut end_abs = if end < 0{
-                    value.len() as i32 + end
-                }else{
-                    end
-                };
-                if start_abs > end_abs{
-                    return "-FAILED\r\n".to_string();
-                }
+            Operation::BPop(keys, left, _) =>{
+                let res = self.bpop(keys, left);
+                self.response(res);
+            }
 
-                if start_abs >= value.len() as i32{
-                    return "+\r\n".to_string();
+            Operation::LMove(source, destination, from_left, to_left, _) =>{
+                let res = self.lmove(source, destination.clone(), from_left, to_left);
+                if let Reply::Bulk(_) = res{
+                    self.ready_keys.push(destination);
                 }
+                self.response(res);
+            }
 
-                if end_abs >= value.len() as i32{
-                    end_abs = value.len() as i32 - 1;
-                }
+            Operation::LLen(key) =>{
+                self.response(self.llen(key));
+            }
 
-                let start_abs = start_abs as usize;
-                let end_abs = end_abs as usize;
-                let slice = value.get(start_abs..end_abs);
-                match slice{
-                    Some(res) =>{
-                        return format!("+{}\r\n", res);
-                    },
+            Operation::LRange(key, start, stop) =>{
+                self.response(self.lrange(key, start, stop));
+            }
 
-                    None =>{
-                        return "-FAILED\r\n".to_string();
-                    }
-                }
-            },
+            Operation::LIndex(key, index) =>{
+                self.response(self.lindex(key, index));
+            }
 
-            Err(e) =>{
-                match e {
-                    DBError::NotFound => return "-FAILED NOT FOUND\r\n".to_string(),
-                    _ => return "-FAILED BY ERROR\r\n".to_string(),
-                }
+            Operation::LSet(key, index, value) =>{
+                let res = self.lset(key, index, value);
+                self.response(res);
             }
-        }
-    }
 
-    fn set_range(&mut self, key: String, off: usize, data: String) -> String{
-        let res;
-        {
-            res = self.db.read().unwrap().raw_get(key.clone());
-        }
+            Operation::LTrim(key, start, stop) =>{
+                let res = self.ltrim(key, start, stop);
+                self.response(res);
+            }
 
-        {
-            match res{
-                Ok(old_value) =>{
-                    let mut new_value = old_value.clone();
-                    if off >= new_value.len(){
-                        // off execeeds len of old value and write "0" bytes
-                        let nil: Vec<u8> = vec![0; off - new_value.len()];
-                        let nil = String::from_utf8(nil).unwrap();
-                        new_value += &nil;
-                        new_value += &data;
-                    }else if off + data.len() >= new_value.len(){
-                        new_value.truncate(off);
-                        new_value += &data;
-                    }else{
-                        println!("value is {}, off is {}, data.len() is {}",old_value, off, data.len());
-                        new_value.replace_range(off..(off + data.len()), &data);
-                    }
-                    let new_len = new_value.len();
-                    self.db.write().unwrap().raw_put(key, new_value).unwrap();
-                    return format!("+{}\r\n", new_len);
-                },
+            Operation::LRem(key, count, value) =>{
+                let res = self.lrem(key, count, value);
+                self.response(res);
+            }
 
-                Err(e) =>{
-                    match e {
-                        DBError::NotFound => {
-                            let nil: Vec<u8> = vec![0; off];
-                            let nil = String::from_utf8(nil).unwrap();
-                            let new_value = nil + &data;
-                            self.db.write().unwrap().raw_put(key, new_value.clone()).unwrap();
-                            return format!("+{}\r\n", new_value.len());
-                        },
-                        _ => return "-FAILED BY ERROR\r\n".to_string(),
-                    }
-                }
+            Operation::LInsert(key, before, pivot, value) =>{
+                let res = self.linsert(key, before, pivot, value);
+                self.response(res);
             }
-        }
 
-    }
-}
+            Operation::HSet(key, fields, reply_ok) =>{
+                let res = self.hset(key, fields, reply_ok);
+                self.response(res);
+            }
 
-#[cfg(test)]
-mod tests{
-    use crate::simple_mem_db;
-    use crate::redis_server::DB;
-    use crate::executor::Executor;
-    use std::sync::{Arc, RwLock};
-    use std::sync::mpsc::{channel, Sender, Receiver};
+            Operation::HGet(key, field) =>{
+                self.response(self.hget(key, field));
+            }
 
-    fn gen_redis_code(raw_code: String) -> String{
-        let args: Vec<&str> = raw_code.split(" ").collect();
-        let args_num = args.len();
-        let args = args.iter().map(|x| format!("${}\r\n{}\r\n", x.len(), x));
-        let mut output = format!("*{}\r\n", args_num);
-        for item in args{
-            output += &item;
-        }
-        //println!("get command {}", output);
-        output
-    }
+            Operation::HMGet(key, fields) =>{
+                self.response(self.hmget(key, fields));
+            }
 
-    fn exec<E: DB>(db: Arc<RwLock<E>>, command: String, tx: Sender<String>){
-        let mut executor = Executor::new(
-            db.clone(),
-            command.into_bytes(),
-            tx.clone());
-        executor.parse();
-        executor.exec_command();
-    }
+            Operation::HDel(key, fields) =>{
+                let res = self.hdel(key, fields);
+                self.response(res);
+            }
 
-    #[test]
-    fn test_executor_basic_put_get(){
-        let db = simple_mem_db::SimpleMemDB::new();
-        let db = Arc::new(RwLock::new(db));
-        let put_command = gen_redis_code("set foo bar".to_string());
-        let (tx, rx) = channel();
-        exec(db.clone(), put_command, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+OK\r\n".to_string());
+            Operation::HGetAll(key, with_fields, with_values) =>{
+                self.response(self.hget_all(key, with_fields, with_values));
+            }
 
-        let get_command = gen_redis_code("get foo".to_string());
-        exec(db.clone(), get_command, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "*1\r\n$3\r\nbar\r\n".to_string());
+            Operation::HLen(key) =>{
+                self.response(self.hlen(key));
+            }
 
-        let get_command = gen_redis_code("get no".to_string());
-        exec(db.clone(), get_command, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "-FAILED\r\n".to_string());
-    }
+            Operation::HExists(key, field) =>{
+                let res = match self.hget(key, field){
+                    Reply::Bulk(_) => Reply::Integer(1),
+                    Reply::Nil => Reply::Integer(0),
+                    res => res,
+                };
+                self.response(res);
+            }
 
-    #[test]
-    fn test_executor_multi_put_get(){
-        let db = simple_mem_db::SimpleMemDB::new();
-        let db = Arc::new(RwLock::new(db));
-        let mset_command = gen_redis_code("mset foo1 bar1 foo2 bar2 foo3 bar3".to_string());
-        let (tx, rx) = channel();
-        exec(db.clone(), mset_command, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+OK\r\n".to_string());
+            Operation::HIncrBy(key, field, delta) =>{
+                let res = self.hincr_by(key, field, delta);
+                self.response(res);
+            }
 
-        let mget_command = gen_redis_code("mget foo1 foo2 foo3".to_string());
-        exec(db.clone(), mget_command, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "*3\r\n$4\r\nbar1\r\n$4\r\nbar2\r\n$4\r\nbar3\r\n".to_string());
-    }
+            Operation::HScan(key, after, pattern, count) =>{
+                self.response(self.hscan(key, after, pattern, count));
+            }
 
-    #[test]
-    fn test_executor_strlen(){
-        let db = simple_mem_db::SimpleMemDB::new();
-        let db = Arc::new(RwLock::new(db));
-        let set_command = gen_redis_code("set foo bar".to_string());
-        let strlen_command = gen_redis_code("strlen foo".to_string());
-        let (tx, rx) = channel();
-        exec(db.clone(), set_command, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+OK\r\n".to_string());
+            Operation::SAdd(key, members) =>{
+                let res = self.sadd(key, members);
+                self.response(res);
+            }
 
-        exec(db.clone(), strlen_command, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+3\r\n".to_string());
-    }
+            Operation::SRem(key, members) =>{
+                let res = self.srem(key, members);
+                self.response(res);
+            }
 
+            Operation::SMembers(key) =>{
+                self.response(self.smembers(key));
+            }
 
-    #[test]
-    fn test_executor_getset(){
-        let db = simple_mem_db::SimpleMemDB::new();
-        let db = Arc::new(RwLock::new(db));
-        let command1 = gen_redis_code("set foo bar".to_string());
-        let command2 = gen_redis_code("getset foo bar2".to_string());
-        let command3 = gen_redis_code("getset foo3 bar3".to_string());
-        let (tx, rx) = channel();
-        exec(db.clone(), command1, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+OK\r\n".to_string());
+            Operation::SIsMember(key, member) =>{
+                self.response(self.sismember(key, member));
+            }
 
-        exec(db.clone(), command2, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+bar\r\n".to_string());
+            Operation::SCard(key) =>{
+                self.response(self.scard(key));
+            }
+
+            Operation::SCombine(op, keys, dest) =>{
+                let res = self.combine_sets(op, keys, dest);
+                self.response(res);
+            }
+
+            Operation::SPop(key, count) =>{
+                let res = self.spop(key, count);
+                self.response(res);
+            }
+
+            Operation::SRandMember(key, count) =>{
+                self.response(self.srandmember(key, count));
+            }
+
+            Operation::ZAdd(key, members, options) =>{
+                let res = self.zadd(key, members, options);
+                self.response(res);
+            }
+
+            Operation::ZIncrBy(key, delta, member) =>{
+                let res = self.zincr_by(key, delta, member);
+                self.response(res);
+            }
+
+            Operation::ZRange(key, start, stop, with_scores) =>{
+                self.response(self.zrange(key, start, stop, with_scores));
+            }
+
+            Operation::ZRangeByScore(key, min, max, with_scores, offset, count) =>{
+                self.response(self.zrange_by_score(key, min, max, with_scores, offset, count));
+            }
+
+            Operation::ZRank(key, member) =>{
+                self.response(self.zrank(key, member));
+            }
+
+            Operation::ZScore(key, member) =>{
+                self.response(self.zscore(key, member));
+            }
+
+            Operation::ZRem(key, members) =>{
+                let res = self.zrem(key, members);
+                self.response(res);
+            }
+
+            Operation::ZCard(key) =>{
+                self.response(self.zcard(key));
+            }
+
+            Operation::XAdd(key, id, fields, no_create, trim) =>{
+                let res = self.xadd(key.clone(), id, fields, no_create, trim);
+                if let Reply::Bulk(_) = res{
+                    self.ready_keys.push(key);
+                }
+                self.response(res);
+            }
+
+            Operation::XRange(key, start, end, count, reverse) =>{
+                self.response(self.xrange(key, start, end, count, reverse));
+            }
+
+            Operation::XLen(key) =>{
+                self.response(self.xlen(key));
+            }
+
+            Operation::XTrim(key, trim) =>{
+                let res = self.xtrim(key, trim);
+                self.response(res);
+            }
+
+            Operation::XRead(keys, ids, count, _) =>{
+                self.response(self.xread(keys, ids, count));
+            }
+
+            Operation::XGroup(key, group, command) =>{
+                let destroy = command == GroupCommand::Destroy;
+                let res = self.xgroup(key.clone(), group, command);
+                // clients blocked reading for the group get their error
+                if destroy && res == Reply::Integer(1){
+                    self.ready_keys.push(key);
+                }
+                self.response(res);
+            }
+
+            Operation::XReadGroup(group, consumer, keys, ids, count, _, no_ack) =>{
+                let res = self.xread_group(group, consumer, keys, ids, count, no_ack);
+                self.response(res);
+            }
+
+            Operation::XAck(key, group, ids) =>{
+                let res = self.xack(key, group, ids);
+                self.response(res);
+            }
+
+            Operation::XPending(key, group, range) =>{
+                self.response(self.xpending(key, group, range));
+            }
+
+            Operation::XClaim(key, group, consumer, min_idle, ids, options) =>{
+                let res = self.xclaim(key, group, consumer, min_idle, ids, options);
+                self.response(res);
+            }
+
+            Operation::XAutoClaim(key, group, consumer, min_idle, start, count, just_id) =>{
+                let res = self.xautoclaim(key, group, consumer, min_idle, start, count, just_id);
+                self.response(res);
+            }
+        }
+    }
+
+    pub fn parse(&mut self){
+        // EXEC comes with its commands parsed already
+        if self.is_exec(){
+            return;
+        }
+        if self.args.is_empty(){
+            self.op = Operation::NotParsed;
+            return;
+        }
+        let args = self.args.clone();
+        //println!("raw command {:?}", args);
+        self.op = self.get_op(&args);
+    }
+
+    fn get_op(&self, args: &Vec<Vec<u8>>) -> Operation{
+        // only the command name has to be text, keys and values stay raw bytes
+        let op_str = String::from_utf8_lossy(&args[0]).to_uppercase();
+        println!("OP[{}]", op_str);
+        let arity_ok = match op_str.as_ref(){
+            "GET" | "STRLEN" | "TTL" | "PTTL" | "PERSIST" | "TYPE" | "INCR" | "DECR" => args.len() == 2,
+            "INCRBY" | "DECRBY" | "INCRBYFLOAT" => args.len() == 3,
+            "DEL" | "UNLINK" | "EXISTS" => args.len() >= 2,
+            "RENAME" | "RENAMENX" => args.len() == 3,
+            "KEYS" => args.len() == 2,
+            "SCAN" => args.len() >= 2,
+            "DBSIZE" => args.len() == 1,
+            "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => args.len() == 3,
+            "SET" => args.len() >= 3,
+            "SETNX" | "GETSET" | "APPEND" => args.len() == 3,
+            "SETRANGE" | "GETRANGE" => args.len() == 4,
+            "MSET" => args.len() >= 3 && args.len() % 2 == 1,
+            "MGET" => args.len() >= 2,
+            "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" => args.len() >= 3,
+            "LPOP" | "RPOP" => args.len() == 2 || args.len() == 3,
+            "BLPOP" | "BRPOP" => args.len() >= 3,
+            "LMOVE" => args.len() == 5,
+            "BLMOVE" => args.len() == 6,
+            "LLEN" => args.len() == 2,
+            "LINDEX" => args.len() == 3,
+            "LRANGE" | "LTRIM" | "LSET" | "LREM" => args.len() == 4,
+            "LINSERT" => args.len() == 5,
+            "HSET" | "HMSET" => args.len() >= 4 && args.len() % 2 == 0,
+            "HGET" | "HEXISTS" => args.len() == 3,
+            "HMGET" | "HDEL" => args.len() >= 3,
+            "HGETALL" | "HKEYS" | "HVALS" | "HLEN" => args.len() == 2,
+            "HINCRBY" => args.len() == 4,
+            "HSCAN" => args.len() >= 3,
+            "SADD" | "SREM" => args.len() >= 3,
+            "SMEMBERS" | "SCARD" => args.len() == 2,
+            "SISMEMBER" => args.len() == 3,
+            "SINTER" | "SUNION" | "SDIFF" => args.len() >= 2,
+            "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => args.len() >= 3,
+            "SPOP" | "SRANDMEMBER" => args.len() == 2 || args.len() == 3,
+            "ZADD" => args.len() >= 4,
+            "ZINCRBY" => args.len() == 4,
+            "ZRANGE" => args.len() == 4 || args.len() == 5,
+            "ZRANGEBYSCORE" => args.len() >= 4,
+            "ZRANK" | "ZSCORE" => args.len() == 3,
+            "ZREM" => args.len() >= 3,
+            "ZCARD" => args.len() == 2,
+            "XADD" => args.len() >= 5,
+            "XRANGE" | "XREVRANGE" => args.len() == 4 || args.len() == 6,
+            "XLEN" => args.len() == 2,
+            "XTRIM" => args.len() >= 4,
+            "XREAD" => args.len() >= 4,
+            "XGROUP" => args.len() >= 2,
+            "XREADGROUP" => args.len() >= 7,
+            "XACK" => args.len() >= 4,
+            "XPENDING" => args.len() >= 3,
+            "XCLAIM" | "XAUTOCLAIM" => args.len() >= 6,
+            "PUBLISH" => args.len() == 3,
+            _ => return Operation::NotParsed,
+        };
+        if !arity_ok{
+            return Operation::Error(
+                format!("ERR wrong number of arguments for '{}' command", op_str.to_lowercase()));
+        }
+        match op_str.as_ref(){
+            "GET" => Operation::Get(args[1].clone()),
+            "STRLEN" => Operation::StrLen(args[1].clone()),
+            "SET" => self.parse_set(args),
+            "SETNX" => Operation::SetNx(args[1].clone(), args[2].clone()),
+            "GETSET" => Operation::GetSet(args[1].clone(), args[2].clone()),
+            "APPEND" => Operation::Append(args[1].clone(), args[2].clone()),
+            "SETRANGE" => {
+                match parse_arg::<usize>(&args[2]){
+                    Some(off) => Operation::SetRange(args[1].clone(), off, args[3].clone()),
+                    None => Operation::Error("ERR offset is out of range".to_string()),
+                }
+            },
+            "GETRANGE" => {
+                match (parse_arg::<i32>(&args[2]), parse_arg::<i32>(&args[3])){
+                    (Some(start_off), Some(end_off)) => Operation::GetRange(args[1].clone(), start_off, end_off),
+                    _ => Operation::Error("ERR value is not an integer or out of range".to_string()),
+                }
+            },
+            "MSET" =>{
+                let args_kv: Vec<(Vec<u8>, Vec<u8>)> = args[1..].chunks(2)
+                    .map(|kv| (kv[0].clone(), kv[1].clone()))
+                    .collect();
+                Operation::Mset(args_kv)
+            },
+            "MGET" => Operation::Mget(args[1..].to_vec()),
+            "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" =>{
+                let time = match parse_arg::<i64>(&args[2]){
+                    Some(time) => time,
+                    None => return Operation::Error("ERR value is not an integer or out of range".to_string()),
+                };
+                let expire_at = match op_str.as_ref(){
+                    "EXPIRE" => time.checked_mul(1000).and_then(|ms| ms.checked_add(now_millis() as i64)),
+                    "PEXPIRE" => time.checked_add(now_millis() as i64),
+                    "EXPIREAT" => time.checked_mul(1000),
+                    _ => Some(time),
+                };
+                match expire_at{
+                    Some(expire_at) => Operation::Expire(args[1].clone(), expire_at),
+                    None => Operation::Error(
+                        format!("ERR invalid expire time in '{}' command", op_str.to_lowercase())),
+                }
+            },
+            "TTL" => Operation::Ttl(args[1].clone(), false),
+            "PTTL" => Operation::Ttl(args[1].clone(), true),
+            "PERSIST" => Operation::Persist(args[1].clone()),
+            // values are dropped synchronously, so UNLINK is an alias of DEL
+            "DEL" | "UNLINK" => Operation::Del(args[1..].to_vec()),
+            "EXISTS" => Operation::Exists(args[1..].to_vec()),
+            "TYPE" => Operation::Type(args[1].clone()),
+            "RENAME" => Operation::Rename(args[1].clone(), args[2].clone(), false),
+            "RENAMENX" => Operation::Rename(args[1].clone(), args[2].clone(), true),
+            "KEYS" => Operation::Keys(args[1].clone()),
+            "SCAN" =>{
+                let after = match decode_cursor(&args[1]){
+                    Some(after) => after,
+                    None => return Operation::Error("ERR invalid cursor".to_string()),
+                };
+                match parse_scan_options(&args[2..], true){
+                    Ok(options) => Operation::Scan(after, options.pattern, options.count, options.key_type),
+                    Err(msg) => Operation::Error(msg),
+                }
+            },
+            "DBSIZE" => Operation::DBSize,
+            "INCR" => Operation::IncrBy(args[1].clone(), 1),
+            "DECR" => Operation::IncrBy(args[1].clone(), -1),
+            "INCRBY" | "DECRBY" =>{
+                let delta = match parse_arg::<i64>(&args[2]){
+                    Some(delta) => delta,
+                    None => return Operation::Error("ERR value is not an integer or out of range".to_string()),
+                };
+                if op_str == "INCRBY"{
+                    Operation::IncrBy(args[1].clone(), delta)
+                }else{
+                    match delta.checked_neg(){
+                        Some(delta) => Operation::IncrBy(args[1].clone(), delta),
+                        None => Operation::Error("ERR decrement would overflow".to_string()),
+                    }
+                }
+            },
+            "INCRBYFLOAT" =>{
+                match parse_float(&args[2]){
+                    Some(delta) => Operation::IncrByFloat(args[1].clone(), delta),
+                    None => Operation::Error("ERR value is not a valid float".to_string()),
+                }
+            },
+            "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" =>{
+                let left = op_str.starts_with('L');
+                let only_existing = op_str.ends_with('X');
+                Operation::Push(args[1].clone(), args[2..].to_vec(), left, only_existing)
+            },
+            "LPOP" | "RPOP" =>{
+                let count = match args.get(2){
+                    Some(count) => match parse_arg::<usize>(count){
+                        Some(count) => Some(count),
+                        None => return Operation::Error("ERR value is out of range, must be positive".to_string()),
+                    },
+                    None => None,
+                };
+                Operation::Pop(args[1].clone(), op_str == "LPOP", count)
+            },
+            "BLPOP" | "BRPOP" =>{
+                match parse_timeout(&args[args.len() - 1]){
+                    Ok(timeout) => Operation::BPop(args[1..args.len() - 1].to_vec(), op_str == "BLPOP", timeout),
+                    Err(msg) => Operation::Error(msg),
+                }
+            },
+            "LMOVE" | "BLMOVE" =>{
+                let (from_left, to_left) = match (parse_side(&args[3]), parse_side(&args[4])){
+                    (Some(from_left), Some(to_left)) => (from_left, to_left),
+                    _ => return Operation::Error("ERR syntax error".to_string()),
+                };
+                let timeout = match args.get(5).map(|timeout| parse_timeout(timeout)){
+                    Some(Ok(timeout)) => Some(timeout),
+                    Some(Err(msg)) => return Operation::Error(msg),
+                    None => None,
+                };
+                Operation::LMove(args[1].clone(), args[2].clone(), from_left, to_left, timeout)
+            },
+            "LLEN" => Operation::LLen(args[1].clone()),
+            "LINDEX" =>{
+                match parse_arg::<i64>(&args[2]){
+                    Some(index) => Operation::LIndex(args[1].clone(), index),
+                    None => Operation::Error("ERR value is not an integer or out of range".to_string()),
+                }
+            },
+            "LSET" =>{
+                match parse_arg::<i64>(&args[2]){
+                    Some(index) => Operation::LSet(args[1].clone(), index, args[3].clone()),
+                    None => Operation::Error("ERR value is not an integer or out of range".to_string()),
+                }
+            },
+            "LRANGE" | "LTRIM" =>{
+                let (start, stop) = match (parse_arg::<i64>(&args[2]), parse_arg::<i64>(&args[3])){
+                    (Some(start), Some(stop)) => (start, stop),
+                    _ => return Operation::Error("ERR value is not an integer or out of range".to_string()),
+                };
+                if op_str == "LRANGE"{
+                    Operation::LRange(args[1].clone(), start, stop)
+                }else{
+                    Operation::LTrim(args[1].clone(), start, stop)
+                }
+            },
+            "LREM" =>{
+                match parse_arg::<i64>(&args[2]){
+                    Some(count) => Operation::LRem(args[1].clone(), count, args[3].clone()),
+                    None => Operation::Error("ERR value is not an integer or out of range".to_string()),
+                }
+            },
+            "LINSERT" =>{
+                let before = match String::from_utf8_lossy(&args[2]).to_uppercase().as_ref(){
+                    "BEFORE" => true,
+                    "AFTER" => false,
+                    _ => return Operation::Error("ERR syntax error".to_string()),
+                };
+                Operation::LInsert(args[1].clone(), before, args[3].clone(), args[4].clone())
+            },
+            "HSET" | "HMSET" =>{
+                let fields = args[2..].chunks(2)
+                    .map(|fv| (fv[0].clone(), fv[1].clone()))
+                    .collect();
+                Operation::HSet(args[1].clone(), fields, op_str == "HMSET")
+            },
+            "HGET" => Operation::HGet(args[1].clone(), args[2].clone()),
+            "HMGET" => Operation::HMGet(args[1].clone(), args[2..].to_vec()),
+            "HDEL" => Operation::HDel(args[1].clone(), args[2..].to_vec()),
+            "HGETALL" => Operation::HGetAll(args[1].clone(), true, true),
+            "HKEYS" => Operation::HGetAll(args[1].clone(), true, false),
+            "HVALS" => Operation::HGetAll(args[1].clone(), false, true),
+            "HLEN" => Operation::HLen(args[1].clone()),
+            "HEXISTS" => Operation::HExists(args[1].clone(), args[2].clone()),
+            "HINCRBY" =>{
+                match parse_arg::<i64>(&args[3]){
+                    Some(delta) => Operation::HIncrBy(args[1].clone(), args[2].clone(), delta),
+                    None => Operation::Error("ERR value is not an integer or out of range".to_string()),
+                }
+            },
+            "HSCAN" =>{
+                let after = match decode_cursor(&args[2]){
+                    Some(after) => after,
+                    None => return Operation::Error("ERR invalid cursor".to_string()),
+                };
+                match parse_scan_options(&args[3..], false){
+                    Ok(options) => Operation::HScan(args[1].clone(), after, options.pattern, options.count),
+                    Err(msg) => Operation::Error(msg),
+                }
+            },
+            "SADD" => Operation::SAdd(args[1].clone(), args[2..].to_vec()),
+            "SREM" => Operation::SRem(args[1].clone(), args[2..].to_vec()),
+            "SMEMBERS" => Operation::SMembers(args[1].clone()),
+            "SISMEMBER" => Operation::SIsMember(args[1].clone(), args[2].clone()),
+            "SCARD" => Operation::SCard(args[1].clone()),
+            "SINTER" => Operation::SCombine(SetOp::Inter, args[1..].to_vec(), None),
+            "SUNION" => Operation::SCombine(SetOp::Union, args[1..].to_vec(), None),
+            "SDIFF" => Operation::SCombine(SetOp::Diff, args[1..].to_vec(), None),
+            "SINTERSTORE" => Operation::SCombine(SetOp::Inter, args[2..].to_vec(), Some(args[1].clone())),
+            "SUNIONSTORE" => Operation::SCombine(SetOp::Union, args[2..].to_vec(), Some(args[1].clone())),
+            "SDIFFSTORE" => Operation::SCombine(SetOp::Diff, args[2..].to_vec(), Some(args[1].clone())),
+            "SPOP" =>{
+                match args.get(2).map(|count| parse_arg::<usize>(count)){
+                    Some(None) => Operation::Error("ERR value is out of range, must be positive".to_string()),
+                    Some(count) => Operation::SPop(args[1].clone(), count),
+                    None => Operation::SPop(args[1].clone(), None),
+                }
+            },
+            "SRANDMEMBER" =>{
+                match args.get(2).map(|count| parse_arg::<i64>(count)){
+                    Some(None) => Operation::Error("ERR value is not an integer or out of range".to_string()),
+                    // like redis, -count must fit too
+                    Some(Some(i64::MIN)) => Operation::Error(
+                        "ERR value is out of range, must be between -9223372036854775807 and 9223372036854775807".to_string()),
+                    Some(count) => Operation::SRandMember(args[1].clone(), count),
+                    None => Operation::SRandMember(args[1].clone(), None),
+                }
+            },
+            "ZADD" => self.parse_zadd(args),
+            "ZINCRBY" =>{
+                match parse_score(&args[2]){
+                    Some(delta) => Operation::ZIncrBy(args[1].clone(), delta, args[3].clone()),
+                    None => Operation::Error("ERR value is not a valid float".to_string()),
+                }
+            },
+            "ZRANGE" =>{
+                let with_scores = match args.get(4){
+                    Some(arg) if String::from_utf8_lossy(arg).to_uppercase() == "WITHSCORES" => true,
+                    Some(_) => return Operation::Error("ERR syntax error".to_string()),
+                    None => false,
+                };
+                match (parse_arg::<i64>(&args[2]), parse_arg::<i64>(&args[3])){
+                    (Some(start), Some(stop)) => Operation::ZRange(args[1].clone(), start, stop, with_scores),
+                    _ => Operation::Error("ERR value is not an integer or out of range".to_string()),
+                }
+            },
+            "ZRANGEBYSCORE" => self.parse_zrange_by_score(args),
+            "ZRANK" => Operation::ZRank(args[1].clone(), args[2].clone()),
+            "ZSCORE" => Operation::ZScore(args[1].clone(), args[2].clone()),
+            "ZREM" => Operation::ZRem(args[1].clone(), args[2..].to_vec()),
+            "ZCARD" => Operation::ZCard(args[1].clone()),
+            "XADD" => self.parse_xadd(args),
+            "XRANGE" | "XREVRANGE" =>{
+                let reverse = op_str == "XREVRANGE";
+                // XREVRANGE takes the end first
+                let (start, end) = if reverse{ (&args[3], &args[2]) }else{ (&args[2], &args[3]) };
+                let (start, end) = match (parse_range_bound(start, true), parse_range_bound(end, false)){
+                    (Some(start), Some(end)) => (start, end),
+                    _ => return Operation::Error(INVALID_STREAM_ID.to_string()),
+                };
+                let count = match args.get(4).map(|option| String::from_utf8_lossy(option).to_uppercase()){
+                    Some(ref option) if option == "COUNT" => match parse_arg::<usize>(&args[5]){
+                        Some(count) => count,
+                        None => return Operation::Error("ERR value is not an integer or out of range".to_string()),
+                    },
+                    Some(_) => return Operation::Error("ERR syntax error".to_string()),
+                    None => usize::MAX,
+                };
+                Operation::XRange(args[1].clone(), start, end, count, reverse)
+            },
+            "XLEN" => Operation::XLen(args[1].clone()),
+            "XTRIM" =>{
+                let mut pos = 2;
+                match parse_stream_trim(args, &mut pos){
+                    Ok(trim) if pos == args.len() => Operation::XTrim(args[1].clone(), trim),
+                    Ok(_) => Operation::Error("ERR syntax error".to_string()),
+                    Err(msg) => Operation::Error(msg),
+                }
+            },
+            "XREAD" => self.parse_xread(args, false),
+            "XGROUP" => self.parse_xgroup(args),
+            "XREADGROUP" => self.parse_xread(args, true),
+            "XACK" =>{
+                let mut ids = Vec::with_capacity(args.len() - 3);
+                for id in &args[3..]{
+                    match parse_stream_id(id, 0){
+                        Some(id) => ids.push(id),
+                        None => return Operation::Error(INVALID_STREAM_ID.to_string()),
+                    }
+                }
+                Operation::XAck(args[1].clone(), args[2].clone(), ids)
+            },
+            "XPENDING" => self.parse_xpending(args),
+            "XCLAIM" => self.parse_xclaim(args),
+            "XAUTOCLAIM" => self.parse_xautoclaim(args),
+            "PUBLISH" => Operation::Publish(args[1].clone(), args[2].clone()),
+
+            _ => Operation::NotParsed,
+
+        }
+    }
+
+    // ZADD key [NX|XX] [CH] [INCR] score member [score member ...]
+    fn parse_zadd(&self, args: &[Vec<u8>]) -> Operation{
+        let mut options = ZAddOptions::default();
+        let mut pos = 2;
+        while pos < args.len(){
+            match String::from_utf8_lossy(&args[pos]).to_uppercase().as_ref(){
+                "NX" => options.nx = true,
+                "XX" => options.xx = true,
+                "CH" => options.ch = true,
+                "INCR" => options.incr = true,
+                _ => break,
+            }
+            pos += 1;
+        }
+        if options.nx && options.xx{
+            return Operation::Error("ERR XX and NX options at the same time are not compatible".to_string());
+        }
+        let pairs = &args[pos..];
+        if pairs.is_empty() || pairs.len() % 2 != 0{
+            return Operation::Error("ERR syntax error".to_string());
+        }
+        if options.incr && pairs.len() != 2{
+            return Operation::Error("ERR INCR option supports a single increment-element pair".to_string());
+        }
+        let mut members = Vec::with_capacity(pairs.len() / 2);
+        for pair in pairs.chunks(2){
+            match parse_score(&pair[0]){
+                Some(score) => members.push((pair[1].clone(), score)),
+                None => return Operation::Error("ERR value is not a valid float".to_string()),
+            }
+        }
+        Operation::ZAdd(args[1].clone(), members, options)
+    }
+
+    // ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count]
+    fn parse_zrange_by_score(&self, args: &[Vec<u8>]) -> Operation{
+        let (min, max) = match (parse_score_bound(&args[2]), parse_score_bound(&args[3])){
+            (Some(min), Some(max)) => (min, max),
+            _ => return Operation::Error("ERR min or max is not a float".to_string()),
+        };
+        let mut with_scores = false;
+        let (mut offset, mut count) = (0, usize::MAX);
+        let mut pos = 4;
+        while pos < args.len(){
+            match String::from_utf8_lossy(&args[pos]).to_uppercase().as_ref(){
+                "WITHSCORES" => with_scores = true,
+                "LIMIT" if pos + 2 < args.len() =>{
+                    let limit = (parse_arg::<i64>(&args[pos + 1]), parse_arg::<i64>(&args[pos + 2]));
+                    match limit{
+                        (Some(o), Some(c)) =>{
+                            // a negative offset matches nothing, a negative count means all
+                            offset = if o < 0{ usize::MAX }else{ o as usize };
+                            count = if c < 0{ usize::MAX }else{ c as usize };
+                        }
+                        _ => return Operation::Error("ERR value is not an integer or out of range".to_string()),
+                    }
+                    pos += 2;
+                }
+                _ => return Operation::Error("ERR syntax error".to_string()),
+            }
+            pos += 1;
+        }
+        Operation::ZRangeByScore(args[1].clone(), min, max, with_scores, offset, count)
+    }
+
+    // SET key value [NX|XX] [GET] [EX seconds|PX milliseconds|KEEPTTL]
+    // follows redis 7, which allows NX together with GET
+    fn parse_set(&self, args: &[Vec<u8>]) -> Operation{
+        let mut options = SetOptions::default();
+        let mut pos = 3;
+        while pos < args.len(){
+            let flag = String::from_utf8_lossy(&args[pos]).to_uppercase();
+            let has_expire = options.keep_ttl || options.expire.is_some();
+            match flag.as_ref(){
+                "NX" if !options.xx => options.nx = true,
+                "XX" if !options.nx => options.xx = true,
+                "GET" => options.get = true,
+                "KEEPTTL" if !has_expire => options.keep_ttl = true,
+                "EX" | "PX" if !has_expire && pos + 1 < args.len() =>{
+                    pos += 1;
+                    let ttl = match parse_arg::<i64>(&args[pos]){
+                        Some(ttl) => ttl,
+                        None => return Operation::Error("ERR value is not an integer or out of range".to_string()),
+                    };
+                    let ttl = if flag == "EX"{ ttl.checked_mul(1000) }else{ Some(ttl) };
+                    match ttl{
+                        Some(ttl) if ttl > 0 => options.expire = Some(ttl as u64),
+                        _ => return Operation::Error("ERR invalid expire time in 'set' command".to_string()),
+                    }
+                }
+                _ => return Operation::Error("ERR syntax error".to_string()),
+            }
+            pos += 1;
+        }
+        Operation::Set(args[1].clone(), args[2].clone(), options)
+    }
+
+    // XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold [LIMIT count]] *|id field value ...
+    fn parse_xadd(&self, args: &[Vec<u8>]) -> Operation{
+        let (mut no_create, mut trim) = (false, None);
+        let mut pos = 2;
+        while pos < args.len(){
+            match String::from_utf8_lossy(&args[pos]).to_uppercase().as_ref(){
+                "NOMKSTREAM" =>{
+                    no_create = true;
+                    pos += 1;
+                }
+                "MAXLEN" | "MINID" =>{
+                    match parse_stream_trim(args, &mut pos){
+                        Ok(parsed) => trim = Some(parsed),
+                        Err(msg) => return Operation::Error(msg),
+                    }
+                }
+                _ => break,
+            }
+        }
+        // the id, then at least one field with its value
+        if pos + 3 > args.len() || (args.len() - pos - 1) % 2 != 0{
+            return Operation::Error("ERR wrong number of arguments for 'xadd' command".to_string());
+        }
+        let id = match parse_new_stream_id(&args[pos]){
+            Some(NewStreamId::Id(StreamId::MIN)) =>
+                return Operation::Error("ERR The ID specified in XADD must be greater than 0-0".to_string()),
+            Some(id) => id,
+            None => return Operation::Error(INVALID_STREAM_ID.to_string()),
+        };
+        let fields = args[pos + 1..].chunks(2)
+            .map(|fv| (fv[0].clone(), fv[1].clone()))
+            .collect();
+        Operation::XAdd(args[1].clone(), id, fields, no_create, trim)
+    }
+
+    // XREAD [COUNT count] [BLOCK milliseconds] STREAMS key ... id ...
+    // XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK] STREAMS key ... id ...
+    fn parse_xread(&self, args: &[Vec<u8>], group: bool) -> Operation{
+        let (mut count, mut block, mut no_ack) = (usize::MAX, None, false);
+        let mut pos = 1;
+        if group{
+            if String::from_utf8_lossy(&args[1]).to_uppercase() != "GROUP"{
+                return Operation::Error("ERR syntax error".to_string());
+            }
+            pos = 4;
+        }
+        loop{
+            let option = match args.get(pos){
+                Some(option) => String::from_utf8_lossy(option).to_uppercase(),
+                None => return Operation::Error("ERR syntax error".to_string()),
+            };
+            match (option.as_ref(), args.get(pos + 1)){
+                ("STREAMS", _) => break,
+                ("NOACK", _) if group =>{
+                    no_ack = true;
+                    pos += 1;
+                    continue;
+                }
+                ("COUNT", Some(arg)) =>{
+                    count = match parse_arg::<i64>(arg){
+                        // like redis, a count below 1 reads everything
+                        Some(count) if count > 0 => count as usize,
+                        Some(_) => usize::MAX,
+                        None => return Operation::Error("ERR value is not an integer or out of range".to_string()),
+                    };
+                }
+                ("BLOCK", Some(arg)) =>{
+                    block = match parse_arg::<i64>(arg){
+                        Some(timeout) if timeout < 0 => return Operation::Error("ERR timeout is negative".to_string()),
+                        Some(timeout) => Some(timeout as u64),
+                        None => return Operation::Error("ERR timeout is not an integer or out of range".to_string()),
+                    };
+                }
+                _ => return Operation::Error("ERR syntax error".to_string()),
+            }
+            pos += 2;
+        }
+        let streams = &args[pos + 1..];
+        if streams.is_empty() || streams.len() % 2 != 0{
+            let (name, newest) = if group{ ("xreadgroup", ">") }else{ ("xread", "$") };
+            return Operation::Error(format!(
+                "ERR Unbalanced '{}' list of streams: for each stream key an ID or '{}' must be specified.", name, newest));
+        }
+        let (keys, ids) = streams.split_at(streams.len() / 2);
+        let mut after = Vec::with_capacity(ids.len());
+        for id in ids{
+            after.push(match (id.as_slice(), group){
+                (b"$", false) | (b">", true) => None,
+                (id, _) => match parse_stream_id(id, 0){
+                    Some(id) => Some(id),
+                    None => return Operation::Error(INVALID_STREAM_ID.to_string()),
+                },
+            });
+        }
+        if group{
+            Operation::XReadGroup(args[2].clone(), args[3].clone(), keys.to_vec(), after, count, block, no_ack)
+        }else{
+            Operation::XRead(keys.to_vec(), after, count, block)
+        }
+    }
+
+    // XGROUP CREATE key group id|$ [MKSTREAM] [ENTRIESREAD n], DESTROY key group,
+    // CREATECONSUMER key group consumer, DELCONSUMER key group consumer,
+    // SETID key group id|$ [ENTRIESREAD n]
+    fn parse_xgroup(&self, args: &[Vec<u8>]) -> Operation{
+        let subcommand = String::from_utf8_lossy(&args[1]).to_uppercase();
+        let arity_ok = match subcommand.as_ref(){
+            "CREATE" | "SETID" => args.len() >= 5,
+            "DESTROY" => args.len() == 4,
+            "CREATECONSUMER" | "DELCONSUMER" => args.len() == 5,
+            _ => false,
+        };
+        if !arity_ok{
+            return Operation::Error(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try XGROUP HELP.",
+                String::from_utf8_lossy(&args[1])));
+        }
+        let last_id = |arg: &[u8]| match arg{
+            b"$" => Ok(None),
+            _ => parse_stream_id(arg, 0).map(Some).ok_or_else(|| INVALID_STREAM_ID.to_string()),
+        };
+        let command = match subcommand.as_ref(){
+            "CREATE" | "SETID" =>{
+                let last_id = match last_id(&args[4]){
+                    Ok(last_id) => last_id,
+                    Err(msg) => return Operation::Error(msg),
+                };
+                let mut make_stream = false;
+                let mut pos = 5;
+                while pos < args.len(){
+                    match String::from_utf8_lossy(&args[pos]).to_uppercase().as_ref(){
+                        "MKSTREAM" if subcommand == "CREATE" => make_stream = true,
+                        // groups do not keep track of the entries read, the
+                        // number is only checked
+                        "ENTRIESREAD" if pos + 1 < args.len() =>{
+                            if parse_arg::<i64>(&args[pos + 1]).is_none(){
+                                return Operation::Error("ERR value is not an integer or out of range".to_string());
+                            }
+                            pos += 1;
+                        }
+                        _ => return Operation::Error("ERR syntax error".to_string()),
+                    }
+                    pos += 1;
+                }
+                if subcommand == "CREATE"{
+                    GroupCommand::Create(last_id, make_stream)
+                }else{
+                    GroupCommand::SetId(last_id)
+                }
+            }
+            "DESTROY" => GroupCommand::Destroy,
+            "CREATECONSUMER" => GroupCommand::CreateConsumer(args[4].clone()),
+            _ => GroupCommand::DelConsumer(args[4].clone()),
+        };
+        Operation::XGroup(args[2].clone(), args[3].clone(), command)
+    }
+
+    // XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+    fn parse_xpending(&self, args: &[Vec<u8>]) -> Operation{
+        if args.len() == 3{
+            return Operation::XPending(args[1].clone(), args[2].clone(), None);
+        }
+        let mut pos = 3;
+        let mut min_idle = 0;
+        if String::from_utf8_lossy(&args[pos]).to_uppercase() == "IDLE" && pos + 1 < args.len(){
+            min_idle = match parse_arg::<i64>(&args[pos + 1]){
+                Some(idle) => idle.max(0) as u64,
+                None => return Operation::Error("ERR value is not an integer or out of range".to_string()),
+            };
+            pos += 2;
+        }
+        if args.len() - pos != 3 && args.len() - pos != 4{
+            return Operation::Error("ERR syntax error".to_string());
+        }
+        let (start, end) = match (parse_range_bound(&args[pos], true), parse_range_bound(&args[pos + 1], false)){
+            (Some(start), Some(end)) => (start, end),
+            _ => return Operation::Error(INVALID_STREAM_ID.to_string()),
+        };
+        let count = match parse_arg::<i64>(&args[pos + 2]){
+            Some(count) => count.max(0) as usize,
+            None => return Operation::Error("ERR value is not an integer or out of range".to_string()),
+        };
+        let consumer = args.get(pos + 3).cloned();
+        Operation::XPending(args[1].clone(), args[2].clone(), Some((min_idle, start, end, count, consumer)))
+    }
+
+    // XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms] [TIME unix-time-ms]
+    // [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID id]
+    fn parse_xclaim(&self, args: &[Vec<u8>]) -> Operation{
+        let min_idle = match parse_arg::<i64>(&args[4]){
+            Some(idle) => idle.max(0) as u64,
+            None => return Operation::Error("ERR Invalid min-idle-time argument for XCLAIM".to_string()),
+        };
+        let mut pos = 5;
+        let mut ids = Vec::new();
+        while let Some(id) = args.get(pos).and_then(|arg| parse_stream_id(arg, 0)){
+            ids.push(id);
+            pos += 1;
+        }
+        if ids.is_empty(){
+            return Operation::Error(INVALID_STREAM_ID.to_string());
+        }
+        let mut options = ClaimOptions::default();
+        while pos < args.len(){
+            let option = String::from_utf8_lossy(&args[pos]).to_uppercase();
+            match (option.as_ref(), args.get(pos + 1)){
+                ("FORCE", _) => options.force = true,
+                ("JUSTID", _) => options.just_id = true,
+                ("IDLE", Some(arg)) | ("TIME", Some(arg)) | ("RETRYCOUNT", Some(arg)) =>{
+                    let value = match parse_arg::<i64>(arg){
+                        Some(value) => value.max(0) as u64,
+                        None => return Operation::Error(format!("ERR Invalid {} option argument for XCLAIM", option)),
+                    };
+                    match option.as_ref(){
+                        "IDLE" => options.idle = Some(value),
+                        "TIME" => options.time = Some(value),
+                        _ => options.retry_count = Some(value),
+                    }
+                    pos += 1;
+                }
+                ("LASTID", Some(arg)) =>{
+                    match parse_stream_id(arg, 0){
+                        Some(id) => options.last_id = Some(id),
+                        None => return Operation::Error(INVALID_STREAM_ID.to_string()),
+                    }
+                    pos += 1;
+                }
+                _ => return Operation::Error(format!("ERR Unrecognized XCLAIM option '{}'", String::from_utf8_lossy(&args[pos]))),
+            }
+            pos += 1;
+        }
+        Operation::XClaim(args[1].clone(), args[2].clone(), args[3].clone(), min_idle, ids, options)
+    }
+
+    // XAUTOCLAIM key group consumer min-idle-time start [COUNT count] [JUSTID]
+    fn parse_xautoclaim(&self, args: &[Vec<u8>]) -> Operation{
+        let min_idle = match parse_arg::<i64>(&args[4]){
+            Some(idle) => idle.max(0) as u64,
+            None => return Operation::Error("ERR Invalid min-idle-time argument for XAUTOCLAIM".to_string()),
+        };
+        let start = match parse_range_bound(&args[5], true){
+            Some(start) => start,
+            None => return Operation::Error(INVALID_STREAM_ID.to_string()),
+        };
+        let (mut count, mut just_id) = (DEFAULT_AUTOCLAIM_COUNT, false);
+        let mut pos = 6;
+        while pos < args.len(){
+            match (String::from_utf8_lossy(&args[pos]).to_uppercase().as_ref(), args.get(pos + 1)){
+                ("JUSTID", _) => just_id = true,
+                ("COUNT", Some(arg)) =>{
+                    count = match parse_arg::<i64>(arg){
+                        Some(count) if count > 0 && (count as usize) < usize::MAX / AUTOCLAIM_ATTEMPTS => count as usize,
+                        _ => return Operation::Error("ERR COUNT must be > 0".to_string()),
+                    };
+                    pos += 1;
+                }
+                _ => return Operation::Error("ERR syntax error".to_string()),
+            }
+            pos += 1;
+        }
+        Operation::XAutoClaim(args[1].clone(), args[2].clone(), args[3].clone(), min_idle, start, count, just_id)
+    }
+
+    fn set_with_options(&mut self, key: Vec<u8>, value: Vec<u8>, options: SetOptions) -> Reply{
+        let expire_at = match options.expire{
+            Some(ttl) => match now_millis().checked_add(ttl){
+                Some(at) => Some(at),
+                None => return Reply::Error("ERR invalid expire time in 'set' command".to_string()),
+            },
+            None => None,
+        };
+        let mut skipped = false;
+        let res = self.update_string(&key, |current|{
+            if (options.nx && current.is_some()) || (options.xx && current.is_none()){
+                skipped = true;
+                return Ok(None);
+            }
+            skipped = false;
+            let expire_at = if options.keep_ttl{ current.and_then(|c| c.1) }else{ expire_at };
+            Ok(Some((value.clone(), expire_at)))
+        });
+        let old = match res{
+            Ok(old) => old,
+            // a value of another type is replaced unless GET wants to return it
+            Err(ref reply) if !options.get && *reply == error_reply(DBError::WrongType) =>{
+                return self.replace_value(key, value, expire_at, options);
+            },
+            Err(reply) => return reply,
+        };
+        if options.get{
+            old.map_or(Reply::Nil, |(old_value, _)| Reply::Bulk(old_value))
+        }else if skipped{
+            Reply::Nil
+        }else{
+            Reply::ok()
+        }
+    }
+
+    fn replace_value(&mut self, key: Vec<u8>, value: Vec<u8>, expire_at: Option<u64>, options: SetOptions) -> Reply{
+        let mut db = self.db.write().unwrap();
+        let current_expire = match db.get_expire(key.clone()){
+            Ok(current_expire) => Some(current_expire),
+            Err(DBError::NotFound) => None,
+            Err(e) => return error_reply(e),
+        };
+        if (options.nx && current_expire.is_some()) || (options.xx && current_expire.is_none()){
+            return Reply::Nil;
+        }
+        let expire_at = if options.keep_ttl{ current_expire.and_then(|at| at) }else{ expire_at };
+        match db.raw_put_with_expire(key, value, expire_at){
+            Ok(_) => Reply::ok(),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn set(&mut self, kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Reply{
+        match self.db.write().unwrap().batch_put(kvs){
+            Ok(_) => Reply::ok(),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn get(&self, key: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().raw_get(key){
+            Ok(value) => Reply::Bulk(value),
+            Err(DBError::NotFound) => Reply::Nil,
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn mget(&self, keys: Vec<Vec<u8>>) -> Reply{
+        // MGET never fails on a key of another type, it reads as nil
+        match self.db.read().unwrap().batch_get(keys){
+            Ok(values) => Reply::Array(values.into_iter().map(|value| value.map_or(Reply::Nil, Reply::Bulk)).collect()),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn getset(&mut self, key: Vec<u8>, value: Vec<u8>) -> Reply{
+        // GETSET replaces the expiry like SET does
+        match self.update_string(&key, |_| Ok(Some((value.clone(), None)))){
+            Ok(old) => old.map_or(Reply::Nil, |(old_value, _)| Reply::Bulk(old_value)),
+            Err(reply) => reply,
+        }
+    }
+
+    fn strlen(&self, key: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().raw_get(key){
+            Ok(value) => Reply::Integer(value.len() as i64),
+            Err(DBError::NotFound) => Reply::Integer(0),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn append(&mut self, key: Vec<u8>, value: Vec<u8>) -> Reply{
+        let mut new_len = 0;
+        let res = self.update_string(&key, |current|{
+            // appending to a missing key works like SET
+            let (mut new_value, expire_at) = current.cloned().unwrap_or_default();
+            new_value.extend_from_slice(&value);
+            new_len = new_value.len();
+            Ok(Some((new_value, expire_at)))
+        });
+        match res{
+            Ok(_) => Reply::Integer(new_len as i64),
+            Err(reply) => reply,
+        }
+    }
+
+    fn get_range(&self, key: Vec<u8>, start: i32, end: i32) -> Reply{
+        let value = match self.db.read().unwrap().raw_get(key){
+            Ok(value) => value,
+            Err(DBError::NotFound) => return Reply::Bulk(Vec::new()),
+            Err(e) => return error_reply(e),
+        };
+        // both ends are inclusive and negative offsets count from the tail
+        let len = value.len() as i64;
+        let mut start_abs = if start < 0{ len + start as i64 }else{ start as i64 };
+        let mut end_abs = if end < 0{ len + end as i64 }else{ end as i64 };
+        if start_abs < 0{
+            start_abs = 0;
+        }
+        if end_abs < 0{
+            end_abs = 0;
+        }
+        if end_abs >= len{
+            end_abs = len - 1;
+        }
+        if len == 0 || start_abs > end_abs{
+            return Reply::Bulk(Vec::new());
+        }
+        Reply::Bulk(value[start_abs as usize..=end_abs as usize].to_vec())
+    }
+
+    fn set_range(&mut self, key: Vec<u8>, off: usize, data: Vec<u8>) -> Reply{
+        if data.is_empty(){
+            // nothing to write, redis leaves the key untouched
+            return self.strlen(key);
+        }
+        let end = match off.checked_add(data.len()){
+            Some(end) if end <= MAX_STRING_LEN => end,
+            _ => return Reply::Error("ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string()),
+        };
+        let mut new_len = 0;
+        let res = self.update_string(&key, |current|{
+            let (mut new_value, expire_at) = current.cloned().unwrap_or_default();
+            if end > new_value.len(){
+                // off execeeds len of old value and write "0" bytes
+                new_value.resize(end, 0);
+            }
+            new_value[off..end].copy_from_slice(&data);
+            new_len = new_value.len();
+            Ok(Some((new_value, expire_at)))
+        });
+        match res{
+            Ok(_) => Reply::Integer(new_len as i64),
+            Err(reply) => reply,
+        }
+    }
+
+    fn expire(&mut self, key: Vec<u8>, expire_at: i64) -> Reply{
+        // a deadline in the past makes the key invisible right away and the
+        // sweeper reclaims it, 0 is reserved for "no expiry" by the backends
+        let expire_at = if expire_at < 1{ 1 }else{ expire_at as u64 };
+        match self.db.write().unwrap().set_expire(key, Some(expire_at)){
+            Ok(true) => Reply::Integer(1),
+            Ok(false) => Reply::Integer(0),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn ttl(&self, key: Vec<u8>, in_millis: bool) -> Reply{
+        match self.db.read().unwrap().get_expire(key){
+            Ok(Some(expire_at)) =>{
+                let left = expire_at.saturating_sub(now_millis()) as i64;
+                if in_millis{
+                    Reply::Integer(left)
+                }else{
+                    Reply::Integer((left + 500) / 1000)
+                }
+            },
+            Ok(None) => Reply::Integer(-1),
+            Err(DBError::NotFound) => Reply::Integer(-2),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> Reply{
+        let mut db = self.db.write().unwrap();
+        match db.get_expire(key.clone()){
+            Ok(Some(_)) =>{},
+            Ok(None) | Err(DBError::NotFound) => return Reply::Integer(0),
+            Err(e) => return error_reply(e),
+        }
+        match db.set_expire(key, None){
+            Ok(true) => Reply::Integer(1),
+            Ok(false) => Reply::Integer(0),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn del(&mut self, keys: Vec<Vec<u8>>) -> Reply{
+        let mut db = self.db.write().unwrap();
+        let mut deleted = 0;
+        for key in keys{
+            match db.delete(key){
+                Ok(true) => deleted += 1,
+                Ok(false) =>{},
+                Err(e) => return error_reply(e),
+            }
+        }
+        Reply::Integer(deleted)
+    }
+
+    fn exists(&self, keys: Vec<Vec<u8>>) -> Reply{
+        let db = self.db.read().unwrap();
+        let mut found = 0;
+        for key in keys{
+            match db.exists(key){
+                Ok(true) => found += 1,
+                Ok(false) =>{},
+                Err(e) => return error_reply(e),
+            }
+        }
+        Reply::Integer(found)
+    }
+
+    fn key_type(&self, key: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().key_type(key){
+            Ok(key_type) => Reply::Status(key_type.name().to_string()),
+            Err(DBError::NotFound) => Reply::Status("none".to_string()),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn keys(&self, pattern: Vec<u8>) -> Reply{
+        let (start, end) = scan_bounds(None, &pattern);
+        let mut keys = Vec::new();
+        let res = walk_keys(&*self.db.read().unwrap(), start, end, |key, _|{
+            if glob_match(&pattern, &key){
+                keys.push(Reply::Bulk(key));
+            }
+        });
+        match res{
+            Ok(_) => Reply::Array(keys),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    // The cursor is the last key read, so the next call resumes right after
+    // it whatever was written in between, and on TiKV whatever regions were
+    // split or merged.
+    fn scan(&self, after: Option<Vec<u8>>, pattern: Option<Vec<u8>>, count: usize, key_type: Option<String>) -> Reply{
+        let (start, end) = scan_bounds(after, pattern.as_deref().unwrap_or(b""));
+        let keys = match self.db.read().unwrap().scan_keys(start, end, count){
+            Ok(keys) => keys,
+            Err(e) => return error_reply(e),
+        };
+        let cursor = match keys.last(){
+            Some((last, _)) if keys.len() == count => encode_cursor(last),
+            _ => b"0".to_vec(),
+        };
+        let items = keys.into_iter()
+            .filter(|(key, _)| pattern.as_ref().map_or(true, |pattern| glob_match(pattern, key)))
+            .filter(|(_, t)| key_type.as_ref().map_or(true, |key_type| t.name() == key_type))
+            .map(|(key, _)| Reply::Bulk(key))
+            .collect();
+        Reply::Array(vec![Reply::Bulk(cursor), Reply::Array(items)])
+    }
+
+    fn dbsize(&self) -> Reply{
+        let mut size = 0;
+        match walk_keys(&*self.db.read().unwrap(), Bound::Unbounded, Bound::Unbounded, |_, _| size += 1){
+            Ok(_) => Reply::Integer(size),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn rename(&mut self, key: Vec<u8>, new_key: Vec<u8>, nx: bool) -> Reply{
+        let mut db = self.db.write().unwrap();
+        let exists = match db.exists(key.clone()){
+            Ok(exists) => exists,
+            Err(e) => return error_reply(e),
+        };
+        if !exists{
+            return error_reply(DBError::NotFound);
+        }
+        if nx{
+            match db.exists(new_key.clone()){
+                Ok(true) => return Reply::Integer(0),
+                Ok(false) =>{},
+                Err(e) => return error_reply(e),
+            }
+        }
+        if key != new_key{
+            if let Err(e) = db.rename(key, new_key){
+                return error_reply(e);
+            }
+        }
+        if nx{
+            Reply::Integer(1)
+        }else{
+            Reply::ok()
+        }
+    }
+
+    fn incr_by(&mut self, key: Vec<u8>, delta: i64) -> Reply{
+        let mut new_value = 0;
+        let res = self.update_string(&key, |current|{
+            let (value, expire_at) = match current{
+                Some((value, expire_at)) => match parse_arg::<i64>(value){
+                    Some(value) => (value, *expire_at),
+                    None => return Err(Reply::Error("ERR value is not an integer or out of range".to_string())),
+                },
+                None => (0, None),
+            };
+            new_value = match value.checked_add(delta){
+                Some(new_value) => new_value,
+                None => return Err(Reply::Error("ERR increment or decrement would overflow".to_string())),
+            };
+            Ok(Some((new_value.to_string().into_bytes(), expire_at)))
+        });
+        match res{
+            Ok(_) => Reply::Integer(new_value),
+            Err(reply) => reply,
+        }
+    }
+
+    fn incr_by_float(&mut self, key: Vec<u8>, delta: f64) -> Reply{
+        let mut new_value = Vec::new();
+        let res = self.update_string(&key, |current|{
+            let (value, expire_at) = match current{
+                Some((value, expire_at)) => match parse_float(value){
+                    Some(value) => (value, *expire_at),
+                    None => return Err(Reply::Error("ERR value is not a valid float".to_string())),
+                },
+                None => (0.0, None),
+            };
+            let sum = value + delta;
+            if !sum.is_finite(){
+                return Err(Reply::Error("ERR increment would produce NaN or Infinity".to_string()));
+            }
+            new_value = float_sum_string(value, delta);
+            Ok(Some((new_value.clone(), expire_at)))
+        });
+        match res{
+            Ok(_) => Reply::Bulk(new_value),
+            Err(reply) => reply,
+        }
+    }
+
+    fn push(&mut self, key: Vec<u8>, values: Vec<Vec<u8>>, left: bool, only_existing: bool) -> Reply{
+        match self.db.write().unwrap().list_push(key, values, left, only_existing){
+            Ok(len) => Reply::Integer(len as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn pop(&mut self, key: Vec<u8>, left: bool, count: Option<usize>) -> Reply{
+        let popped = match self.db.write().unwrap().list_pop(key, left, count.unwrap_or(1)){
+            Ok(popped) => popped,
+            Err(e) => return error_reply(e),
+        };
+        match count{
+            // a missing key is a null array, an explicit count of 0 an empty one
+            Some(count) if popped.is_empty() && count > 0 => Reply::NilArray,
+            Some(_) => Reply::Array(popped.into_iter().map(Reply::Bulk).collect()),
+            None => popped.into_iter().next().map_or(Reply::Nil, Reply::Bulk),
+        }
+    }
+
+    // Pops from the first of `keys` holding a list, replying with the key too.
+    fn bpop(&mut self, keys: Vec<Vec<u8>>, left: bool) -> Reply{
+        let mut db = self.db.write().unwrap();
+        for key in keys{
+            match db.list_pop(key.clone(), left, 1){
+                Ok(popped) =>{
+                    if let Some(value) = popped.into_iter().next(){
+                        return Reply::Array(vec![Reply::Bulk(key), Reply::Bulk(value)]);
+                    }
+                }
+                Err(e) => return error_reply(e),
+            }
+        }
+        Reply::NilArray
+    }
+
+    fn lmove(&mut self, source: Vec<u8>, destination: Vec<u8>, from_left: bool, to_left: bool) -> Reply{
+        let mut db = self.db.write().unwrap();
+        // nothing is popped when the destination holds another type
+        match db.key_type(destination.clone()){
+            Ok(KeyType::List) | Err(DBError::NotFound) => {},
+            Ok(_) => return error_reply(DBError::WrongType),
+            Err(e) => return error_reply(e),
+        }
+        let value = match db.list_pop(source, from_left, 1){
+            Ok(popped) => match popped.into_iter().next(){
+                Some(value) => value,
+                None => return Reply::Nil,
+            },
+            Err(e) => return error_reply(e),
+        };
+        match db.list_push(destination, vec![value.clone()], to_left, false){
+            Ok(_) => Reply::Bulk(value),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn llen(&self, key: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().list_len(key){
+            Ok(len) => Reply::Integer(len as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn lrange(&self, key: Vec<u8>, start: i64, stop: i64) -> Reply{
+        match self.db.read().unwrap().list_range(key, start, stop){
+            Ok(values) => Reply::Array(values.into_iter().map(Reply::Bulk).collect()),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn lindex(&self, key: Vec<u8>, index: i64) -> Reply{
+        match self.db.read().unwrap().list_index(key, index){
+            Ok(value) => value.map_or(Reply::Nil, Reply::Bulk),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn lset(&mut self, key: Vec<u8>, index: i64, value: Vec<u8>) -> Reply{
+        match self.db.write().unwrap().list_set(key, index, value){
+            Ok(_) => Reply::ok(),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn ltrim(&mut self, key: Vec<u8>, start: i64, stop: i64) -> Reply{
+        match self.db.write().unwrap().list_trim(key, start, stop){
+            Ok(_) => Reply::ok(),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn lrem(&mut self, key: Vec<u8>, count: i64, value: Vec<u8>) -> Reply{
+        match self.db.write().unwrap().list_rem(key, count, value){
+            Ok(removed) => Reply::Integer(removed as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn linsert(&mut self, key: Vec<u8>, before: bool, pivot: Vec<u8>, value: Vec<u8>) -> Reply{
+        match self.db.write().unwrap().list_insert(key, before, pivot, value){
+            Ok(Some(len)) => Reply::Integer(len as i64),
+            Ok(None) => Reply::Integer(-1),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn hset(&mut self, key: Vec<u8>, fields: Vec<FieldValue>, reply_ok: bool) -> Reply{
+        match self.db.write().unwrap().hash_set(key, fields){
+            Ok(_) if reply_ok => Reply::ok(),
+            Ok(added) => Reply::Integer(added as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn hget(&self, key: Vec<u8>, field: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().hash_get(key, field){
+            Ok(value) => value.map_or(Reply::Nil, Reply::Bulk),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn hmget(&self, key: Vec<u8>, fields: Vec<Vec<u8>>) -> Reply{
+        let db = self.db.read().unwrap();
+        let mut values = Vec::with_capacity(fields.len());
+        for field in fields{
+            match db.hash_get(key.clone(), field){
+                Ok(value) => values.push(value.map_or(Reply::Nil, Reply::Bulk)),
+                Err(e) => return error_reply(e),
+            }
+        }
+        Reply::Array(values)
+    }
+
+    fn hdel(&mut self, key: Vec<u8>, fields: Vec<Vec<u8>>) -> Reply{
+        match self.db.write().unwrap().hash_del(key, fields){
+            Ok(removed) => Reply::Integer(removed as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn hget_all(&self, key: Vec<u8>, with_fields: bool, with_values: bool) -> Reply{
+        let fields = match self.db.read().unwrap().hash_get_all(key){
+            Ok(fields) => fields,
+            Err(e) => return error_reply(e),
+        };
+        let mut items = Vec::new();
+        for (field, value) in fields{
+            if with_fields{
+                items.push(Reply::Bulk(field));
+            }
+            if with_values{
+                items.push(Reply::Bulk(value));
+            }
+        }
+        Reply::Array(items)
+    }
+
+    fn hlen(&self, key: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().hash_len(key){
+            Ok(len) => Reply::Integer(len as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn hincr_by(&mut self, key: Vec<u8>, field: Vec<u8>, delta: i64) -> Reply{
+        // the write lock keeps the read and the write together
+        let mut db = self.db.write().unwrap();
+        let value = match db.hash_get(key.clone(), field.clone()){
+            Ok(Some(value)) => match parse_arg::<i64>(&value){
+                Some(value) => value,
+                None => return Reply::Error("ERR hash value is not an integer".to_string()),
+            },
+            Ok(None) => 0,
+            Err(e) => return error_reply(e),
+        };
+        let new_value = match value.checked_add(delta){
+            Some(new_value) => new_value,
+            None => return Reply::Error("ERR increment or decrement would overflow".to_string()),
+        };
+        match db.hash_set(key, vec![(field, new_value.to_string().into_bytes())]){
+            Ok(_) => Reply::Integer(new_value),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn hscan(&self, key: Vec<u8>, after: Option<Vec<u8>>, pattern: Option<Vec<u8>>, count: usize) -> Reply{
+        let fields = match self.db.read().unwrap().hash_scan(key, after, count){
+            Ok(fields) => fields,
+            Err(e) => return error_reply(e),
+        };
+        let cursor = match fields.last(){
+            Some((last, _)) if fields.len() == count => encode_cursor(last),
+            _ => b"0".to_vec(),
+        };
+        let mut items = Vec::new();
+        for (field, value) in fields{
+            // MATCH filters the page after it was read, like redis does
+            if pattern.as_ref().map_or(true, |pattern| glob_match(pattern, &field)){
+                items.push(Reply::Bulk(field));
+                items.push(Reply::Bulk(value));
+            }
+        }
+        Reply::Array(vec![Reply::Bulk(cursor), Reply::Array(items)])
+    }
+
+    fn sadd(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> Reply{
+        match self.db.write().unwrap().set_add(key, members){
+            Ok(added) => Reply::Integer(added as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn srem(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> Reply{
+        match self.db.write().unwrap().set_rem(key, members){
+            Ok(removed) => Reply::Integer(removed as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn smembers(&self, key: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().set_members(key){
+            Ok(members) => Reply::Array(members.into_iter().map(Reply::Bulk).collect()),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn sismember(&self, key: Vec<u8>, member: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().set_is_member(key, member){
+            Ok(found) => Reply::Integer(found as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn scard(&self, key: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().set_card(key){
+            Ok(card) => Reply::Integer(card as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn combine_sets(&mut self, op: SetOp, keys: Vec<Vec<u8>>, dest: Option<Vec<u8>>) -> Reply{
+        let dest = match dest{
+            Some(dest) => dest,
+            None =>{
+                return match combine(&*self.db.read().unwrap(), op, keys){
+                    Ok(members) => Reply::Array(members.into_iter().map(Reply::Bulk).collect()),
+                    Err(e) => error_reply(e),
+                };
+            }
+        };
+        // the *STORE variants read and write under one lock
+        let mut db = self.db.write().unwrap();
+        let members = match combine(&*db, op, keys){
+            Ok(members) => members,
+            Err(e) => return error_reply(e),
+        };
+        let card = members.len();
+        // the destination is replaced whatever its type, or removed when empty
+        if let Err(e) = db.delete(dest.clone()){
+            return error_reply(e);
+        }
+        if card > 0{
+            if let Err(e) = db.set_add(dest, members.into_iter().collect()){
+                return error_reply(e);
+            }
+        }
+        Reply::Integer(card as i64)
+    }
+
+    fn spop(&mut self, key: Vec<u8>, count: Option<usize>) -> Reply{
+        let mut db = self.db.write().unwrap();
+        let members = match db.set_random_members(key.clone(), count.unwrap_or(1)){
+            Ok(members) => members,
+            Err(e) => return error_reply(e),
+        };
+        if !members.is_empty(){
+            if let Err(e) = db.set_rem(key, members.clone()){
+                return error_reply(e);
+            }
+        }
+        match count{
+            Some(_) => Reply::Array(members.into_iter().map(Reply::Bulk).collect()),
+            None => members.into_iter().next().map_or(Reply::Nil, Reply::Bulk),
+        }
+    }
+
+    fn srandmember(&self, key: Vec<u8>, count: Option<i64>) -> Reply{
+        let members = match count{
+            Some(count) if count < 0 =>{
+                // members may repeat, each one is drawn on its own from the
+                // members read once, after the lock is released
+                let set = self.db.read().unwrap().set_members(key);
+                set.map(|set|{
+                    if set.is_empty(){
+                        return Vec::new();
+                    }
+                    (0..-count).map(|_| set[(random_u64() % set.len() as u64) as usize].clone()).collect()
+                })
+            },
+            Some(count) => self.db.read().unwrap().set_random_members(key, count as usize),
+            None => self.db.read().unwrap().set_random_members(key, 1),
+        };
+        match (members, count){
+            (Ok(members), Some(_)) => Reply::Array(members.into_iter().map(Reply::Bulk).collect()),
+            (Ok(members), None) => members.into_iter().next().map_or(Reply::Nil, Reply::Bulk),
+            (Err(e), _) => error_reply(e),
+        }
+    }
+
+    fn zadd(&mut self, key: Vec<u8>, members: Vec<ScoredMember>, options: ZAddOptions) -> Reply{
+        if options.incr{
+            let (member, delta) = members.into_iter().next().unwrap();
+            return self.zincr(key, member, delta, options.nx, options.xx);
+        }
+        match self.db.write().unwrap().zset_add(key, members, options.nx, options.xx){
+            Ok((added, changed)) if options.ch => Reply::Integer((added + changed) as i64),
+            Ok((added, _)) => Reply::Integer(added as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn zincr_by(&mut self, key: Vec<u8>, delta: f64, member: Vec<u8>) -> Reply{
+        self.zincr(key, member, delta, false, false)
+    }
+
+    // the score is read and written under one lock, Nil when NX or XX skip the member
+    fn zincr(&mut self, key: Vec<u8>, member: Vec<u8>, delta: f64, nx: bool, xx: bool) -> Reply{
+        let mut db = self.db.write().unwrap();
+        let current = match db.zset_score(key.clone(), member.clone()){
+            Ok(current) => current,
+            Err(e) => return error_reply(e),
+        };
+        if (nx && current.is_some()) || (xx && current.is_none()){
+            return Reply::Nil;
+        }
+        let score = current.unwrap_or(0.0) + delta;
+        if score.is_nan(){
+            return Reply::Error("ERR resulting score is not a number (NaN)".to_string());
+        }
+        match db.zset_add(key, vec![(member, score)], false, false){
+            Ok(_) => Reply::Bulk(format_score(score)),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn zrange(&self, key: Vec<u8>, start: i64, stop: i64, with_scores: bool) -> Reply{
+        match self.db.read().unwrap().zset_range(key, start, stop){
+            Ok(members) => scored_reply(members, with_scores),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn zrange_by_score(&self, key: Vec<u8>, min: Bound<f64>, max: Bound<f64>, with_scores: bool,
+                       offset: usize, count: usize) -> Reply{
+        match self.db.read().unwrap().zset_range_by_score(key, min, max, offset, count){
+            Ok(members) => scored_reply(members, with_scores),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn zrank(&self, key: Vec<u8>, member: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().zset_rank(key, member){
+            Ok(Some(rank)) => Reply::Integer(rank as i64),
+            Ok(None) => Reply::Nil,
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn zscore(&self, key: Vec<u8>, member: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().zset_score(key, member){
+            Ok(Some(score)) => Reply::Bulk(format_score(score)),
+            Ok(None) => Reply::Nil,
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn zrem(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> Reply{
+        match self.db.write().unwrap().zset_rem(key, members){
+            Ok(removed) => Reply::Integer(removed as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn zcard(&self, key: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().zset_card(key){
+            Ok(card) => Reply::Integer(card as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn xadd(&mut self, key: Vec<u8>, id: NewStreamId, fields: Vec<FieldValue>, no_create: bool, trim: Option<StreamTrim>)
+        -> Reply{
+        let mut db = self.db.write().unwrap();
+        if no_create{
+            match db.key_type(key.clone()){
+                Ok(_) => {},
+                Err(DBError::NotFound) => return Reply::Nil,
+                Err(e) => return error_reply(e),
+            }
+        }
+        let id = match db.stream_add(key.clone(), id, fields){
+            Ok(Some(id)) => id,
+            Ok(None) => return Reply::Error(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item".to_string()),
+            Err(e) => return error_reply(e),
+        };
+        if let Some(trim) = trim{
+            if let Err(e) = db.stream_trim(key, trim){
+                return error_reply(e);
+            }
+        }
+        Reply::Bulk(id.to_string().into_bytes())
+    }
+
+    fn xrange(&self, key: Vec<u8>, start: StreamId, end: StreamId, count: usize, reverse: bool) -> Reply{
+        match self.db.read().unwrap().stream_range(key, start, end, count, reverse){
+            Ok(entries) => entries_reply(entries),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn xlen(&self, key: Vec<u8>) -> Reply{
+        match self.db.read().unwrap().stream_len(key){
+            Ok(len) => Reply::Integer(len as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn xtrim(&mut self, key: Vec<u8>, trim: StreamTrim) -> Reply{
+        match self.db.write().unwrap().stream_trim(key, trim){
+            Ok(trimmed) => Reply::Integer(trimmed as i64),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    // Entries after the given id of every stream, only the streams that have
+    // some. `$` finds nothing here, blocking pins it first.
+    fn xread(&self, keys: Vec<Vec<u8>>, ids: Vec<Option<StreamId>>, count: usize) -> Reply{
+        let db = self.db.read().unwrap();
+        let mut streams = Vec::new();
+        for (key, after) in keys.into_iter().zip(ids){
+            let start = match after.and_then(|after| after.next()){
+                Some(start) => start,
+                None => continue,
+            };
+            match db.stream_range(key.clone(), start, StreamId::MAX, count, false){
+                Ok(ref entries) if entries.is_empty() => {},
+                Ok(entries) => streams.push(Reply::Array(vec![Reply::Bulk(key), entries_reply(entries)])),
+                Err(e) => return error_reply(e),
+            }
+        }
+        if streams.is_empty(){
+            Reply::NilArray
+        }else{
+            Reply::Array(streams)
+        }
+    }
+
+    fn xgroup(&mut self, key: Vec<u8>, group: Vec<u8>, command: GroupCommand) -> Reply{
+        let mut db = self.db.write().unwrap();
+        let res = match command{
+            GroupCommand::Create(last_id, make_stream) =>{
+                db.stream_create_group(key.clone(), group.clone(), last_id, make_stream).map(|created| if created{
+                    Reply::ok()
+                }else{
+                    Reply::Error("BUSYGROUP Consumer Group name already exists".to_string())
+                })
+            }
+            GroupCommand::Destroy =>
+                db.stream_destroy_group(key.clone(), group.clone()).map(|destroyed| Reply::Integer(destroyed as i64)),
+            GroupCommand::CreateConsumer(consumer) =>
+                db.stream_create_consumer(key.clone(), group.clone(), consumer).map(|created| Reply::Integer(created as i64)),
+            GroupCommand::DelConsumer(consumer) =>
+                db.stream_delete_consumer(key.clone(), group.clone(), consumer).map(|pending| Reply::Integer(pending as i64)),
+            GroupCommand::SetId(last_id) =>{
+                let last_id = match last_id{
+                    Some(last_id) => Ok(last_id),
+                    None => db.stream_last_id(key.clone()).map(|last_id| last_id.unwrap_or(StreamId::MIN)),
+                };
+                last_id.and_then(|last_id| db.stream_set_group_last_id(key.clone(), group.clone(), last_id))
+                    .map(|_| Reply::ok())
+            }
+        };
+        match res{
+            Ok(reply) => reply,
+            // either the stream or the group is missing
+            Err(DBError::NotFound) => match db.key_type(key.clone()){
+                Ok(_) => Reply::Error(format!("NOGROUP No such consumer group '{}' for key name '{}'",
+                                              String::from_utf8_lossy(&group), String::from_utf8_lossy(&key))),
+                Err(DBError::NotFound) => Reply::Error("ERR The XGROUP subcommand requires the key to exist. \
+                    Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically."
+                    .to_string()),
+                Err(e) => error_reply(e),
+            },
+            Err(e) => error_reply(e),
+        }
+    }
+
+    // Entries of every stream for a consumer of the group. For `>` those not
+    // delivered to the group yet, only the streams that have some. For an id
+    // the history of the consumer, what it has pending after that id.
+    fn xread_group(&mut self, group: Vec<u8>, consumer: Vec<u8>, keys: Vec<Vec<u8>>, ids: Vec<Option<StreamId>>,
+                   count: usize, no_ack: bool) -> Reply{
+        let mut db = self.db.write().unwrap();
+        // every group is checked before anything is delivered
+        for key in &keys{
+            match db.stream_group_last_id(key.clone(), group.clone()){
+                Ok(_) => {},
+                Err(DBError::NotFound) => return no_group_reply(key, &group, " in XREADGROUP with GROUP option"),
+                Err(e) => return error_reply(e),
+            }
+        }
+        let now = now_millis();
+        let mut streams = Vec::new();
+        for (key, after) in keys.into_iter().zip(ids){
+            let res = match after{
+                None => deliver_new(&mut *db, &key, &group, &consumer, count, no_ack, now)
+                    .map(|entries| if entries.is_empty(){ None }else{ Some(entries_reply(entries)) }),
+                Some(after) => deliver_pending(&mut *db, &key, &group, &consumer, after, count, now)
+                    .map(|entries| Some(Reply::Array(entries.into_iter().map(|(id, fields)| Reply::Array(vec![
+                        Reply::Bulk(id.to_string().into_bytes()),
+                        // deleted from the stream while pending, a null array like redis
+                        fields.map_or(Reply::NilArray, fields_reply),
+                    ])).collect()))),
+            };
+            match res{
+                Ok(Some(entries)) => streams.push(Reply::Array(vec![Reply::Bulk(key), entries])),
+                Ok(None) => {},
+                Err(e) => return error_reply(e),
+            }
+        }
+        if streams.is_empty(){
+            Reply::NilArray
+        }else{
+            Reply::Array(streams)
+        }
+    }
+
+    fn xack(&mut self, key: Vec<u8>, group: Vec<u8>, ids: Vec<StreamId>) -> Reply{
+        match self.db.write().unwrap().stream_ack(key, group, ids){
+            Ok(acked) => Reply::Integer(acked as i64),
+            // nothing is pending without the group
+            Err(DBError::NotFound) => Reply::Integer(0),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    fn xpending(&self, key: Vec<u8>, group: Vec<u8>, range: Option<PendingRange>) -> Reply{
+        let db = self.db.read().unwrap();
+        let (min_idle, start, end, count, consumer) = match range{
+            Some(range) => range,
+            None => return match db.stream_pending(key.clone(), group.clone(), StreamId::MIN, StreamId::MAX, usize::MAX, None){
+                Ok(pending) => pending_summary(pending),
+                Err(DBError::NotFound) => no_group_reply(&key, &group, ""),
+                Err(e) => error_reply(e),
+            },
+        };
+        // the idle time is only known once read, IDLE filters all of them
+        let limit = if min_idle > 0{ usize::MAX }else{ count };
+        let pending = match db.stream_pending(key.clone(), group.clone(), start, end, limit, consumer){
+            Ok(pending) => pending,
+            Err(DBError::NotFound) => return no_group_reply(&key, &group, ""),
+            Err(e) => return error_reply(e),
+        };
+        let now = now_millis();
+        Reply::Array(pending.into_iter()
+            .map(|entry| (now.saturating_sub(entry.delivered_at), entry))
+            .filter(|(idle, _)| *idle >= min_idle)
+            .take(count)
+            .map(|(idle, entry)| Reply::Array(vec![
+                Reply::Bulk(entry.id.to_string().into_bytes()),
+                Reply::Bulk(entry.consumer),
+                Reply::Integer(idle as i64),
+                Reply::Integer(entry.deliveries as i64),
+            ])).collect())
+    }
+
+    fn xclaim(&mut self, key: Vec<u8>, group: Vec<u8>, consumer: Vec<u8>, min_idle: u64, ids: Vec<StreamId>,
+              options: ClaimOptions) -> Reply{
+        let mut db = self.db.write().unwrap();
+        match claim_ids(&mut *db, &key, &group, &consumer, min_idle, ids, &options){
+            Ok(entries) if options.just_id => ids_reply(entries.into_iter().map(|(id, _)| id).collect()),
+            Ok(entries) => entries_reply(entries),
+            Err(DBError::NotFound) => no_group_reply(&key, &group, ""),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    // The cursor to go on from, 0-0 once every pending entry was looked at,
+    // the claimed entries and the ids deleted from the stream meanwhile.
+    fn xautoclaim(&mut self, key: Vec<u8>, group: Vec<u8>, consumer: Vec<u8>, min_idle: u64, start: StreamId, count: usize,
+                  just_id: bool) -> Reply{
+        let mut db = self.db.write().unwrap();
+        match auto_claim(&mut *db, &key, &group, &consumer, min_idle, start, count, just_id){
+            Ok((next, entries, deleted)) =>{
+                let claimed = if just_id{
+                    ids_reply(entries.into_iter().map(|(id, _)| id).collect())
+                }else{
+                    entries_reply(entries)
+                };
+                Reply::Array(vec![Reply::Bulk(next.to_string().into_bytes()), claimed, ids_reply(deleted)])
+            }
+            Err(DBError::NotFound) => no_group_reply(&key, &group, ""),
+            Err(e) => error_reply(e),
+        }
+    }
+
+    // Read-modify-write of a string key. `update` sees the current value and
+    // expiry and returns what to store; the write is a compare-and-swap on
+    // what was read, retried until no other writer got in between. The write
+    // lock makes the first attempt win for the in-memory backend, the swap
+    // covers other servers sharing the same TiKV cluster.
+    // Returns the value seen by the attempt that landed. A lost swap waits a
+    // little longer each time, without the lock, before reading again.
+    fn update_string<F>(&mut self, key: &[u8], mut update: F) -> Result<Option<StringEntry>, Reply>
+    where F: FnMut(Option<&StringEntry>) -> Result<Option<StringEntry>, Reply>{
+        let mut delay = CAS_RETRY_BASE_DELAY;
+        loop{
+            let mut db = self.db.write().unwrap();
+            let current = match db.raw_get_with_expire(key.to_vec()){
+                Ok(current) => Some(current),
+                Err(DBError::NotFound) => None,
+                Err(e) => return Err(error_reply(e)),
+            };
+            let (value, expire_at) = match update(current.as_ref())?{
+                Some(new) => new,
+                // nothing to write
+                None => return Ok(current),
+            };
+            match db.raw_compare_and_swap(key.to_vec(), current.clone(), value, expire_at){
+                Ok(true) => return Ok(current),
+                Ok(false) => (),
+                Err(e) => return Err(error_reply(e)),
+            }
+            drop(db);
+            thread::sleep(delay);
+            delay = (delay * 2).min(CAS_RETRY_MAX_DELAY);
+        }
+    }
+}
+
+// SINTER, SUNION and SDIFF over the members of `keys`, missing keys count as empty sets
+fn combine<E: DB>(db: &E, op: SetOp, keys: Vec<Vec<u8>>) -> Result<BTreeSet<Vec<u8>>, DBError>{
+    let mut result: Option<BTreeSet<Vec<u8>>> = None;
+    for key in keys{
+        let members: BTreeSet<Vec<u8>> = db.set_members(key)?.into_iter().collect();
+        result = Some(match result{
+            None => members,
+            Some(result) => match op{
+                SetOp::Inter => result.intersection(&members).cloned().collect(),
+                SetOp::Union => result.union(&members).cloned().collect(),
+                SetOp::Diff => result.difference(&members).cloned().collect(),
+            },
+        });
+    }
+    Ok(result.unwrap_or_default())
+}
+
+// XREADGROUP with `>`: the entries the group has not seen yet go to the
+// consumer, waiting in its pending entries for XACK unless NOACK.
+fn deliver_new<E: DB>(db: &mut E, key: &[u8], group: &[u8], consumer: &[u8], count: usize, no_ack: bool, now: u64)
+    -> Result<Vec<StreamEntry>, DBError>{
+    let last_id = db.stream_group_last_id(key.to_vec(), group.to_vec())?;
+    db.stream_create_consumer(key.to_vec(), group.to_vec(), consumer.to_vec())?;
+    let entries = match last_id.next(){
+        Some(start) => db.stream_range(key.to_vec(), start, StreamId::MAX, count, false)?,
+        None => Vec::new(),
+    };
+    if let Some((last_id, _)) = entries.last(){
+        db.stream_set_group_last_id(key.to_vec(), group.to_vec(), *last_id)?;
+    }
+    if !no_ack && !entries.is_empty(){
+        let pending = entries.iter().map(|(id, _)| PendingEntry{
+            id: *id,
+            consumer: consumer.to_vec(),
+            delivered_at: now,
+            deliveries: 1,
+        }).collect();
+        db.stream_set_pending(key.to_vec(), group.to_vec(), pending)?;
+    }
+    Ok(entries)
+}
+
+// XREADGROUP with an id: the entries pending for the consumer after it, which
+// count as delivered again. Entries deleted from the stream have no fields.
+fn deliver_pending<E: DB>(db: &mut E, key: &[u8], group: &[u8], consumer: &[u8], after: StreamId, count: usize, now: u64)
+    -> Result<Vec<(StreamId, Option<Vec<FieldValue>>)>, DBError>{
+    let start = match after.next(){
+        Some(start) => start,
+        None => return Ok(Vec::new()),
+    };
+    let pending = db.stream_pending(key.to_vec(), group.to_vec(), start, StreamId::MAX, count, Some(consumer.to_vec()))?;
+    let mut entries = Vec::with_capacity(pending.len());
+    let mut delivered = Vec::with_capacity(pending.len());
+    for mut entry in pending{
+        let fields = db.stream_range(key.to_vec(), entry.id, entry.id, 1, false)?.pop().map(|(_, fields)| fields);
+        // deleted entries are reported as nil without counting a delivery
+        let found = fields.is_some();
+        entries.push((entry.id, fields));
+        if found{
+            entry.delivered_at = now;
+            entry.deliveries += 1;
+            delivered.push(entry);
+        }
+    }
+    db.stream_set_pending(key.to_vec(), group.to_vec(), delivered)?;
+    Ok(entries)
+}
+
+// XCLAIM: the entries among `ids` pending for at least `min_idle`
+// milliseconds go to `consumer`, with FORCE also those not pending at all.
+fn claim_ids<E: DB>(db: &mut E, key: &[u8], group: &[u8], consumer: &[u8], min_idle: u64, ids: Vec<StreamId>,
+                    options: &ClaimOptions) -> Result<Vec<StreamEntry>, DBError>{
+    let group_last_id = db.stream_group_last_id(key.to_vec(), group.to_vec())?;
+    if let Some(last_id) = options.last_id.filter(|last_id| *last_id > group_last_id){
+        db.stream_set_group_last_id(key.to_vec(), group.to_vec(), last_id)?;
+    }
+    let now = now_millis();
+    let delivered_at = match (options.time, options.idle){
+        (Some(time), _) => time,
+        (None, Some(idle)) => now.saturating_sub(idle),
+        (None, None) => now,
+    };
+    let mut claims: Vec<PendingEntry> = Vec::with_capacity(ids.len());
+    for id in ids{
+        if claims.iter().any(|claim| claim.id == id){
+            continue;
+        }
+        let mut entry = match db.stream_pending(key.to_vec(), group.to_vec(), id, id, 1, None)?.pop(){
+            Some(ref entry) if now.saturating_sub(entry.delivered_at) < min_idle => continue,
+            Some(entry) => entry,
+            // FORCE only claims what the stream still has
+            None if options.force && !db.stream_range(key.to_vec(), id, id, 1, false)?.is_empty() =>
+                PendingEntry{ id, consumer: consumer.to_vec(), delivered_at, deliveries: 0 },
+            None => continue,
+        };
+        entry.consumer = consumer.to_vec();
+        entry.delivered_at = delivered_at;
+        match options.retry_count{
+            Some(retry_count) => entry.deliveries = retry_count,
+            None if !options.just_id => entry.deliveries += 1,
+            None => {},
+        }
+        claims.push(entry);
+    }
+    Ok(claim(db, key, group, claims)?.0)
+}
+
+// XAUTOCLAIM: like XCLAIM for the pending entries from `start` on, looking
+// at up to AUTOCLAIM_ATTEMPTS of them for each of the `count` to claim.
+// Returns where to go on, 0-0 when done, with what `claim` returns.
+fn auto_claim<E: DB>(db: &mut E, key: &[u8], group: &[u8], consumer: &[u8], min_idle: u64, start: StreamId, count: usize,
+                     just_id: bool) -> Result<(StreamId, Vec<StreamEntry>, Vec<StreamId>), DBError>{
+    let attempts = count * AUTOCLAIM_ATTEMPTS;
+    // one more tells where the next call goes on
+    let pending = db.stream_pending(key.to_vec(), group.to_vec(), start, StreamId::MAX, attempts + 1, None)?;
+    let now = now_millis();
+    let mut next = StreamId::MIN;
+    let mut claims = Vec::new();
+    for (looked_at, mut entry) in pending.into_iter().enumerate(){
+        if looked_at == attempts || claims.len() == count{
+            next = entry.id;
+            break;
+        }
+        if now.saturating_sub(entry.delivered_at) < min_idle{
+            continue;
+        }
+        entry.consumer = consumer.to_vec();
+        entry.delivered_at = now;
+        if !just_id{
+            entry.deliveries += 1;
+        }
+        claims.push(entry);
+    }
+    let (entries, deleted) = claim(db, key, group, claims)?;
+    Ok((next, entries, deleted))
+}
+
+// Stores the claimed pending entries. Those deleted from the stream
+// meanwhile are acknowledged instead and returned apart, the others are
+// returned with their fields.
+fn claim<E: DB>(db: &mut E, key: &[u8], group: &[u8], claims: Vec<PendingEntry>)
+    -> Result<(Vec<StreamEntry>, Vec<StreamId>), DBError>{
+    let (mut entries, mut claimed, mut deleted) = (Vec::new(), Vec::new(), Vec::new());
+    for claim in claims{
+        match db.stream_range(key.to_vec(), claim.id, claim.id, 1, false)?.pop(){
+            Some(entry) =>{
+                entries.push(entry);
+                claimed.push(claim);
+            }
+            None => deleted.push(claim.id),
+        }
+    }
+    if !deleted.is_empty(){
+        db.stream_ack(key.to_vec(), group.to_vec(), deleted.clone())?;
+    }
+    if !claimed.is_empty(){
+        db.stream_set_pending(key.to_vec(), group.to_vec(), claimed)?;
+    }
+    Ok((entries, deleted))
+}
+
+pub fn error_reply(e: DBError) -> Reply{
+    match e{
+        DBError::NotFound => Reply::Error("ERR no such key".to_string()),
+        DBError::AlreadyExited(msg) => Reply::Error(format!("ERR {}", msg)),
+        DBError::WrongType => Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+        DBError::OutOfRange => Reply::Error("ERR index out of range".to_string()),
+        DBError::Storage(msg) => Reply::Error(format!("ERR storage failure: {}", msg)),
+        DBError::Other => Reply::Error("ERR storage failure".to_string()),
+    }
+}
+
+fn parse_scan_options(args: &[Vec<u8>], with_type: bool) -> Result<ScanOptions, String>{
+    let mut options = ScanOptions{
+        pattern: None,
+        count: DEFAULT_SCAN_COUNT,
+        key_type: None,
+    };
+    let mut pos = 0;
+    while pos < args.len(){
+        let option = String::from_utf8_lossy(&args[pos]).to_uppercase();
+        match (option.as_ref(), args.get(pos + 1)){
+            ("MATCH", Some(arg)) => options.pattern = Some(arg.clone()),
+            ("COUNT", Some(arg)) =>{
+                options.count = match parse_arg::<usize>(arg){
+                    Some(count) if count > 0 => count,
+                    Some(_) => return Err("ERR syntax error".to_string()),
+                    None => return Err("ERR value is not an integer or out of range".to_string()),
+                };
+            },
+            ("TYPE", Some(arg)) if with_type => options.key_type = Some(String::from_utf8_lossy(arg).to_lowercase()),
+            _ => return Err("ERR syntax error".to_string()),
+        }
+        pos += 2;
+    }
+    Ok(options)
+}
+
+// Key bounds of a keyspace scan resuming after `after`, narrowed to the keys
+// that can match `pattern`.
+fn scan_bounds(after: Option<Vec<u8>>, pattern: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>){
+    let prefix = literal_prefix(pattern);
+    let start = match after{
+        Some(after) if after.as_slice() >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix.to_vec()),
+    };
+    // the first key past every key starting with the prefix
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&0xff){
+        end.pop();
+    }
+    let end = match end.last_mut(){
+        Some(last) =>{
+            *last += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    };
+    (start, end)
+}
+
+// Calls `f` on every live key within the bounds, reading them page by page.
+fn walk_keys<E: DB, F: FnMut(Vec<u8>, KeyType)>(db: &E, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, mut f: F)
+    -> Result<(), DBError>{
+    let mut start = start;
+    loop{
+        let keys = db.scan_keys(start, end.clone(), KEYS_BATCH)?;
+        let done = keys.len() < KEYS_BATCH;
+        start = match keys.last(){
+            Some((last, _)) => Bound::Excluded(last.clone()),
+            None => return Ok(()),
+        };
+        for (key, key_type) in keys{
+            f(key, key_type);
+        }
+        if done{
+            return Ok(());
+        }
+    }
+}
+
+// Scan cursors carry the last field or key returned. Clients parse them as
+// numbers, so the bytes are spelled in decimal: a leading "1" followed by
+// three digits per byte. "0" starts and ends an iteration.
+fn encode_cursor(last: &[u8]) -> Vec<u8>{
+    let mut cursor = String::with_capacity(1 + last.len() * 3);
+    cursor.push('1');
+    for b in last{
+        cursor.push_str(&format!("{:03}", b));
+    }
+    cursor.into_bytes()
+}
+
+// `Some(None)` for a new iteration, `None` for a malformed cursor
+fn decode_cursor(cursor: &[u8]) -> Option<Option<Vec<u8>>>{
+    if cursor == b"0"{
+        return Some(None);
+    }
+    if cursor.first() != Some(&b'1') || (cursor.len() - 1) % 3 != 0{
+        return None;
+    }
+    cursor[1..].chunks(3)
+        .map(parse_arg::<u8>)
+        .collect::<Option<Vec<u8>>>()
+        .map(Some)
+}
+
+fn scored_reply(members: Vec<ScoredMember>, with_scores: bool) -> Reply{
+    let mut replies = Vec::with_capacity(members.len() * if with_scores{ 2 }else{ 1 });
+    for (member, score) in members{
+        replies.push(Reply::Bulk(member));
+        if with_scores{
+            replies.push(Reply::Bulk(format_score(score)));
+        }
+    }
+    Reply::Array(replies)
+}
+
+// shortest form that reads back to the same score, "inf" and "-inf" included
+fn format_score(score: f64) -> Vec<u8>{
+    format!("{}", score).into_bytes()
+}
+
+// unlike string values, scores may be infinite
+fn parse_score(arg: &[u8]) -> Option<f64>{
+    parse_arg::<f64>(arg).filter(|f| !f.is_nan())
+}
+
+// a score, or an exclusive one when prefixed with "("
+fn parse_score_bound(arg: &[u8]) -> Option<Bound<f64>>{
+    match arg.first(){
+        Some(b'(') => parse_score(&arg[1..]).map(Bound::Excluded),
+        _ => parse_score(arg).map(Bound::Included),
+    }
+}
+
+fn parse_float(arg: &[u8]) -> Option<f64>{
+    parse_arg::<f64>(arg).filter(|f| f.is_finite())
+}
+
+// Redis prints the long double sum of INCRBYFLOAT with "%.17Lf" and strips
+// the trailing zeros, so never with an exponent. Up to 17 decimals are kept,
+// and 17 significant digits stand in for the precision of a long double.
+const FLOAT_DIGITS: u32 = 17;
+
+// `value + delta` written the way redis does. Both operands are taken as the
+// shortest decimals that read back to them and added exactly, so the binary
+// error of 0.1 + 0.2 does not show up in the 17 digits: that gives "0.3".
+fn float_sum_string(value: f64, delta: f64) -> Vec<u8>{
+    let (mut a, mut b) = (float_to_decimal(value), float_to_decimal(delta));
+    if a.1 < b.1{
+        std::mem::swap(&mut a, &mut b);
+    }
+    // both mantissas have 17 digits, past 20 digits of shift `b` is far
+    // below the last digit kept
+    let (mut mantissa, mut exp) = match a.1 - b.1{
+        _ if b.0 == 0 => a,
+        _ if a.0 == 0 => b,
+        shift if shift > 20 => a,
+        shift => (a.0 * 10i128.pow(shift as u32) + b.0, b.1),
+    };
+    let mut digits = decimal_digits(mantissa);
+    let drop = (digits as i32 - FLOAT_DIGITS as i32).max(-(FLOAT_DIGITS as i32) - exp);
+    if drop > digits as i32{
+        mantissa = 0;
+    }else if drop > 0{
+        let scale = 10i128.pow(drop as u32);
+        let rest = mantissa % scale;
+        mantissa /= scale;
+        if rest.abs() * 2 >= scale{
+            mantissa += if rest < 0{ -1 }else{ 1 };
+        }
+        exp += drop;
+    }
+    // "-0" comes out as "0" too
+    if mantissa == 0{
+        return b"0".to_vec();
+    }
+    while mantissa % 10 == 0{
+        mantissa /= 10;
+        exp += 1;
+    }
+    digits = decimal_digits(mantissa);
+    let sign = if mantissa < 0{ "-" }else{ "" };
+    let text = mantissa.abs().to_string();
+    // exponent of the leading digit
+    let lead = exp + digits as i32 - 1;
+    let out = if exp >= 0{
+        format!("{}{}{}", sign, text, "0".repeat(exp as usize))
+    }else if lead >= 0{
+        let point = (lead + 1) as usize;
+        format!("{}{}.{}", sign, &text[..point], &text[point..])
+    }else{
+        format!("{}0.{}{}", sign, "0".repeat((-lead - 1) as usize), text)
+    };
+    out.into_bytes()
+}
+
+// a finite float as `mantissa * 10^exp` with a 17 digit mantissa
+fn float_to_decimal(value: f64) -> (i128, i32){
+    let text = format!("{:e}", value);
+    let (mantissa, exp) = text.split_at(text.find('e').unwrap());
+    let fraction_len = mantissa.find('.').map_or(0, |point| mantissa.len() - point - 1);
+    let mantissa: i128 = mantissa.replace('.', "").parse().unwrap();
+    let exp = exp[1..].parse::<i32>().unwrap() - fraction_len as i32;
+    if mantissa == 0{
+        return (0, 0);
+    }
+    let pad = FLOAT_DIGITS - decimal_digits(mantissa);
+    (mantissa * 10i128.pow(pad), exp - pad as i32)
+}
+
+fn decimal_digits(value: i128) -> u32{
+    let mut value = value.abs();
+    let mut digits = 1;
+    while value >= 10{
+        value /= 10;
+        digits += 1;
+    }
+    digits
+}
+
+const INVALID_STREAM_ID: &str = "ERR Invalid stream ID specified as stream command argument";
+
+// every entry as its id followed by its fields and values
+fn entries_reply(entries: Vec<StreamEntry>) -> Reply{
+    Reply::Array(entries.into_iter().map(|(id, fields)| {
+        Reply::Array(vec![Reply::Bulk(id.to_string().into_bytes()), fields_reply(fields)])
+    }).collect())
+}
+
+fn fields_reply(fields: Vec<FieldValue>) -> Reply{
+    Reply::Array(fields.into_iter().flat_map(|(field, value)| vec![Reply::Bulk(field), Reply::Bulk(value)]).collect())
+}
+
+fn ids_reply(ids: Vec<StreamId>) -> Reply{
+    Reply::Array(ids.into_iter().map(|id| Reply::Bulk(id.to_string().into_bytes())).collect())
+}
+
+// XPENDING without a range: how many entries are pending, the smallest and
+// greatest of their ids and how many each consumer has.
+fn pending_summary(pending: Vec<PendingEntry>) -> Reply{
+    let (first, last) = match (pending.first(), pending.last()){
+        (Some(first), Some(last)) => (first.id, last.id),
+        _ => return Reply::Array(vec![Reply::Integer(0), Reply::Nil, Reply::Nil, Reply::NilArray]),
+    };
+    let mut consumers = BTreeMap::new();
+    for entry in &pending{
+        *consumers.entry(entry.consumer.clone()).or_insert(0usize) += 1;
+    }
+    let consumers = consumers.into_iter().map(|(consumer, count)| {
+        Reply::Array(vec![Reply::Bulk(consumer), Reply::Bulk(count.to_string().into_bytes())])
+    }).collect();
+    Reply::Array(vec![
+        Reply::Integer(pending.len() as i64),
+        Reply::Bulk(first.to_string().into_bytes()),
+        Reply::Bulk(last.to_string().into_bytes()),
+        Reply::Array(consumers),
+    ])
+}
+
+// NOGROUP error of the commands reading from a group
+fn no_group_reply(key: &[u8], group: &[u8], suffix: &str) -> Reply{
+    Reply::Error(format!("NOGROUP No such key '{}' or consumer group '{}'{}",
+                         String::from_utf8_lossy(key), String::from_utf8_lossy(group), suffix))
+}
+
+// `ms-seq`, or `ms` alone with `missing_seq` as sequence number
+fn parse_stream_id(arg: &[u8], missing_seq: u64) -> Option<StreamId>{
+    let arg = std::str::from_utf8(arg).ok()?;
+    let mut parts = arg.splitn(2, '-');
+    let ms = parts.next()?.parse::<u64>().ok()?;
+    let seq = match parts.next(){
+        Some(seq) => seq.parse::<u64>().ok()?,
+        None => missing_seq,
+    };
+    Some(StreamId::new(ms, seq))
+}
+
+// the id of XADD: `*`, `ms-*` or a complete id
+fn parse_new_stream_id(arg: &[u8]) -> Option<NewStreamId>{
+    if arg == b"*"{
+        return Some(NewStreamId::Auto);
+    }
+    if arg.ends_with(b"-*"){
+        return parse_arg::<u64>(&arg[..arg.len() - 2]).map(NewStreamId::AutoSeq);
+    }
+    parse_stream_id(arg, 0).map(NewStreamId::Id)
+}
+
+// An XRANGE bound: `-`, `+`, an id with the sequence number defaulting to
+// the widest range, or an exclusive one after `(`.
+fn parse_range_bound(arg: &[u8], start: bool) -> Option<StreamId>{
+    let missing_seq = if start{ 0 }else{ u64::MAX };
+    match arg{
+        b"-" => Some(StreamId::MIN),
+        b"+" => Some(StreamId::MAX),
+        _ if arg.starts_with(b"(") =>{
+            let id = parse_stream_id(&arg[1..], missing_seq)?;
+            if start{ id.next() }else{ id.prev() }
+        }
+        _ => parse_stream_id(arg, missing_seq),
+    }
+}
+
+// MAXLEN|MINID [=|~] threshold [LIMIT count] at `pos`, which is moved past it.
+// `~` trims exactly here, LIMIT only bounds the work redis does for it.
+fn parse_stream_trim(args: &[Vec<u8>], pos: &mut usize) -> Result<StreamTrim, String>{
+    let strategy = String::from_utf8_lossy(&args[*pos]).to_uppercase();
+    *pos += 1;
+    let mut approximate = false;
+    if let Some(b"=") | Some(b"~") = args.get(*pos).map(|arg| arg.as_slice()){
+        approximate = args[*pos] == b"~";
+        *pos += 1;
+    }
+    let threshold = match args.get(*pos){
+        Some(threshold) => threshold,
+        None => return Err("ERR syntax error".to_string()),
+    };
+    let trim = match strategy.as_ref(){
+        "MAXLEN" => match parse_arg::<usize>(threshold){
+            Some(max_len) => StreamTrim::MaxLen(max_len),
+            None => return Err("ERR value is not an integer or out of range".to_string()),
+        },
+        "MINID" => match parse_stream_id(threshold, 0){
+            Some(min_id) => StreamTrim::MinId(min_id),
+            None => return Err(INVALID_STREAM_ID.to_string()),
+        },
+        _ => return Err("ERR syntax error".to_string()),
+    };
+    *pos += 1;
+    if args.get(*pos).map_or(false, |arg| String::from_utf8_lossy(arg).to_uppercase() == "LIMIT"){
+        if !approximate{
+            return Err("ERR syntax error, LIMIT cannot be used without the special ~ option".to_string());
+        }
+        if args.get(*pos + 1).and_then(|limit| parse_arg::<usize>(limit)).is_none(){
+            return Err("ERR value is not an integer or out of range".to_string());
+        }
+        *pos += 2;
+    }
+    Ok(trim)
+}
+
+// LEFT or RIGHT of LMOVE, true for the head
+fn parse_side(arg: &[u8]) -> Option<bool>{
+    match String::from_utf8_lossy(arg).to_uppercase().as_ref(){
+        "LEFT" => Some(true),
+        "RIGHT" => Some(false),
+        _ => None,
+    }
+}
+
+// timeout of the blocking commands, in seconds with decimals
+fn parse_timeout(arg: &[u8]) -> Result<u64, String>{
+    match parse_float(arg){
+        Some(timeout) if timeout < 0.0 => Err("ERR timeout is negative".to_string()),
+        Some(timeout) => Ok((timeout * 1000.0).ceil() as u64),
+        None => Err("ERR timeout is not a float or out of range".to_string()),
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(arg: &[u8]) -> Option<T>{
+    std::str::from_utf8(arg).ok().and_then(|s| s.parse::<T>().ok())
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::simple_mem_db;
+    use crate::redis_server::DB;
+    use crate::executor::{Executor, float_sum_string};
+    use crate::pubsub::{PubSub, Subscriber, Outbox};
+    use crate::protocol::RespParser;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::sync::mpsc::{channel, Sender, Receiver};
+    use crate::protocol::Reply;
+    use std::thread;
+    use std::time::Duration;
+
+    fn gen_redis_code(raw_code: String) -> String{
+        let args: Vec<&str> = raw_code.split(" ").collect();
+        let args_num = args.len();
+        let args = args.iter().map(|x| format!("${}\r\n{}\r\n", x.len(), x));
+        let mut output = format!("*{}\r\n", args_num);
+        for item in args{
+            output += &item;
+        }
+        //println!("get command {}", output);
+        output
+    }
+
+    fn exec<E: DB>(db: Arc<RwLock<E>>, command: String, tx: Sender<Reply>){
+        exec_raw(db, command.into_bytes(), tx);
+    }
+
+    fn exec_raw<E: DB>(db: Arc<RwLock<E>>, command: Vec<u8>, tx: Sender<Reply>){
+        let mut parser = RespParser::new();
+        parser.feed(&command);
+        let args = parser.next_command().unwrap().unwrap();
+        let mut executor = Executor::new(
+            db.clone(),
+            args,
+            tx.clone());
+        executor.parse();
+        executor.exec_command();
+    }
+
+    #[test]
+    fn test_executor_basic_put_get(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let put_command = gen_redis_code("set foo bar".to_string());
+        let (tx, rx) = channel();
+        exec(db.clone(), put_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        let get_command = gen_redis_code("get foo".to_string());
+        exec(db.clone(), get_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$3\r\nbar\r\n".to_vec());
+
+        let get_command = gen_redis_code("get no".to_string());
+        exec(db.clone(), get_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$-1\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_multi_put_get(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let mset_command = gen_redis_code("mset foo1 bar1 foo2 bar2 foo3 bar3".to_string());
+        let (tx, rx) = channel();
+        exec(db.clone(), mset_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        let mget_command = gen_redis_code("mget foo1 foo2 foo3".to_string());
+        exec(db.clone(), mget_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"*3\r\n$4\r\nbar1\r\n$4\r\nbar2\r\n$4\r\nbar3\r\n".to_vec());
+
+        let mget_command = gen_redis_code("mget foo1 nokey foo3".to_string());
+        exec(db.clone(), mget_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"*3\r\n$4\r\nbar1\r\n$-1\r\n$4\r\nbar3\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_strlen(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let set_command = gen_redis_code("set foo bar".to_string());
+        let strlen_command = gen_redis_code("strlen foo".to_string());
+        let (tx, rx) = channel();
+        exec(db.clone(), set_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        exec(db.clone(), strlen_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":3\r\n".to_vec());
+
+        let strlen_command = gen_redis_code("strlen nokey".to_string());
+        exec(db.clone(), strlen_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":0\r\n".to_vec());
+    }
+
+
+    #[test]
+    fn test_executor_getset(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let command1 = gen_redis_code("set foo bar".to_string());
+        let command2 = gen_redis_code("getset foo bar2".to_string());
+        let command3 = gen_redis_code("getset foo3 bar3".to_string());
+        let (tx, rx) = channel();
+        exec(db.clone(), command1, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        exec(db.clone(), command2, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$3\r\nbar\r\n".to_vec());
+
+        exec(db.clone(), command3, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$-1\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_append(){
+        println!("init db");
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        println!("get new db");
+        let command1 = gen_redis_code("set hello world".to_string());
+        let command2 = gen_redis_code("append hello bye".to_string());
+        let command3 = gen_redis_code("get hello".to_string());
+        let (tx, rx) = channel();
+        println!("before new db");
+        exec(db.clone(), command1, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+        println!("finish set");
+
+        exec(db.clone(), command2, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":8\r\n".to_vec());
+
+        exec(db.clone(), command3, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$8\r\nworldbye\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_getrange(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let command1 = gen_redis_code("set me 18696192030".to_string());
+        let command2 = gen_redis_code("getrange me 1 -1".to_string());
+        let command3 = gen_redis_code("getrange me -1 1".to_string());
+        let (tx, rx) = channel();
+        exec(db.clone(), command1, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        exec(db.clone(), command2, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$10\r\n8696192030\r\n".to_vec());
+
+        exec(db.clone(), command3, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$0\r\n\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_setrange(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let command1 = gen_redis_code("set me 18696192030".to_string());
+        let command2 = gen_redis_code("setrange me 4 00".to_string());
+        let command3 = gen_redis_code("setrange me 11 ++++++".to_string());
+        let command4 = gen_redis_code("setrange empty 5 hahhaha".to_string());
+        let (tx, rx) = channel();
+        exec(db.clone(), command1, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        exec(db.clone(), command2, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":11\r\n".to_vec());
 
         exec(db.clone(), command3, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+bar3\r\n".to_string());
+        assert_eq!(rx.recv().unwrap().encode(), b":17\r\n".to_vec());
+
+        exec(db.clone(), command4, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":12\r\n".to_vec());
+
+        let command5 = gen_redis_code("setrange me 4000000000 x".to_string());
+        exec(db.clone(), command5, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_binary_safe(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        let mut set_command = b"*3\r\n$3\r\nset\r\n$2\r\n\xff\x00\r\n$4\r\n".to_vec();
+        set_command.extend_from_slice(b"\x80\r\n\xfe\r\n");
+        exec_raw(db.clone(), set_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        let get_command = b"*2\r\n$3\r\nget\r\n$2\r\n\xff\x00\r\n".to_vec();
+        exec_raw(db.clone(), get_command, tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$4\r\n\x80\r\n\xfe\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_error_replies(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("nosuch foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Error("ERR unknown command 'nosuch'".to_string()));
+
+        exec(db.clone(), gen_redis_code("get".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Error("ERR wrong number of arguments for 'get' command".to_string()));
+
+        exec(db.clone(), gen_redis_code("getrange foo a 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Error("ERR value is not an integer or out of range".to_string()));
+    }
+
+    #[test]
+    fn test_executor_set_options(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("set lock a NX PX 30000".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("set lock b NX PX 30000".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$-1\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("set lock c XX GET".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$1\r\na\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("set nokey c XX".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$-1\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("setnx lock d".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":0\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("setnx other d".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":1\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("set lock a NX XX".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR syntax error\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("set lock a EX 10 KEEPTTL".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR syntax error\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("set lock a EX 0".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR invalid expire time in 'set' command\r\n".to_vec());
+
+        // NX together with GET is accepted as in redis 7
+        exec(db.clone(), gen_redis_code("set lock e NX GET".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$1\r\nc\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("get lock".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$1\r\nc\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("set fresh e NX GET".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$-1\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("get fresh".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$1\r\ne\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_set_expire(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("set foo bar PX 50".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("set foo baz KEEPTTL".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("append foo 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":4\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("get foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$4\r\nbaz1\r\n".to_vec());
+
+        thread::sleep(Duration::from_millis(100));
+        exec(db.clone(), gen_redis_code("get foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"$-1\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_expire_ttl(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("set foo bar".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+OK\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("ttl foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(-1));
+
+        exec(db.clone(), gen_redis_code("ttl nokey".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(-2));
+
+        exec(db.clone(), gen_redis_code("expire nokey 10".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("expire foo 100".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("ttl foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(100));
+
+        exec(db.clone(), gen_redis_code("persist foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("persist foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("pexpire foo 50".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("pttl foo".to_string()), tx.clone());
+        match rx.recv().unwrap(){
+            Reply::Integer(ms) => assert!(ms > 0 && ms <= 50),
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        thread::sleep(Duration::from_millis(100));
+        exec(db.clone(), gen_redis_code("get foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Nil);
+
+        exec(db.clone(), gen_redis_code("set foo bar".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("expireat foo 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("ttl foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(-2));
+    }
+
+    #[test]
+    fn test_executor_keyspace(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("mset a 1 b 2 c 3".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("exists a b nokey a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(3));
+
+        exec(db.clone(), gen_redis_code("type a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+string\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("type nokey".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+none\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("del a nokey".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("unlink b".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("rename a d".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR no such key\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("set e 5 EX 100".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("renamenx e c".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("rename e c".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("get c".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"5".to_vec()));
+
+        exec(db.clone(), gen_redis_code("ttl c".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(100));
+
+        exec(db.clone(), gen_redis_code("renamenx c f".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("exists c".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
     }
 
     #[test]
-    fn test_executor_append(){
-        println!("init db");
+    fn test_executor_incr_decr(){
         let db = simple_mem_db::SimpleMemDB::new();
         let db = Arc::new(RwLock::new(db));
-        println!("get new db");
-        let command1 = gen_redis_code("set hello world".to_string());
-        let command2 = gen_redis_code("append hello bye".to_string());
-        let command3 = gen_redis_code("get hello".to_string());
         let (tx, rx) = channel();
-        println!("before new db");
-        exec(db.clone(), command1, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+OK\r\n".to_string());
-        println!("finish set");
+        exec(db.clone(), gen_redis_code("incr counter".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":1\r\n".to_vec());
 
-        exec(db.clone(), command2, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+OK\r\n".to_string());
+        exec(db.clone(), gen_redis_code("incrby counter 10".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(11));
 
-        exec(db.clone(), command3, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "*1\r\n$8\r\nworldbye\r\n".to_string());
+        exec(db.clone(), gen_redis_code("decrby counter 20".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(-9));
+
+        exec(db.clone(), gen_redis_code("decr counter".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(-10));
+
+        exec(db.clone(), gen_redis_code("set counter 9223372036854775807 EX 100".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("incr counter".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR increment or decrement would overflow\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("decr counter".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(9223372036854775806));
+
+        exec(db.clone(), gen_redis_code("ttl counter".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(100));
+
+        exec(db.clone(), gen_redis_code("set foo bar".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("incr foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR value is not an integer or out of range\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("incrbyfloat foo 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR value is not a valid float\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("incrbyfloat float 10.5".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"10.5".to_vec()));
+
+        exec(db.clone(), gen_redis_code("incrbyfloat float -5.5".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"5".to_vec()));
+
+        exec(db.clone(), gen_redis_code("incrbyfloat float inf".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR value is not a valid float\r\n".to_vec());
     }
 
     #[test]
-    fn test_executor_getrange(){
+    fn test_executor_incrbyfloat_format(){
         let db = simple_mem_db::SimpleMemDB::new();
         let db = Arc::new(RwLock::new(db));
-        let command1 = gen_redis_code("set me 18696192030".to_string());
-        let command2 = gen_redis_code("getrange me 1 -1".to_string());
-        let command3 = gen_redis_code("getrange me -1 1".to_string());
         let (tx, rx) = channel();
-        exec(db.clone(), command1, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+OK\r\n".to_string());
+        exec(db.clone(), gen_redis_code("incrbyfloat f 0.1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"0.1".to_vec()));
+        exec(db.clone(), gen_redis_code("incrbyfloat f 0.2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"0.3".to_vec()));
+        exec(db.clone(), gen_redis_code("incrbyfloat f -0.3".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"0".to_vec()));
+        exec(db.clone(), gen_redis_code("incrbyfloat f -0.00001".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"-0.00001".to_vec()));
+
+        // plain digits, never an exponent
+        let mut huge = b"1".to_vec();
+        huge.extend_from_slice(&[b'0'; 300]);
+        assert_eq!(float_sum_string(1e300, 0.0), huge);
+        assert_eq!(float_sum_string(1e-5, 1e-17), b"0.00001000000000001".to_vec());
+        assert_eq!(float_sum_string(-1e-20, 0.0), b"0".to_vec());
+        assert_eq!(float_sum_string(5.0e3, 1.0e-2), b"5000.01".to_vec());
+        assert_eq!(float_sum_string(1.0 / 3.0, 0.0), b"0.3333333333333333".to_vec());
+        assert_eq!(float_sum_string(12345678901234567.0, 1.0), b"12345678901234569".to_vec());
+    }
 
-        exec(db.clone(), command2, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+869619203\r\n".to_string());
+    #[test]
+    fn test_executor_concurrent_incr(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let mut workers = Vec::new();
+        for _ in 0..8{
+            let db = db.clone();
+            workers.push(thread::spawn(move ||{
+                let (tx, rx) = channel();
+                for _ in 0..100{
+                    exec(db.clone(), gen_redis_code("incr counter".to_string()), tx.clone());
+                    rx.recv().unwrap();
+                }
+            }));
+        }
+        for worker in workers{
+            worker.join().unwrap();
+        }
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("get counter".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"800".to_vec()));
+    }
 
-        exec(db.clone(), command3, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "-FAILED\r\n".to_string());
+    #[test]
+    fn test_executor_concurrent_append(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let mut workers = Vec::new();
+        for _ in 0..4{
+            let db = db.clone();
+            workers.push(thread::spawn(move ||{
+                let (tx, rx) = channel();
+                for _ in 0..50{
+                    exec(db.clone(), gen_redis_code("append log x".to_string()), tx.clone());
+                    rx.recv().unwrap();
+                    exec(db.clone(), gen_redis_code("setrange log 0 y".to_string()), tx.clone());
+                    rx.recv().unwrap();
+                }
+            }));
+        }
+        for worker in workers{
+            worker.join().unwrap();
+        }
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("strlen log".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(200));
     }
 
     #[test]
-    fn test_executor_setrange(){
+    fn test_executor_list(){
         let db = simple_mem_db::SimpleMemDB::new();
         let db = Arc::new(RwLock::new(db));
-        let command1 = gen_redis_code("set me 18696192030".to_string());
-        let command2 = gen_redis_code("setrange me 4 00".to_string());
-        let command3 = gen_redis_code("setrange me 11 ++++++".to_string());
-        let command4 = gen_redis_code("setrange empty 5 hahhaha".to_string());
         let (tx, rx) = channel();
-        exec(db.clone(), command1, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+OK\r\n".to_string());
+        exec(db.clone(), gen_redis_code("rpush list b c d".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":3\r\n".to_vec());
 
-        exec(db.clone(), command2, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+11\r\n".to_string());
+        exec(db.clone(), gen_redis_code("lpush list a z".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(5));
 
-        exec(db.clone(), command3, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+17\r\n".to_string());
+        exec(db.clone(), gen_redis_code("lrange list 0 -1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"*5\r\n$1\r\nz\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n".to_vec());
 
-        exec(db.clone(), command4, tx.clone());
-        assert_eq!(rx.recv().unwrap(), "+12\r\n".to_string());
+        exec(db.clone(), gen_redis_code("lrange list -2 100".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"c".to_vec()), Reply::Bulk(b"d".to_vec())]));
+
+        exec(db.clone(), gen_redis_code("lpop list".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"z".to_vec()));
+
+        exec(db.clone(), gen_redis_code("rpop list 2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"d".to_vec()), Reply::Bulk(b"c".to_vec())]));
+
+        exec(db.clone(), gen_redis_code("llen list".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(2));
+
+        exec(db.clone(), gen_redis_code("lindex list -1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"b".to_vec()));
+
+        exec(db.clone(), gen_redis_code("lindex list 5".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Nil);
+
+        exec(db.clone(), gen_redis_code("lset list 0 x".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("lset list 5 x".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR index out of range\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("linsert list before b x".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(3));
+
+        exec(db.clone(), gen_redis_code("linsert list after nopivot y".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(-1));
+
+        exec(db.clone(), gen_redis_code("rpush list x".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(4));
+
+        exec(db.clone(), gen_redis_code("lrem list -2 x".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(2));
+
+        exec(db.clone(), gen_redis_code("lrange list 0 -1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"x".to_vec()), Reply::Bulk(b"b".to_vec())]));
+
+        exec(db.clone(), gen_redis_code("ltrim list 1 -1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("ltrim list 5 10".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        // emptied lists are removed
+        exec(db.clone(), gen_redis_code("exists list".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("rpushx list a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("lpop list 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"*-1\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("linsert list before a b".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+    }
+
+    #[test]
+    fn test_executor_wrong_type(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        let wrong_type = Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string());
+        exec(db.clone(), gen_redis_code("set foo bar".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("lpush foo a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), wrong_type);
+
+        exec(db.clone(), gen_redis_code("rpush list a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("get list".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), wrong_type);
+
+        exec(db.clone(), gen_redis_code("incr list".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), wrong_type);
+
+        exec(db.clone(), gen_redis_code("mget foo list".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"bar".to_vec()), Reply::Nil]));
+
+        exec(db.clone(), gen_redis_code("type list".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+list\r\n".to_vec());
+
+        // SET replaces a value of any type
+        exec(db.clone(), gen_redis_code("set list bar".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("get list".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"bar".to_vec()));
+    }
+
+    #[test]
+    fn test_executor_hash(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("hset user name alice age 30".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":2\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("hset user name bob city paris".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("hmset user zip 75001".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("hget user name".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"bob".to_vec()));
+
+        exec(db.clone(), gen_redis_code("hmget user age nofield".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"30".to_vec()), Reply::Nil]));
+
+        exec(db.clone(), gen_redis_code("hlen user".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(4));
+
+        exec(db.clone(), gen_redis_code("hexists user city".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("hincrby user age 5".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(35));
+
+        exec(db.clone(), gen_redis_code("hincrby user name 5".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR hash value is not an integer\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("hkeys user".to_string()), tx.clone());
+        let keys = ["age", "city", "name", "zip"].iter().map(|k| Reply::Bulk(k.as_bytes().to_vec())).collect();
+        assert_eq!(rx.recv().unwrap(), Reply::Array(keys));
+
+        exec(db.clone(), gen_redis_code("hdel user city zip nofield".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(2));
+
+        exec(db.clone(), gen_redis_code("hgetall user".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"*4\r\n$3\r\nage\r\n$2\r\n35\r\n$4\r\nname\r\n$3\r\nbob\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("hvals user".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"35".to_vec()), Reply::Bulk(b"bob".to_vec())]));
+
+        exec(db.clone(), gen_redis_code("hdel user age name".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(2));
+
+        exec(db.clone(), gen_redis_code("exists user".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("lpush list a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("hget list a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_hscan(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("hset h a 1 b 2 c 3 d 4 e 5".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(5));
+
+        let mut cursor = "0".to_string();
+        let mut fields = Vec::new();
+        loop{
+            exec(db.clone(), gen_redis_code(format!("hscan h {} count 2", cursor)), tx.clone());
+            let (next, items) = match rx.recv().unwrap(){
+                Reply::Array(mut reply) => (reply.remove(0), reply.remove(0)),
+                other => panic!("unexpected reply {:?}", other),
+            };
+            if let Reply::Array(items) = items{
+                fields.extend(items.into_iter().step_by(2));
+            }
+            cursor = match next{
+                Reply::Bulk(next) => String::from_utf8(next).unwrap(),
+                other => panic!("unexpected cursor {:?}", other),
+            };
+            assert!(cursor.chars().all(|c| c.is_ascii_digit()));
+            if cursor == "0"{
+                break;
+            }
+        }
+        let expected: Vec<Reply> = ["a", "b", "c", "d", "e"].iter().map(|k| Reply::Bulk(k.as_bytes().to_vec())).collect();
+        assert_eq!(fields, expected);
+
+        exec(db.clone(), gen_redis_code("hscan h 0 match [bd] count 100".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![
+            Reply::Bulk(b"0".to_vec()),
+            Reply::Array(vec![
+                Reply::Bulk(b"b".to_vec()), Reply::Bulk(b"2".to_vec()),
+                Reply::Bulk(b"d".to_vec()), Reply::Bulk(b"4".to_vec()),
+            ]),
+        ]));
+
+        exec(db.clone(), gen_redis_code("hscan h abc".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR invalid cursor\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_set(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        let bulks = |items: &[&str]| Reply::Array(items.iter().map(|i| Reply::Bulk(i.as_bytes().to_vec())).collect());
+        exec(db.clone(), gen_redis_code("sadd s1 a b c a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":3\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("sadd s2 b c d".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(3));
+
+        exec(db.clone(), gen_redis_code("smembers s1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["a", "b", "c"]));
+
+        exec(db.clone(), gen_redis_code("sismember s1 a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("sismember s1 d".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("sinter s1 s2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["b", "c"]));
+
+        exec(db.clone(), gen_redis_code("sunion s1 s2 nokey".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["a", "b", "c", "d"]));
+
+        exec(db.clone(), gen_redis_code("sdiff s1 s2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["a"]));
+
+        exec(db.clone(), gen_redis_code("sinter s1 nokey".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&[]));
+
+        exec(db.clone(), gen_redis_code("set str x".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("sunionstore str s1 s2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(4));
+
+        exec(db.clone(), gen_redis_code("scard str".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(4));
+
+        exec(db.clone(), gen_redis_code("sdiffstore str s1 s1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("exists str".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("srem s1 a z".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("srandmember s1 5".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["b", "c"]));
+
+        exec(db.clone(), gen_redis_code("srandmember s1 -5".to_string()), tx.clone());
+        match rx.recv().unwrap(){
+            Reply::Array(members) =>{
+                assert_eq!(members.len(), 5);
+                assert!(members.iter().all(|m| *m == Reply::Bulk(b"b".to_vec()) || *m == Reply::Bulk(b"c".to_vec())));
+            },
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        exec(db.clone(), gen_redis_code("srandmember s1 -9223372036854775808".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(),
+            b"-ERR value is out of range, must be between -9223372036854775807 and 9223372036854775807\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("srandmember nosuchset -9223372036854775807".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![]));
+
+        exec(db.clone(), gen_redis_code("spop s1".to_string()), tx.clone());
+        let popped = rx.recv().unwrap();
+        assert!(popped == Reply::Bulk(b"b".to_vec()) || popped == Reply::Bulk(b"c".to_vec()));
+
+        exec(db.clone(), gen_redis_code("spop s1 3".to_string()), tx.clone());
+        match rx.recv().unwrap(){
+            Reply::Array(members) => assert_eq!(members.len(), 1),
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        exec(db.clone(), gen_redis_code("scard s1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("spop s1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Nil);
+
+        exec(db.clone(), gen_redis_code("sadd s2 x".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("lpush list a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("sinter s2 list".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("sadd list a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_executor_zset(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        let bulks = |items: &[&str]| Reply::Array(items.iter().map(|i| Reply::Bulk(i.as_bytes().to_vec())).collect());
+        exec(db.clone(), gen_redis_code("zadd z 1 a 2 b 3 c -1.5 d".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b":4\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("zadd z ch 2 b 5 c 7 e".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(2));
+
+        exec(db.clone(), gen_redis_code("zadd z nx 10 a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+
+        exec(db.clone(), gen_redis_code("zadd z nx xx 10 a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR XX and NX options at the same time are not compatible\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("zadd z xx incr 10 nomember".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Nil);
+
+        exec(db.clone(), gen_redis_code("zrange z 0 -1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["d", "a", "b", "c", "e"]));
+
+        exec(db.clone(), gen_redis_code("zrange z 0 1 withscores".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["d", "-1.5", "a", "1"]));
+
+        exec(db.clone(), gen_redis_code("zrangebyscore z (1 5".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["b", "c"]));
+
+        exec(db.clone(), gen_redis_code("zrangebyscore z -inf +inf withscores limit 1 2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["a", "1", "b", "2"]));
+
+        exec(db.clone(), gen_redis_code("zrangebyscore z (2 (2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&[]));
+
+        exec(db.clone(), gen_redis_code("zrangebyscore z x 2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR min or max is not a float\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("zincrby z 2.5 a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"3.5".to_vec()));
+
+        exec(db.clone(), gen_redis_code("zrank z a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(2));
+
+        exec(db.clone(), gen_redis_code("zrank z nomember".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Nil);
+
+        exec(db.clone(), gen_redis_code("zscore z d".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"-1.5".to_vec()));
+
+        exec(db.clone(), gen_redis_code("zrem z a d nomember".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(2));
+
+        exec(db.clone(), gen_redis_code("zcard z".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(3));
+
+        exec(db.clone(), gen_redis_code("type z".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"+zset\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("zrem z b c e".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(3));
+
+        exec(db.clone(), gen_redis_code("exists z".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+    }
+
+    #[test]
+    fn test_executor_keys_scan(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        let bulks = |items: &[&str]| Reply::Array(items.iter().map(|i| Reply::Bulk(i.as_bytes().to_vec())).collect());
+        exec(db.clone(), gen_redis_code("mset user:1 a user:2 b user:3 c other d".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+
+        exec(db.clone(), gen_redis_code("sadd user:set x".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        exec(db.clone(), gen_redis_code("dbsize".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(5));
+
+        exec(db.clone(), gen_redis_code("keys user:?".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["user:1", "user:2", "user:3"]));
+
+        exec(db.clone(), gen_redis_code("keys *".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), bulks(&["other", "user:1", "user:2", "user:3", "user:set"]));
+
+        exec(db.clone(), gen_redis_code("scan 0 count 2".to_string()), tx.clone());
+        let cursor = match rx.recv().unwrap(){
+            Reply::Array(mut items) =>{
+                assert_eq!(items[1], bulks(&["other", "user:1"]));
+                items.remove(0)
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        let cursor = match cursor{
+            Reply::Bulk(cursor) => String::from_utf8(cursor).unwrap(),
+            reply => panic!("unexpected cursor {:?}", reply),
+        };
+        assert!(cursor.bytes().all(|b| b.is_ascii_digit()));
+
+        exec(db.clone(), gen_redis_code(format!("scan {} count 10 match user:* type string", cursor)), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"0".to_vec()), bulks(&["user:2", "user:3"])]));
+
+        exec(db.clone(), gen_redis_code("scan 0 type set".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"0".to_vec()), bulks(&["user:set"])]));
+
+        exec(db.clone(), gen_redis_code("scan abc".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR invalid cursor\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("hscan user:1 0 type string".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR syntax error\r\n".to_vec());
+    }
+
+    fn exec_queued<E: DB>(db: Arc<RwLock<E>>, queued: &[&str], watched: Vec<(Vec<u8>, u64)>, tx: Sender<Reply>){
+        let queued = queued.iter().map(|command| command.split(' ').map(|arg| arg.as_bytes().to_vec()).collect()).collect();
+        let mut executor = Executor::new_exec(db, queued, watched, tx);
+        executor.parse();
+        executor.exec_command();
+    }
+
+    #[test]
+    fn test_executor_blocking_list(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        // a single try replies nil, the connection does the waiting
+        exec(db.clone(), gen_redis_code("blpop jobs urgent 0.5".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::NilArray);
+
+        exec(db.clone(), gen_redis_code("rpush urgent a b".to_string()), tx.clone());
+        rx.recv().unwrap();
+        exec(db.clone(), gen_redis_code("brpop jobs urgent 0".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"urgent".to_vec()), Reply::Bulk(b"b".to_vec())]));
+
+        exec(db.clone(), gen_redis_code("blpop jobs -1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR timeout is negative\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("lmove urgent jobs left right".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"a".to_vec()));
+        exec(db.clone(), gen_redis_code("lrange jobs 0 -1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"a".to_vec())]));
+        exec(db.clone(), gen_redis_code("blmove urgent jobs left right 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Nil);
+
+        exec(db.clone(), gen_redis_code("set str v".to_string()), tx.clone());
+        rx.recv().unwrap();
+        exec(db.clone(), gen_redis_code("lmove jobs str left left".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()));
+        exec(db.clone(), gen_redis_code("llen jobs".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+
+        let mut executor = Executor::new(db.clone(), vec![b"BLMOVE".to_vec(), b"src".to_vec(), b"dst".to_vec(),
+                                                          b"LEFT".to_vec(), b"LEFT".to_vec(), b"2.5".to_vec()], tx.clone());
+        executor.parse();
+        assert_eq!(executor.blocking(), Some((vec![b"src".to_vec()], 2500)));
+        assert_eq!(executor.timeout_reply(), Reply::Nil);
+
+        // a client only tries the keys it is first in line on
+        let args: Vec<Vec<u8>> = "BLPOP a b c 0".split(' ').map(|arg| arg.as_bytes().to_vec()).collect();
+        let mut executor = Executor::new(db.clone(), args.clone(), tx.clone());
+        executor.parse();
+        assert_eq!(executor.blocking_args_on(&args, &[b"c".to_vec(), b"a".to_vec()]),
+                   vec![b"BLPOP".to_vec(), b"a".to_vec(), b"c".to_vec(), b"0".to_vec()]);
+        assert_eq!(executor.timeout_reply(), Reply::NilArray);
+        let args: Vec<Vec<u8>> = "XREADGROUP GROUP g c BLOCK 0 STREAMS s t > >".split(' ')
+            .map(|arg| arg.as_bytes().to_vec()).collect();
+        let mut executor = Executor::new(db.clone(), args.clone(), tx.clone());
+        executor.parse();
+        assert!(executor.consumes());
+        assert_eq!(executor.blocking_args_on(&args, &[b"t".to_vec()]), args[..7].iter().cloned()
+            .chain(vec![b"t".to_vec(), b">".to_vec()]).collect::<Vec<_>>());
+        let mut executor = Executor::new(db.clone(), vec![b"RPUSH".to_vec(), b"dst".to_vec(), b"x".to_vec()], tx.clone());
+        executor.parse();
+        executor.exec_command();
+        rx.recv().unwrap();
+        assert_eq!(executor.ready_keys(), &[b"dst".to_vec()][..]);
+    }
+
+    #[test]
+    fn test_executor_stream(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("xadd s 1-1 a 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"1-1".to_vec()));
+        exec(db.clone(), gen_redis_code("xadd s 1-* b 2 c 3".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"1-2".to_vec()));
+        exec(db.clone(), gen_redis_code("xadd s 1-2 a 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(),
+                   b"-ERR The ID specified in XADD is equal or smaller than the target stream top item\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xadd s 0-0 a 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR The ID specified in XADD must be greater than 0-0\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xadd s 5-0 a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR wrong number of arguments for 'xadd' command\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xadd s nomkstream maxlen ~ 2 * d 4".to_string()), tx.clone());
+        let auto = match rx.recv().unwrap(){
+            Reply::Bulk(id) => id,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        exec(db.clone(), gen_redis_code("xadd missing nomkstream * a 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Nil);
+
+        exec(db.clone(), gen_redis_code("xlen s".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(2));
+        exec(db.clone(), gen_redis_code("xrange s - + count 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(),
+                   b"*1\r\n*2\r\n$3\r\n1-2\r\n*4\r\n$1\r\nb\r\n$1\r\n2\r\n$1\r\nc\r\n$1\r\n3\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xrevrange s + (1-2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Array(vec![
+            Reply::Bulk(auto.clone()), Reply::Array(vec![Reply::Bulk(b"d".to_vec()), Reply::Bulk(b"4".to_vec())])])]));
+        exec(db.clone(), gen_redis_code("xrange s 1 1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(),
+                   b"*1\r\n*2\r\n$3\r\n1-2\r\n*4\r\n$1\r\nb\r\n$1\r\n2\r\n$1\r\nc\r\n$1\r\n3\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("xread count 1 streams s missing 0 0".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(),
+                   b"*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n1-2\r\n*4\r\n$1\r\nb\r\n$1\r\n2\r\n$1\r\nc\r\n$1\r\n3\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xread streams s $".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::NilArray);
+        exec(db.clone(), gen_redis_code("xread streams s t 0".to_string()), tx.clone());
+        assert!(rx.recv().unwrap().encode().starts_with(b"-ERR Unbalanced"));
+
+        // a blocked XREAD tries again after what the stream holds now
+        let mut executor = Executor::new(db.clone(), vec![b"XREAD".to_vec(), b"BLOCK".to_vec(), b"0".to_vec(),
+                                                          b"STREAMS".to_vec(), b"s".to_vec(), b"$".to_vec()], tx.clone());
+        executor.parse();
+        assert_eq!(executor.blocking(), Some((vec![b"s".to_vec()], 0)));
+        assert_eq!(executor.blocking_args().unwrap()[5], auto);
+
+        exec(db.clone(), gen_redis_code("xtrim s minid 1-3".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+        exec(db.clone(), gen_redis_code("xtrim s maxlen 0 limit 10".to_string()), tx.clone());
+        assert!(rx.recv().unwrap().encode().starts_with(b"-ERR syntax error, LIMIT"));
+        exec(db.clone(), gen_redis_code("xtrim s maxlen = 0".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+        exec(db.clone(), gen_redis_code("type s".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Status("stream".to_string()));
+    }
+
+    #[test]
+    fn test_executor_stream_group(){
+        let db = simple_mem_db::SimpleMemDB::new();
+        let db = Arc::new(RwLock::new(db));
+        let (tx, rx) = channel();
+        exec(db.clone(), gen_redis_code("xgroup create s g $".to_string()), tx.clone());
+        assert!(rx.recv().unwrap().encode().starts_with(b"-ERR The XGROUP subcommand requires the key to exist"));
+        exec(db.clone(), gen_redis_code("xgroup create s g $ mkstream".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+        exec(db.clone(), gen_redis_code("xgroup create s g 0".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-BUSYGROUP Consumer Group name already exists\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xadd s 1-1 a 1".to_string()), tx.clone());
+        rx.recv().unwrap();
+        exec(db.clone(), gen_redis_code("xadd s 1-2 b 2".to_string()), tx.clone());
+        rx.recv().unwrap();
+
+        // new entries go to one consumer each
+        exec(db.clone(), gen_redis_code("xreadgroup group g alice count 1 streams s >".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(),
+                   b"*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n1-1\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xreadgroup group g bob streams s >".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(),
+                   b"*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n1-2\r\n*2\r\n$1\r\nb\r\n$1\r\n2\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xreadgroup group g bob streams s >".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::NilArray);
+        exec(db.clone(), gen_redis_code("xreadgroup group other bob streams s >".to_string()), tx.clone());
+        assert!(rx.recv().unwrap().encode().starts_with(b"-NOGROUP No such key 's' or consumer group 'other'"));
+        exec(db.clone(), gen_redis_code("xpending s g".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(),
+                   b"*4\r\n:2\r\n$3\r\n1-1\r\n$3\r\n1-2\r\n*2\r\n*2\r\n$5\r\nalice\r\n$1\r\n1\r\n\
+                     *2\r\n$3\r\nbob\r\n$1\r\n1\r\n".to_vec());
+
+        // the history of a consumer is delivered again
+        exec(db.clone(), gen_redis_code("xreadgroup group g alice streams s 0".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(),
+                   b"*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n1-1\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xpending s g - + 10 alice".to_string()), tx.clone());
+        match rx.recv().unwrap(){
+            Reply::Array(mut entries) =>{
+                assert_eq!(entries.len(), 1);
+                match entries.remove(0){
+                    // the idle time in between is left out
+                    Reply::Array(entry) => assert_eq!((&entry[..2], &entry[3]), (
+                        &[Reply::Bulk(b"1-1".to_vec()), Reply::Bulk(b"alice".to_vec())][..], &Reply::Integer(2))),
+                    reply => panic!("unexpected reply {:?}", reply),
+                }
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        exec(db.clone(), gen_redis_code("xpending s g idle 3600000 - + 10".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![]));
+
+        // claiming
+        exec(db.clone(), gen_redis_code("xclaim s g bob 3600000 1-1".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![]));
+        exec(db.clone(), gen_redis_code("xclaim s g bob 0 1-1 justid".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Bulk(b"1-1".to_vec())]));
+        exec(db.clone(), gen_redis_code("xclaim s g bob 0 1-1 retrycount 7 foo".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR Unrecognized XCLAIM option 'foo'\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xpending s g - + 10 bob".to_string()), tx.clone());
+        match rx.recv().unwrap(){
+            Reply::Array(entries) => assert_eq!(entries.len(), 2),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        // an entry deleted from the stream is acknowledged by XAUTOCLAIM
+        exec(db.clone(), gen_redis_code("xtrim s minid 1-2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+        exec(db.clone(), gen_redis_code("xautoclaim s g alice 0 0 count 10".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(),
+                   b"*3\r\n$3\r\n0-0\r\n*1\r\n*2\r\n$3\r\n1-2\r\n*2\r\n$1\r\nb\r\n$1\r\n2\r\n*1\r\n$3\r\n1-1\r\n".to_vec());
+        exec(db.clone(), gen_redis_code("xautoclaim s g alice 0 0 count 0".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-ERR COUNT must be > 0\r\n".to_vec());
+
+        exec(db.clone(), gen_redis_code("xack s g 1-1 1-2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+        exec(db.clone(), gen_redis_code("xack s other 1-2".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+        exec(db.clone(), gen_redis_code("xgroup createconsumer s g carol".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+        exec(db.clone(), gen_redis_code("xgroup createconsumer s g carol".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+        exec(db.clone(), gen_redis_code("xgroup delconsumer s g alice".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+        exec(db.clone(), gen_redis_code("xgroup createconsumer s other carol".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"-NOGROUP No such consumer group 'other' for key name 's'\r\n".to_vec());
+
+        // NOACK leaves nothing pending
+        exec(db.clone(), gen_redis_code("xgroup setid s g 0".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+        exec(db.clone(), gen_redis_code("xreadgroup group g carol noack streams s >".to_string()), tx.clone());
+        assert!(rx.recv().unwrap().encode().ends_with(b"$3\r\n1-2\r\n*2\r\n$1\r\nb\r\n$1\r\n2\r\n"));
+        exec(db.clone(), gen_redis_code("xpending s g".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap().encode(), b"*4\r\n:0\r\n$-1\r\n$-1\r\n*-1\r\n".to_vec());
+
+        let mut executor = Executor::new(db.clone(), vec![b"XREADGROUP".to_vec(), b"GROUP".to_vec(), b"g".to_vec(),
+                                                          b"carol".to_vec(), b"BLOCK".to_vec(), b"100".to_vec(),
+                                                          b"STREAMS".to_vec(), b"s".to_vec(), b">".to_vec()], tx.clone());
+        executor.parse();
+        assert_eq!(executor.blocking(), Some((vec![b"s".to_vec()], 100)));
+
+        exec(db.clone(), gen_redis_code("xgroup destroy s g".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(1));
+        exec(db.clone(), gen_redis_code("xgroup destroy s g".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Integer(0));
+        exec(db.clone(), gen_redis_code("xgroup destroy missing g".to_string()), tx.clone());
+        assert!(rx.recv().unwrap().encode().starts_with(b"-ERR The XGROUP subcommand requires the key to exist"));
+    }
+
+    #[test]
+    fn test_executor_stream_group_trimmed(){
+        let db = Arc::new(RwLock::new(simple_mem_db::SimpleMemDB::new()));
+        let (tx, rx) = channel();
+        for command in &["xgroup create s g $ mkstream", "xadd s 1-1 a 1", "xadd s 1-2 b 2",
+                         "xreadgroup group g alice streams s >", "xtrim s maxlen 1"]{
+            exec(db.clone(), gen_redis_code(command.to_string()), tx.clone());
+            rx.recv().unwrap();
+        }
+        // a pending entry trimmed off the stream comes back as nil and
+        // keeps its delivery count
+        exec(db.clone(), gen_redis_code("xreadgroup group g alice streams s 0".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::Array(vec![
+            Reply::Bulk(b"s".to_vec()),
+            Reply::Array(vec![
+                Reply::Array(vec![Reply::Bulk(b"1-1".to_vec()), Reply::NilArray]),
+                Reply::Array(vec![Reply::Bulk(b"1-2".to_vec()), Reply::Array(vec![
+                    Reply::Bulk(b"b".to_vec()), Reply::Bulk(b"2".to_vec())])]),
+            ]),
+        ])]));
+        exec(db.clone(), gen_redis_code("xpending s g - + 10 alice".to_string()), tx.clone());
+        match rx.recv().unwrap(){
+            Reply::Array(entries) =>{
+                let deliveries: Vec<Reply> = entries.into_iter().map(|entry| match entry{
+                    Reply::Array(mut entry) => entry.remove(3),
+                    reply => panic!("unexpected reply {:?}", reply),
+                }).collect();
+                assert_eq!(deliveries, vec![Reply::Integer(1), Reply::Integer(2)]);
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_executor_exec(){
+        let db = Arc::new(RwLock::new(simple_mem_db::SimpleMemDB::new()));
+        let (tx, rx) = channel();
+        exec_queued(db.clone(), &["set a 1", "incr a", "lpush a x", "get a"], Vec::new(), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![
+            Reply::ok(),
+            Reply::Integer(2),
+            Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+            Reply::Bulk(b"2".to_vec()),
+        ]));
+
+        // a watched key written after WATCH fails EXEC
+        let version = db.read().unwrap().key_version(b"a".to_vec()).unwrap();
+        exec(db.clone(), gen_redis_code("set a 5".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::ok());
+        exec_queued(db.clone(), &["set a 6"], vec![(b"a".to_vec(), version)], tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::NilArray);
+        exec(db.clone(), gen_redis_code("get a".to_string()), tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Bulk(b"5".to_vec()));
+
+        let version = db.read().unwrap().key_version(b"a".to_vec()).unwrap();
+        exec_queued(db.clone(), &["set a 6", "get a"], vec![(b"a".to_vec(), version)], tx.clone());
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::ok(), Reply::Bulk(b"6".to_vec())]));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_executor_publish(){
+        let db = Arc::new(RwLock::new(simple_mem_db::SimpleMemDB::new()));
+        let registry = Arc::new(Mutex::new(PubSub::new()));
+        let (sub_tx, sub_rx) = channel();
+        let mut subscriber = Subscriber::new(registry.clone(), 1, Outbox::new(sub_tx, None));
+        subscriber.handle(&[b"subscribe".to_vec(), b"news".to_vec()]).unwrap();
+
+        // MULTI queues PUBLISH, the message goes out with the commit
+        let (tx, rx) = channel();
+        let queued = vec![vec![b"set".to_vec(), b"a".to_vec(), b"1".to_vec()],
+                          vec![b"publish".to_vec(), b"news".to_vec(), b"hello".to_vec()]];
+        let mut executor = Executor::new_exec(db.clone(), queued, Vec::new(), tx.clone());
+        executor.set_pubsub(registry);
+        executor.parse();
+        executor.exec_command();
+        assert_eq!(rx.recv().unwrap(), Reply::Array(vec![Reply::ok(), Reply::Integer(1)]));
+        assert_eq!(sub_rx.try_recv().unwrap(), Reply::Array(vec![
+            Reply::Bulk(b"message".to_vec()), Reply::Bulk(b"news".to_vec()), Reply::Bulk(b"hello".to_vec())]));
+
+        exec(db, gen_redis_code("publish news".to_string()), tx);
+        assert_eq!(rx.recv().unwrap(), Reply::Error("ERR wrong number of arguments for 'publish' command".to_string()));
+    }
+}
@@ -0,0 +1,133 @@
+// Glob-style matching as used by KEYS, the MATCH option of the SCAN family
+// and pattern subscriptions. Supports `*`, `?`, `[abc]`, `[^a-z]` and `\`
+// escapes, all on raw bytes.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool{
+    let (mut p, mut s) = (0, 0);
+    // where to resume after the last `*` when the rest fails to match
+    let mut backtrack: Option<(usize, usize)> = None;
+    while s < string.len(){
+        if p < pattern.len(){
+            match pattern[p]{
+                b'*' =>{
+                    p += 1;
+                    backtrack = Some((p, s));
+                    continue;
+                }
+                b'?' =>{
+                    p += 1;
+                    s += 1;
+                    continue;
+                }
+                b'[' =>{
+                    if let Some((matched, next)) = match_class(pattern, p, string[s]){
+                        if matched{
+                            p = next;
+                            s += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() =>{
+                    if pattern[p + 1] == string[s]{
+                        p += 2;
+                        s += 1;
+                        continue;
+                    }
+                }
+                c =>{
+                    if c == string[s]{
+                        p += 1;
+                        s += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        match backtrack{
+            Some((star_p, star_s)) =>{
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, star_s + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// The bytes every match has to start with, up to the first special character.
+pub fn literal_prefix(pattern: &[u8]) -> &[u8]{
+    let end = pattern.iter().position(|c| b"*?[\\".contains(c)).unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+// Matches `c` against the class starting at `pattern[start] == '['`.
+// Returns whether it matched and where the pattern continues, `None` for an
+// unterminated class which then never matches.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)>{
+    let mut p = start + 1;
+    let negate = p < pattern.len() && pattern[p] == b'^';
+    if negate{
+        p += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while p < pattern.len(){
+        if pattern[p] == b']' && !first{
+            return Some((matched != negate, p + 1));
+        }
+        first = false;
+        if pattern[p] == b'\\' && p + 1 < pattern.len(){
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        }else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']'{
+            let (low, high) = if pattern[p] <= pattern[p + 2]{
+                (pattern[p], pattern[p + 2])
+            }else{
+                (pattern[p + 2], pattern[p])
+            };
+            matched |= low <= c && c <= high;
+            p += 3;
+        }else{
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::glob::{glob_match, literal_prefix};
+
+    #[test]
+    fn test_glob_match(){
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"h*llo", b"hllo"));
+        assert!(glob_match(b"user:*:name", b"user:42:name"));
+        assert!(!glob_match(b"user:*:name", b"user:42:age"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(!glob_match(b"h[a-b]llo", b"hcllo"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(!glob_match(b"[abc", b"a"));
+        assert!(glob_match(b"*a*b*", b"xxaxxbxx"));
+    }
+
+    #[test]
+    fn test_literal_prefix(){
+        assert_eq!(literal_prefix(b"user:*"), b"user:");
+        assert_eq!(literal_prefix(b"a?c"), b"a");
+        assert_eq!(literal_prefix(b"[ab]"), b"");
+        assert_eq!(literal_prefix(b"a\\*"), b"a");
+        assert_eq!(literal_prefix(b"plain"), b"plain");
+    }
+}
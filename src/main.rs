@@ -9,6 +9,10 @@ extern crate threadpool;
 mod redis_server;
 mod simple_mem_db;
 mod executor;
+mod protocol;
+mod glob;
+mod pubsub;
+mod wait_queue;
 mod tikv;
 
 fn main(){
@@ -16,7 +20,12 @@ fn main(){
     let pd_endpoint = vec!["127.0.0.1:2379".to_string()];
     let listener = TcpListener::bind(&addr).unwrap();
     //let db = simple_mem_db::SimpleMemDB::new();
-    let db = tikv::tikv_db::TikvDB::connect(pd_endpoint).unwrap();
+    // "txn" as second argument makes multi-key commands atomic across servers
+    let mode = match env::args().nth(2).as_ref().map(|mode| mode.as_str()){
+        Some("txn") => tikv::tikv_db::Mode::Txn,
+        _ => tikv::tikv_db::Mode::Raw,
+    };
+    let db = tikv::tikv_db::TikvDB::connect_with_mode(pd_endpoint, mode).unwrap();
     let mut server = redis_server::Server::new(db, "8080".to_string());
     for connection in listener.incoming(){
         match connection{
@@ -0,0 +1,279 @@
+use std::fmt;
+use std::mem;
+
+// limits follow the ones redis itself enforces on the request path
+const MAX_INLINE_SIZE: usize = 64 * 1024;
+const MAX_MULTIBULK_LEN: i64 = 1024 * 1024;
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct ProtocolError(pub String);
+
+impl fmt::Display for ProtocolError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "Protocol error: {}", self.0)
+    }
+}
+
+/// A reply value, encoded to RESP2 in one place right before it is written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply{
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    // null multi-bulk, e.g. LPOP with a count on a missing key
+    NilArray,
+    Array(Vec<Reply>),
+}
+
+impl Reply{
+    pub fn ok() -> Reply{
+        Reply::Status("OK".to_string())
+    }
+
+    pub fn encode(&self) -> Vec<u8>{
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+
+    pub fn encode_to(&self, out: &mut Vec<u8>){
+        match self{
+            Reply::Status(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            Reply::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(b) =>{
+                out.extend_from_slice(format!("${}\r\n", b.len()).as_bytes());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Reply::NilArray => out.extend_from_slice(b"*-1\r\n"),
+            Reply::Array(items) =>{
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items{
+                    item.encode_to(out);
+                }
+            }
+        }
+    }
+}
+
+/// Incremental RESP2 decoder, one per connection.
+///
+/// Bytes are appended with `feed` as they come off the socket and complete
+/// commands are pulled out with `next_command`. A frame split over several
+/// reads stays buffered until the rest of it arrives, and a read carrying
+/// several pipelined frames yields all of them.
+pub struct RespParser{
+    buf: Vec<u8>,
+    pos: usize,
+    // number of bulk strings still missing from the current multi-bulk frame
+    multibulk_len: usize,
+    // length of the bulk string whose header has been consumed
+    bulk_len: Option<usize>,
+    args: Vec<Vec<u8>>,
+}
+
+impl RespParser{
+    pub fn new() -> Self{
+        RespParser{
+            buf: Vec::new(),
+            pos: 0,
+            multibulk_len: 0,
+            bulk_len: None,
+            args: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]){
+        if self.pos > 0{
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next complete command, or `None` when more input is needed.
+    pub fn next_command(&mut self) -> Result<Option<Vec<Vec<u8>>>, ProtocolError>{
+        loop{
+            if self.multibulk_len == 0{
+                if self.pos >= self.buf.len(){
+                    return Ok(None);
+                }
+                if self.buf[self.pos] != b'*'{
+                    match self.parse_inline()?{
+                        Some(args) if args.is_empty() => continue,
+                        res => return Ok(res),
+                    }
+                }
+                let line = match self.read_line(b'\r')?{
+                    Some(line) => line,
+                    None => return Ok(None),
+                };
+                let len = parse_len(&line[1..], "invalid multibulk length")?;
+                if len > MAX_MULTIBULK_LEN{
+                    return Err(ProtocolError("invalid multibulk length".to_string()));
+                }
+                if len <= 0{
+                    // empty frames are skipped like redis does
+                    continue;
+                }
+                self.multibulk_len = len as usize;
+                self.args = Vec::with_capacity(self.multibulk_len.min(1024));
+            }
+
+            while self.multibulk_len > 0{
+                if self.bulk_len.is_none(){
+                    let line = match self.read_line(b'\r')?{
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    if line.is_empty() || line[0] != b'$'{
+                        let got = line.first().map(|c| *c as char).unwrap_or(' ');
+                        return Err(ProtocolError(format!("expected '$', got '{}'", got)));
+                    }
+                    let len = parse_len(&line[1..], "invalid bulk length")?;
+                    if !(0..=MAX_BULK_LEN).contains(&len){
+                        return Err(ProtocolError("invalid bulk length".to_string()));
+                    }
+                    self.bulk_len = Some(len as usize);
+                }
+
+                let len = self.bulk_len.unwrap();
+                if self.buf.len() - self.pos < len + 2{
+                    return Ok(None);
+                }
+                if &self.buf[self.pos + len..self.pos + len + 2] != b"\r\n"{
+                    return Err(ProtocolError("bulk string is not terminated by CRLF".to_string()));
+                }
+                self.args.push(self.buf[self.pos..self.pos + len].to_vec());
+                self.pos += len + 2;
+                self.bulk_len = None;
+                self.multibulk_len -= 1;
+            }
+
+            return Ok(Some(mem::take(&mut self.args)));
+        }
+    }
+
+    // inline commands (`PING\r\n`) as typed into telnet
+    fn parse_inline(&mut self) -> Result<Option<Vec<Vec<u8>>>, ProtocolError>{
+        let line = match self.read_line(b'\n')?{
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let args = line
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        Ok(Some(args))
+    }
+
+    // reads up to the next CRLF (or bare LF for inline commands) and consumes it
+    fn read_line(&mut self, term: u8) -> Result<Option<Vec<u8>>, ProtocolError>{
+        let rest = &self.buf[self.pos..];
+        let end = match rest.iter().position(|c| *c == b'\n'){
+            Some(end) => end,
+            None =>{
+                if rest.len() > MAX_INLINE_SIZE{
+                    return Err(ProtocolError("too big request".to_string()));
+                }
+                return Ok(None);
+            }
+        };
+        let line = if end > 0 && rest[end - 1] == b'\r'{
+            rest[..end - 1].to_vec()
+        }else if term == b'\n'{
+            rest[..end].to_vec()
+        }else{
+            return Err(ProtocolError("line is not terminated by CRLF".to_string()));
+        };
+        self.pos += end + 1;
+        Ok(Some(line))
+    }
+}
+
+fn parse_len(raw: &[u8], msg: &str) -> Result<i64, ProtocolError>{
+    std::str::from_utf8(raw)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| ProtocolError(msg.to_string()))
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::protocol::{RespParser, ProtocolError, Reply};
+
+    fn args(raw: &[&str]) -> Vec<Vec<u8>>{
+        raw.iter().map(|x| x.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_parse_partial_frame(){
+        let mut parser = RespParser::new();
+        parser.feed(b"*3\r\n$3\r\nset\r\n$3\r\nfo");
+        assert_eq!(parser.next_command(), Ok(None));
+        parser.feed(b"o\r\n$3\r");
+        assert_eq!(parser.next_command(), Ok(None));
+        parser.feed(b"\nbar\r\n");
+        assert_eq!(parser.next_command(), Ok(Some(args(&["set", "foo", "bar"]))));
+        assert_eq!(parser.next_command(), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_pipelined_frames(){
+        let mut parser = RespParser::new();
+        parser.feed(b"*1\r\n$4\r\nping\r\n*2\r\n$3\r\nget\r\n$3\r\nfoo\r\n*2\r\n$3\r\nget");
+        assert_eq!(parser.next_command(), Ok(Some(args(&["ping"]))));
+        assert_eq!(parser.next_command(), Ok(Some(args(&["get", "foo"]))));
+        assert_eq!(parser.next_command(), Ok(None));
+        parser.feed(b"\r\n$1\r\nx\r\n");
+        assert_eq!(parser.next_command(), Ok(Some(args(&["get", "x"]))));
+    }
+
+    #[test]
+    fn test_parse_bulk_with_crlf_inside(){
+        let mut parser = RespParser::new();
+        parser.feed(b"*2\r\n$4\r\necho\r\n$4\r\na\r\nb\r\n");
+        assert_eq!(parser.next_command(), Ok(Some(args(&["echo", "a\r\nb"]))));
+    }
+
+    #[test]
+    fn test_parse_inline(){
+        let mut parser = RespParser::new();
+        parser.feed(b"\r\nget  foo\r\nping\n");
+        assert_eq!(parser.next_command(), Ok(Some(args(&["get", "foo"]))));
+        assert_eq!(parser.next_command(), Ok(Some(args(&["ping"]))));
+    }
+
+    #[test]
+    fn test_parse_invalid_frame(){
+        let mut parser = RespParser::new();
+        parser.feed(b"*1\r\n+get\r\n");
+        assert_eq!(parser.next_command(), Err(ProtocolError("expected '$', got '+'".to_string())));
+
+        let mut parser = RespParser::new();
+        parser.feed(b"*x\r\n");
+        assert_eq!(parser.next_command(), Err(ProtocolError("invalid multibulk length".to_string())));
+    }
+
+    #[test]
+    fn test_encode_reply(){
+        assert_eq!(Reply::ok().encode(), b"+OK\r\n".to_vec());
+        assert_eq!(Reply::Error("ERR oops".to_string()).encode(), b"-ERR oops\r\n".to_vec());
+        assert_eq!(Reply::Integer(-3).encode(), b":-3\r\n".to_vec());
+        assert_eq!(Reply::Bulk(b"".to_vec()).encode(), b"$0\r\n\r\n".to_vec());
+        assert_eq!(Reply::Nil.encode(), b"$-1\r\n".to_vec());
+        assert_eq!(Reply::NilArray.encode(), b"*-1\r\n".to_vec());
+        let nested = Reply::Array(vec![
+            Reply::Bulk(b"foo".to_vec()),
+            Reply::Nil,
+            Reply::Array(vec![Reply::Integer(1)]),
+        ]);
+        assert_eq!(nested.encode(), b"*3\r\n$3\r\nfoo\r\n$-1\r\n*1\r\n:1\r\n".to_vec());
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{SendError, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::glob::glob_match;
+use crate::protocol::Reply;
+
+// the hard client-output-buffer-limit of redis for pubsub clients
+pub const OUTPUT_BUFFER_LIMIT: usize = 32 * 1024 * 1024;
+
+// subscribers of one channel or pattern, by connection id
+type Subscribers = HashMap<u64, Outbox>;
+
+// The outgoing queue of a connection, counting the bytes queued and not
+// written yet. A subscriber letting messages pile up past the limit is
+// disconnected.
+#[derive(Clone)]
+pub struct Outbox{
+    tx: Sender<Reply>,
+    queued: Arc<AtomicUsize>,
+    // shut down to disconnect, `None` in tests
+    connection: Option<Arc<TcpStream>>,
+}
+
+impl Outbox{
+    pub fn new(tx: Sender<Reply>, connection: Option<TcpStream>) -> Self{
+        Outbox{
+            tx,
+            queued: Arc::new(AtomicUsize::new(0)),
+            connection: connection.map(Arc::new),
+        }
+    }
+
+    // the byte count the writer of the connection takes written bytes off
+    pub fn queued(&self) -> Arc<AtomicUsize>{
+        self.queued.clone()
+    }
+
+    pub fn send(&self, reply: Reply) -> Result<(), SendError<Reply>>{
+        self.queued.fetch_add(reply.encode().len(), Ordering::SeqCst);
+        self.tx.send(reply)
+    }
+
+    // Queues a published message, `false` when the connection is gone or
+    // was just disconnected for reaching the limit.
+    fn push(&self, reply: Reply) -> bool{
+        let len = reply.encode().len();
+        if self.queued.fetch_add(len, Ordering::SeqCst) + len > OUTPUT_BUFFER_LIMIT{
+            self.queued.fetch_sub(len, Ordering::SeqCst);
+            if let Some(ref connection) = self.connection{
+                let _ = connection.shutdown(Shutdown::Both);
+            }
+            return false;
+        }
+        self.tx.send(reply).is_ok()
+    }
+}
+
+// Channel and pattern subscriptions of every connection of the server.
+// Messages are pushed to the outgoing queue of the subscribed connections.
+#[derive(Default)]
+pub struct PubSub{
+    channels: HashMap<Vec<u8>, Subscribers>,
+    patterns: HashMap<Vec<u8>, Subscribers>,
+}
+
+impl PubSub{
+    pub fn new() -> Self{
+        Default::default()
+    }
+
+    fn subscribe(&mut self, pattern: bool, name: Vec<u8>, id: u64, outbox: Outbox){
+        let registry = if pattern{ &mut self.patterns }else{ &mut self.channels };
+        registry.entry(name).or_default().insert(id, outbox);
+    }
+
+    fn unsubscribe(&mut self, pattern: bool, name: &[u8], id: u64){
+        let registry = if pattern{ &mut self.patterns }else{ &mut self.channels };
+        let empty = match registry.get_mut(name){
+            Some(subscribers) =>{
+                subscribers.remove(&id);
+                subscribers.is_empty()
+            }
+            None => false,
+        };
+        // channels without subscribers are no longer active
+        if empty{
+            registry.remove(name);
+        }
+    }
+
+    // Sends `message` to the subscribers of `channel` and of the patterns
+    // matching it. Returns how many messages went out.
+    pub fn publish(&self, channel: &[u8], message: &[u8]) -> usize{
+        let mut received = 0;
+        if let Some(subscribers) = self.channels.get(channel){
+            for outbox in subscribers.values(){
+                let reply = Reply::Array(vec![
+                    Reply::Bulk(b"message".to_vec()),
+                    Reply::Bulk(channel.to_vec()),
+                    Reply::Bulk(message.to_vec()),
+                ]);
+                // a connection going away drops its subscriptions soon
+                if outbox.push(reply){
+                    received += 1;
+                }
+            }
+        }
+        for (pattern, subscribers) in &self.patterns{
+            if !glob_match(pattern, channel){
+                continue;
+            }
+            for outbox in subscribers.values(){
+                let reply = Reply::Array(vec![
+                    Reply::Bulk(b"pmessage".to_vec()),
+                    Reply::Bulk(pattern.clone()),
+                    Reply::Bulk(channel.to_vec()),
+                    Reply::Bulk(message.to_vec()),
+                ]);
+                if outbox.push(reply){
+                    received += 1;
+                }
+            }
+        }
+        received
+    }
+
+    // how many messages `publish` of `channel` would send
+    pub fn receivers(&self, channel: &[u8]) -> usize{
+        let patterns = self.patterns.iter()
+            .filter(|(pattern, _)| glob_match(pattern, channel))
+            .map(|(_, subscribers)| subscribers.len())
+            .sum::<usize>();
+        self.num_sub(channel) + patterns
+    }
+
+    // the channels with at least one subscriber, sorted
+    pub fn channels(&self, pattern: Option<&[u8]>) -> Vec<Vec<u8>>{
+        let mut channels: Vec<Vec<u8>> = self.channels.keys()
+            .filter(|channel| pattern.map_or(true, |pattern| glob_match(pattern, channel)))
+            .cloned()
+            .collect();
+        channels.sort();
+        channels
+    }
+
+    pub fn num_sub(&self, channel: &[u8]) -> usize{
+        self.channels.get(channel).map_or(0, |subscribers| subscribers.len())
+    }
+
+    // patterns subscribed by any connection
+    pub fn num_pat(&self) -> usize{
+        self.patterns.len()
+    }
+}
+
+// The pub/sub side of a connection: its subscriptions, dropped with it, and
+// the commands of subscriber mode.
+pub struct Subscriber{
+    registry: Arc<Mutex<PubSub>>,
+    id: u64,
+    // outgoing queue of the connection
+    outbox: Outbox,
+    channels: BTreeSet<Vec<u8>>,
+    patterns: BTreeSet<Vec<u8>>,
+}
+
+impl Subscriber{
+    pub fn new(registry: Arc<Mutex<PubSub>>, id: u64, outbox: Outbox) -> Self{
+        Subscriber{
+            registry,
+            id,
+            outbox,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+        }
+    }
+
+    fn count(&self) -> usize{
+        self.channels.len() + self.patterns.len()
+    }
+
+    // Replies to a pub/sub command, `None` for the other commands. A
+    // connection with subscriptions only takes the commands of subscriber
+    // mode, the others are refused here. PUBLISH is run by the executor, so
+    // MULTI can queue it.
+    pub fn handle(&mut self, args: &[Vec<u8>]) -> Option<Vec<Reply>>{
+        let name = args.first().map(|name| String::from_utf8_lossy(name).to_uppercase()).unwrap_or_default();
+        let replies = match name.as_ref(){
+            "SUBSCRIBE" | "PSUBSCRIBE" if args.len() < 2 => vec![wrong_arity(&name)],
+            "SUBSCRIBE" => args[1..].iter().map(|channel| self.subscribe(false, channel.clone())).collect(),
+            "PSUBSCRIBE" => args[1..].iter().map(|pattern| self.subscribe(true, pattern.clone())).collect(),
+            "UNSUBSCRIBE" => self.unsubscribe(false, &args[1..]),
+            "PUNSUBSCRIBE" => self.unsubscribe(true, &args[1..]),
+            "PING" if self.count() > 0 && args.len() > 2 => vec![wrong_arity(&name)],
+            "PING" if self.count() > 0 =>{
+                let message = args.get(1).cloned().unwrap_or_default();
+                vec![Reply::Array(vec![Reply::Bulk(b"pong".to_vec()), Reply::Bulk(message)])]
+            }
+            _ if self.count() > 0 => vec![Reply::Error(format!(
+                "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context",
+                name.to_lowercase()))],
+            "PUBSUB" => vec![self.pubsub(args)],
+            _ => return None,
+        };
+        Some(replies)
+    }
+
+    fn subscribe(&mut self, pattern: bool, name: Vec<u8>) -> Reply{
+        let subscriptions = if pattern{ &mut self.patterns }else{ &mut self.channels };
+        if subscriptions.insert(name.clone()){
+            self.registry.lock().unwrap().subscribe(pattern, name.clone(), self.id, self.outbox.clone());
+        }
+        let kind = if pattern{ "psubscribe" }else{ "subscribe" };
+        Reply::Array(vec![Reply::Bulk(kind.as_bytes().to_vec()), Reply::Bulk(name), Reply::Integer(self.count() as i64)])
+    }
+
+    // without names drops every subscription of the kind
+    fn unsubscribe(&mut self, pattern: bool, names: &[Vec<u8>]) -> Vec<Reply>{
+        let kind = if pattern{ "punsubscribe" }else{ "unsubscribe" };
+        let names: Vec<Vec<u8>> = if names.is_empty(){
+            let subscriptions = if pattern{ &self.patterns }else{ &self.channels };
+            subscriptions.iter().cloned().collect()
+        }else{
+            names.to_vec()
+        };
+        if names.is_empty(){
+            return vec![Reply::Array(vec![Reply::Bulk(kind.as_bytes().to_vec()), Reply::Nil, Reply::Integer(self.count() as i64)])];
+        }
+        names.into_iter().map(|name| {
+            let subscriptions = if pattern{ &mut self.patterns }else{ &mut self.channels };
+            if subscriptions.remove(&name){
+                self.registry.lock().unwrap().unsubscribe(pattern, &name, self.id);
+            }
+            Reply::Array(vec![Reply::Bulk(kind.as_bytes().to_vec()), Reply::Bulk(name), Reply::Integer(self.count() as i64)])
+        }).collect()
+    }
+
+    // PUBSUB CHANNELS [pattern], NUMSUB [channel ...] and NUMPAT
+    fn pubsub(&self, args: &[Vec<u8>]) -> Reply{
+        let sub = args.get(1).map(|sub| String::from_utf8_lossy(sub).to_uppercase()).unwrap_or_default();
+        let registry = self.registry.lock().unwrap();
+        match (sub.as_ref(), args.len()){
+            ("CHANNELS", 2) | ("CHANNELS", 3) =>{
+                let channels = registry.channels(args.get(2).map(|pattern| pattern.as_slice()));
+                Reply::Array(channels.into_iter().map(Reply::Bulk).collect())
+            }
+            ("NUMSUB", _) => Reply::Array(args[2..].iter().flat_map(|channel| {
+                vec![Reply::Bulk(channel.clone()), Reply::Integer(registry.num_sub(channel) as i64)]
+            }).collect()),
+            ("NUMPAT", 2) => Reply::Integer(registry.num_pat() as i64),
+            _ => Reply::Error(format!(
+                "ERR Unknown subcommand or wrong number of arguments for '{}'. Try PUBSUB HELP.",
+                String::from_utf8_lossy(args.get(1).map_or(&[][..], |sub| sub.as_slice())))),
+        }
+    }
+}
+
+impl Drop for Subscriber{
+    fn drop(&mut self){
+        let mut registry = self.registry.lock().unwrap();
+        for channel in &self.channels{
+            registry.unsubscribe(false, channel, self.id);
+        }
+        for pattern in &self.patterns{
+            registry.unsubscribe(true, pattern, self.id);
+        }
+    }
+}
+
+fn wrong_arity(name: &str) -> Reply{
+    Reply::Error(format!("ERR wrong number of arguments for '{}' command", name.to_lowercase()))
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::pubsub::{PubSub, Subscriber, Outbox, OUTPUT_BUFFER_LIMIT};
+    use crate::protocol::Reply;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+
+    fn command(command: &str) -> Vec<Vec<u8>>{
+        command.split(' ').map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    fn bulks(items: &[&str]) -> Reply{
+        Reply::Array(items.iter().map(|item| Reply::Bulk(item.as_bytes().to_vec())).collect())
+    }
+
+    #[test]
+    fn test_publish(){
+        let registry = Arc::new(Mutex::new(PubSub::new()));
+        let (tx, rx) = channel();
+        let mut subscriber = Subscriber::new(registry.clone(), 1, Outbox::new(tx, None));
+        let (other_tx, _other_rx) = channel();
+        let mut publisher = Subscriber::new(registry.clone(), 2, Outbox::new(other_tx, None));
+
+        let replies = subscriber.handle(&command("subscribe news sports")).unwrap();
+        assert_eq!(replies[1], Reply::Array(vec![
+            Reply::Bulk(b"subscribe".to_vec()), Reply::Bulk(b"sports".to_vec()), Reply::Integer(2)]));
+        subscriber.handle(&command("psubscribe new*")).unwrap();
+
+        assert!(publisher.handle(&command("publish news hello")).is_none());
+        assert_eq!(registry.lock().unwrap().receivers(b"news"), 2);
+        assert_eq!(registry.lock().unwrap().publish(b"news", b"hello"), 2);
+        assert_eq!(rx.recv().unwrap(), bulks(&["message", "news", "hello"]));
+        assert_eq!(rx.recv().unwrap(), bulks(&["pmessage", "new*", "news", "hello"]));
+        assert_eq!(registry.lock().unwrap().publish(b"weather", b"rain"), 0);
+
+        assert_eq!(publisher.handle(&command("pubsub channels n*")).unwrap(), vec![bulks(&["news"])]);
+        assert_eq!(publisher.handle(&command("pubsub numsub news other")).unwrap(), vec![Reply::Array(vec![
+            Reply::Bulk(b"news".to_vec()), Reply::Integer(1), Reply::Bulk(b"other".to_vec()), Reply::Integer(0)])]);
+        assert_eq!(publisher.handle(&command("pubsub numpat")).unwrap(), vec![Reply::Integer(1)]);
+
+        // a closed connection leaves no subscription behind
+        drop(subscriber);
+        assert_eq!(publisher.handle(&command("pubsub channels")).unwrap(), vec![Reply::Array(vec![])]);
+        assert_eq!(registry.lock().unwrap().publish(b"news", b"hello"), 0);
+    }
+
+    #[test]
+    fn test_subscriber_mode(){
+        let registry = Arc::new(Mutex::new(PubSub::new()));
+        let (tx, _rx) = channel();
+        let mut subscriber = Subscriber::new(registry, 1, Outbox::new(tx, None));
+        assert!(subscriber.handle(&command("get foo")).is_none());
+        assert!(subscriber.handle(&command("ping")).is_none());
+
+        subscriber.handle(&command("subscribe a b")).unwrap();
+        let refused = subscriber.handle(&command("get foo")).unwrap();
+        assert_eq!(refused[0].encode(),
+                   b"-ERR Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context\r\n".to_vec());
+        assert_eq!(subscriber.handle(&command("ping")).unwrap(), vec![bulks(&["pong", ""])]);
+        assert_eq!(subscriber.handle(&command("ping a b")).unwrap(), vec![
+            Reply::Error("ERR wrong number of arguments for 'ping' command".to_string())]);
+
+        let replies = subscriber.handle(&command("unsubscribe")).unwrap();
+        assert_eq!(replies, vec![
+            Reply::Array(vec![Reply::Bulk(b"unsubscribe".to_vec()), Reply::Bulk(b"a".to_vec()), Reply::Integer(1)]),
+            Reply::Array(vec![Reply::Bulk(b"unsubscribe".to_vec()), Reply::Bulk(b"b".to_vec()), Reply::Integer(0)]),
+        ]);
+        assert!(subscriber.handle(&command("get foo")).is_none());
+        assert_eq!(subscriber.handle(&command("punsubscribe")).unwrap(), vec![Reply::Array(vec![
+            Reply::Bulk(b"punsubscribe".to_vec()), Reply::Nil, Reply::Integer(0)])]);
+    }
+
+    #[test]
+    fn test_output_buffer_limit(){
+        let registry = Arc::new(Mutex::new(PubSub::new()));
+        let (tx, rx) = channel();
+        let outbox = Outbox::new(tx, None);
+        let mut subscriber = Subscriber::new(registry.clone(), 1, outbox.clone());
+        subscriber.handle(&command("subscribe news")).unwrap();
+        let message = vec![b'x'; OUTPUT_BUFFER_LIMIT / 2];
+        assert_eq!(registry.lock().unwrap().publish(b"news", &message), 1);
+        // the subscriber is not reading, the next message is past the limit
+        assert_eq!(registry.lock().unwrap().publish(b"news", &message), 0);
+
+        // written bytes make room again
+        let written: usize = rx.try_iter().map(|reply| reply.encode().len()).sum();
+        outbox.queued().fetch_sub(written, Ordering::SeqCst);
+        assert_eq!(registry.lock().unwrap().publish(b"news", &message), 1);
+    }
+}
@@ -1,26 +1,41 @@
 use std::net::TcpStream;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, RwLock, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::io::prelude::*;
+use std::io::ErrorKind;
 use std::thread;
 use threadpool::ThreadPool;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Bound;
+use std::mem;
+use std::fmt;
 
-use crate::executor::Executor;
+use crate::executor::{Executor, error_reply};
+use crate::protocol::{RespParser, Reply};
+use crate::pubsub::{PubSub, Subscriber, Outbox};
+use crate::wait_queue::WaitQueues;
 
 struct Client<E: DB>{
     db: Arc<RwLock<E>>,
     id: u64,
     tx: Sender<Executor<E>>,
+    pubsub: Arc<Mutex<PubSub>>,
+    waits: Arc<Mutex<WaitQueues>>,
 }
 
 impl<E: DB> Client<E>{
-    fn new(db: Arc<RwLock<E>>, id: u64, tx: Sender<Executor<E>>) -> Self{
+    fn new(db: Arc<RwLock<E>>, id: u64, tx: Sender<Executor<E>>, pubsub: Arc<Mutex<PubSub>>,
+           waits: Arc<Mutex<WaitQueues>>) -> Self{
         println!("NewClient: {}", id);
         Client{
             db,
             id,
             tx,
+            pubsub,
+            waits,
         }
     }
 
@@ -29,40 +44,62 @@ impl<E: DB> Client<E>{
         // 通过sender发送给server
         let tx = self.tx.clone();
         let db = self.db.clone();
+        let (writer, peer, closer) = match (connection.try_clone(), connection.try_clone(), connection.try_clone()){
+            (Ok(writer), Ok(peer), Ok(closer)) => (writer, peer, closer),
+            _ =>{
+                println!("error");
+                return;
+            }
+        };
+        // replies and published messages go out in the order they are queued
+        let (out_tx, out_rx) = channel();
+        let outbox = Outbox::new(out_tx, Some(closer));
+        let queued = outbox.queued();
+        thread::spawn(move ||{
+            write_replies(writer, out_rx, queued);
+        });
+        let mut subscriber = Subscriber::new(self.pubsub.clone(), self.id, outbox.clone());
+        let mut blocker = Blocker::new(self.waits.clone(), self.id, peer);
         thread::spawn(move ||{
+            let mut parser = RespParser::new();
+            let mut multi = MultiState::default();
+            let mut raw_command = vec![0; 40960];
             loop{
-                let mut inited = false;
+                let len = match connection.read(&mut raw_command){
+                    // connection closed by peer
+                    Ok(0) => break,
+                    Ok(l) => l,
+                    Err(_) =>{
+                        println!("error");
+                        break;
+                    }
+                };
+                parser.feed(&raw_command[..len]);
+                // one read may carry any number of pipelined commands
                 loop{
-                    let mut raw_command = vec![0; 40960];
-                    let res= connection.read(&mut raw_command);
-                    if inited{
-                        match res{
-                            Ok(l) =>{
-                                // received a command
-                                if l != 0{
-                                    //println!("read message from conenction {:?}", l);
-                                    //stream.write("+OK\r\n".as_bytes());
-                                    let (sender, receiver) = channel();
-                                    let db = db.clone();
-                                    tx.send(Executor::new(db, raw_command,sender)).unwrap();
-                                    match receiver.recv_timeout(Duration::from_secs(10)){
-                                        Ok(res) =>{
-                                            connection.write(res.as_bytes()).unwrap();
-                                        }
-                                        Err(_) =>{
-                                            connection.write("-ReceiveTimeout\r\n".as_bytes()).unwrap();
-                                        }
-                                    }
-                                }
-                            }
-                            _ =>{
-                                println!("error");
-                            }
+                    let args = match parser.next_command(){
+                        Ok(Some(args)) => args,
+                        Ok(None) => break,
+                        Err(e) =>{
+                            let _ = outbox.send(Reply::Error(format!("ERR {}", e)));
+                            return;
                         }
+                    };
+                    // pub/sub commands are not queued by MULTI, blocking ones
+                    // do not block in there
+                    let replies = if multi.is_queuing(){
+                        vec![multi.handle(&db, &tx, args)]
+                    }else if let Some(replies) = subscriber.handle(&args){
+                        replies
+                    }else if let Some(reply) = blocker.handle(&db, &tx, &args){
+                        vec![reply]
                     }else{
-                        println!("new client");
-                        connection.write("+OK\r\n".as_bytes()).unwrap();
-                        inited = true;
+                        vec![multi.handle(&db, &tx, args)]
+                    };
+                    for reply in replies{
+                        if outbox.send(reply).is_err(){
+                            return;
+                        }
                     }
                 }
             }
@@ -70,6 +107,214 @@ impl<E: DB> Client<E>{
     }
 }
 
+// Writes what the connection has to send, batching whatever is queued at
+// once, and takes it off the `queued` bytes of its outbox. Ends when every
+// sender is gone or the peer stopped reading.
+fn write_replies(mut connection: TcpStream, replies: Receiver<Reply>, queued: Arc<AtomicUsize>){
+    while let Ok(reply) = replies.recv(){
+        let mut buf = reply.encode();
+        for reply in replies.try_iter(){
+            reply.encode_to(&mut buf);
+        }
+        if connection.write_all(&buf).is_err(){
+            break;
+        }
+        queued.fetch_sub(buf.len(), Ordering::SeqCst);
+    }
+}
+
+// Runs the executor made by `new` on the pool and waits for its reply.
+fn execute<E: DB, F>(tx: &Sender<Executor<E>>, new: F) -> Reply
+where F: FnOnce(Sender<Reply>) -> Executor<E>{
+    let (sender, receiver) = channel();
+    tx.send(new(sender)).unwrap();
+    match receiver.recv_timeout(Duration::from_secs(10)){
+        Ok(res) => res,
+        Err(_) => Reply::Error("ERR ReceiveTimeout".to_string()),
+    }
+}
+
+// Pushes made through another server sharing the storage are not signalled,
+// blocked clients look at their keys again this often.
+const BLOCK_POLL_MS: u64 = 1000;
+
+// BLPOP, BRPOP, BLMOVE and the BLOCK option of XREAD and XREADGROUP on a
+// connection. The connection waits for a push between tries instead of a
+// thread of the pool.
+struct Blocker{
+    waits: Arc<Mutex<WaitQueues>>,
+    id: u64,
+    wake_tx: Sender<()>,
+    wake_rx: Receiver<()>,
+    // to notice the client going away while blocked
+    peer: TcpStream,
+}
+
+impl Blocker{
+    fn new(waits: Arc<Mutex<WaitQueues>>, id: u64, peer: TcpStream) -> Self{
+        let (wake_tx, wake_rx) = channel();
+        Blocker{
+            waits,
+            id,
+            wake_tx,
+            wake_rx,
+            peer,
+        }
+    }
+
+    // Replies to a blocking command once it got something or timed out,
+    // `None` for the other commands.
+    fn handle<E: DB>(&mut self, db: &Arc<RwLock<E>>, tx: &Sender<Executor<E>>, args: &[Vec<u8>]) -> Option<Reply>{
+        let name = args.first().map(|name| String::from_utf8_lossy(name).to_uppercase()).unwrap_or_default();
+        if name != "BLPOP" && name != "BRPOP" && name != "BLMOVE" && name != "XREAD" && name != "XREADGROUP"{
+            return None;
+        }
+        let (sender, _) = channel();
+        let mut executor = Executor::new(db.clone(), args.to_vec(), sender);
+        executor.parse();
+        if let Some(err) = executor.parse_error(){
+            return Some(err);
+        }
+        let (keys, timeout) = executor.blocking()?;
+        let args = match executor.blocking_args(){
+            Ok(args) => args,
+            Err(err) => return Some(err),
+        };
+        // a deadline too far to represent is never reached
+        let deadline = if timeout == 0{ None }else{ Instant::now().checked_add(Duration::from_millis(timeout)) };
+        // wakeups meant for an earlier command
+        while self.wake_rx.try_recv().is_ok(){}
+        // queued before the first try, a push right after it is not missed
+        self.waits.lock().unwrap().wait(&keys, self.id, &self.wake_tx);
+        let reply = loop{
+            // only the first client in line on a key takes from it, another
+            // one would take what a push was signalled for
+            let first_on = if executor.consumes(){
+                self.waits.lock().unwrap().first_on(&keys, self.id)
+            }else{
+                keys.clone()
+            };
+            if !first_on.is_empty(){
+                let args = executor.blocking_args_on(&args, &first_on);
+                let reply = execute(tx, |sender| Executor::new(db.clone(), args, sender));
+                if reply != Reply::Nil && reply != Reply::NilArray{
+                    break reply;
+                }
+            }
+            let poll = Duration::from_millis(BLOCK_POLL_MS);
+            let wait = match deadline{
+                Some(deadline) =>{
+                    let now = Instant::now();
+                    if now >= deadline{
+                        break executor.timeout_reply();
+                    }
+                    (deadline - now).min(poll)
+                }
+                None => poll,
+            };
+            let _ = self.wake_rx.recv_timeout(wait);
+            // a closed connection must not take elements
+            if self.peer_closed(){
+                break executor.timeout_reply();
+            }
+        };
+        self.waits.lock().unwrap().leave(&keys, self.id);
+        Some(reply)
+    }
+
+    // Peeks under a short read timeout. Non-blocking mode would be shared
+    // with the writer thread, the timeout only affects reads, all made here.
+    fn peer_closed(&self) -> bool{
+        if self.peer.set_read_timeout(Some(Duration::from_millis(1))).is_err(){
+            return false;
+        }
+        let closed = match self.peer.peek(&mut [0]){
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::TimedOut,
+        };
+        let _ = self.peer.set_read_timeout(None);
+        closed
+    }
+}
+
+// MULTI/EXEC and WATCH state of a connection
+#[derive(Default)]
+struct MultiState{
+    // commands queued since MULTI, `None` outside of MULTI
+    queued: Option<Vec<Vec<Vec<u8>>>>,
+    // a command was refused while queuing, EXEC discards the transaction
+    failed: bool,
+    // keys under WATCH with the version they had
+    watched: Vec<(Vec<u8>, u64)>,
+}
+
+impl MultiState{
+    fn is_queuing(&self) -> bool{
+        self.queued.is_some()
+    }
+
+    // Runs a command of the connection, or queues it after MULTI.
+    fn handle<E: DB>(&mut self, db: &Arc<RwLock<E>>, tx: &Sender<Executor<E>>, args: Vec<Vec<u8>>) -> Reply{
+        let name = args.first().map(|name| String::from_utf8_lossy(name).to_uppercase()).unwrap_or_default();
+        match (name.as_ref(), self.queued.is_some()){
+            ("MULTI", true) => Reply::Error("ERR MULTI calls can not be nested".to_string()),
+            ("MULTI", false) =>{
+                self.queued = Some(Vec::new());
+                Reply::ok()
+            }
+            ("EXEC", false) => Reply::Error("ERR EXEC without MULTI".to_string()),
+            ("EXEC", true) =>{
+                let queued = self.queued.take().unwrap_or_default();
+                let watched = mem::take(&mut self.watched);
+                if mem::take(&mut self.failed){
+                    return Reply::Error("EXECABORT Transaction discarded because of previous errors.".to_string());
+                }
+                execute(tx, |sender| Executor::new_exec(db.clone(), queued, watched, sender))
+            }
+            ("DISCARD", false) => Reply::Error("ERR DISCARD without MULTI".to_string()),
+            ("DISCARD", true) =>{
+                *self = MultiState::default();
+                Reply::ok()
+            }
+            ("WATCH", true) => Reply::Error("ERR WATCH inside MULTI is not allowed".to_string()),
+            ("WATCH", false) if args.len() < 2 =>
+                Reply::Error("ERR wrong number of arguments for 'watch' command".to_string()),
+            ("WATCH", false) =>{
+                for key in args.into_iter().skip(1){
+                    match db.read().unwrap().key_version(key.clone()){
+                        Ok(version) => self.watched.push((key, version)),
+                        Err(e) => return error_reply(e),
+                    }
+                }
+                Reply::ok()
+            }
+            ("UNWATCH", _) =>{
+                self.watched.clear();
+                Reply::ok()
+            }
+            (_, true) =>{
+                let (sender, _) = channel();
+                let mut executor = Executor::new(db.clone(), args.clone(), sender);
+                executor.parse();
+                match executor.parse_error(){
+                    Some(err) =>{
+                        self.failed = true;
+                        err
+                    }
+                    None =>{
+                        if let Some(ref mut queued) = self.queued{
+                            queued.push(args);
+                        }
+                        Reply::Status("QUEUED".to_string())
+                    }
+                }
+            }
+            _ => execute(tx, |sender| Executor::new(db.clone(), args, sender)),
+        }
+    }
+}
+
 struct TikvClient{
 
 }
@@ -77,39 +322,647 @@ struct TikvClient{
 pub enum DBError{
     NotFound,
     AlreadyExited(String),
+    // the key holds a value of another type than the command works on
+    WrongType,
+    OutOfRange,
+    // the storage backend failed, with its description of the failure
+    Storage(String),
     Other,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyType{
+    String,
+    List,
+    Hash,
+    Set,
+    ZSet,
+    Stream,
+}
+
+impl KeyType{
+    pub fn name(&self) -> &'static str{
+        match self{
+            KeyType::String => "string",
+            KeyType::List => "list",
+            KeyType::Hash => "hash",
+            KeyType::Set => "set",
+            KeyType::ZSet => "zset",
+            KeyType::Stream => "stream",
+        }
+    }
+}
+
+// a hash field with its value
+pub type FieldValue = (Vec<u8>, Vec<u8>);
+// a sorted set member with its score
+pub type ScoredMember = (Vec<u8>, f64);
+// a stream entry with its fields in the order they were added
+pub type StreamEntry = (StreamId, Vec<FieldValue>);
+// the extended form of XPENDING: min idle time, first and last id both
+// inclusive, count and consumer
+pub type PendingRange = (u64, StreamId, StreamId, usize, Option<Vec<u8>>);
+
+/// Id of a stream entry, the unix time in milliseconds it was added at and
+/// a sequence number among the entries of that millisecond.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId{
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId{
+    pub const MIN: StreamId = StreamId{ ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId{ ms: u64::MAX, seq: u64::MAX };
+
+    pub fn new(ms: u64, seq: u64) -> Self{
+        StreamId{ ms, seq }
+    }
+
+    /// The id right after this one, `None` for `MAX`.
+    pub fn next(self) -> Option<StreamId>{
+        match self.seq.checked_add(1){
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => self.ms.checked_add(1).map(|ms| StreamId::new(ms, 0)),
+        }
+    }
+
+    /// The id right before this one, `None` for `MIN`.
+    pub fn prev(self) -> Option<StreamId>{
+        match self.seq.checked_sub(1){
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => self.ms.checked_sub(1).map(|ms| StreamId::new(ms, u64::MAX)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The id XADD asks for: `*`, `<ms>-*` or a complete one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NewStreamId{
+    Auto,
+    AutoSeq(u64),
+    Id(StreamId),
+}
+
+impl NewStreamId{
+    /// The id of an entry added after `last`, `None` if it would not be
+    /// greater. `now` is the current unix time in milliseconds.
+    pub fn resolve(self, last: StreamId, now: u64) -> Option<StreamId>{
+        let id = match self{
+            // a clock going backwards keeps counting on the last millisecond
+            NewStreamId::Auto if now > last.ms => StreamId::new(now, 0),
+            NewStreamId::Auto => last.next()?,
+            NewStreamId::AutoSeq(ms) if ms == last.ms => StreamId::new(ms, last.seq.checked_add(1)?),
+            NewStreamId::AutoSeq(ms) => StreamId::new(ms, 0),
+            NewStreamId::Id(id) => id,
+        };
+        if id > last{
+            Some(id)
+        }else{
+            None
+        }
+    }
+}
+
+/// An entry delivered to a consumer of a group and not acknowledged yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEntry{
+    pub id: StreamId,
+    pub consumer: Vec<u8>,
+    // unix time in milliseconds of the last delivery
+    pub delivered_at: u64,
+    pub deliveries: u64,
+}
+
+/// How XTRIM and XADD cut the oldest entries of a stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamTrim{
+    // keep at most that many entries
+    MaxLen(usize),
+    // drop the entries with a smaller id
+    MinId(StreamId),
+}
+
+/// The subcommands of XGROUP, after the key and the group.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GroupCommand{
+    // the id to deliver after (`None` for `$`), MKSTREAM
+    Create(Option<StreamId>, bool),
+    Destroy,
+    CreateConsumer(Vec<u8>),
+    DelConsumer(Vec<u8>),
+    // the new last delivered id, `None` for `$`
+    SetId(Option<StreamId>),
+}
+
+#[derive(Clone, Default)]
+pub struct ClaimOptions{
+    // milliseconds the claimed entries count as idle for, or the unix time
+    // in milliseconds of their last delivery
+    pub idle: Option<u64>,
+    pub time: Option<u64>,
+    pub retry_count: Option<u64>,
+    // claim ids that are not pending yet
+    pub force: bool,
+    // reply with the ids only, leaving the delivery counts alone
+    pub just_id: bool,
+    // raises the last delivered id of the group
+    pub last_id: Option<StreamId>,
+}
+
+pub fn now_millis() -> u64{
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// Maps a score to an integer with the same order, so zset members sort the
+/// same way in memory and in TiKV keys.
+pub fn score_to_ordered(score: f64) -> u64{
+    // -0.0 and 0.0 are the same score
+    let bits = if score == 0.0{ 0 }else{ score.to_bits() };
+    if bits >> 63 == 1{
+        !bits
+    }else{
+        bits | 1 << 63
+    }
+}
+
+pub fn ordered_to_score(ordered: u64) -> f64{
+    let bits = if ordered >> 63 == 1{ ordered & !(1 << 63) }else{ !ordered };
+    f64::from_bits(bits)
+}
+
+/// Turns ZRANGEBYSCORE bounds into `[start, end)` over `score_to_ordered`
+/// values, `end` being `None` when open. `None` if no score can match.
+pub fn ordered_score_range(min: Bound<f64>, max: Bound<f64>) -> Option<(u64, Option<u64>)>{
+    let start = match min{
+        Bound::Included(score) => score_to_ordered(score),
+        Bound::Excluded(score) => score_to_ordered(score).checked_add(1)?,
+        Bound::Unbounded => 0,
+    };
+    let end = match max{
+        Bound::Included(score) => score_to_ordered(score).checked_add(1),
+        Bound::Excluded(score) => Some(score_to_ordered(score)),
+        Bound::Unbounded => None,
+    };
+    match end{
+        Some(end) if end <= start => None,
+        _ => Some((start, end)),
+    }
+}
+
+// every RandomState is seeded with fresh random keys, which is plenty for
+// picking set members
+pub fn random_u64() -> u64{
+    RandomState::new().build_hasher().finish()
+}
+
+/// Up to `count` distinct items of `items`, every subset being equally
+/// likely. Reservoir sampling, so one pass whatever the count.
+pub fn random_sample<T, I: Iterator<Item = T>>(items: I, count: usize) -> Vec<T>{
+    let mut picked = Vec::new();
+    for (i, item) in items.enumerate(){
+        if i < count{
+            picked.push(item);
+        }else{
+            let j = (random_u64() % (i as u64 + 1)) as usize;
+            if j < count{
+                picked[j] = item;
+            }
+        }
+    }
+    picked
+}
+
+/// Resolves redis style `start`/`stop` indexes, both inclusive and negative
+/// ones counting from the tail, to positions in a sequence of `len` items.
+/// Returns `None` when the range selects nothing.
+pub fn normalize_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)>{
+    let len = len as i64;
+    let start = if start < 0{ (len + start).max(0) }else{ start };
+    let stop = if stop < 0{ len + stop }else{ stop.min(len - 1) };
+    if start > stop || start >= len{
+        None
+    }else{
+        Some((start as usize, stop as usize))
+    }
+}
+
+/// Resolves a single, possibly negative, index. `None` when out of range.
+pub fn normalize_index(index: i64, len: usize) -> Option<usize>{
+    let index = if index < 0{ len as i64 + index }else{ index };
+    if index < 0 || index >= len as i64{
+        None
+    }else{
+        Some(index as usize)
+    }
+}
+
 pub trait DB: Send + Sync + 'static{
-    fn raw_put(&mut self, key: String, value: String) -> Result<String, DBError>;
+    /// Overwrites the value and drops any expiry, like a plain SET.
+    fn raw_put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DBError>{
+        self.raw_put_with_expire(key, value, None)
+    }
 
-    fn raw_get(&self, key: String) -> Result<String, DBError>;
+    /// `expire_at` is a unix timestamp in milliseconds.
+    fn raw_put_with_expire(&mut self, key: Vec<u8>, value: Vec<u8>, expire_at: Option<u64>) -> Result<(), DBError>;
 
-    fn txn_put(&self){
+    fn raw_get(&self, key: Vec<u8>) -> Result<Vec<u8>, DBError>;
 
+    /// Returns `NotFound` for a missing key and `None` for a key without expiry.
+    fn get_expire(&self, key: Vec<u8>) -> Result<Option<u64>, DBError>;
+
+    /// Values of `keys` in the same order, `None` for a key that is missing or
+    /// holds another type. Backends behind a network batch the reads.
+    fn batch_get(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>, DBError>{
+        keys.into_iter().map(|key| {
+            match self.raw_get(key){
+                Ok(value) => Ok(Some(value)),
+                Err(DBError::NotFound) | Err(DBError::WrongType) => Ok(None),
+                Err(e) => Err(e),
+            }
+        }).collect()
     }
 
-    fn txn_get(&self){
+    /// A SET of every pair, a repeated key ends up with its last value.
+    fn batch_put(&mut self, kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DBError>{
+        for (key, value) in kvs{
+            self.raw_put(key, value)?;
+        }
+        Ok(())
+    }
 
+    /// Value and expiry of a string key in one read.
+    fn raw_get_with_expire(&self, key: Vec<u8>) -> Result<(Vec<u8>, Option<u64>), DBError>{
+        let value = self.raw_get(key.clone())?;
+        let expire_at = self.get_expire(key)?;
+        Ok((value, expire_at))
     }
+
+    /// Atomically stores `value` only if the key still holds `expected`, as
+    /// returned by `raw_get_with_expire`, or is missing when `expected` is `None`.
+    /// Returns false when another writer changed the key in the meantime.
+    fn raw_compare_and_swap(&mut self, key: Vec<u8>, expected: Option<(Vec<u8>, Option<u64>)>,
+                            value: Vec<u8>, expire_at: Option<u64>) -> Result<bool, DBError>;
+
+    /// Sets or clears the expiry of an existing key, returns false if the key is missing.
+    fn set_expire(&mut self, key: Vec<u8>, expire_at: Option<u64>) -> Result<bool, DBError>;
+
+    /// Removes a key of any type, returns false if it did not exist.
+    fn delete(&mut self, key: Vec<u8>) -> Result<bool, DBError>;
+
+    fn exists(&self, key: Vec<u8>) -> Result<bool, DBError>;
+
+    fn key_type(&self, key: Vec<u8>) -> Result<KeyType, DBError>;
+
+    /// Up to `count` live keys within the bounds in key order, with their type.
+    /// Callers page through the keyspace by starting after the last key.
+    fn scan_keys(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, count: usize) -> Result<Vec<(Vec<u8>, KeyType)>, DBError>;
+
+    /// Moves the value and its expiry to `new_key`, overwriting whatever is there.
+    fn rename(&mut self, key: Vec<u8>, new_key: Vec<u8>) -> Result<(), DBError>;
+
+    /// Physically removes up to `limit` keys whose expiry has passed.
+    /// Backends that only expire lazily keep the default.
+    fn evict_expired(&mut self, _limit: usize) -> Result<usize, DBError>{
+        Ok(0)
+    }
+
+    // Lists. A list that becomes empty is removed, like in redis, and every
+    // method fails with `WrongType` when the key holds something else.
+
+    /// Pushes `values` one by one to the head (`left`) or the tail, creating
+    /// the list unless `only_existing` is set. Returns the new length, 0 if
+    /// nothing was pushed.
+    fn list_push(&mut self, key: Vec<u8>, values: Vec<Vec<u8>>, left: bool, only_existing: bool) -> Result<usize, DBError>;
+
+    /// Pops up to `count` elements from the head or the tail, empty for a missing key.
+    fn list_pop(&mut self, key: Vec<u8>, left: bool, count: usize) -> Result<Vec<Vec<u8>>, DBError>;
+
+    fn list_len(&self, key: Vec<u8>) -> Result<usize, DBError>;
+
+    /// Elements between two inclusive indexes, see `normalize_range`.
+    fn list_range(&self, key: Vec<u8>, start: i64, stop: i64) -> Result<Vec<Vec<u8>>, DBError>;
+
+    fn list_index(&self, key: Vec<u8>, index: i64) -> Result<Option<Vec<u8>>, DBError>;
+
+    /// `NotFound` for a missing key and `OutOfRange` for a bad index.
+    fn list_set(&mut self, key: Vec<u8>, index: i64, value: Vec<u8>) -> Result<(), DBError>;
+
+    /// Keeps only the elements between the two inclusive indexes.
+    fn list_trim(&mut self, key: Vec<u8>, start: i64, stop: i64) -> Result<(), DBError>;
+
+    /// Removes elements equal to `value`: the first `count` ones from the head
+    /// when positive, from the tail when negative, all of them for 0.
+    fn list_rem(&mut self, key: Vec<u8>, count: i64, value: Vec<u8>) -> Result<usize, DBError>;
+
+    /// Inserts `value` next to the first `pivot`. Returns the new length,
+    /// `None` if the pivot is not in the list and 0 if the key is missing.
+    fn list_insert(&mut self, key: Vec<u8>, before: bool, pivot: Vec<u8>, value: Vec<u8>) -> Result<Option<usize>, DBError>;
+
+    // Hashes, same conventions as lists.
+
+    /// Sets the given fields, returns how many of them were not there before.
+    fn hash_set(&mut self, key: Vec<u8>, fields: Vec<FieldValue>) -> Result<usize, DBError>;
+
+    fn hash_get(&self, key: Vec<u8>, field: Vec<u8>) -> Result<Option<Vec<u8>>, DBError>;
+
+    /// Returns how many of the fields existed.
+    fn hash_del(&mut self, key: Vec<u8>, fields: Vec<Vec<u8>>) -> Result<usize, DBError>;
+
+    fn hash_len(&self, key: Vec<u8>) -> Result<usize, DBError>;
+
+    /// Up to `count` fields with their values, ordered by field and all
+    /// greater than `after` when given. Cursor based HSCAN builds on this.
+    fn hash_scan(&self, key: Vec<u8>, after: Option<Vec<u8>>, count: usize) -> Result<Vec<FieldValue>, DBError>;
+
+    fn hash_get_all(&self, key: Vec<u8>) -> Result<Vec<FieldValue>, DBError>{
+        self.hash_scan(key, None, usize::MAX)
+    }
+
+    // Sets, same conventions as lists.
+
+    /// Returns how many members were not in the set before.
+    fn set_add(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> Result<usize, DBError>;
+
+    /// Returns how many members were in the set.
+    fn set_rem(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> Result<usize, DBError>;
+
+    fn set_members(&self, key: Vec<u8>) -> Result<Vec<Vec<u8>>, DBError>;
+
+    fn set_is_member(&self, key: Vec<u8>, member: Vec<u8>) -> Result<bool, DBError>;
+
+    fn set_card(&self, key: Vec<u8>) -> Result<usize, DBError>;
+
+    /// Up to `count` distinct members picked at random, without removing them.
+    fn set_random_members(&self, key: Vec<u8>, count: usize) -> Result<Vec<Vec<u8>>, DBError>;
+
+    // Sorted sets, same conventions as lists. Members are ordered by score,
+    // then by member bytes.
+
+    /// Adds members or updates their score. `nx` only adds new members, `xx`
+    /// only updates existing ones. Returns how many were added and how many
+    /// existing ones got a different score.
+    fn zset_add(&mut self, key: Vec<u8>, members: Vec<ScoredMember>, nx: bool, xx: bool) -> Result<(usize, usize), DBError>;
+
+    fn zset_rem(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> Result<usize, DBError>;
+
+    fn zset_score(&self, key: Vec<u8>, member: Vec<u8>) -> Result<Option<f64>, DBError>;
+
+    fn zset_card(&self, key: Vec<u8>) -> Result<usize, DBError>;
+
+    /// 0-based position of the member in score order.
+    fn zset_rank(&self, key: Vec<u8>, member: Vec<u8>) -> Result<Option<usize>, DBError>;
+
+    /// Members between two inclusive ranks, see `normalize_range`.
+    fn zset_range(&self, key: Vec<u8>, start: i64, stop: i64) -> Result<Vec<ScoredMember>, DBError>;
+
+    /// Members whose score is within the bounds, skipping the first `offset`
+    /// matches and returning at most `count`.
+    fn zset_range_by_score(&self, key: Vec<u8>, min: Bound<f64>, max: Bound<f64>, offset: usize, count: usize)
+        -> Result<Vec<ScoredMember>, DBError>;
+
+    // Streams, same conventions as lists except that a stream stays when
+    // trimmed down to no entries, like in redis. Entry ids only grow.
+
+    /// Appends an entry with the id `id` resolves to after the last one the
+    /// stream ever had, creating the stream. `None` when no id fits.
+    fn stream_add(&mut self, key: Vec<u8>, id: NewStreamId, fields: Vec<FieldValue>) -> Result<Option<StreamId>, DBError>;
+
+    fn stream_len(&self, key: Vec<u8>) -> Result<usize, DBError>;
+
+    /// The greatest id the stream has given out, `None` for a missing key.
+    fn stream_last_id(&self, key: Vec<u8>) -> Result<Option<StreamId>, DBError>;
+
+    /// Up to `count` entries with an id between `start` and `end`, both
+    /// inclusive, in id order or from `end` down when `reverse`.
+    fn stream_range(&self, key: Vec<u8>, start: StreamId, end: StreamId, count: usize, reverse: bool)
+        -> Result<Vec<StreamEntry>, DBError>;
+
+    /// Drops the oldest entries as `trim` says, returns how many.
+    fn stream_trim(&mut self, key: Vec<u8>, trim: StreamTrim) -> Result<usize, DBError>;
+
+    // Consumer groups of a stream. A group remembers the last entry it
+    // delivered and the entries delivered but not acknowledged yet, its
+    // pending entries. Methods on a group fail with `NotFound` when the
+    // stream or the group is missing.
+
+    /// Creates a group delivering the entries after `last_id`, after the last
+    /// entry of the stream for `None`. A missing stream is created empty with
+    /// `make_stream`, `NotFound` otherwise. false when the group exists.
+    fn stream_create_group(&mut self, key: Vec<u8>, group: Vec<u8>, last_id: Option<StreamId>, make_stream: bool)
+        -> Result<bool, DBError>;
+
+    /// Drops the group with its consumers and pending entries, false if it
+    /// did not exist.
+    fn stream_destroy_group(&mut self, key: Vec<u8>, group: Vec<u8>) -> Result<bool, DBError>;
+
+    /// The id of the last entry delivered to the group.
+    fn stream_group_last_id(&self, key: Vec<u8>, group: Vec<u8>) -> Result<StreamId, DBError>;
+
+    fn stream_set_group_last_id(&mut self, key: Vec<u8>, group: Vec<u8>, last_id: StreamId) -> Result<(), DBError>;
+
+    /// false when the consumer exists already.
+    fn stream_create_consumer(&mut self, key: Vec<u8>, group: Vec<u8>, consumer: Vec<u8>) -> Result<bool, DBError>;
+
+    /// Drops the consumer with its pending entries, returns how many it had.
+    fn stream_delete_consumer(&mut self, key: Vec<u8>, group: Vec<u8>, consumer: Vec<u8>) -> Result<usize, DBError>;
+
+    /// Up to `count` pending entries with an id between `start` and `end`,
+    /// both inclusive, in id order. Only those of `consumer` when given.
+    fn stream_pending(&self, key: Vec<u8>, group: Vec<u8>, start: StreamId, end: StreamId, count: usize,
+                      consumer: Option<Vec<u8>>) -> Result<Vec<PendingEntry>, DBError>;
+
+    /// Adds or replaces pending entries, creating the consumers they name.
+    fn stream_set_pending(&mut self, key: Vec<u8>, group: Vec<u8>, entries: Vec<PendingEntry>) -> Result<(), DBError>;
+
+    /// Removes pending entries, returns how many of them were pending.
+    fn stream_ack(&mut self, key: Vec<u8>, group: Vec<u8>, ids: Vec<StreamId>) -> Result<usize, DBError>;
+
+    // Transactions. Every command runs between `begin_transaction` and
+    // `commit_transaction` on the same thread, so a backend without atomic
+    // multi-key writes can make them atomic. Backends already atomic under
+    // the DB lock keep the defaults.
+
+    fn begin_transaction(&self) -> Result<(), DBError>{
+        Ok(())
+    }
+
+    /// Commits the writes made since `begin_transaction`. `false` means the
+    /// transaction conflicted with another one and was rolled back, the
+    /// command may be run again. After a failed write nothing is committed
+    /// and the error comes back here.
+    fn commit_transaction(&self) -> Result<bool, DBError>{
+        Ok(true)
+    }
+
+    /// A version of `key` taken by WATCH, which EXEC hands back to
+    /// `begin_watched_transaction`.
+    fn key_version(&self, key: Vec<u8>) -> Result<u64, DBError>;
+
+    /// Like `begin_transaction` for EXEC after WATCH, with each watched key
+    /// and the version WATCH saw. `false` when one of them has changed since.
+    /// A change after that makes `commit_transaction` fail, EXEC then runs
+    /// again and gets `false` here. Comparing the versions is only safe while
+    /// nothing else runs, see `isolates_transactions`.
+    fn begin_watched_transaction(&self, watched: Vec<(Vec<u8>, u64)>) -> Result<bool, DBError>{
+        for (key, version) in watched{
+            if self.key_version(key)? != version{
+                return Ok(false);
+            }
+        }
+        self.begin_transaction()?;
+        Ok(true)
+    }
+
+    /// Whether the storage keeps concurrent transactions apart. Otherwise
+    /// EXEC runs with no other command of the server going on.
+    fn isolates_transactions(&self) -> bool{
+        false
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SetOptions{
+    pub nx: bool,
+    pub xx: bool,
+    // relative expiry in milliseconds from EX/PX
+    pub expire: Option<u64>,
+    pub keep_ttl: bool,
+    pub get: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct ZAddOptions{
+    pub nx: bool,
+    pub xx: bool,
+    // count changed scores in the reply, not only added members
+    pub ch: bool,
+    // work like ZINCRBY
+    pub incr: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetOp{
+    Inter,
+    Union,
+    Diff,
 }
 
 #[derive(Clone)]
 pub enum Operation{
-    Set(String, String, bool),
-    Get(String),
-    GetSet(String, String),
-    StrLen(String),
-    Append(String, String),
-    SetRange(String, usize, String),
-    GetRange(String, i32, i32),
-    Mset(Vec<(String, String)>),
-    Mget(Vec<String>),
+    Set(Vec<u8>, Vec<u8>, SetOptions),
+    SetNx(Vec<u8>, Vec<u8>),
+    Get(Vec<u8>),
+    GetSet(Vec<u8>, Vec<u8>),
+    StrLen(Vec<u8>),
+    Append(Vec<u8>, Vec<u8>),
+    SetRange(Vec<u8>, usize, Vec<u8>),
+    GetRange(Vec<u8>, i32, i32),
+    Mset(Vec<(Vec<u8>, Vec<u8>)>),
+    Mget(Vec<Vec<u8>>),
+    // absolute unix time in milliseconds
+    Expire(Vec<u8>, i64),
+    // key, reply in milliseconds
+    Ttl(Vec<u8>, bool),
+    Persist(Vec<u8>),
+    Del(Vec<Vec<u8>>),
+    Exists(Vec<Vec<u8>>),
+    Type(Vec<u8>),
+    // src, dst, only if dst does not exist
+    Rename(Vec<u8>, Vec<u8>, bool),
+    Keys(Vec<u8>),
+    // cursor, MATCH pattern, COUNT, TYPE
+    Scan(Option<Vec<u8>>, Option<Vec<u8>>, usize, Option<String>),
+    DBSize,
+    IncrBy(Vec<u8>, i64),
+    IncrByFloat(Vec<u8>, f64),
+    // key, values, to the head, only if the list exists
+    Push(Vec<u8>, Vec<Vec<u8>>, bool, bool),
+    // key, from the head, count when given explicitly
+    Pop(Vec<u8>, bool, Option<usize>),
+    // keys, from the head, timeout in milliseconds (0 waits forever)
+    BPop(Vec<Vec<u8>>, bool, u64),
+    // source, destination, from the head, to the head, timeout of BLMOVE
+    LMove(Vec<u8>, Vec<u8>, bool, bool, Option<u64>),
+    LLen(Vec<u8>),
+    LRange(Vec<u8>, i64, i64),
+    LIndex(Vec<u8>, i64),
+    LSet(Vec<u8>, i64, Vec<u8>),
+    LTrim(Vec<u8>, i64, i64),
+    LRem(Vec<u8>, i64, Vec<u8>),
+    // key, before the pivot, pivot, value
+    LInsert(Vec<u8>, bool, Vec<u8>, Vec<u8>),
+    // key, fields, reply OK like HMSET
+    HSet(Vec<u8>, Vec<FieldValue>, bool),
+    HGet(Vec<u8>, Vec<u8>),
+    HMGet(Vec<u8>, Vec<Vec<u8>>),
+    HDel(Vec<u8>, Vec<Vec<u8>>),
+    // key, with fields, with values
+    HGetAll(Vec<u8>, bool, bool),
+    HLen(Vec<u8>),
+    HExists(Vec<u8>, Vec<u8>),
+    HIncrBy(Vec<u8>, Vec<u8>, i64),
+    // key, cursor, MATCH pattern, COUNT
+    HScan(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>, usize),
+    SAdd(Vec<u8>, Vec<Vec<u8>>),
+    SRem(Vec<u8>, Vec<Vec<u8>>),
+    SMembers(Vec<u8>),
+    SIsMember(Vec<u8>, Vec<u8>),
+    SCard(Vec<u8>),
+    // keys, destination of the *STORE variants
+    SCombine(SetOp, Vec<Vec<u8>>, Option<Vec<u8>>),
+    SPop(Vec<u8>, Option<usize>),
+    // a negative count allows repeated members
+    SRandMember(Vec<u8>, Option<i64>),
+    ZAdd(Vec<u8>, Vec<ScoredMember>, ZAddOptions),
+    ZIncrBy(Vec<u8>, f64, Vec<u8>),
+    // key, start, stop, with scores
+    ZRange(Vec<u8>, i64, i64, bool),
+    // key, min, max, with scores, LIMIT offset and count
+    ZRangeByScore(Vec<u8>, Bound<f64>, Bound<f64>, bool, usize, usize),
+    ZRank(Vec<u8>, Vec<u8>),
+    ZScore(Vec<u8>, Vec<u8>),
+    ZRem(Vec<u8>, Vec<Vec<u8>>),
+    ZCard(Vec<u8>),
+    // key, the id asked for, fields, NOMKSTREAM, trimming
+    XAdd(Vec<u8>, NewStreamId, Vec<FieldValue>, bool, Option<StreamTrim>),
+    // key, first and last id both inclusive, COUNT, from the last id down
+    XRange(Vec<u8>, StreamId, StreamId, usize, bool),
+    XLen(Vec<u8>),
+    XTrim(Vec<u8>, StreamTrim),
+    // keys, the id to read after for each (`None` for `$`), COUNT, BLOCK
+    // timeout in milliseconds
+    XRead(Vec<Vec<u8>>, Vec<Option<StreamId>>, usize, Option<u64>),
+    // key, group, subcommand
+    XGroup(Vec<u8>, Vec<u8>, GroupCommand),
+    // group, consumer, keys, the id to read after for each (`None` for `>`),
+    // COUNT, BLOCK timeout in milliseconds, NOACK
+    XReadGroup(Vec<u8>, Vec<u8>, Vec<Vec<u8>>, Vec<Option<StreamId>>, usize, Option<u64>, bool),
+    XAck(Vec<u8>, Vec<u8>, Vec<StreamId>),
+    // key, group, the range of the extended form
+    XPending(Vec<u8>, Vec<u8>, Option<PendingRange>),
+    // key, group, consumer, min idle time, ids
+    XClaim(Vec<u8>, Vec<u8>, Vec<u8>, u64, Vec<StreamId>, ClaimOptions),
+    // key, group, consumer, min idle time, first id, COUNT, JUSTID
+    XAutoClaim(Vec<u8>, Vec<u8>, Vec<u8>, u64, StreamId, usize, bool),
+    // channel, message
+    Publish(Vec<u8>, Vec<u8>),
+    // the commands queued by MULTI, the keys under WATCH with their version
+    Exec(Vec<Operation>, Vec<(Vec<u8>, u64)>),
+    Error(String),
     Other,
     NotParsed,
 }
 
 const MAX_BGWORK_NUM: usize = 32;
+const EXPIRE_CYCLE_MS: u64 = 100;
+const EXPIRE_CYCLE_KEYS: usize = 256;
 
 pub struct Server<E: DB>{
     db: Arc<RwLock<E>>,
@@ -118,6 +971,10 @@ pub struct Server<E: DB>{
     connections: Vec<Client<E>>,
     executor_tx: Sender<Executor<E>>,
     exec_pool: ThreadPool,
+    // channel and pattern subscriptions of all connections
+    pubsub: Arc<Mutex<PubSub>>,
+    // clients blocked on list keys
+    waits: Arc<Mutex<WaitQueues>>,
 }
 
 impl<E: DB> Server<E>{
@@ -130,28 +987,65 @@ impl<E: DB> Server<E>{
             connections: vec![],
             executor_tx,
             exec_pool: ThreadPool::new(MAX_BGWORK_NUM),
+            pubsub: Arc::new(Mutex::new(PubSub::new())),
+            waits: Arc::new(Mutex::new(WaitQueues::new())),
         };
         let thread_pool = server.exec_pool.clone();
+        let db = server.db.clone();
+        // taken shared by every command and exclusive by EXEC, unless the
+        // storage keeps transactions apart itself
+        let exec_lock = Arc::new(RwLock::new(()));
+        let waits = server.waits.clone();
+        let pubsub = server.pubsub.clone();
         thread::spawn(move | |{
             loop{
                 let res = executor_rx.recv();
                 match res{
                     Ok(mut executor) => {
+                        let db = db.clone();
+                        let exec_lock = exec_lock.clone();
+                        let waits = waits.clone();
+                        let pubsub = pubsub.clone();
                         thread_pool.execute(move ||{
+                            executor.set_pubsub(pubsub);
                             executor.parse();
-                            executor.exec_command();
+                            if executor.is_exec() && !db.read().unwrap().isolates_transactions(){
+                                let _alone = exec_lock.write().unwrap();
+                                executor.exec_command();
+                            }else{
+                                let _shared = exec_lock.read().unwrap();
+                                executor.exec_command();
+                            }
+                            for key in executor.ready_keys(){
+                                waits.lock().unwrap().signal(key);
+                            }
                         });
                     },
                     Err(_) => (),
                 }
             }
         });
+        // active expiration, complements the lazy check done on access
+        let db = server.db.clone();
+        thread::spawn(move ||{
+            loop{
+                thread::sleep(Duration::from_millis(EXPIRE_CYCLE_MS));
+                loop{
+                    let evicted = db.write().unwrap().evict_expired(EXPIRE_CYCLE_KEYS).unwrap_or(0);
+                    if evicted < EXPIRE_CYCLE_KEYS{
+                        break;
+                    }
+                }
+            }
+        });
         server
     }
 
     pub fn new_connection(&mut self, stream: TcpStream){
-        let client_id = self.client_seq + 1;
-        let client = Client::new(self.db.clone(), client_id,  self.executor_tx.clone());
+        self.client_seq += 1;
+        let client_id = self.client_seq;
+        let client = Client::new(self.db.clone(), client_id,  self.executor_tx.clone(), self.pubsub.clone(),
+                                 self.waits.clone());
         client.spawn(stream);
         self.connections.push(client);
     }
@@ -1,30 +1,962 @@
 use crate::redis_server::DB;
 use crate::redis_server::DBError;
+use crate::redis_server::KeyType;
+use crate::redis_server::{now_millis, normalize_range, normalize_index, random_sample, FieldValue};
+use crate::redis_server::{score_to_ordered, ordered_score_range, ScoredMember};
+use crate::redis_server::{NewStreamId, PendingEntry, StreamEntry, StreamId, StreamTrim};
 use std::collections::btree_map::BTreeMap;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ops::Bound;
+
+// removed keys whose version is kept, older ones are forgotten
+const MAX_TOMBSTONES: usize = 65536;
+
+// ordered by field so HSCAN can resume from the last one returned
+type Hash = BTreeMap<Vec<u8>, Vec<u8>>;
+
+// a member's score, plus the members ordered by score for ranges and ranks
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ZSet{
+    scores: HashMap<Vec<u8>, f64>,
+    index: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl ZSet{
+    // returns the previous score
+    fn insert(&mut self, member: Vec<u8>, score: f64) -> Option<f64>{
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old{
+            self.index.remove(&(score_to_ordered(old), member.clone()));
+        }
+        self.index.insert((score_to_ordered(score), member));
+        old
+    }
+
+    fn remove(&mut self, member: &[u8]) -> bool{
+        match self.scores.remove(member){
+            Some(score) =>{
+                self.index.remove(&(score_to_ordered(score), member.to_vec()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn scored(&self, member: &[u8]) -> ScoredMember{
+        (member.to_vec(), self.scores[member])
+    }
+}
+
+// entries by id, with the greatest id given out which trimming keeps
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Stream{
+    entries: BTreeMap<StreamId, Vec<FieldValue>>,
+    last_id: StreamId,
+    groups: BTreeMap<Vec<u8>, ConsumerGroup>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ConsumerGroup{
+    last_id: StreamId,
+    consumers: BTreeSet<Vec<u8>>,
+    pending: BTreeMap<StreamId, PendingEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value{
+    Str(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(Hash),
+    Set(BTreeSet<Vec<u8>>),
+    ZSet(ZSet),
+    Stream(Stream),
+}
+
+impl Value{
+    fn key_type(&self) -> KeyType{
+        match self{
+            Value::Str(_) => KeyType::String,
+            Value::List(_) => KeyType::List,
+            Value::Hash(_) => KeyType::Hash,
+            Value::Set(_) => KeyType::Set,
+            Value::ZSet(_) => KeyType::ZSet,
+            Value::Stream(_) => KeyType::Stream,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct SimpleMemDB{
-    table: BTreeMap<String, String>,
+    table: BTreeMap<Vec<u8>, Value>,
+    // unix time in milliseconds after which a key is no longer visible
+    expires: HashMap<Vec<u8>, u64>,
+    // the same deadlines ordered by time, so the sweeper only visits due keys
+    expire_index: BTreeSet<(u64, Vec<u8>)>,
+    // WATCH versions of the live keys, taken from `version_seq`
+    versions: HashMap<Vec<u8>, u64>,
+    version_seq: u64,
+    // Versions of removed keys, so a key created and removed again after
+    // WATCH still counts as changed. A missing key without one has the
+    // version of the last tombstone forgotten to make room.
+    tombstones: HashMap<Vec<u8>, u64>,
+    // the same keys ordered by version, oldest first
+    tombstone_order: BTreeMap<u64, Vec<u8>>,
+    forgotten_version: u64,
 }
 
 impl SimpleMemDB {
     pub fn new() -> Self{
         SimpleMemDB{
             table: BTreeMap::new(),
+            expires: HashMap::new(),
+            expire_index: BTreeSet::new(),
+            versions: HashMap::new(),
+            version_seq: 0,
+            tombstones: HashMap::new(),
+            tombstone_order: BTreeMap::new(),
+            forgotten_version: 0,
+        }
+    }
+
+    // called on every write of a key, it gets a new version
+    fn touch(&mut self, key: &[u8]){
+        self.version_seq += 1;
+        if self.table.contains_key(key){
+            self.versions.insert(key.to_vec(), self.version_seq);
+            if let Some(version) = self.tombstones.remove(key){
+                self.tombstone_order.remove(&version);
+            }
+        }else if self.versions.remove(key).is_some(){
+            self.tombstones.insert(key.to_vec(), self.version_seq);
+            self.tombstone_order.insert(self.version_seq, key.to_vec());
+            if self.tombstones.len() > MAX_TOMBSTONES{
+                let oldest = *self.tombstone_order.keys().next().unwrap();
+                let key = self.tombstone_order.remove(&oldest).unwrap();
+                self.tombstones.remove(&key);
+                self.forgotten_version = oldest;
+            }
+        }
+    }
+
+    fn is_expired(&self, key: &[u8]) -> bool{
+        match self.expires.get(key){
+            Some(at) => *at <= now_millis(),
+            None => false,
+        }
+    }
+
+    fn update_expire(&mut self, key: &[u8], expire_at: Option<u64>){
+        if let Some(old) = self.expires.remove(key){
+            self.expire_index.remove(&(old, key.to_vec()));
+        }
+        if let Some(at) = expire_at{
+            self.expires.insert(key.to_vec(), at);
+            self.expire_index.insert((at, key.to_vec()));
+        }
+    }
+
+    // lazy expiration: writers drop a dead key before touching it
+    fn expire_if_needed(&mut self, key: &[u8]) -> bool{
+        if !self.is_expired(key){
+            return false;
+        }
+        self.update_expire(key, None);
+        self.table.remove(key);
+        self.touch(key);
+        true
+    }
+
+    fn get_value(&self, key: &[u8]) -> Option<&Value>{
+        if self.is_expired(key){
+            return None;
+        }
+        self.table.get(key)
+    }
+
+    fn get_list(&self, key: &[u8]) -> Result<Option<&VecDeque<Vec<u8>>>, DBError>{
+        match self.get_value(key){
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err(DBError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    // `create` adds an empty list for a missing key, the caller fills it
+    fn get_list_mut(&mut self, key: &[u8], create: bool) -> Result<Option<&mut VecDeque<Vec<u8>>>, DBError>{
+        self.expire_if_needed(key);
+        if create && !self.table.contains_key(key){
+            self.table.insert(key.to_vec(), Value::List(VecDeque::new()));
+        }
+        self.touch(key);
+        match self.table.get_mut(key){
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err(DBError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_hash(&self, key: &[u8]) -> Result<Option<&Hash>, DBError>{
+        match self.get_value(key){
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(DBError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_hash_mut(&mut self, key: &[u8], create: bool) -> Result<Option<&mut Hash>, DBError>{
+        self.expire_if_needed(key);
+        if create && !self.table.contains_key(key){
+            self.table.insert(key.to_vec(), Value::Hash(BTreeMap::new()));
+        }
+        self.touch(key);
+        match self.table.get_mut(key){
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(DBError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_set(&self, key: &[u8]) -> Result<Option<&BTreeSet<Vec<u8>>>, DBError>{
+        match self.get_value(key){
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(DBError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_set_mut(&mut self, key: &[u8], create: bool) -> Result<Option<&mut BTreeSet<Vec<u8>>>, DBError>{
+        self.expire_if_needed(key);
+        if create && !self.table.contains_key(key){
+            self.table.insert(key.to_vec(), Value::Set(BTreeSet::new()));
+        }
+        self.touch(key);
+        match self.table.get_mut(key){
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(DBError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_zset(&self, key: &[u8]) -> Result<Option<&ZSet>, DBError>{
+        match self.get_value(key){
+            Some(Value::ZSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(DBError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_zset_mut(&mut self, key: &[u8], create: bool) -> Result<Option<&mut ZSet>, DBError>{
+        self.expire_if_needed(key);
+        if create && !self.table.contains_key(key){
+            self.table.insert(key.to_vec(), Value::ZSet(ZSet::default()));
+        }
+        self.touch(key);
+        match self.table.get_mut(key){
+            Some(Value::ZSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(DBError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_stream(&self, key: &[u8]) -> Result<Option<&Stream>, DBError>{
+        match self.get_value(key){
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(DBError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_stream_mut(&mut self, key: &[u8], create: bool) -> Result<Option<&mut Stream>, DBError>{
+        self.expire_if_needed(key);
+        if create && !self.table.contains_key(key){
+            self.table.insert(key.to_vec(), Value::Stream(Stream::default()));
+        }
+        self.touch(key);
+        match self.table.get_mut(key){
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(DBError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_group(&self, key: &[u8], group: &[u8]) -> Result<&ConsumerGroup, DBError>{
+        self.get_stream(key)?.and_then(|stream| stream.groups.get(group)).ok_or(DBError::NotFound)
+    }
+
+    fn get_group_mut(&mut self, key: &[u8], group: &[u8]) -> Result<&mut ConsumerGroup, DBError>{
+        self.get_stream_mut(key, false)?.and_then(|stream| stream.groups.get_mut(group)).ok_or(DBError::NotFound)
+    }
+
+    // collections never stay around empty, streams excepted
+    fn remove_if_empty(&mut self, key: &[u8]){
+        let empty = match self.table.get(key){
+            Some(Value::List(list)) => list.is_empty(),
+            Some(Value::Hash(hash)) => hash.is_empty(),
+            Some(Value::Set(set)) => set.is_empty(),
+            Some(Value::ZSet(zset)) => zset.scores.is_empty(),
+            _ => false,
+        };
+        if empty{
+            self.update_expire(key, None);
+            self.table.remove(key);
+            self.touch(key);
         }
     }
 }
 
 impl DB for SimpleMemDB{
-    fn raw_put(&mut self, key: String, value: String) -> Result<String, DBError>{
-        self.table.insert(key, value);
-        Ok("Ok".to_string())
+    fn raw_put_with_expire(&mut self, key: Vec<u8>, value: Vec<u8>, expire_at: Option<u64>) -> Result<(), DBError>{
+        self.update_expire(&key, expire_at);
+        self.table.insert(key.clone(), Value::Str(value));
+        self.touch(&key);
+        Ok(())
+    }
+
+    fn raw_get(&self, key: Vec<u8>) -> Result<Vec<u8>, DBError>{
+        match self.get_value(&key){
+            Some(Value::Str(value)) => Ok(value.clone()),
+            Some(_) => Err(DBError::WrongType),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn get_expire(&self, key: Vec<u8>) -> Result<Option<u64>, DBError>{
+        if self.is_expired(&key) || !self.table.contains_key(&key){
+            return Err(DBError::NotFound);
+        }
+        Ok(self.expires.get(&key).cloned())
+    }
+
+    fn raw_compare_and_swap(&mut self, key: Vec<u8>, expected: Option<(Vec<u8>, Option<u64>)>,
+                            value: Vec<u8>, expire_at: Option<u64>) -> Result<bool, DBError>{
+        let current = match self.raw_get_with_expire(key.clone()){
+            Ok(current) => Some(current),
+            Err(DBError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if current != expected{
+            return Ok(false);
+        }
+        self.raw_put_with_expire(key, value, expire_at)?;
+        Ok(true)
+    }
+
+    fn set_expire(&mut self, key: Vec<u8>, expire_at: Option<u64>) -> Result<bool, DBError>{
+        if self.expire_if_needed(&key) || !self.table.contains_key(&key){
+            return Ok(false);
+        }
+        self.update_expire(&key, expire_at);
+        self.touch(&key);
+        Ok(true)
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<bool, DBError>{
+        if self.expire_if_needed(&key){
+            return Ok(false);
+        }
+        self.update_expire(&key, None);
+        let removed = self.table.remove(&key).is_some();
+        self.touch(&key);
+        Ok(removed)
+    }
+
+    fn exists(&self, key: Vec<u8>) -> Result<bool, DBError>{
+        Ok(!self.is_expired(&key) && self.table.contains_key(&key))
+    }
+
+    fn key_type(&self, key: Vec<u8>) -> Result<KeyType, DBError>{
+        self.get_value(&key).map(|value| value.key_type()).ok_or(DBError::NotFound)
+    }
+
+    fn scan_keys(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, count: usize) -> Result<Vec<(Vec<u8>, KeyType)>, DBError>{
+        // BTreeMap::range panics on inverted bounds
+        let empty = match (&start, &end){
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s), Bound::Excluded(e)) | (Bound::Excluded(s), Bound::Included(e))
+                | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            _ => false,
+        };
+        if empty{
+            return Ok(Vec::new());
+        }
+        Ok(self.table.range((start, end))
+            .filter(|(key, _)| !self.is_expired(key))
+            .take(count)
+            .map(|(key, value)| (key.clone(), value.key_type()))
+            .collect())
+    }
+
+    fn rename(&mut self, key: Vec<u8>, new_key: Vec<u8>) -> Result<(), DBError>{
+        if self.expire_if_needed(&key){
+            return Err(DBError::NotFound);
+        }
+        let value = match self.table.remove(&key){
+            Some(value) => value,
+            None => return Err(DBError::NotFound),
+        };
+        let expire_at = self.expires.get(&key).cloned();
+        self.update_expire(&key, None);
+        self.update_expire(&new_key, expire_at);
+        self.table.insert(new_key.clone(), value);
+        self.touch(&key);
+        self.touch(&new_key);
+        Ok(())
+    }
+
+    fn evict_expired(&mut self, limit: usize) -> Result<usize, DBError>{
+        let now = now_millis();
+        let mut evicted = 0;
+        while evicted < limit{
+            let (at, key) = match self.expire_index.iter().next(){
+                Some((at, key)) if *at <= now => (*at, key.clone()),
+                _ => break,
+            };
+            self.expire_index.remove(&(at, key.clone()));
+            self.expires.remove(&key);
+            self.table.remove(&key);
+            self.touch(&key);
+            evicted += 1;
+        }
+        Ok(evicted)
+    }
+
+    fn list_push(&mut self, key: Vec<u8>, values: Vec<Vec<u8>>, left: bool, only_existing: bool) -> Result<usize, DBError>{
+        let list = match self.get_list_mut(&key, !only_existing)?{
+            Some(list) => list,
+            None => return Ok(0),
+        };
+        for value in values{
+            if left{
+                list.push_front(value);
+            }else{
+                list.push_back(value);
+            }
+        }
+        Ok(list.len())
     }
 
-    fn raw_get(&self, key: String) -> Result<String, DBError>{
-        match self.table.get(&key){
-            Some(value) => Ok(value.clone()),
+    fn list_pop(&mut self, key: Vec<u8>, left: bool, count: usize) -> Result<Vec<Vec<u8>>, DBError>{
+        let list = match self.get_list_mut(&key, false)?{
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        let count = count.min(list.len());
+        let popped = if left{
+            list.drain(..count).collect()
+        }else{
+            let len = list.len();
+            list.drain(len - count..).rev().collect()
+        };
+        self.remove_if_empty(&key);
+        Ok(popped)
+    }
+
+    fn list_len(&self, key: Vec<u8>) -> Result<usize, DBError>{
+        Ok(self.get_list(&key)?.map_or(0, |list| list.len()))
+    }
+
+    fn list_range(&self, key: Vec<u8>, start: i64, stop: i64) -> Result<Vec<Vec<u8>>, DBError>{
+        let list = match self.get_list(&key)?{
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        match normalize_range(start, stop, list.len()){
+            Some((start, stop)) => Ok(list.range(start..=stop).cloned().collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn list_index(&self, key: Vec<u8>, index: i64) -> Result<Option<Vec<u8>>, DBError>{
+        let list = match self.get_list(&key)?{
+            Some(list) => list,
+            None => return Ok(None),
+        };
+        Ok(normalize_index(index, list.len()).map(|index| list[index].clone()))
+    }
+
+    fn list_set(&mut self, key: Vec<u8>, index: i64, value: Vec<u8>) -> Result<(), DBError>{
+        let list = self.get_list_mut(&key, false)?.ok_or(DBError::NotFound)?;
+        let index = normalize_index(index, list.len()).ok_or(DBError::OutOfRange)?;
+        list[index] = value;
+        Ok(())
+    }
+
+    fn list_trim(&mut self, key: Vec<u8>, start: i64, stop: i64) -> Result<(), DBError>{
+        let list = match self.get_list_mut(&key, false)?{
+            Some(list) => list,
+            None => return Ok(()),
+        };
+        match normalize_range(start, stop, list.len()){
+            Some((start, stop)) =>{
+                list.truncate(stop + 1);
+                list.drain(..start);
+            },
+            None => list.clear(),
+        }
+        self.remove_if_empty(&key);
+        Ok(())
+    }
+
+    fn list_rem(&mut self, key: Vec<u8>, count: i64, value: Vec<u8>) -> Result<usize, DBError>{
+        let list = match self.get_list_mut(&key, false)?{
+            Some(list) => list,
+            None => return Ok(0),
+        };
+        let limit = if count == 0{ usize::MAX }else{ count.wrapping_abs() as u64 as usize };
+        let mut removed = 0;
+        if count >= 0{
+            let mut i = 0;
+            while i < list.len() && removed < limit{
+                if list[i] == value{
+                    list.remove(i);
+                    removed += 1;
+                }else{
+                    i += 1;
+                }
+            }
+        }else{
+            let mut i = list.len();
+            while i > 0 && removed < limit{
+                i -= 1;
+                if list[i] == value{
+                    list.remove(i);
+                    removed += 1;
+                }
+            }
+        }
+        self.remove_if_empty(&key);
+        Ok(removed)
+    }
+
+    fn list_insert(&mut self, key: Vec<u8>, before: bool, pivot: Vec<u8>, value: Vec<u8>) -> Result<Option<usize>, DBError>{
+        let list = match self.get_list_mut(&key, false)?{
+            Some(list) => list,
+            None => return Ok(Some(0)),
+        };
+        let pos = match list.iter().position(|item| *item == pivot){
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        list.insert(if before{ pos }else{ pos + 1 }, value);
+        Ok(Some(list.len()))
+    }
+
+    fn hash_set(&mut self, key: Vec<u8>, fields: Vec<FieldValue>) -> Result<usize, DBError>{
+        let hash = self.get_hash_mut(&key, true)?.unwrap();
+        let mut added = 0;
+        for (field, value) in fields{
+            if hash.insert(field, value).is_none(){
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    fn hash_get(&self, key: Vec<u8>, field: Vec<u8>) -> Result<Option<Vec<u8>>, DBError>{
+        Ok(self.get_hash(&key)?.and_then(|hash| hash.get(&field).cloned()))
+    }
+
+    fn hash_del(&mut self, key: Vec<u8>, fields: Vec<Vec<u8>>) -> Result<usize, DBError>{
+        let hash = match self.get_hash_mut(&key, false)?{
+            Some(hash) => hash,
+            None => return Ok(0),
+        };
+        let removed = fields.iter().filter(|field| hash.remove(*field).is_some()).count();
+        self.remove_if_empty(&key);
+        Ok(removed)
+    }
+
+    fn hash_len(&self, key: Vec<u8>) -> Result<usize, DBError>{
+        Ok(self.get_hash(&key)?.map_or(0, |hash| hash.len()))
+    }
+
+    fn hash_scan(&self, key: Vec<u8>, after: Option<Vec<u8>>, count: usize) -> Result<Vec<FieldValue>, DBError>{
+        let hash = match self.get_hash(&key)?{
+            Some(hash) => hash,
+            None => return Ok(Vec::new()),
+        };
+        let fields = match after{
+            Some(after) => hash.range((Bound::Excluded(after), Bound::Unbounded)),
+            None => hash.range::<Vec<u8>, _>(..),
+        };
+        Ok(fields.take(count).map(|(field, value)| (field.clone(), value.clone())).collect())
+    }
+
+    fn set_add(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> Result<usize, DBError>{
+        let set = self.get_set_mut(&key, true)?.unwrap();
+        Ok(members.into_iter().filter(|member| set.insert(member.clone())).count())
+    }
+
+    fn set_rem(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> Result<usize, DBError>{
+        let set = match self.get_set_mut(&key, false)?{
+            Some(set) => set,
+            None => return Ok(0),
+        };
+        let removed = members.iter().filter(|member| set.remove(*member)).count();
+        self.remove_if_empty(&key);
+        Ok(removed)
+    }
+
+    fn set_members(&self, key: Vec<u8>) -> Result<Vec<Vec<u8>>, DBError>{
+        Ok(self.get_set(&key)?.map_or(Vec::new(), |set| set.iter().cloned().collect()))
+    }
+
+    fn set_is_member(&self, key: Vec<u8>, member: Vec<u8>) -> Result<bool, DBError>{
+        Ok(self.get_set(&key)?.map_or(false, |set| set.contains(&member)))
+    }
+
+    fn set_card(&self, key: Vec<u8>) -> Result<usize, DBError>{
+        Ok(self.get_set(&key)?.map_or(0, |set| set.len()))
+    }
+
+    fn set_random_members(&self, key: Vec<u8>, count: usize) -> Result<Vec<Vec<u8>>, DBError>{
+        let set = match self.get_set(&key)?{
+            Some(set) => set,
+            None => return Ok(Vec::new()),
+        };
+        Ok(random_sample(set.iter().cloned(), count))
+    }
+
+    fn zset_add(&mut self, key: Vec<u8>, members: Vec<ScoredMember>, nx: bool, xx: bool) -> Result<(usize, usize), DBError>{
+        let zset = self.get_zset_mut(&key, true)?.unwrap();
+        let (mut added, mut changed) = (0, 0);
+        for (member, score) in members{
+            match zset.scores.get(&member){
+                Some(_) if nx => continue,
+                Some(old) if *old == score => continue,
+                Some(_) => changed += 1,
+                None if xx => continue,
+                None => added += 1,
+            }
+            zset.insert(member, score);
+        }
+        // XX on a missing key leaves an empty set behind
+        self.remove_if_empty(&key);
+        Ok((added, changed))
+    }
+
+    fn zset_rem(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> Result<usize, DBError>{
+        let zset = match self.get_zset_mut(&key, false)?{
+            Some(zset) => zset,
+            None => return Ok(0),
+        };
+        let removed = members.iter().filter(|member| zset.remove(member)).count();
+        self.remove_if_empty(&key);
+        Ok(removed)
+    }
+
+    fn zset_score(&self, key: Vec<u8>, member: Vec<u8>) -> Result<Option<f64>, DBError>{
+        Ok(self.get_zset(&key)?.and_then(|zset| zset.scores.get(&member).cloned()))
+    }
+
+    fn zset_card(&self, key: Vec<u8>) -> Result<usize, DBError>{
+        Ok(self.get_zset(&key)?.map_or(0, |zset| zset.scores.len()))
+    }
+
+    fn zset_rank(&self, key: Vec<u8>, member: Vec<u8>) -> Result<Option<usize>, DBError>{
+        let zset = match self.get_zset(&key)?{
+            Some(zset) => zset,
+            None => return Ok(None),
+        };
+        Ok(zset.scores.get(&member).map(|score| {
+            zset.index.range(..(score_to_ordered(*score), member.clone())).count()
+        }))
+    }
+
+    fn zset_range(&self, key: Vec<u8>, start: i64, stop: i64) -> Result<Vec<ScoredMember>, DBError>{
+        let zset = match self.get_zset(&key)?{
+            Some(zset) => zset,
+            None => return Ok(Vec::new()),
+        };
+        Ok(match normalize_range(start, stop, zset.index.len()){
+            Some((start, stop)) => zset.index.iter().skip(start).take(stop - start + 1)
+                .map(|(_, member)| zset.scored(member)).collect(),
+            None => Vec::new(),
+        })
+    }
+
+    fn zset_range_by_score(&self, key: Vec<u8>, min: Bound<f64>, max: Bound<f64>, offset: usize, count: usize)
+        -> Result<Vec<ScoredMember>, DBError>{
+        let zset = match self.get_zset(&key)?{
+            Some(zset) => zset,
+            None => return Ok(Vec::new()),
+        };
+        let (start, end) = match ordered_score_range(min, max){
+            Some(range) => range,
+            None => return Ok(Vec::new()),
+        };
+        let upper = match end{
+            Some(end) => Bound::Excluded((end, Vec::new())),
+            None => Bound::Unbounded,
+        };
+        Ok(zset.index.range((Bound::Included((start, Vec::new())), upper))
+            .skip(offset).take(count).map(|(_, member)| zset.scored(member)).collect())
+    }
+
+    fn stream_add(&mut self, key: Vec<u8>, id: NewStreamId, fields: Vec<FieldValue>) -> Result<Option<StreamId>, DBError>{
+        // checked first, so a refused id does not leave an empty stream
+        let last_id = self.get_stream(&key)?.map_or(StreamId::MIN, |stream| stream.last_id);
+        let id = match id.resolve(last_id, now_millis()){
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let stream = self.get_stream_mut(&key, true)?.unwrap();
+        stream.entries.insert(id, fields);
+        stream.last_id = id;
+        Ok(Some(id))
+    }
+
+    fn stream_len(&self, key: Vec<u8>) -> Result<usize, DBError>{
+        Ok(self.get_stream(&key)?.map_or(0, |stream| stream.entries.len()))
+    }
+
+    fn stream_last_id(&self, key: Vec<u8>) -> Result<Option<StreamId>, DBError>{
+        Ok(self.get_stream(&key)?.map(|stream| stream.last_id))
+    }
+
+    fn stream_range(&self, key: Vec<u8>, start: StreamId, end: StreamId, count: usize, reverse: bool)
+        -> Result<Vec<StreamEntry>, DBError>{
+        let stream = match self.get_stream(&key)?{
+            Some(stream) => stream,
+            None => return Ok(Vec::new()),
+        };
+        // BTreeMap::range panics on inverted bounds
+        if start > end{
+            return Ok(Vec::new());
+        }
+        let entries = stream.entries.range(start..=end).map(|(id, fields)| (*id, fields.clone()));
+        Ok(if reverse{
+            entries.rev().take(count).collect()
+        }else{
+            entries.take(count).collect()
+        })
+    }
+
+    fn stream_trim(&mut self, key: Vec<u8>, trim: StreamTrim) -> Result<usize, DBError>{
+        let stream = match self.get_stream_mut(&key, false)?{
+            Some(stream) => stream,
+            None => return Ok(0),
+        };
+        let len = stream.entries.len();
+        let first_kept = match trim{
+            StreamTrim::MaxLen(max_len) if len > max_len => stream.entries.keys().nth(len - max_len).cloned(),
+            StreamTrim::MaxLen(_) => return Ok(0),
+            StreamTrim::MinId(min_id) => Some(min_id),
+        };
+        stream.entries = match first_kept{
+            Some(id) => stream.entries.split_off(&id),
+            None => BTreeMap::new(),
+        };
+        Ok(len - stream.entries.len())
+    }
+
+    fn stream_create_group(&mut self, key: Vec<u8>, group: Vec<u8>, last_id: Option<StreamId>, make_stream: bool)
+        -> Result<bool, DBError>{
+        let stream = match self.get_stream_mut(&key, make_stream)?{
+            Some(stream) => stream,
+            None => return Err(DBError::NotFound),
+        };
+        if stream.groups.contains_key(&group){
+            return Ok(false);
+        }
+        let last_id = last_id.unwrap_or(stream.last_id);
+        stream.groups.insert(group, ConsumerGroup{ last_id, ..Default::default() });
+        Ok(true)
+    }
+
+    fn stream_destroy_group(&mut self, key: Vec<u8>, group: Vec<u8>) -> Result<bool, DBError>{
+        match self.get_stream_mut(&key, false)?{
+            Some(stream) => Ok(stream.groups.remove(&group).is_some()),
             None => Err(DBError::NotFound),
         }
     }
-}
\ No newline at end of file
+
+    fn stream_group_last_id(&self, key: Vec<u8>, group: Vec<u8>) -> Result<StreamId, DBError>{
+        Ok(self.get_group(&key, &group)?.last_id)
+    }
+
+    fn stream_set_group_last_id(&mut self, key: Vec<u8>, group: Vec<u8>, last_id: StreamId) -> Result<(), DBError>{
+        self.get_group_mut(&key, &group)?.last_id = last_id;
+        Ok(())
+    }
+
+    fn stream_create_consumer(&mut self, key: Vec<u8>, group: Vec<u8>, consumer: Vec<u8>) -> Result<bool, DBError>{
+        Ok(self.get_group_mut(&key, &group)?.consumers.insert(consumer))
+    }
+
+    fn stream_delete_consumer(&mut self, key: Vec<u8>, group: Vec<u8>, consumer: Vec<u8>) -> Result<usize, DBError>{
+        let group = self.get_group_mut(&key, &group)?;
+        group.consumers.remove(&consumer);
+        let before = group.pending.len();
+        group.pending.retain(|_, entry| entry.consumer != consumer);
+        Ok(before - group.pending.len())
+    }
+
+    fn stream_pending(&self, key: Vec<u8>, group: Vec<u8>, start: StreamId, end: StreamId, count: usize,
+                      consumer: Option<Vec<u8>>) -> Result<Vec<PendingEntry>, DBError>{
+        let group = self.get_group(&key, &group)?;
+        if start > end{
+            return Ok(Vec::new());
+        }
+        Ok(group.pending.range(start..=end).map(|(_, entry)| entry)
+            .filter(|entry| consumer.as_ref().map_or(true, |consumer| entry.consumer == *consumer))
+            .take(count).cloned().collect())
+    }
+
+    fn stream_set_pending(&mut self, key: Vec<u8>, group: Vec<u8>, entries: Vec<PendingEntry>) -> Result<(), DBError>{
+        let group = self.get_group_mut(&key, &group)?;
+        for entry in entries{
+            group.consumers.insert(entry.consumer.clone());
+            group.pending.insert(entry.id, entry);
+        }
+        Ok(())
+    }
+
+    fn stream_ack(&mut self, key: Vec<u8>, group: Vec<u8>, ids: Vec<StreamId>) -> Result<usize, DBError>{
+        let group = self.get_group_mut(&key, &group)?;
+        Ok(ids.iter().filter(|id| group.pending.remove(id).is_some()).count())
+    }
+
+    fn key_version(&self, key: Vec<u8>) -> Result<u64, DBError>{
+        match self.versions.get(&key).or_else(|| self.tombstones.get(&key)){
+            Some(version) => Ok(*version),
+            None => Ok(self.forgotten_version),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::simple_mem_db::SimpleMemDB;
+    use crate::redis_server::{DB, KeyType, NewStreamId, PendingEntry, StreamId, StreamTrim, now_millis};
+    use std::ops::Bound;
+
+    #[test]
+    fn test_evict_expired(){
+        let mut db = SimpleMemDB::new();
+        let now = now_millis();
+        db.raw_put_with_expire(b"a".to_vec(), b"1".to_vec(), Some(now - 10)).unwrap();
+        db.raw_put_with_expire(b"b".to_vec(), b"2".to_vec(), Some(now - 5)).unwrap();
+        db.raw_put_with_expire(b"c".to_vec(), b"3".to_vec(), Some(now + 60000)).unwrap();
+        db.raw_put(b"d".to_vec(), b"4".to_vec()).unwrap();
+
+        assert_eq!(db.evict_expired(1).unwrap(), 1);
+        assert_eq!(db.evict_expired(10).unwrap(), 1);
+        assert_eq!(db.evict_expired(10).unwrap(), 0);
+        assert_eq!(db.table.len(), 2);
+        assert_eq!(db.expires.len(), 1);
+        assert_eq!(db.expire_index.len(), 1);
+    }
+
+    #[test]
+    fn test_scan_keys(){
+        let mut db = SimpleMemDB::new();
+        for key in &["a", "b", "c", "d"]{
+            db.raw_put(key.as_bytes().to_vec(), b"1".to_vec()).unwrap();
+        }
+        db.set_expire(b"b".to_vec(), Some(now_millis() - 1)).unwrap();
+        db.set_add(b"e".to_vec(), vec![b"x".to_vec()]).unwrap();
+
+        let keys = db.scan_keys(Bound::Excluded(b"a".to_vec()), Bound::Unbounded, 2).unwrap();
+        assert_eq!(keys, vec![(b"c".to_vec(), KeyType::String), (b"d".to_vec(), KeyType::String)]);
+        let keys = db.scan_keys(Bound::Included(b"d".to_vec()), Bound::Included(b"e".to_vec()), 10).unwrap();
+        assert_eq!(keys, vec![(b"d".to_vec(), KeyType::String), (b"e".to_vec(), KeyType::Set)]);
+        assert!(db.scan_keys(Bound::Excluded(b"e".to_vec()), Bound::Excluded(b"a".to_vec()), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_key_version(){
+        let mut db = SimpleMemDB::new();
+        db.raw_put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let a = db.key_version(b"a".to_vec()).unwrap();
+        let missing = db.key_version(b"h".to_vec()).unwrap();
+        db.raw_get(b"a".to_vec()).unwrap();
+        assert_eq!(db.key_version(b"a".to_vec()).unwrap(), a);
+
+        // a field write changes the version of the hash only
+        db.hash_set(b"h".to_vec(), vec![(b"f".to_vec(), b"1".to_vec())]).unwrap();
+        let h = db.key_version(b"h".to_vec()).unwrap();
+        assert_ne!(h, missing);
+        db.hash_set(b"h".to_vec(), vec![(b"f".to_vec(), b"2".to_vec())]).unwrap();
+        assert_ne!(db.key_version(b"h".to_vec()).unwrap(), h);
+        assert_eq!(db.key_version(b"a".to_vec()).unwrap(), a);
+
+        // so does a removal, even when the key was missing before
+        let missing = db.key_version(b"b".to_vec()).unwrap();
+        db.raw_put(b"b".to_vec(), b"1".to_vec()).unwrap();
+        db.delete(b"b".to_vec()).unwrap();
+        assert_ne!(db.key_version(b"b".to_vec()).unwrap(), missing);
+        db.rename(b"a".to_vec(), b"c".to_vec()).unwrap();
+        assert_ne!(db.key_version(b"a".to_vec()).unwrap(), a);
+
+        // removing or expiring other keys leaves a missing key as it was
+        let missing = db.key_version(b"m".to_vec()).unwrap();
+        db.delete(b"c".to_vec()).unwrap();
+        db.raw_put_with_expire(b"d".to_vec(), b"1".to_vec(), Some(now_millis() - 1)).unwrap();
+        db.evict_expired(10).unwrap();
+        assert_eq!(db.key_version(b"m".to_vec()).unwrap(), missing);
+    }
+
+    #[test]
+    fn test_stream(){
+        let mut db = SimpleMemDB::new();
+        let key = b"events".to_vec();
+        let fields = vec![(b"f".to_vec(), b"v".to_vec())];
+        let first = db.stream_add(key.clone(), NewStreamId::Id(StreamId::new(5, 1)), fields.clone()).unwrap();
+        assert_eq!(first, Some(StreamId::new(5, 1)));
+        assert_eq!(db.stream_add(key.clone(), NewStreamId::Id(StreamId::new(5, 1)), fields.clone()).unwrap(), None);
+        assert_eq!(db.stream_add(key.clone(), NewStreamId::AutoSeq(5), fields.clone()).unwrap(), Some(StreamId::new(5, 2)));
+        let auto = db.stream_add(key.clone(), NewStreamId::Auto, fields.clone()).unwrap().unwrap();
+        assert!(auto > StreamId::new(5, 2));
+        assert_eq!(db.key_type(key.clone()).unwrap(), KeyType::Stream);
+        assert_eq!(db.stream_len(key.clone()).unwrap(), 3);
+
+        let ids = |entries: Vec<(StreamId, _)>| entries.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        let range = db.stream_range(key.clone(), StreamId::new(5, 2), StreamId::MAX, 10, false).unwrap();
+        assert_eq!(ids(range), vec![StreamId::new(5, 2), auto]);
+        let range = db.stream_range(key.clone(), StreamId::MIN, StreamId::MAX, 2, true).unwrap();
+        assert_eq!(range[1], (StreamId::new(5, 2), fields.clone()));
+        assert!(db.stream_range(key.clone(), auto, StreamId::MIN, 10, false).unwrap().is_empty());
+
+        // a trimmed stream stays, and keeps counting from its last id
+        assert_eq!(db.stream_trim(key.clone(), StreamTrim::MinId(StreamId::new(5, 2))).unwrap(), 1);
+        assert_eq!(db.stream_trim(key.clone(), StreamTrim::MaxLen(5)).unwrap(), 0);
+        assert_eq!(db.stream_trim(key.clone(), StreamTrim::MaxLen(0)).unwrap(), 2);
+        assert_eq!(db.stream_len(key.clone()).unwrap(), 0);
+        assert_eq!(db.stream_last_id(key.clone()).unwrap(), Some(auto));
+        assert_eq!(db.stream_add(key.clone(), NewStreamId::Id(auto), fields).unwrap(), None);
+        assert!(db.exists(key).unwrap());
+    }
+
+    #[test]
+    fn test_stream_group(){
+        let mut db = SimpleMemDB::new();
+        let (key, group) = (b"events".to_vec(), b"workers".to_vec());
+        assert!(db.stream_create_group(key.clone(), group.clone(), None, false).is_err());
+        assert!(db.stream_create_group(key.clone(), group.clone(), None, true).unwrap());
+        assert!(!db.stream_create_group(key.clone(), group.clone(), None, true).unwrap());
+        assert_eq!(db.stream_len(key.clone()).unwrap(), 0);
+        assert_eq!(db.stream_group_last_id(key.clone(), group.clone()).unwrap(), StreamId::MIN);
+        db.stream_set_group_last_id(key.clone(), group.clone(), StreamId::new(3, 0)).unwrap();
+        assert_eq!(db.stream_group_last_id(key.clone(), group.clone()).unwrap(), StreamId::new(3, 0));
+
+        let pending = |id, consumer: &[u8]| PendingEntry{
+            id: StreamId::new(id, 0),
+            consumer: consumer.to_vec(),
+            delivered_at: 10,
+            deliveries: 1,
+        };
+        db.stream_set_pending(key.clone(), group.clone(), vec![pending(1, b"a"), pending(2, b"b"), pending(3, b"a")]).unwrap();
+        assert!(!db.stream_create_consumer(key.clone(), group.clone(), b"a".to_vec()).unwrap());
+        let of_a = db.stream_pending(key.clone(), group.clone(), StreamId::MIN, StreamId::MAX, 10, Some(b"a".to_vec())).unwrap();
+        assert_eq!(of_a, vec![pending(1, b"a"), pending(3, b"a")]);
+        let all = db.stream_pending(key.clone(), group.clone(), StreamId::new(2, 0), StreamId::MAX, 1, None).unwrap();
+        assert_eq!(all, vec![pending(2, b"b")]);
+
+        assert_eq!(db.stream_ack(key.clone(), group.clone(), vec![StreamId::new(1, 0), StreamId::new(9, 0)]).unwrap(), 1);
+        assert_eq!(db.stream_delete_consumer(key.clone(), group.clone(), b"a".to_vec()).unwrap(), 1);
+        assert!(db.stream_destroy_group(key.clone(), group.clone()).unwrap());
+        assert!(!db.stream_destroy_group(key.clone(), group.clone()).unwrap());
+        assert!(db.stream_pending(key, group, StreamId::MIN, StreamId::MAX, 10, None).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::thread;
+use std::time::Duration;
+
+use super::tikv_db::{Error, Result};
+
+// attempts of a request before its last error is returned
+const MAX_ATTEMPTS: u32 = 10;
+const BASE_DELAY_MS: u64 = 2;
+const MAX_DELAY_MS: u64 = 1000;
+
+// Delays between the attempts of a request. The delay doubles after every
+// retryable error up to MAX_DELAY_MS, a busy TiKV may ask for a longer one.
+pub struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff { attempts: 0 }
+    }
+
+    // the delay before the next attempt, `None` once the attempts are used up
+    pub fn next_delay(&mut self, err: &Error) -> Option<Duration> {
+        self.attempts += 1;
+        if self.attempts >= MAX_ATTEMPTS {
+            return None;
+        }
+        let delay = (BASE_DELAY_MS << (self.attempts - 1)).min(MAX_DELAY_MS);
+        let hint = match err {
+            Error::RegionError(region_err) if region_err.has_server_is_busy() =>
+                region_err.get_server_is_busy().get_backoff_ms(),
+            _ => 0,
+        };
+        Some(Duration::from_millis(delay.max(hint)))
+    }
+
+    // Sleeps before the next attempt after `err`, or hands `err` back when
+    // it is fatal or the attempts are used up.
+    pub fn wait(&mut self, err: Error) -> Result<()> {
+        if !err.is_retryable() {
+            return Err(err);
+        }
+        match self.next_delay(&err) {
+            Some(delay) => {
+                thread::sleep(delay);
+                Ok(())
+            }
+            None => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::tikv::backoff::{Backoff, MAX_ATTEMPTS, MAX_DELAY_MS};
+    use crate::tikv::tikv_db::Error;
+    use kvproto::errorpb;
+    use protobuf::Message;
+
+    #[test]
+    fn test_backoff_delays(){
+        let mut backoff = Backoff::new();
+        let err = Error::RegionError(Box::new(errorpb::Error::new()));
+        assert_eq!(backoff.next_delay(&err), Some(Duration::from_millis(2)));
+        assert_eq!(backoff.next_delay(&err), Some(Duration::from_millis(4)));
+        for _ in 2..MAX_ATTEMPTS - 1 {
+            assert!(backoff.next_delay(&err).unwrap() <= Duration::from_millis(MAX_DELAY_MS));
+        }
+        assert_eq!(backoff.next_delay(&err), None);
+
+        let mut busy = errorpb::Error::new();
+        busy.mut_server_is_busy().set_backoff_ms(50);
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.next_delay(&Error::RegionError(Box::new(busy))), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_backoff_fatal(){
+        let mut backoff = Backoff::new();
+        assert!(backoff.wait(Error::TiKVError("key is locked".to_string())).is_err());
+        let mut too_large = errorpb::Error::new();
+        too_large.mut_raft_entry_too_large().set_entry_size(1 << 30);
+        assert!(backoff.wait(Error::RegionError(Box::new(too_large))).is_err());
+        assert!(backoff.wait(Error::RegionError(Box::new(errorpb::Error::new()))).is_ok());
+    }
+}
@@ -0,0 +1,701 @@
+use super::tikv_db::{Key, Value, Result, Error};
+use crate::redis_server::{KeyType, FieldValue, PendingEntry, StreamId};
+
+// Every redis key owns one meta record stored under `META_PREFIX + key`.
+// The record value starts with a fixed header followed by the payload:
+//
+//     [type: u8][expire_at: u64 big endian, 0 means persistent][writes: u64 big endian][payload]
+//
+// `writes` counts the writes of the key. Every write saves the meta with it
+// bumped, so the record changes even when the payload does not, which is
+// what WATCH compares. For strings the payload is the value itself, so a GET is still one RPC.
+//
+// Collections keep their elements in separate records under
+// `DATA_PREFIX + id + ...`, where `id` is allocated once per collection and
+// stored first in the meta payload. RENAME only moves the meta record and
+// DEL drops all elements with a single range delete.
+pub const META_PREFIX: u8 = b'm';
+pub const DATA_PREFIX: u8 = b'd';
+const META_HEADER_LEN: usize = 17;
+const DATA_KEY_HEADER_LEN: usize = 9;
+
+// set members only need their key
+pub const MEMBER_VALUE: &[u8] = b"1";
+
+// List elements and hash values may be empty, but TiKV reads an empty value
+// back as a missing key, so they are stored after this flag byte.
+const ELEMENT_VALUE_FLAG: u8 = b'v';
+
+// server wide records, currently only the collection id allocator
+pub const ID_ALLOC_KEY: &[u8] = b"s_next_id";
+
+// sub key tags of the two records every sorted set member owns
+const ZSET_MEMBER_TAG: u8 = b'm';
+const ZSET_SCORE_TAG: u8 = b's';
+
+// sub key tags of the entries of a stream and of its consumer groups
+const STREAM_ENTRY_TAG: u8 = b'e';
+const STREAM_GROUP_TAG: u8 = b'g';
+const STREAM_CONSUMER_TAG: u8 = b'c';
+const STREAM_PENDING_TAG: u8 = b'p';
+const STREAM_OWNED_TAG: u8 = b'o';
+
+// lists grow in both directions from the middle of the index space
+const LIST_INIT_INDEX: u64 = 1 << 63;
+
+fn type_to_byte(key_type: KeyType) -> u8{
+    match key_type{
+        KeyType::String => 0,
+        KeyType::List => 1,
+        KeyType::Hash => 2,
+        KeyType::Set => 3,
+        KeyType::ZSet => 4,
+        KeyType::Stream => 5,
+    }
+}
+
+fn type_from_byte(b: u8) -> Result<KeyType>{
+    match b{
+        0 => Ok(KeyType::String),
+        1 => Ok(KeyType::List),
+        2 => Ok(KeyType::Hash),
+        3 => Ok(KeyType::Set),
+        4 => Ok(KeyType::ZSet),
+        5 => Ok(KeyType::Stream),
+        _ => Err(Error::OperationError(format!("unknown data type {}", b))),
+    }
+}
+
+pub fn encode_meta_key(key: &[u8]) -> Key{
+    let mut meta_key = Vec::with_capacity(key.len() + 1);
+    meta_key.push(META_PREFIX);
+    meta_key.extend_from_slice(key);
+    meta_key
+}
+
+pub fn decode_meta_key(meta_key: &[u8]) -> &[u8]{
+    &meta_key[1.min(meta_key.len())..]
+}
+
+// Meta records and the id allocator are updated with compare-and-swap in raw
+// mode. TiKV only keeps that atomic when every other write of such a key is
+// sent in its atomic mode too.
+pub fn is_cas_key(key: &[u8]) -> bool{
+    key.first() == Some(&META_PREFIX) || key == ID_ALLOC_KEY
+}
+
+pub fn encode_data_key(id: u64, sub_key: &[u8]) -> Key{
+    let mut data_key = Vec::with_capacity(sub_key.len() + DATA_KEY_HEADER_LEN);
+    data_key.push(DATA_PREFIX);
+    data_key.extend_from_slice(&id.to_be_bytes());
+    data_key.extend_from_slice(sub_key);
+    data_key
+}
+
+/// The part of a data key following the collection id.
+pub fn decode_data_key(data_key: &[u8]) -> &[u8]{
+    &data_key[DATA_KEY_HEADER_LEN.min(data_key.len())..]
+}
+
+/// `[start, end)` covering every element of the collection `id`.
+pub fn data_key_range(id: u64) -> (Key, Key){
+    (encode_data_key(id, b""), encode_data_key(id + 1, b""))
+}
+
+pub fn encode_element_value(value: &[u8]) -> Value{
+    let mut raw = Vec::with_capacity(1 + value.len());
+    raw.push(ELEMENT_VALUE_FLAG);
+    raw.extend_from_slice(value);
+    raw
+}
+
+pub fn decode_element_value(mut raw: Value) -> Result<Value>{
+    if raw.first() != Some(&ELEMENT_VALUE_FLAG){
+        return Err(Error::OperationError("element value without flag".to_string()));
+    }
+    raw.remove(0);
+    Ok(raw)
+}
+
+pub fn read_u64(raw: &[u8], pos: usize) -> Result<u64>{
+    if raw.len() < pos + 8{
+        return Err(Error::OperationError("meta payload too short".to_string()));
+    }
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&raw[pos..pos + 8]);
+    Ok(u64::from_be_bytes(buf))
+}
+
+// Elements live at `[head, tail)`, keyed by their big endian index so they
+// are ordered like the list itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListMeta{
+    pub id: u64,
+    pub head: u64,
+    pub tail: u64,
+}
+
+impl ListMeta{
+    pub fn new(id: u64) -> Self{
+        ListMeta{
+            id,
+            head: LIST_INIT_INDEX,
+            tail: LIST_INIT_INDEX,
+        }
+    }
+
+    pub fn len(&self) -> u64{
+        self.tail - self.head
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.head == self.tail
+    }
+
+    pub fn element_key(&self, index: u64) -> Key{
+        encode_data_key(self.id, &index.to_be_bytes())
+    }
+
+    pub fn encode(&self) -> Vec<u8>{
+        let mut raw = Vec::with_capacity(24);
+        raw.extend_from_slice(&self.id.to_be_bytes());
+        raw.extend_from_slice(&self.head.to_be_bytes());
+        raw.extend_from_slice(&self.tail.to_be_bytes());
+        raw
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<ListMeta>{
+        Ok(ListMeta{
+            id: read_u64(raw, 0)?,
+            head: read_u64(raw, 8)?,
+            tail: read_u64(raw, 16)?,
+        })
+    }
+}
+
+// Hashes keep one record per field at `encode_data_key(id, field)`, so a
+// field read is the meta lookup plus that single record. Sets do the same
+// with members, storing `MEMBER_VALUE` in every record. The element count
+// lives in the meta for HLEN and SCARD.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollectionMeta{
+    pub id: u64,
+    pub len: u64,
+}
+
+impl CollectionMeta{
+    pub fn new(id: u64) -> Self{
+        CollectionMeta{
+            id,
+            len: 0,
+        }
+    }
+
+    pub fn element_key(&self, sub_key: &[u8]) -> Key{
+        encode_data_key(self.id, sub_key)
+    }
+
+    pub fn encode(&self) -> Vec<u8>{
+        let mut raw = Vec::with_capacity(16);
+        raw.extend_from_slice(&self.id.to_be_bytes());
+        raw.extend_from_slice(&self.len.to_be_bytes());
+        raw
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<CollectionMeta>{
+        Ok(CollectionMeta{
+            id: read_u64(raw, 0)?,
+            len: read_u64(raw, 8)?,
+        })
+    }
+}
+
+// Sorted sets use a CollectionMeta too. Each member has a record at
+// `ZSET_MEMBER_TAG + member` holding its ordered score, for ZSCORE, and an
+// index record at `ZSET_SCORE_TAG + ordered score + member`. The ordered
+// score is `score_to_ordered` in big endian, so the index keys sort by score
+// and ranges by score or by rank are a single raw scan.
+pub fn zset_member_key(id: u64, member: &[u8]) -> Key{
+    let mut sub_key = Vec::with_capacity(member.len() + 1);
+    sub_key.push(ZSET_MEMBER_TAG);
+    sub_key.extend_from_slice(member);
+    encode_data_key(id, &sub_key)
+}
+
+pub fn zset_score_key(id: u64, ordered: u64, member: &[u8]) -> Key{
+    let mut sub_key = Vec::with_capacity(member.len() + 9);
+    sub_key.push(ZSET_SCORE_TAG);
+    sub_key.extend_from_slice(&ordered.to_be_bytes());
+    sub_key.extend_from_slice(member);
+    encode_data_key(id, &sub_key)
+}
+
+/// `[start, end)` covering the score index of the sorted set `id`.
+pub fn zset_score_range(id: u64) -> (Key, Key){
+    (encode_data_key(id, &[ZSET_SCORE_TAG]), encode_data_key(id, &[ZSET_SCORE_TAG + 1]))
+}
+
+/// The ordered score and the member of a score index key.
+pub fn decode_zset_score_key(score_key: &[u8]) -> Result<(u64, Vec<u8>)>{
+    let sub_key = decode_data_key(score_key);
+    let ordered = read_u64(sub_key, 1)?;
+    Ok((ordered, sub_key[9..].to_vec()))
+}
+
+// Streams keep each entry at `STREAM_ENTRY_TAG + ms + seq`, both big
+// endian, so the entry keys sort by id and XRANGE is a single raw scan. The
+// meta counts the entries and holds the last id given out, which outlives
+// the entry when it is trimmed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamMeta{
+    pub id: u64,
+    pub len: u64,
+    pub last_id: StreamId,
+}
+
+impl StreamMeta{
+    pub fn new(id: u64) -> Self{
+        StreamMeta{
+            id,
+            len: 0,
+            last_id: StreamId::MIN,
+        }
+    }
+
+    pub fn entry_key(&self, entry_id: StreamId) -> Key{
+        let mut sub_key = Vec::with_capacity(17);
+        sub_key.push(STREAM_ENTRY_TAG);
+        sub_key.extend_from_slice(&entry_id.ms.to_be_bytes());
+        sub_key.extend_from_slice(&entry_id.seq.to_be_bytes());
+        encode_data_key(self.id, &sub_key)
+    }
+
+    /// `[start, end)` covering the entries with an id within `[first, last]`.
+    pub fn entry_range(&self, first: StreamId, last: StreamId) -> (Key, Key){
+        let end = match last.next(){
+            Some(next) => self.entry_key(next),
+            None => encode_data_key(self.id, &[STREAM_ENTRY_TAG + 1]),
+        };
+        (self.entry_key(first), end)
+    }
+
+    pub fn encode(&self) -> Vec<u8>{
+        let mut raw = Vec::with_capacity(32);
+        raw.extend_from_slice(&self.id.to_be_bytes());
+        raw.extend_from_slice(&self.len.to_be_bytes());
+        raw.extend_from_slice(&self.last_id.ms.to_be_bytes());
+        raw.extend_from_slice(&self.last_id.seq.to_be_bytes());
+        raw
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<StreamMeta>{
+        Ok(StreamMeta{
+            id: read_u64(raw, 0)?,
+            len: read_u64(raw, 8)?,
+            last_id: StreamId::new(read_u64(raw, 16)?, read_u64(raw, 24)?),
+        })
+    }
+}
+
+pub fn decode_stream_entry_key(entry_key: &[u8]) -> Result<StreamId>{
+    let sub_key = decode_data_key(entry_key);
+    Ok(StreamId::new(read_u64(sub_key, 1)?, read_u64(sub_key, 9)?))
+}
+
+// The fields of an entry, every field and value prefixed by its length as
+// a big endian u32.
+pub fn encode_stream_fields(fields: &[FieldValue]) -> Value{
+    let mut raw = Vec::new();
+    for (field, value) in fields{
+        for part in &[field, value]{
+            raw.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            raw.extend_from_slice(part);
+        }
+    }
+    raw
+}
+
+pub fn decode_stream_fields(raw: &[u8]) -> Result<Vec<FieldValue>>{
+    let mut parts = Vec::new();
+    let mut pos = 0;
+    while pos < raw.len(){
+        if raw.len() < pos + 4{
+            return Err(Error::OperationError("stream entry too short".to_string()));
+        }
+        let mut len = [0; 4];
+        len.copy_from_slice(&raw[pos..pos + 4]);
+        let end = pos + 4 + u32::from_be_bytes(len) as usize;
+        if raw.len() < end{
+            return Err(Error::OperationError("stream entry too short".to_string()));
+        }
+        parts.push(raw[pos + 4..end].to_vec());
+        pos = end;
+    }
+    if parts.len() % 2 != 0{
+        return Err(Error::OperationError("stream entry without value".to_string()));
+    }
+    let mut parts = parts.into_iter();
+    let mut fields = Vec::new();
+    while let (Some(field), Some(value)) = (parts.next(), parts.next()){
+        fields.push((field, value));
+    }
+    Ok(fields)
+}
+
+// Consumer groups live with the entries of their stream, so DEL drops them
+// too. A group has a record at `STREAM_GROUP_TAG + group` holding the last
+// delivered id, one per consumer at `STREAM_CONSUMER_TAG + group + consumer`
+// and one per pending entry at `STREAM_PENDING_TAG + group + ms + seq`. The
+// pending entries of a consumer are indexed again at
+// `STREAM_OWNED_TAG + group + consumer + ms + seq`. Group and consumer names
+// in front of other parts are prefixed by their length as a big endian u32,
+// so the records of one never share a prefix with those of another one.
+fn group_prefix(tag: u8, group: &[u8]) -> Vec<u8>{
+    let mut prefix = Vec::with_capacity(group.len() + 5);
+    prefix.push(tag);
+    prefix.extend_from_slice(&(group.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(group);
+    prefix
+}
+
+// `[start, end)` covering every key starting with `prefix`, which is never
+// all 0xff since data keys start with `DATA_PREFIX`
+fn prefix_range(prefix: Key) -> (Key, Key){
+    let mut end = prefix.clone();
+    while end.last() == Some(&0xff){
+        end.pop();
+    }
+    if let Some(last) = end.last_mut(){
+        *last += 1;
+    }
+    (prefix, end)
+}
+
+impl StreamMeta{
+    pub fn group_key(&self, group: &[u8]) -> Key{
+        encode_data_key(self.id, &group_prefix(STREAM_GROUP_TAG, group))
+    }
+
+    pub fn consumer_key(&self, group: &[u8], consumer: &[u8]) -> Key{
+        let mut sub_key = group_prefix(STREAM_CONSUMER_TAG, group);
+        sub_key.extend_from_slice(consumer);
+        encode_data_key(self.id, &sub_key)
+    }
+
+    pub fn consumer_range(&self, group: &[u8]) -> (Key, Key){
+        prefix_range(encode_data_key(self.id, &group_prefix(STREAM_CONSUMER_TAG, group)))
+    }
+
+    pub fn pending_key(&self, group: &[u8], entry_id: StreamId) -> Key{
+        let mut sub_key = group_prefix(STREAM_PENDING_TAG, group);
+        sub_key.extend_from_slice(&entry_id.ms.to_be_bytes());
+        sub_key.extend_from_slice(&entry_id.seq.to_be_bytes());
+        encode_data_key(self.id, &sub_key)
+    }
+
+    /// `[start, end)` covering the pending entries of the group with an id
+    /// within `[first, last]`.
+    pub fn pending_range(&self, group: &[u8], first: StreamId, last: StreamId) -> (Key, Key){
+        let end = match last.next(){
+            Some(next) => self.pending_key(group, next),
+            None => prefix_range(encode_data_key(self.id, &group_prefix(STREAM_PENDING_TAG, group))).1,
+        };
+        (self.pending_key(group, first), end)
+    }
+
+    fn owned_prefix(&self, group: &[u8], consumer: &[u8]) -> Key{
+        let mut sub_key = group_prefix(STREAM_OWNED_TAG, group);
+        sub_key.extend_from_slice(&(consumer.len() as u32).to_be_bytes());
+        sub_key.extend_from_slice(consumer);
+        encode_data_key(self.id, &sub_key)
+    }
+
+    // the index record of a pending entry under the consumer owning it
+    pub fn owned_key(&self, group: &[u8], consumer: &[u8], entry_id: StreamId) -> Key{
+        let mut owned_key = self.owned_prefix(group, consumer);
+        owned_key.extend_from_slice(&entry_id.ms.to_be_bytes());
+        owned_key.extend_from_slice(&entry_id.seq.to_be_bytes());
+        owned_key
+    }
+
+    /// `[start, end)` covering the index records of the entries pending for
+    /// `consumer` with an id within `[first, last]`.
+    pub fn owned_range(&self, group: &[u8], consumer: &[u8], first: StreamId, last: StreamId) -> (Key, Key){
+        let end = match last.next(){
+            Some(next) => self.owned_key(group, consumer, next),
+            None => prefix_range(self.owned_prefix(group, consumer)).1,
+        };
+        (self.owned_key(group, consumer, first), end)
+    }
+
+    // the index records of every consumer of the group
+    pub fn owned_group_range(&self, group: &[u8]) -> (Key, Key){
+        prefix_range(encode_data_key(self.id, &group_prefix(STREAM_OWNED_TAG, group)))
+    }
+}
+
+// the entry id an index record of `owned_key` stands for
+pub fn decode_owned_key(owned_key: &[u8]) -> Result<StreamId>{
+    if owned_key.len() < 16{
+        return Err(Error::OperationError("pending index record too short".to_string()));
+    }
+    let pos = owned_key.len() - 16;
+    Ok(StreamId::new(read_u64(owned_key, pos)?, read_u64(owned_key, pos + 8)?))
+}
+
+// the value of a group record, its last delivered id
+pub fn encode_stream_id(id: StreamId) -> Value{
+    let mut raw = Vec::with_capacity(16);
+    raw.extend_from_slice(&id.ms.to_be_bytes());
+    raw.extend_from_slice(&id.seq.to_be_bytes());
+    raw
+}
+
+pub fn decode_stream_id(raw: &[u8]) -> Result<StreamId>{
+    Ok(StreamId::new(read_u64(raw, 0)?, read_u64(raw, 8)?))
+}
+
+// a pending entry record holds `[delivered_at][deliveries][consumer]`
+pub fn encode_pending(entry: &PendingEntry) -> Value{
+    let mut raw = Vec::with_capacity(entry.consumer.len() + 16);
+    raw.extend_from_slice(&entry.delivered_at.to_be_bytes());
+    raw.extend_from_slice(&entry.deliveries.to_be_bytes());
+    raw.extend_from_slice(&entry.consumer);
+    raw
+}
+
+pub fn decode_pending(pending_key: &[u8], raw: &[u8]) -> Result<PendingEntry>{
+    if pending_key.len() < 16 || raw.len() < 16{
+        return Err(Error::OperationError("pending entry too short".to_string()));
+    }
+    let pos = pending_key.len() - 16;
+    Ok(PendingEntry{
+        id: StreamId::new(read_u64(pending_key, pos)?, read_u64(pending_key, pos + 8)?),
+        consumer: raw[16..].to_vec(),
+        delivered_at: read_u64(raw, 0)?,
+        deliveries: read_u64(raw, 8)?,
+    })
+}
+
+// What a write path keeps of the meta it loaded to save it back, see
+// `MetaValue::written`. The default is the one of a new key.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MetaHeader{
+    pub expire_at: Option<u64>,
+    pub writes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetaValue{
+    pub data_type: KeyType,
+    // unix time in milliseconds
+    pub expire_at: Option<u64>,
+    pub writes: u64,
+    pub payload: Vec<u8>,
+}
+
+impl MetaValue{
+    pub fn new(data_type: KeyType, expire_at: Option<u64>, payload: Vec<u8>) -> Self{
+        MetaValue{
+            data_type,
+            expire_at,
+            writes: 0,
+            payload,
+        }
+    }
+
+    /// The meta a write saves over one with `header`, counting the write.
+    pub fn written(data_type: KeyType, header: MetaHeader, payload: Vec<u8>) -> Self{
+        let mut meta = MetaValue::new(data_type, header.expire_at, payload);
+        meta.writes = header.writes.wrapping_add(1);
+        meta
+    }
+
+    pub fn header(&self) -> MetaHeader{
+        MetaHeader{
+            expire_at: self.expire_at,
+            writes: self.writes,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool{
+        self.expire_at.map_or(false, |at| at <= now)
+    }
+
+    /// Id of the element records of a collection, `None` for strings.
+    pub fn collection_id(&self) -> Option<u64>{
+        match self.data_type{
+            KeyType::String => None,
+            _ => read_u64(&self.payload, 0).ok(),
+        }
+    }
+
+    pub fn encode(&self) -> Value{
+        let mut raw = Vec::with_capacity(META_HEADER_LEN + self.payload.len());
+        raw.push(type_to_byte(self.data_type));
+        raw.extend_from_slice(&self.expire_at.unwrap_or(0).to_be_bytes());
+        raw.extend_from_slice(&self.writes.to_be_bytes());
+        raw.extend_from_slice(&self.payload);
+        raw
+    }
+
+    pub fn decode(mut raw: Value) -> Result<MetaValue>{
+        if raw.len() < META_HEADER_LEN{
+            return Err(Error::OperationError("meta value too short".to_string()));
+        }
+        let data_type = type_from_byte(raw[0])?;
+        let expire_at = match read_u64(&raw, 1)?{
+            0 => None,
+            at => Some(at),
+        };
+        let writes = read_u64(&raw, 9)?;
+        let payload = raw.split_off(META_HEADER_LEN);
+        Ok(MetaValue{ data_type, expire_at, writes, payload })
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use crate::tikv::codec::{MetaValue, MetaHeader, ListMeta, CollectionMeta, encode_meta_key, decode_meta_key, data_key_range, decode_data_key};
+    use crate::tikv::codec::{zset_member_key, zset_score_key, zset_score_range, decode_zset_score_key};
+    use crate::tikv::codec::{StreamMeta, decode_stream_entry_key, encode_stream_fields, decode_stream_fields, decode_owned_key};
+    use crate::tikv::codec::{encode_pending, decode_pending, encode_element_value, decode_element_value};
+    use crate::redis_server::{KeyType, PendingEntry, StreamId, score_to_ordered};
+
+    #[test]
+    fn test_meta_value_roundtrip(){
+        assert_eq!(encode_meta_key(b"foo"), b"mfoo".to_vec());
+        assert_eq!(decode_meta_key(b"mfoo"), b"foo");
+        let meta = MetaValue::new(KeyType::String, Some(1234), b"".to_vec());
+        let decoded = MetaValue::decode(meta.encode()).unwrap();
+        assert_eq!(decoded, meta);
+        assert!(decoded.is_expired(1234));
+        assert!(!decoded.is_expired(1233));
+
+        let meta = MetaValue::new(KeyType::String, None, b"bar".to_vec());
+        assert_eq!(MetaValue::decode(meta.encode()).unwrap(), meta);
+        assert!(MetaValue::decode(b"short".to_vec()).is_err());
+        assert_eq!(meta.collection_id(), None);
+
+        // rewriting the same value still changes the record
+        let rewritten = MetaValue::written(KeyType::String, meta.header(), meta.payload.clone());
+        assert_eq!(rewritten.writes, 1);
+        assert_ne!(rewritten.encode(), meta.encode());
+        assert_eq!(MetaValue::decode(rewritten.encode()).unwrap(), rewritten);
+        assert_eq!(MetaValue::written(KeyType::String, MetaHeader::default(), b"bar".to_vec()).writes, 1);
+    }
+
+    #[test]
+    fn test_element_value_roundtrip(){
+        assert_eq!(encode_element_value(b""), b"v".to_vec());
+        assert_eq!(decode_element_value(encode_element_value(b"")).unwrap(), b"".to_vec());
+        assert_eq!(decode_element_value(encode_element_value(b"bar")).unwrap(), b"bar".to_vec());
+        assert!(decode_element_value(b"".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_list_meta(){
+        let mut list = ListMeta::new(7);
+        assert!(list.is_empty());
+        list.head -= 1;
+        list.tail += 2;
+        assert_eq!(list.len(), 3);
+        assert!(list.element_key(list.head) < list.element_key(list.head + 1));
+        let (start, end) = data_key_range(7);
+        assert!(start <= list.element_key(list.head) && list.element_key(list.tail) < end);
+
+        let meta = MetaValue::new(KeyType::List, None, list.encode());
+        assert_eq!(meta.collection_id(), Some(7));
+        assert_eq!(ListMeta::decode(&meta.payload).unwrap(), list);
+        assert!(ListMeta::decode(b"short").is_err());
+    }
+
+    #[test]
+    fn test_collection_meta(){
+        let mut hash = CollectionMeta::new(9);
+        hash.len = 3;
+        assert_eq!(decode_data_key(&hash.element_key(b"field")), b"field");
+        let meta = MetaValue::new(KeyType::Hash, Some(10), hash.encode());
+        let decoded = MetaValue::decode(meta.encode()).unwrap();
+        assert_eq!(decoded.collection_id(), Some(9));
+        assert_eq!(CollectionMeta::decode(&decoded.payload).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_zset_keys(){
+        let (start, end) = zset_score_range(3);
+        let scores = [-1.5, 0.0, 2.0, 10.0];
+        let keys: Vec<_> = scores.iter().map(|s| zset_score_key(3, score_to_ordered(*s), b"m")).collect();
+        for pair in keys.windows(2){
+            assert!(pair[0] < pair[1]);
+        }
+        assert!(start <= keys[0] && keys[3] < end);
+        let member_key = zset_member_key(3, b"m");
+        assert!(member_key < start || member_key >= end);
+        assert_eq!(decode_zset_score_key(&keys[2]).unwrap(), (score_to_ordered(2.0), b"m".to_vec()));
+    }
+
+    #[test]
+    fn test_stream_keys(){
+        let mut stream = StreamMeta::new(4);
+        stream.len = 2;
+        stream.last_id = StreamId::new(10, 3);
+        let meta = MetaValue::new(KeyType::Stream, None, stream.encode());
+        let decoded = MetaValue::decode(meta.encode()).unwrap();
+        assert_eq!(decoded.collection_id(), Some(4));
+        assert_eq!(StreamMeta::decode(&decoded.payload).unwrap(), stream);
+
+        let ids = [StreamId::new(9, u64::MAX), StreamId::new(10, 0), StreamId::new(10, 3), StreamId::MAX];
+        let keys: Vec<_> = ids.iter().map(|id| stream.entry_key(*id)).collect();
+        for pair in keys.windows(2){
+            assert!(pair[0] < pair[1]);
+        }
+        assert_eq!(decode_stream_entry_key(&keys[2]).unwrap(), ids[2]);
+        let (start, end) = stream.entry_range(ids[1], ids[2]);
+        assert!(start <= keys[1] && keys[2] < end && end <= keys[3]);
+        let (_, end) = stream.entry_range(StreamId::MIN, StreamId::MAX);
+        assert!(keys[3] < end && end <= data_key_range(4).1);
+
+        let fields = vec![(b"f".to_vec(), b"".to_vec()), (b"".to_vec(), b"value".to_vec())];
+        assert_eq!(decode_stream_fields(&encode_stream_fields(&fields)).unwrap(), fields);
+        assert!(decode_stream_fields(&[0, 0, 0, 2, b'f']).is_err());
+    }
+
+    #[test]
+    fn test_stream_group_keys(){
+        let stream = StreamMeta::new(4);
+        let (entries_start, entries_end) = stream.entry_range(StreamId::MIN, StreamId::MAX);
+        let (start, end) = stream.pending_range(b"g", StreamId::MIN, StreamId::MAX);
+        let pending = stream.pending_key(b"g", StreamId::new(5, 1));
+        assert!(start <= pending && pending < end);
+        // records of the group "g" and of "gg" never mix
+        let other = stream.pending_key(b"gg", StreamId::MIN);
+        assert!(other < start || other >= end);
+        let (start, end) = stream.consumer_range(b"g");
+        let consumer = stream.consumer_key(b"g", &[0xff, 0xff]);
+        assert!(start <= consumer && consumer < end);
+        for key in &[pending, consumer, stream.group_key(b"g")]{
+            assert!(*key < entries_start || *key >= entries_end);
+        }
+
+        let entry = PendingEntry{ id: StreamId::new(5, 1), consumer: b"alice".to_vec(), delivered_at: 100, deliveries: 2 };
+        let raw = encode_pending(&entry);
+        assert_eq!(decode_pending(&stream.pending_key(b"g", entry.id), &raw).unwrap(), entry);
+        assert!(decode_pending(b"short", &raw).is_err());
+
+        // the index of a consumer holds only its entries, those of "al" and
+        // "alice" never mix
+        let owned = stream.owned_key(b"g", b"alice", entry.id);
+        assert_eq!(decode_owned_key(&owned).unwrap(), entry.id);
+        let (start, end) = stream.owned_range(b"g", b"alice", StreamId::MIN, StreamId::MAX);
+        assert!(start <= owned && owned < end);
+        let other = stream.owned_key(b"g", b"al", StreamId::MAX);
+        assert!(other < start || other >= end);
+        let (start, end) = stream.owned_range(b"g", b"alice", StreamId::new(5, 2), StreamId::MAX);
+        assert!(owned < start && start < end);
+        let (start, end) = stream.owned_group_range(b"g");
+        assert!(start <= owned && owned < end && start <= other && other < end);
+        let pending = stream.pending_key(b"g", entry.id);
+        assert!(pending < start || pending >= end);
+    }
+}
@@ -23,6 +23,10 @@ impl RawContext {
         Arc::clone(&self.client)
     }
 
+    pub fn region(&self) -> &RegionContext {
+        &self.region
+    }
+
     pub fn into_inner(self) -> (RegionContext, Option<String>) {
         (self.region, self.cf)
     }
@@ -1,4 +1,8 @@
 pub mod tikv_db;
 mod tikv_client;
 mod pd_client;
-mod context;
\ No newline at end of file
+mod context;
+mod region_cache;
+mod backoff;
+mod codec;
+mod txn;
\ No newline at end of file
@@ -1,12 +1,14 @@
 use std::{
     fmt,
     sync::{Arc, RwLock},
+    thread,
     time::Duration,
 };
 
 use std::collections::HashSet;
 
-use grpcio::{CallOption, Environment, ChannelBuilder};
+use futures::{executor::block_on, SinkExt, StreamExt};
+use grpcio::{CallOption, Environment, ChannelBuilder, WriteFlags};
 use kvproto::{metapb, pdpb, pdpb::PdClient as RpcClient};
 use protobuf::Message;
 
@@ -14,6 +16,28 @@ use super::tikv_db::Result;
 use super::tikv_db::Error;
 use super::context::{Region};
 
+// how long a PD request may take before it counts as failed
+const PD_TIMEOUT: Duration = Duration::from_secs(3);
+// a PD leader election takes a few seconds, requests wait that long for it
+const PD_MAX_ATTEMPTS: u32 = 10;
+const PD_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MEMBER_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+// a timestamp is the physical time in milliseconds shifted left by this
+// many bits, plus a logical counter
+const PHYSICAL_SHIFT_BITS: u32 = 18;
+
+fn call_option() -> CallOption {
+    CallOption::default().timeout(PD_TIMEOUT)
+}
+
+fn check_header(header: &pdpb::ResponseHeader) -> Result<()> {
+    if header.has_error() {
+        Err(Error::PdError(header.get_error().get_message().to_string()))
+    } else {
+        Ok(())
+    }
+}
+
 fn connect_pd_client(
     env: Arc<Environment>,
     addr: &str,
@@ -28,8 +52,8 @@ fn connect_pd_client(
     let channel = cb.connect(addr);
 
     let pd_client = pdpb::PdClient::new(channel);
-    let option = CallOption::default();
-    let resp = pd_client.get_members_opt(&pdpb::GetMembersRequest::new(), option).unwrap();
+    let resp = pd_client.get_members_opt(&pdpb::GetMembersRequest::new(), call_option())
+        .map_err(|e| Error::PdError(format!("failed to get members from {}: {}", addr, e)))?;
     Ok((pd_client, resp))
 }
 
@@ -97,6 +121,11 @@ pub struct LeaderClient {
     pub members: pdpb::GetMembersResponse,
     env: Arc<Environment>,
     cluster_id: u64,
+    // the configured endpoints, tried when no known member answers
+    endpoints: Vec<String>,
+    // bumped on every reconnection, so concurrent failures of the same
+    // client reconnect only once
+    generation: u64,
 }
 
 impl LeaderClient{
@@ -111,10 +140,39 @@ impl LeaderClient{
             members,
             env,
             cluster_id,
+            endpoints: endpoints.to_vec(),
+            generation: 0,
         }));
         Ok(client)
     }
 
+    // Connects to the current PD leader, asking the known members first
+    // and the configured endpoints when none of them answers.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let (client, members) = match try_connect_pd_leader(&self.env, &self.members) {
+            Ok(connected) => connected,
+            Err(e) => {
+                println!("no known PD member answers, {:?}", e);
+                LeaderClient::validate_endpoints(&self.env, &self.endpoints)?
+            }
+        };
+        if members.get_header().get_cluster_id() != self.cluster_id {
+            return Err(Error::PdError("cluster id not match".to_string()));
+        }
+        println!("connect to PD leader@{:?}", members.get_leader().get_client_urls());
+        self.client = client;
+        self.members = members;
+        self.generation += 1;
+        Ok(())
+    }
+
+    pub fn fetch_members(&self) -> Result<pdpb::GetMembersResponse> {
+        let members = self.client.get_members_opt(&pdpb::GetMembersRequest::new(), call_option())
+            .map_err(|e| Error::PdError(format!("{}", e)))?;
+        check_header(members.get_header())?;
+        Ok(members)
+    }
+
     pub fn validate_endpoints(
         env: &Arc<Environment>,
         endpoints: &[String]
@@ -170,6 +228,40 @@ impl LeaderClient{
     }
 }
 
+// Asks the leader for the members every MEMBER_REFRESH_INTERVAL and
+// reconnects when it does not answer or another member got elected, so a
+// PD election is followed before requests fail on it. The thread stops
+// with the last PDClient.
+fn spawn_member_refresh(leader: &Arc<RwLock<LeaderClient>>) {
+    let leader = Arc::downgrade(leader);
+    thread::spawn(move || loop {
+        thread::sleep(MEMBER_REFRESH_INTERVAL);
+        let leader = match leader.upgrade() {
+            Some(leader) => leader,
+            None => return,
+        };
+        let (generation, fetched) = {
+            let client = leader.read().unwrap();
+            (client.generation, client.fetch_members())
+        };
+        let mut client = leader.write().unwrap();
+        if client.generation != generation {
+            // a failed request reconnected meanwhile
+            continue;
+        }
+        match fetched {
+            Ok(ref members) if members.get_leader() == client.members.get_leader() => {
+                client.members = members.clone();
+            }
+            _ => {
+                if let Err(e) = client.reconnect() {
+                    println!("failed to reconnect to PD, {:?}", e);
+                }
+            }
+        }
+    });
+}
+
 pub struct PDClient{
     cluster_id: u64,
     // PD也是一个集群， 与PD的交互通过leader进行
@@ -180,42 +272,112 @@ impl PDClient{
     pub fn new(env: Arc<Environment>, endpoints: &[String]) -> Result<PDClient>{
         let leader = LeaderClient::new(env, endpoints)?;
         let cluster_id = leader.read().unwrap().cluster_id();
+        spawn_member_refresh(&leader);
         Ok(PDClient {
             cluster_id,
             leader,
         })
     }
 
-    pub fn get_region(&self, key: &[u8]) -> Region{
-        let (region, leader) = self.get_region_and_leader(key);
-        Region::new(region, leader)
+    // Sends a request to the PD leader with `f`. When it fails the leader
+    // may have changed, so the client reconnects and sends it again.
+    fn request<R, F>(&self, f: F) -> Result<R>
+    where F: Fn(&pdpb::PdClient, CallOption) -> grpcio::Result<R> {
+        let mut attempts = 1;
+        loop {
+            let (generation, result) = {
+                let leader = self.leader.read().unwrap();
+                (leader.generation, f(&leader.client, call_option()))
+            };
+            let err = match result {
+                Ok(res) => return Ok(res),
+                Err(err) => err,
+            };
+            if attempts >= PD_MAX_ATTEMPTS {
+                return Err(Error::PdError(format!("{}", err)));
+            }
+            attempts += 1;
+            println!("PD request failed, {:?}", err);
+            thread::sleep(PD_RETRY_DELAY);
+            let mut leader = self.leader.write().unwrap();
+            // another request may have reconnected meanwhile
+            if leader.generation == generation {
+                if let Err(e) = leader.reconnect() {
+                    println!("failed to reconnect to PD, {:?}", e);
+                }
+            }
+        }
+    }
+
+    pub fn get_region(&self, key: &[u8]) -> Result<Region>{
+        let (region, leader) = self.get_region_and_leader(key, false)?;
+        Ok(Region::new(region, leader))
+    }
+
+    // the region right before the one holding `key`, reverse scans walk
+    // backwards with it
+    pub fn get_prev_region(&self, key: &[u8]) -> Result<Region>{
+        let (region, leader) = self.get_region_and_leader(key, true)?;
+        Ok(Region::new(region, leader))
+    }
+
+    // A timestamp from the TSO of PD, ordering every transaction of the
+    // cluster. Each call opens the Tso stream, asks for one and closes it.
+    pub fn get_timestamp(&self) -> Result<u64>{
+        let mut req = pdpb::TsoRequest::new();
+        let mut header = pdpb::RequestHeader::new();
+        header.set_cluster_id(self.cluster_id);
+        req.set_header(header);
+        req.set_count(1);
+        let mut res = self.request(|client, option| {
+            let (mut sender, mut receiver) = client.tso_opt(option)?;
+            block_on(async {
+                sender.send((req.clone(), WriteFlags::default())).await?;
+                sender.close().await?;
+                match receiver.next().await {
+                    Some(res) => res,
+                    None => Err(grpcio::Error::RemoteStopped),
+                }
+            })
+        })?;
+        check_header(res.get_header())?;
+        let timestamp = res.take_timestamp();
+        Ok(((timestamp.get_physical() as u64) << PHYSICAL_SHIFT_BITS) + timestamp.get_logical() as u64)
     }
 
-    pub fn get_store(&self, store_id: u64) -> metapb::Store{
+    pub fn get_store(&self, store_id: u64) -> Result<metapb::Store>{
         let mut req = pdpb::GetStoreRequest::new();
         let mut header = pdpb::RequestHeader::new();
         header.set_cluster_id(self.cluster_id);
         req.set_header(header);
         req.set_store_id(store_id);
-        self.leader.write().unwrap().client.get_store(&req).unwrap().take_store().into()
+        let mut res = self.request(|client, option| client.get_store_opt(&req, option))?;
+        check_header(res.get_header())?;
+        if res.has_store(){
+            Ok(res.take_store())
+        }else{
+            Err(Error::PdError(format!("store {} not found", store_id)))
+        }
     }
 
-    fn get_region_and_leader(&self, key: &[u8]) -> (metapb::Region, Option<metapb::Peer>){
+    fn get_region_and_leader(&self, key: &[u8], prev: bool) -> Result<(metapb::Region, Option<metapb::Peer>)>{
         let mut req = pdpb::GetRegionRequest::new();
         let mut header = pdpb::RequestHeader::new();
         header.set_cluster_id(self.cluster_id);
         req.set_header(header);
 
         req.set_region_key(key.to_owned());
-        let key = req.get_region_key().to_owned();
         // 通过rpc调用去获得当前key的region
-        // TODO: handle error
-        let mut res = self.leader.read().unwrap().client.get_region(&req).unwrap();
+        let mut res = self.request(|client, option| if prev{
+            client.get_prev_region_opt(&req, option)
+        }else{
+            client.get_region_opt(&req, option)
+        })?;
+        check_header(res.get_header())?;
         let region = if res.has_region(){
             res.take_region()
         }else{
-            // TODO: find a better way to handle this scene
-            panic!("not get a region");
+            return Err(Error::PdError(format!("no region found for key {:?}", key)));
         };
 
         let leader = if res.has_leader(){
@@ -224,8 +386,6 @@ impl PDClient{
             None
         };
 
-        (region, leader)
+        Ok((region, leader))
     }
-
-
-}
\ No newline at end of file
+}
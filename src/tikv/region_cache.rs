@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+use kvproto::metapb;
+
+use super::context::Region;
+use super::tikv_db::Key;
+
+// Regions are indexed by their end key. The last region has an empty end
+// key in PD, which would sort first, so it is stored as `Infinite`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum EndKey {
+    Key(Key),
+    Infinite,
+}
+
+impl EndKey {
+    fn of(region: &Region) -> EndKey {
+        if region.end_key().is_empty() {
+            EndKey::Infinite
+        } else {
+            EndKey::Key(region.end_key().to_vec())
+        }
+    }
+}
+
+// Locations of regions and stores learned from PD. A region is found by
+// taking the first cached end key above the key and checking its start key,
+// so lookups never need PD until an entry is dropped.
+#[derive(Default)]
+pub struct RegionCache {
+    regions: BTreeMap<EndKey, Region>,
+    stores: HashMap<u64, metapb::Store>,
+}
+
+impl RegionCache {
+    pub fn new() -> Self {
+        RegionCache::default()
+    }
+
+    pub fn get_region(&self, key: &Key) -> Option<Region> {
+        let above = (Bound::Excluded(EndKey::Key(key.clone())), Bound::Unbounded);
+        match self.regions.range(above).next() {
+            Some((_, region)) if region.contains(key) => Some(region.clone()),
+            _ => None,
+        }
+    }
+
+    // a fresh region replaces every cached one it overlaps, they are stale
+    // after a split or a merge
+    pub fn insert_region(&mut self, region: Region) {
+        let start = EndKey::Key(region.start_key().to_vec());
+        let end = EndKey::of(&region);
+        let overlapped: Vec<EndKey> = self.regions
+            .range((Bound::Excluded(start), Bound::Unbounded))
+            .take_while(|(_, cached)| end == EndKey::Infinite || cached.start_key() < region.end_key())
+            .map(|(end_key, _)| end_key.clone())
+            .collect();
+        for end_key in overlapped {
+            self.regions.remove(&end_key);
+        }
+        self.regions.insert(end, region);
+    }
+
+    // drops the cached entry of `region`, unless it was replaced meanwhile
+    pub fn invalidate_region(&mut self, region: &Region) {
+        let end_key = EndKey::of(region);
+        if self.regions.get(&end_key).map_or(false, |cached| cached.id() == region.id()) {
+            self.regions.remove(&end_key);
+        }
+    }
+
+    // follows a NotLeader hint, `false` if the region is not cached
+    pub fn update_leader(&mut self, region: &Region, leader: metapb::Peer) -> bool {
+        match self.regions.get_mut(&EndKey::of(region)) {
+            Some(cached) if cached.id() == region.id() => {
+                cached.leader = Some(leader.into());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn get_store(&self, id: u64) -> Option<metapb::Store> {
+        self.stores.get(&id).cloned()
+    }
+
+    pub fn insert_store(&mut self, store: metapb::Store) {
+        self.stores.insert(store.get_id(), store);
+    }
+
+    pub fn invalidate_store(&mut self, id: u64) {
+        self.stores.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tikv::region_cache::RegionCache;
+    use crate::tikv::context::Region;
+    use kvproto::metapb;
+    use protobuf::Message;
+
+    fn region(id: u64, start: &[u8], end: &[u8]) -> Region {
+        let mut meta = metapb::Region::new();
+        meta.set_id(id);
+        meta.set_start_key(start.to_vec());
+        meta.set_end_key(end.to_vec());
+        let mut leader = metapb::Peer::new();
+        leader.set_store_id(id * 10);
+        Region::new(meta, Some(leader))
+    }
+
+    #[test]
+    fn test_region_lookup(){
+        let mut cache = RegionCache::new();
+        cache.insert_region(region(1, b"", b"b"));
+        cache.insert_region(region(2, b"b", b"d"));
+        cache.insert_region(region(3, b"f", b""));
+        assert_eq!(cache.get_region(&b"a".to_vec()).unwrap().id(), 1);
+        assert_eq!(cache.get_region(&b"b".to_vec()).unwrap().id(), 2);
+        assert!(cache.get_region(&b"e".to_vec()).is_none());
+        assert_eq!(cache.get_region(&b"zzz".to_vec()).unwrap().id(), 3);
+
+        // an entry is only dropped by the region it holds
+        cache.invalidate_region(&region(9, b"b", b"d"));
+        assert!(cache.get_region(&b"c".to_vec()).is_some());
+        cache.invalidate_region(&region(2, b"b", b"d"));
+        assert!(cache.get_region(&b"c".to_vec()).is_none());
+        assert_eq!(cache.get_region(&b"a".to_vec()).unwrap().id(), 1);
+    }
+
+    #[test]
+    fn test_region_split_and_leader(){
+        let mut cache = RegionCache::new();
+        cache.insert_region(region(1, b"", b"m"));
+        cache.insert_region(region(2, b"m", b""));
+        // region 1 split at "f", the new range replaces it
+        cache.insert_region(region(4, b"f", b"m"));
+        assert!(cache.get_region(&b"a".to_vec()).is_none());
+        assert_eq!(cache.get_region(&b"g".to_vec()).unwrap().id(), 4);
+        assert_eq!(cache.get_region(&b"x".to_vec()).unwrap().id(), 2);
+        // a merge of everything drops both
+        cache.insert_region(region(5, b"", b""));
+        assert_eq!(cache.get_region(&b"g".to_vec()).unwrap().id(), 5);
+        assert_eq!(cache.get_region(&b"x".to_vec()).unwrap().id(), 5);
+
+        let mut leader = metapb::Peer::new();
+        leader.set_store_id(7);
+        assert!(cache.update_leader(&region(5, b"", b""), leader));
+        assert_eq!(cache.get_region(&b"a".to_vec()).unwrap().peer().unwrap().get_store_id(), 7);
+        assert!(!cache.update_leader(&region(1, b"", b"m"), metapb::Peer::new()));
+    }
+
+    #[test]
+    fn test_store_cache(){
+        let mut cache = RegionCache::new();
+        let mut store = metapb::Store::new();
+        store.set_id(3);
+        store.set_address("127.0.0.1:20160".to_string());
+        cache.insert_store(store.clone());
+        assert_eq!(cache.get_store(3), Some(store));
+        cache.invalidate_store(3);
+        assert_eq!(cache.get_store(3), None);
+    }
+}
@@ -7,7 +7,7 @@ use kvproto::{errorpb, kvrpcpb, tikvpb::TikvClient};
 use protobuf::Message;
 
 use super::context::RawContext;
-use super::tikv_db::{Key, Value, Result};
+use super::tikv_db::{Key, Value, Result, Error};
 
 pub struct KVClient{
     client: Arc<TikvClient>,
@@ -32,7 +32,8 @@ impl KVClient{
             address: addr.to_owned(),
         })
     }
-    pub fn raw_put(&self, context: RawContext, key: Key, value: Value) {
+    // `for_cas` sends the write in the atomic mode compare-and-swap needs
+    pub fn raw_put(&self, context: RawContext, key: Key, value: Value, for_cas: bool) -> Result<()> {
         let mut req = kvrpcpb::RawPutRequest::new();
         let (region, cf) = context.into_inner();
         req.set_context(region.into());
@@ -41,11 +42,23 @@ impl KVClient{
         }
         req.set_key(key);
         req.set_value(value);
-        // TODO: handle RawPutResponse
-        self.client.raw_put(&req);
+        req.set_for_cas(for_cas);
+        match self.client.raw_put(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if res.get_error().is_empty(){
+                    Ok(())
+                }else{
+                    Err(Error::TiKVError(res.get_error().to_string()))
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
     }
 
-    pub fn raw_get(&self, context: RawContext, key: Key)-> Value {
+    // `None` when the key does not exist, TiKV stores no empty values
+    pub fn raw_get(&self, context: RawContext, key: Key)-> Result<Option<Value>> {
         // RawContext包含region、对应的kvclient、以及cf信息
         // raw_request宏是用于生成一个request
         let mut req = kvrpcpb::RawGetRequest::new();
@@ -56,14 +69,397 @@ impl KVClient{
         }
         req.set_key(key);
         // 通过TiKVClient就可以进行RPC调用
-        /// TODO: handle RawGetResponse
         match self.client.raw_get(&req){
             Ok(mut res) =>{
-                res.take_value()
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if !res.get_error().is_empty(){
+                    Err(Error::TiKVError(res.get_error().to_string()))
+                }else if res.get_value().is_empty(){
+                    Ok(None)
+                }else{
+                    Ok(Some(res.take_value()))
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    pub fn raw_delete(&self, context: RawContext, key: Key, for_cas: bool) -> Result<()> {
+        let mut req = kvrpcpb::RawDeleteRequest::new();
+        let (region, cf) = context.into_inner();
+        req.set_context(region.into());
+        if let Some(cf) = cf {
+            req.set_cf(cf);
+        }
+        req.set_key(key);
+        req.set_for_cas(for_cas);
+        match self.client.raw_delete(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if res.get_error().is_empty(){
+                    Ok(())
+                }else{
+                    Err(Error::TiKVError(res.get_error().to_string()))
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    // the range must not cross the region of the context
+    pub fn raw_delete_range(&self, context: RawContext, start_key: Key, end_key: Key) -> Result<()> {
+        let mut req = kvrpcpb::RawDeleteRangeRequest::new();
+        let (region, cf) = context.into_inner();
+        req.set_context(region.into());
+        if let Some(cf) = cf {
+            req.set_cf(cf);
+        }
+        req.set_start_key(start_key);
+        req.set_end_key(end_key);
+        match self.client.raw_delete_range(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if res.get_error().is_empty(){
+                    Ok(())
+                }else{
+                    Err(Error::TiKVError(res.get_error().to_string()))
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    // scans `[start_key, end_key)` inside the region of the context
+    // Pairs in `[start_key, end_key)`, from the last one down when `reverse`.
+    // Values are left empty with `key_only`.
+    pub fn raw_scan(&self, context: RawContext, start_key: Key, end_key: Key, limit: u32, key_only: bool, reverse: bool)
+        -> Result<Vec<(Key, Value)>> {
+        let mut req = kvrpcpb::RawScanRequest::new();
+        let (region, cf) = context.into_inner();
+        req.set_context(region.into());
+        if let Some(cf) = cf {
+            req.set_cf(cf);
+        }
+        // a reverse scan starts from its exclusive upper bound
+        if reverse {
+            req.set_start_key(end_key);
+            req.set_end_key(start_key);
+        } else {
+            req.set_start_key(start_key);
+            req.set_end_key(end_key);
+        }
+        req.set_limit(limit);
+        req.set_key_only(key_only);
+        req.set_reverse(reverse);
+        match self.client.raw_scan(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    return Err(Error::RegionError(Box::new(res.take_region_error())));
+                }
+                Ok(res.take_kvs().into_iter().map(|mut kv| (kv.take_key(), kv.take_value())).collect())
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    // the pairs found among `keys`, which must all be in the region of the context
+    pub fn raw_batch_get(&self, context: RawContext, keys: Vec<Key>) -> Result<Vec<(Key, Value)>> {
+        let mut req = kvrpcpb::RawBatchGetRequest::new();
+        let (region, cf) = context.into_inner();
+        req.set_context(region.into());
+        if let Some(cf) = cf {
+            req.set_cf(cf);
+        }
+        req.set_keys(keys);
+        match self.client.raw_batch_get(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    return Err(Error::RegionError(Box::new(res.take_region_error())));
+                }
+                Ok(res.take_pairs().into_iter().map(|mut kv| (kv.take_key(), kv.take_value())).collect())
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    pub fn raw_batch_put(&self, context: RawContext, pairs: Vec<(Key, Value)>, for_cas: bool) -> Result<()> {
+        let mut req = kvrpcpb::RawBatchPutRequest::new();
+        let (region, cf) = context.into_inner();
+        req.set_context(region.into());
+        if let Some(cf) = cf {
+            req.set_cf(cf);
+        }
+        let pairs = pairs.into_iter().map(|(key, value)| {
+            let mut pair = kvrpcpb::KvPair::new();
+            pair.set_key(key);
+            pair.set_value(value);
+            pair
+        }).collect();
+        req.set_pairs(pairs);
+        req.set_for_cas(for_cas);
+        match self.client.raw_batch_put(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if !res.get_error().is_empty(){
+                    Err(Error::TiKVError(res.get_error().to_string()))
+                }else{
+                    Ok(())
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    // returns whether the swap happened and the value found before it
+    pub fn raw_compare_and_swap(
+        &self,
+        context: RawContext,
+        key: Key,
+        previous: Option<Value>,
+        value: Value,
+    ) -> Result<(bool, Option<Value>)> {
+        let mut req = kvrpcpb::RawCasRequest::new();
+        let (region, cf) = context.into_inner();
+        req.set_context(region.into());
+        if let Some(cf) = cf {
+            req.set_cf(cf);
+        }
+        req.set_key(key);
+        req.set_value(value);
+        match previous{
+            Some(previous) => req.set_previous_value(previous),
+            None => req.set_previous_not_exist(true),
+        }
+        match self.client.raw_compare_and_swap(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    return Err(Error::RegionError(Box::new(res.take_region_error())));
+                }
+                if !res.get_error().is_empty(){
+                    return Err(Error::TiKVError(res.get_error().to_string()));
+                }
+                let previous = if res.get_previous_not_exist(){
+                    None
+                }else{
+                    Some(res.take_previous_value())
+                };
+                Ok((res.get_succeed(), previous))
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    // Transactional requests, driven by `Transaction`. Locks of other
+    // transactions come back as `Error::KeyError` for the caller to resolve.
+
+    // the value of `key` in the snapshot at `version`
+    pub fn kv_get(&self, context: RawContext, key: Key, version: u64) -> Result<Option<Value>> {
+        let mut req = kvrpcpb::GetRequest::new();
+        let (region, _) = context.into_inner();
+        req.set_context(region.into());
+        req.set_key(key);
+        req.set_version(version);
+        match self.client.kv_get(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if res.has_error(){
+                    Err(Error::KeyError(Box::new(res.take_error())))
+                }else if res.get_not_found(){
+                    Ok(None)
+                }else{
+                    Ok(Some(res.take_value()))
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    // like `raw_scan` in the snapshot at `version`
+    pub fn kv_scan(&self, context: RawContext, start_key: Key, end_key: Key, limit: u32, version: u64, key_only: bool,
+                   reverse: bool) -> Result<Vec<(Key, Value)>> {
+        let mut req = kvrpcpb::ScanRequest::new();
+        let (region, _) = context.into_inner();
+        req.set_context(region.into());
+        if reverse {
+            req.set_start_key(end_key);
+            req.set_end_key(start_key);
+        } else {
+            req.set_start_key(start_key);
+            req.set_end_key(end_key);
+        }
+        req.set_limit(limit);
+        req.set_version(version);
+        req.set_key_only(key_only);
+        req.set_reverse(reverse);
+        match self.client.kv_scan(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if res.has_error(){
+                    Err(Error::KeyError(Box::new(res.take_error())))
+                }else{
+                    take_pairs(res.take_pairs())
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    // like `raw_batch_get` in the snapshot at `version`
+    pub fn kv_batch_get(&self, context: RawContext, keys: Vec<Key>, version: u64) -> Result<Vec<(Key, Value)>> {
+        let mut req = kvrpcpb::BatchGetRequest::new();
+        let (region, _) = context.into_inner();
+        req.set_context(region.into());
+        req.set_keys(keys);
+        req.set_version(version);
+        match self.client.kv_batch_get(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if res.has_error(){
+                    Err(Error::KeyError(Box::new(res.take_error())))
+                }else{
+                    take_pairs(res.take_pairs())
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    // Locks every key of `mutations` for the transaction `start_ts`, with
+    // the operation applied on commit and the value of a put.
+    pub fn kv_prewrite(&self, context: RawContext, mutations: Vec<(Key, kvrpcpb::Op, Value)>, primary: Key,
+                       start_ts: u64, lock_ttl: u64) -> Result<()> {
+        let mut req = kvrpcpb::PrewriteRequest::new();
+        let (region, _) = context.into_inner();
+        req.set_context(region.into());
+        let mutations: Vec<kvrpcpb::Mutation> = mutations.into_iter().map(|(key, op, value)| {
+            let mut mutation = kvrpcpb::Mutation::new();
+            mutation.set_op(op);
+            mutation.set_key(key);
+            mutation.set_value(value);
+            mutation
+        }).collect();
+        req.set_txn_size(mutations.len() as u64);
+        req.set_mutations(mutations);
+        req.set_primary_lock(primary);
+        req.set_start_version(start_ts);
+        req.set_lock_ttl(lock_ttl);
+        match self.client.kv_prewrite(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    return Err(Error::RegionError(Box::new(res.take_region_error())));
+                }
+                match res.take_errors().into_iter().next(){
+                    Some(err) => Err(Error::KeyError(Box::new(err))),
+                    None => Ok(()),
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    pub fn kv_commit(&self, context: RawContext, keys: Vec<Key>, start_ts: u64, commit_ts: u64) -> Result<()> {
+        let mut req = kvrpcpb::CommitRequest::new();
+        let (region, _) = context.into_inner();
+        req.set_context(region.into());
+        req.set_keys(keys);
+        req.set_start_version(start_ts);
+        req.set_commit_version(commit_ts);
+        match self.client.kv_commit(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if res.has_error(){
+                    Err(Error::KeyError(Box::new(res.take_error())))
+                }else{
+                    Ok(())
+                }
             },
-            Err(e) =>{
-                vec![]
-            }
+            Err(e) => Err(Error::GrpcError(e)),
         }
     }
-}
\ No newline at end of file
+
+    pub fn kv_batch_rollback(&self, context: RawContext, keys: Vec<Key>, start_ts: u64) -> Result<()> {
+        let mut req = kvrpcpb::BatchRollbackRequest::new();
+        let (region, _) = context.into_inner();
+        req.set_context(region.into());
+        req.set_keys(keys);
+        req.set_start_version(start_ts);
+        match self.client.kv_batch_rollback(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if res.has_error(){
+                    Err(Error::KeyError(Box::new(res.take_error())))
+                }else{
+                    Ok(())
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    // Settles the transaction `start_ts` at its primary `key`: rolls it back
+    // unless it committed or its lock is still alive at `current_ts`. Returns
+    // the commit ts, 0 when it was rolled back.
+    pub fn kv_cleanup(&self, context: RawContext, key: Key, start_ts: u64, current_ts: u64) -> Result<u64> {
+        let mut req = kvrpcpb::CleanupRequest::new();
+        let (region, _) = context.into_inner();
+        req.set_context(region.into());
+        req.set_key(key);
+        req.set_start_version(start_ts);
+        req.set_current_ts(current_ts);
+        match self.client.kv_cleanup(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if res.has_error(){
+                    Err(Error::KeyError(Box::new(res.take_error())))
+                }else{
+                    Ok(res.get_commit_version())
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+
+    // commits, or rolls back when `commit_ts` is 0, every lock of the
+    // transaction `start_ts` in the region of the context
+    pub fn kv_resolve_lock(&self, context: RawContext, start_ts: u64, commit_ts: u64) -> Result<()> {
+        let mut req = kvrpcpb::ResolveLockRequest::new();
+        let (region, _) = context.into_inner();
+        req.set_context(region.into());
+        req.set_start_version(start_ts);
+        req.set_commit_version(commit_ts);
+        match self.client.kv_resolve_lock(&req){
+            Ok(mut res) =>{
+                if res.has_region_error(){
+                    Err(Error::RegionError(Box::new(res.take_region_error())))
+                }else if res.has_error(){
+                    Err(Error::KeyError(Box::new(res.take_error())))
+                }else{
+                    Ok(())
+                }
+            },
+            Err(e) => Err(Error::GrpcError(e)),
+        }
+    }
+}
+
+// the pairs of a snapshot read, or the error of the first pair that could
+// not be read, typically because it is locked
+fn take_pairs(pairs: Vec<kvrpcpb::KvPair>) -> Result<Vec<(Key, Value)>> {
+    pairs.into_iter().map(|mut pair| {
+        if pair.has_error() {
+            Err(Error::KeyError(Box::new(pair.take_error())))
+        } else {
+            Ok((pair.take_key(), pair.take_value()))
+        }
+    }).collect()
+}
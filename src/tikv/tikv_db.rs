@@ -1,16 +1,26 @@
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, Mutex};
+use std::thread;
 use std::result;
 use std::collections::HashMap;
+use std::thread::ThreadId;
 use std::fmt;
 
 use super::pd_client::PDClient;
 use super::tikv_client::KVClient;
 use super::context::{RawContext, RegionContext, Region, Peer};
+use super::region_cache::RegionCache;
+use super::backoff::Backoff;
+use super::txn::{self, Transaction};
+use super::codec::{self, MetaValue, MetaHeader, ListMeta, CollectionMeta, StreamMeta};
 
-use crate::redis_server::DB;
+use crate::redis_server::{DB, KeyType, FieldValue, now_millis, random_sample, normalize_range, normalize_index};
+use crate::redis_server::{ScoredMember, score_to_ordered, ordered_to_score, ordered_score_range};
+use crate::redis_server::{NewStreamId, PendingEntry, StreamEntry, StreamId, StreamTrim};
+use std::ops::{Bound, Range};
 
 use grpcio::{Environment, EnvBuilder};
-use kvproto::metapb;
+use kvproto::{metapb, errorpb, kvrpcpb};
+use protobuf::Message;
 
 pub type Key = Vec<u8>;
 pub type Value = Vec<u8>;
@@ -20,20 +30,90 @@ pub enum Error {
     TiKVError(String),
     PdError(String),
     OperationError(String),
+    // the region of the request has moved or changed, see `on_region_error`
+    RegionError(Box<errorpb::Error>),
+    // the request did not get an answer, the store may be down
+    GrpcError(grpcio::Error),
+    // a transactional request met the lock or the write of another transaction
+    KeyError(Box<kvrpcpb::KeyError>),
     Other,
 }
 
+impl Error {
+    // Errors that may go away once the region is located again: it moved,
+    // split or lost its leader, or its store is busy or unreachable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::RegionError(err) => !err.has_raft_entry_too_large(),
+            Error::GrpcError(_) => true,
+            // resolved by `on_error`, or gone once its TTL runs out
+            Error::KeyError(err) => err.has_locked(),
+            _ => false,
+        }
+    }
+
+    // The transaction lost against another one and may succeed when run
+    // again from a new snapshot.
+    pub fn is_conflict(&self) -> bool {
+        match self {
+            Error::KeyError(err) =>
+                err.has_conflict() || err.has_locked() || !err.get_retryable().is_empty() || !err.get_abort().is_empty(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::TiKVError(msg) => write!(f, "tikv error: {}", msg),
+            Error::PdError(msg) => write!(f, "pd error: {}", msg),
+            Error::OperationError(msg) => write!(f, "{}", msg),
+            Error::RegionError(err) => write!(f, "region error: {}", err.get_message()),
+            Error::GrpcError(err) => write!(f, "tikv unreachable: {}", err),
+            Error::KeyError(err) => write!(f, "transaction error: {:?}", err),
+            Error::Other => write!(f, "unknown tikv error"),
+        }
+    }
+}
+
 pub type Result<T> = result::Result<T, Error>;
 
+// collection ids reserved from the cluster wide counter at a time
+const ID_ALLOC_BATCH: u64 = 1000;
+// page size when a whole collection is read
+const SCAN_BATCH: usize = 1024;
+
+// a collection meta loaded for writing, with the header to save it back with
+type Loaded<T> = Option<(T, MetaHeader)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    // RawKV requests, every write stands on its own
+    Raw,
+    // transactions, all the reads and writes of a command take effect together
+    Txn,
+}
 
 pub struct TikvDB {
     pd: Arc<PDClient>,
     kvserver: Arc<RwLock<HashMap<String, Arc<KVClient>>>>,
     env: Arc<Environment>,
+    // [next, end) of the reserved collection ids
+    ids: Mutex<(u64, u64)>,
+    // regions and stores already looked up in PD
+    cache: RwLock<RegionCache>,
+    mode: Mode,
+    // in txn mode, the transaction each executor thread has open
+    txns: Mutex<HashMap<ThreadId, Transaction>>,
 }
 
 impl TikvDB {
     pub fn connect(end_points: Vec<String>) -> Result<TikvDB> {
+        TikvDB::connect_with_mode(end_points, Mode::Raw)
+    }
+
+    pub fn connect_with_mode(end_points: Vec<String>, mode: Mode) -> Result<TikvDB> {
         // config中存储了pd的endpoint
         // 新建一个新的grpc enviroment
         let env = Arc::new(
@@ -53,16 +133,104 @@ impl TikvDB {
             pd,
             kvserver: tikv,
             env,
+            ids: Mutex::new((0, 0)),
+            cache: RwLock::new(RegionCache::new()),
+            mode,
+            txns: Mutex::new(HashMap::new()),
         })
     }
 
-    fn locate_key(&self, key: &Key)-> Region{
-        self.pd.get_region(key.as_ref())
+    // a timestamp from the PD oracle, later than every one handed out before
+    pub(super) fn timestamp(&self) -> Result<u64> {
+        self.pd.get_timestamp()
     }
 
-    fn load_store(&self, id: u64) -> metapb::Store{
+    fn locate_key(&self, key: &Key)-> Result<Region>{
+        if let Some(region) = self.cache.read().unwrap().get_region(key){
+            return Ok(region);
+        }
+        let region = self.pd.get_region(key.as_ref())?;
+        self.cache.write().unwrap().insert_region(region.clone());
+        Ok(region)
+    }
+
+    fn load_store(&self, id: u64) -> Result<metapb::Store>{
+        if let Some(store) = self.cache.read().unwrap().get_store(id){
+            return Ok(store);
+        }
         println!("reload info for store {}", id);
-        self.pd.get_store(id)
+        let store = self.pd.get_store(id)?;
+        self.cache.write().unwrap().insert_store(store.clone());
+        Ok(store)
+    }
+
+    // Keeps the caches in line with an error returned for `region`. The
+    // next request for its keys goes to PD again, except when TiKV already
+    // named the new leader.
+    fn on_error(&self, region: &Region, err: &Error){
+        if let Error::KeyError(err) = err{
+            // a lock still within its TTL stays, the request backs off and
+            // meets it again
+            if err.has_locked(){
+                let _ = txn::resolve_lock(self, err.get_locked());
+            }
+            return;
+        }
+        let mut cache = self.cache.write().unwrap();
+        match err{
+            Error::RegionError(err) =>{
+                if err.has_not_leader(){
+                    let not_leader = err.get_not_leader();
+                    if not_leader.has_leader() && cache.update_leader(region, not_leader.get_leader().clone()){
+                        return;
+                    }
+                    cache.invalidate_region(region);
+                }else if err.has_store_not_match(){
+                    if let Ok(peer) = region.peer(){
+                        cache.invalidate_store(peer.get_store_id());
+                    }
+                    cache.invalidate_region(region);
+                }else if err.has_epoch_not_match() || err.has_region_not_found()
+                    || err.has_key_not_in_region() || err.has_stale_command(){
+                    cache.invalidate_region(region);
+                }
+            },
+            // the leader may have moved away from a store that went down
+            Error::GrpcError(_) =>{
+                if let Ok(peer) = region.peer(){
+                    cache.invalidate_store(peer.get_store_id());
+                }
+                cache.invalidate_region(region);
+            },
+            _ => {},
+        }
+    }
+
+    // Sends a request to the region holding `key` with `f`. After a
+    // retryable error the region is located again and the request resent,
+    // backing off between attempts.
+    pub(super) fn request<R, F>(&self, key: &Key, cf: &Option<String>, f: F) -> Result<R>
+    where F: FnMut(RawContext) -> Result<R> {
+        self.request_at(|| self.get_raw_context(key, cf.clone()), f)
+    }
+
+    // Like `request` for the region found by `locate`.
+    fn request_at<R, L, F>(&self, locate: L, mut f: F) -> Result<R>
+    where L: Fn() -> Result<RawContext>, F: FnMut(RawContext) -> Result<R> {
+        let mut backoff = Backoff::new();
+        loop{
+            let result = locate().and_then(|context| {
+                let region = context.region().region.clone();
+                f(context).map_err(|err| {
+                    self.on_error(&region, &err);
+                    err
+                })
+            });
+            match result{
+                Err(err) => backoff.wait(err)?,
+                result => return result,
+            }
+        }
     }
 
     fn kv_client(&self, context: RegionContext) -> Result<(RegionContext, Arc<KVClient>)> {
@@ -82,77 +250,1615 @@ impl TikvDB {
         Ok((context, client))
     }
 
-    fn get_region_context(&self, key: &Key) -> (RegionContext, Arc<KVClient>){
+    // The region holding the keys right below `key`, where a reverse scan
+    // ending at `key` starts. An empty key stands for the end of the keyspace.
+    fn locate_end_key(&self, key: &Key) -> Result<Region>{
+        if key.is_empty(){
+            // PD has no lookup for the last region, walk up to it
+            let mut region = self.locate_key(key)?;
+            while !region.end_key().is_empty(){
+                region = self.locate_key(&region.end_key().to_vec())?;
+            }
+            return Ok(region);
+        }
+        let region = self.locate_key(key)?;
+        if region.start_key() == key.as_slice(){
+            let prev = self.pd.get_prev_region(key)?;
+            self.cache.write().unwrap().insert_region(prev.clone());
+            Ok(prev)
+        }else{
+            Ok(region)
+        }
+    }
+
+    fn get_region_context(&self, key: &Key) -> Result<(RegionContext, Arc<KVClient>)>{
         // 定位key在哪个region
-        let location = self.locate_key(key);
+        let location = self.locate_key(key)?;
+        self.region_context(location)
+    }
+
+    fn region_context(&self, location: Region) -> Result<(RegionContext, Arc<KVClient>)>{
         // 获取到region之后获取peer
-        let peer = location.peer().unwrap();
+        let peer = match location.peer(){
+            Ok(peer) => peer,
+            Err(_) =>{
+                // PD knows no leader while the region holds an election,
+                // answer like TiKV would so the request is retried
+                self.cache.write().unwrap().invalidate_region(&location);
+                let mut err = errorpb::Error::new();
+                err.set_message(format!("region {} has no leader", location.id()));
+                err.mut_not_leader().set_region_id(location.id());
+                return Err(Error::RegionError(Box::new(err)));
+            },
+        };
         // 从peer获取store id
         let store_id = peer.get_store_id();
         // 获取store
-        let store = self.load_store(store_id);
+        let store = self.load_store(store_id)?;
         // 把region和store都返回
         let region_contex = RegionContext{
             region: location,
             store,
         };
-        self.kv_client(region_contex).unwrap()
+        self.kv_client(region_contex)
     }
 
-    fn get_raw_context(&self, key: &Key, cf: Option<String>) -> RawContext {
+    fn get_raw_context(&self, key: &Key, cf: Option<String>) -> Result<RawContext> {
         //获取raw contxt
-        let (region, client) = self.get_region_context(key);
-        RawContext::new(region, client, cf)
+        let (region, client) = self.get_region_context(key)?;
+        Ok(RawContext::new(region, client, cf))
+    }
+
+    fn get_end_raw_context(&self, key: &Key, cf: Option<String>) -> Result<RawContext> {
+        let (region, client) = self.region_context(self.locate_end_key(key)?)?;
+        Ok(RawContext::new(region, client, cf))
     }
 
+    // TiKV reads an empty value back as a missing key. Records never are
+    // empty: strings are the payload of their meta record and empty list
+    // elements or hash values keep their flag byte.
     pub fn tikv_raw_put(&self, key: Key, value: Value, cf: Option<String>) -> Result<()> {
         if value.is_empty() {
             Err(Error::OperationError("No Value".to_string()))
         } else {
-            let context = self.get_raw_context(&key, cf);
             //println!("put {} {}", String::from_utf8(key.clone()).unwrap(), String::from_utf8(value.clone()).unwrap());
-            context.client().raw_put(context, key, value);
-            Ok(())
+            let for_cas = codec::is_cas_key(&key);
+            self.request(&key, &cf, |context| context.client().raw_put(context, key.clone(), value.clone(), for_cas))
         }
     }
 
-    pub fn tikv_raw_get(&self, key: Key, cf: Option<String>)-> Option<Value>{
-        let context = self.get_raw_context(&key, cf);
+    pub fn tikv_raw_get(&self, key: Key, cf: Option<String>)-> Result<Option<Value>>{
         //println!("get {}", String::from_utf8(key.clone()).unwrap());
-        let v = context.client().raw_get(context, key);
-        if v.is_empty(){
-            println!("get failed");
-            None
-        }else{
-            Some(v)
+        self.request(&key, &cf, |context| context.client().raw_get(context, key.clone()))
+    }
+
+    // A swap whose response was lost may have happened, so unlike other
+    // requests it is only resent after region errors, which reject it.
+    pub fn tikv_raw_compare_and_swap(
+        &self,
+        key: Key,
+        previous: Option<Value>,
+        value: Value,
+        cf: Option<String>,
+    ) -> Result<(bool, Option<Value>)> {
+        self.request(&key, &cf, |context| {
+            context.client()
+                .raw_compare_and_swap(context, key.clone(), previous.clone(), value.clone())
+                .map_err(|err| match err {
+                    Error::GrpcError(e) => Error::TiKVError(format!("compare and swap of unknown outcome: {}", e)),
+                    err => err,
+                })
+        })
+    }
+
+    pub fn tikv_raw_delete(&self, key: Key, cf: Option<String>) -> Result<()> {
+        let for_cas = codec::is_cas_key(&key);
+        self.request(&key, &cf, |context| context.client().raw_delete(context, key.clone(), for_cas))
+    }
+
+    // a delete range request must stay inside one region, so the range is
+    // cut at region boundaries and sent region by region. It has no atomic
+    // mode and must not cover keys written with compare-and-swap.
+    pub fn tikv_raw_delete_range(&self, start_key: Key, end_key: Key, cf: Option<String>) -> Result<()> {
+        let mut start_key = start_key;
+        while start_key < end_key {
+            let batch_end = self.request(&start_key, &cf, |context| {
+                let region_end = context.region().end_key();
+                let batch_end = if region_end.is_empty() || region_end > end_key {
+                    end_key.clone()
+                } else {
+                    region_end
+                };
+                context.client().raw_delete_range(context, start_key.clone(), batch_end.clone())?;
+                Ok(batch_end)
+            })?;
+            start_key = batch_end;
         }
+        Ok(())
+    }
+
+    // Returns up to `limit` pairs in `[start_key, end_key)`, an empty end key
+    // meaning no upper bound. `key_only` leaves the values empty.
+    pub fn tikv_raw_scan(&self, start_key: Key, end_key: Key, limit: u32, key_only: bool, cf: Option<String>)
+        -> Result<Vec<(Key, Value)>> {
+        self.scan_regions(start_key, end_key, limit, false, cf, |context, start, end, limit| {
+            context.client().raw_scan(context, start, end, limit, key_only, false)
+        })
     }
+
+    // Like `tikv_raw_scan` from the last pair of the range down.
+    pub fn tikv_raw_reverse_scan(&self, start_key: Key, end_key: Key, limit: u32, key_only: bool, cf: Option<String>)
+        -> Result<Vec<(Key, Value)>> {
+        self.scan_regions(start_key, end_key, limit, true, cf, |context, start, end, limit| {
+            context.client().raw_scan(context, start, end, limit, key_only, true)
+        })
+    }
+
+    // Reads up to `limit` pairs of `[start_key, end_key)` with `scan`, which
+    // gets the part of the range inside one region. Each region is scanned
+    // in turn, continuing from its end key, or from its start key walking
+    // backwards from the one holding the keys right below `end_key` when
+    // `reverse`, until the limit is reached or the range is exhausted.
+    pub(super) fn scan_regions<F>(&self, start_key: Key, end_key: Key, limit: u32, reverse: bool, cf: Option<String>,
+        scan: F) -> Result<Vec<(Key, Value)>>
+    where F: Fn(RawContext, Key, Key, u32) -> Result<Vec<(Key, Value)>> {
+        let (mut start_key, mut end_key) = (start_key, end_key);
+        let mut kvs = Vec::new();
+        while (kvs.len() as u32) < limit && (end_key.is_empty() || start_key < end_key) {
+            let limit = limit - kvs.len() as u32;
+            if reverse {
+                let locate = || self.get_end_raw_context(&end_key, cf.clone());
+                let (batch, batch_start) = self.request_at(locate, |context| {
+                    let region_start = context.region().start_key();
+                    let batch_start = if region_start < start_key {
+                        start_key.clone()
+                    } else {
+                        region_start
+                    };
+                    let batch = scan(context, batch_start.clone(), end_key.clone(), limit)?;
+                    Ok((batch, batch_start))
+                })?;
+                kvs.extend(batch);
+                if batch_start == start_key {
+                    break;
+                }
+                end_key = batch_start;
+            } else {
+                let (batch, batch_end) = self.request(&start_key, &cf, |context| {
+                    let region_end = context.region().end_key();
+                    let batch_end = if region_end.is_empty() || (!end_key.is_empty() && region_end > end_key) {
+                        end_key.clone()
+                    } else {
+                        region_end
+                    };
+                    let batch = scan(context, start_key.clone(), batch_end.clone(), limit)?;
+                    Ok((batch, batch_end))
+                })?;
+                kvs.extend(batch);
+                if batch_end.is_empty() {
+                    break;
+                }
+                start_key = batch_end;
+            }
+        }
+        Ok(kvs)
+    }
+
+    // Values of `keys` in the same order, `None` where a key is missing.
+    // Keys are grouped by region and every region is read with a single
+    // RawBatchGet, all regions at once.
+    pub fn tikv_raw_batch_get(&self, keys: Vec<Key>, cf: Option<String>) -> Result<Vec<Option<Value>>> {
+        let batches = self.batch_request(keys.clone(), |key| key, cf,
+            |context, keys| context.client().raw_batch_get(context, keys))?;
+        let found: HashMap<Key, Value> = batches.into_iter().flatten().collect();
+        Ok(keys.iter().map(|key| found.get(key).cloned()).collect())
+    }
+
+    // One RawBatchPut per region, all regions at once. A batch holding a
+    // key written with compare-and-swap goes in the atomic mode as a whole.
+    pub fn tikv_raw_batch_put(&self, pairs: Vec<(Key, Value)>, cf: Option<String>) -> Result<()> {
+        self.batch_request(pairs, |(key, _)| key, cf, |context, pairs| {
+            let for_cas = pairs.iter().any(|(key, _)| codec::is_cas_key(key));
+            context.client().raw_batch_put(context, pairs, for_cas)
+        })?;
+        Ok(())
+    }
+
+    // Sends `items` with `f`, one request per region and all regions at
+    // once. The items of regions failing with a retryable error are grouped
+    // again and resent, the results of the others are kept.
+    pub(super) fn batch_request<T, R, K, F>(&self, items: Vec<T>, key_of: K, cf: Option<String>, f: F) -> Result<Vec<R>>
+    where T: Clone + Send + 'static, R: Send + 'static, K: Fn(&T) -> &Key,
+          F: Fn(RawContext, Vec<T>) -> Result<R> + Send + Sync + 'static {
+        let f = Arc::new(f);
+        let mut backoff = Backoff::new();
+        let mut pending = items;
+        let mut results = Vec::new();
+        loop {
+            let groups = match self.group_by_region(&pending, &key_of, cf.clone()) {
+                Ok(groups) => groups,
+                Err(err) => {
+                    backoff.wait(err)?;
+                    continue;
+                }
+            };
+            let mut failed = Vec::new();
+            let mut last_err = None;
+            for (region, items, result) in run_parallel(groups, Arc::clone(&f)) {
+                match result {
+                    Ok(value) => results.push(value),
+                    Err(err) => {
+                        self.on_error(&region, &err);
+                        if !err.is_retryable() {
+                            return Err(err);
+                        }
+                        failed.extend(items);
+                        last_err = Some(err);
+                    }
+                }
+            }
+            match last_err {
+                Some(err) => backoff.wait(err)?,
+                None => return Ok(results),
+            }
+            pending = failed;
+        }
+    }
+
+    // Splits `items` by the region holding their key, locating each region once.
+    fn group_by_region<T, F>(&self, items: &[T], key_of: F, cf: Option<String>) -> Result<Vec<(RawContext, Vec<T>)>>
+    where T: Clone, F: Fn(&T) -> &Key {
+        let mut groups: Vec<(RawContext, Vec<T>)> = Vec::new();
+        for item in items {
+            let found = groups.iter().position(|(context, _)| context.region().region.contains(key_of(item)));
+            match found {
+                Some(i) => groups[i].1.push(item.clone()),
+                None => {
+                    let context = self.get_raw_context(key_of(item), cf.clone())?;
+                    groups.push((context, vec![item.clone()]));
+                }
+            }
+        }
+        Ok(groups)
+    }
+
+    // Ids are unique across every server sharing the cluster: each one
+    // reserves a batch by bumping a counter with compare-and-swap, or in a
+    // transaction of its own in txn mode. A command rolled back must not
+    // hand the ids out again.
+    fn alloc_id(&self) -> Result<u64> {
+        let mut ids = self.ids.lock().unwrap();
+        let key = codec::ID_ALLOC_KEY.to_vec();
+        while ids.0 == ids.1 {
+            let reserved = match self.mode {
+                Mode::Raw => {
+                    let current = self.tikv_raw_get(key.clone(), None)?;
+                    let (start, end) = next_ids(&current)?;
+                    let (succeed, _) = self.tikv_raw_compare_and_swap(
+                        key.clone(), current, end.to_be_bytes().to_vec(), None)?;
+                    if succeed { Some((start, end)) } else { None }
+                }
+                Mode::Txn => {
+                    let mut txn = Transaction::begin(self)?;
+                    let current = txn.get(self, key.clone())?;
+                    let (start, end) = next_ids(&current)?;
+                    txn.put(key.clone(), end.to_be_bytes().to_vec());
+                    match txn.commit(self) {
+                        Ok(()) => Some((start, end)),
+                        Err(ref err) if err.is_conflict() => None,
+                        Err(err) => return Err(err),
+                    }
+                }
+            };
+            if let Some(reserved) = reserved {
+                *ids = reserved;
+            }
+        }
+        let id = ids.0;
+        ids.0 += 1;
+        Ok(id)
+    }
+
+    // Runs `f` in the transaction the calling thread opened with
+    // `begin_transaction`, or in one of its own committed right away.
+    fn in_txn<R, F>(&self, f: F) -> Result<R>
+    where F: FnOnce(&mut Transaction) -> Result<R> {
+        let id = thread::current().id();
+        // taken out while in use, only this thread ever looks it up
+        let open = self.txns.lock().unwrap().remove(&id);
+        match open {
+            Some(mut txn) => {
+                let result = f(&mut txn);
+                // the command stopped halfway, its earlier writes must not
+                // commit either
+                if let Err(ref err) = result {
+                    txn.fail(err);
+                }
+                self.txns.lock().unwrap().insert(id, txn);
+                result
+            }
+            None => {
+                let mut txn = Transaction::begin(self)?;
+                let result = f(&mut txn)?;
+                txn.commit(self)?;
+                Ok(result)
+            }
+        }
+    }
+
+    // The storage of the DB commands, RawKV or transactional by `mode`.
+
+    // Makes the open transaction conflict with any other one writing `key`,
+    // even when it only reads it. Without a transaction there is nothing to
+    // guard.
+    fn kv_lock(&self, key: Key) {
+        if self.mode == Mode::Txn {
+            if let Some(txn) = self.txns.lock().unwrap().get_mut(&thread::current().id()) {
+                txn.lock(key);
+            }
+        }
+    }
+
+    fn kv_get(&self, key: Key) -> Result<Option<Value>> {
+        match self.mode {
+            Mode::Raw => self.tikv_raw_get(key, None),
+            Mode::Txn => self.in_txn(|txn| txn.get(self, key)),
+        }
+    }
+
+    fn kv_put(&self, key: Key, value: Value) -> Result<()> {
+        match self.mode {
+            Mode::Raw => self.tikv_raw_put(key, value, None),
+            Mode::Txn => self.in_txn(|txn| {
+                txn.put(key, value);
+                Ok(())
+            }),
+        }
+    }
+
+    fn kv_compare_and_swap(&self, key: Key, previous: Option<Value>, value: Value) -> Result<(bool, Option<Value>)> {
+        match self.mode {
+            Mode::Raw => self.tikv_raw_compare_and_swap(key, previous, value, None),
+            Mode::Txn => self.in_txn(|txn| txn.compare_and_swap(self, key, previous, value)),
+        }
+    }
+
+    fn kv_delete(&self, key: Key) -> Result<()> {
+        match self.mode {
+            Mode::Raw => self.tikv_raw_delete(key, None),
+            Mode::Txn => self.in_txn(|txn| {
+                txn.delete(key);
+                Ok(())
+            }),
+        }
+    }
+
+    fn kv_delete_range(&self, start_key: Key, end_key: Key) -> Result<()> {
+        match self.mode {
+            Mode::Raw => self.tikv_raw_delete_range(start_key, end_key, None),
+            Mode::Txn => self.in_txn(|txn| txn.delete_range(self, start_key, end_key)),
+        }
+    }
+
+    fn kv_scan(&self, start_key: Key, end_key: Key, limit: u32, key_only: bool) -> Result<Vec<(Key, Value)>> {
+        match self.mode {
+            Mode::Raw => self.tikv_raw_scan(start_key, end_key, limit, key_only, None),
+            Mode::Txn => self.in_txn(|txn| txn.scan(self, start_key, end_key, limit, key_only, false)),
+        }
+    }
+
+    fn kv_reverse_scan(&self, start_key: Key, end_key: Key, limit: u32, key_only: bool) -> Result<Vec<(Key, Value)>> {
+        match self.mode {
+            Mode::Raw => self.tikv_raw_reverse_scan(start_key, end_key, limit, key_only, None),
+            Mode::Txn => self.in_txn(|txn| txn.scan(self, start_key, end_key, limit, key_only, true)),
+        }
+    }
+
+    fn kv_batch_get(&self, keys: Vec<Key>) -> Result<Vec<Option<Value>>> {
+        match self.mode {
+            Mode::Raw => self.tikv_raw_batch_get(keys, None),
+            Mode::Txn => self.in_txn(|txn| txn.batch_get(self, keys)),
+        }
+    }
+
+    fn kv_batch_put(&self, pairs: Vec<(Key, Value)>) -> Result<()> {
+        match self.mode {
+            Mode::Raw => self.tikv_raw_batch_put(pairs, None),
+            Mode::Txn => self.in_txn(|txn| {
+                for (key, value) in pairs {
+                    txn.put(key, value);
+                }
+                Ok(())
+            }),
+        }
+    }
+}
+
+// the batch of ids following the counter value `current`
+fn next_ids(current: &Option<Value>) -> Result<(u64, u64)> {
+    let start = match current {
+        Some(raw) => codec::read_u64(raw, 0)?,
+        None => 1,
+    };
+    Ok((start, start + ID_ALLOC_BATCH))
+}
+
+// Runs `f` on every region group, the first one on the calling thread and
+// the others on a thread each. Every result comes back with the region and
+// the items it was sent with, in no particular order.
+fn run_parallel<T, R, F>(groups: Vec<(RawContext, Vec<T>)>, f: Arc<F>) -> Vec<(Region, Vec<T>, Result<R>)>
+where T: Clone + Send + 'static, R: Send + 'static, F: Fn(RawContext, Vec<T>) -> Result<R> + Send + Sync + 'static {
+    let mut groups = groups.into_iter();
+    let first = groups.next();
+    let handles: Vec<_> = groups.map(|(context, items)| {
+        let f = Arc::clone(&f);
+        let region = context.region().region.clone();
+        (region, thread::spawn(move || {
+            let result = f(context, items.clone());
+            (items, result)
+        }))
+    }).collect();
+    let mut results = Vec::with_capacity(handles.len() + 1);
+    if let Some((context, items)) = first {
+        let region = context.region().region.clone();
+        let result = f(context, items.clone());
+        results.push((region, items, result));
+    }
+    for (region, handle) in handles {
+        match handle.join() {
+            Ok((items, result)) => results.push((region, items, result)),
+            Err(_) => results.push((region, Vec::new(), Err(Error::Other))),
+        }
+    }
+    results
 }
 
 use crate::redis_server::DBError;
 
+impl From<Error> for DBError{
+    fn from(e: Error) -> DBError{
+        match e{
+            Error::Other => DBError::Other,
+            e => DBError::Storage(e.to_string()),
+        }
+    }
+}
+
+impl TikvDB{
+    fn get_meta(&self, key: &[u8]) -> result::Result<MetaValue, DBError>{
+        let raw = match self.kv_get(codec::encode_meta_key(key))?{
+            Some(raw) => raw,
+            None => return Err(DBError::NotFound),
+        };
+        let meta = MetaValue::decode(raw).map_err(|_| DBError::Other)?;
+        if meta.is_expired(now_millis()){
+            return Err(DBError::NotFound);
+        }
+        Ok(meta)
+    }
+
+    // Like `get_meta` for write paths: a dead key found here is dropped with
+    // its elements, so the name can be reused right away. The meta is locked
+    // in txn mode, writes touching only the elements of a key still conflict
+    // on it.
+    fn load_meta(&self, key: &[u8]) -> result::Result<Option<MetaValue>, DBError>{
+        self.kv_lock(codec::encode_meta_key(key));
+        let raw = match self.kv_get(codec::encode_meta_key(key))?{
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let meta = MetaValue::decode(raw)?;
+        if meta.is_expired(now_millis()){
+            self.kv_delete(codec::encode_meta_key(key))?;
+            self.drop_elements(&meta)?;
+            return Ok(None);
+        }
+        Ok(Some(meta))
+    }
+
+    // called once the meta no longer points at the elements, a failure in
+    // between leaves unreachable records rather than a broken key
+    fn drop_elements(&self, meta: &MetaValue) -> result::Result<(), DBError>{
+        if let Some(id) = meta.collection_id(){
+            let (start, end) = codec::data_key_range(id);
+            self.kv_delete_range(start, end)?;
+        }
+        Ok(())
+    }
+
+    fn get_list(&self, key: &[u8]) -> result::Result<Option<ListMeta>, DBError>{
+        match self.get_meta(key){
+            Ok(ref meta) if meta.data_type != KeyType::List => Err(DBError::WrongType),
+            Ok(meta) => Ok(Some(ListMeta::decode(&meta.payload)?)),
+            Err(DBError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn load_list(&self, key: &[u8]) -> result::Result<Loaded<ListMeta>, DBError>{
+        match self.load_meta(key)?{
+            Some(ref meta) if meta.data_type != KeyType::List => Err(DBError::WrongType),
+            Some(meta) => Ok(Some((ListMeta::decode(&meta.payload)?, meta.header()))),
+            None => Ok(None),
+        }
+    }
+
+    fn save_list(&self, key: &[u8], list: &ListMeta, header: MetaHeader) -> result::Result<(), DBError>{
+        let meta_key = codec::encode_meta_key(key);
+        if list.is_empty(){
+            self.kv_delete(meta_key)?;
+        }else{
+            let meta = MetaValue::written(KeyType::List, header, list.encode());
+            self.kv_put(meta_key, meta.encode())?;
+        }
+        Ok(())
+    }
+
+    // hashes and sets
+    fn get_collection(&self, key: &[u8], data_type: KeyType) -> result::Result<Option<CollectionMeta>, DBError>{
+        match self.get_meta(key){
+            Ok(ref meta) if meta.data_type != data_type => Err(DBError::WrongType),
+            Ok(meta) => Ok(Some(CollectionMeta::decode(&meta.payload)?)),
+            Err(DBError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn load_collection(&self, key: &[u8], data_type: KeyType) -> result::Result<Loaded<CollectionMeta>, DBError>{
+        match self.load_meta(key)?{
+            Some(ref meta) if meta.data_type != data_type => Err(DBError::WrongType),
+            Some(meta) => Ok(Some((CollectionMeta::decode(&meta.payload)?, meta.header()))),
+            None => Ok(None),
+        }
+    }
+
+    // an emptied collection loses its meta, its elements are already gone
+    fn save_collection(&self, key: &[u8], data_type: KeyType, collection: &CollectionMeta, header: MetaHeader)
+        -> result::Result<(), DBError>{
+        let meta_key = codec::encode_meta_key(key);
+        if collection.len == 0{
+            self.kv_delete(meta_key)?;
+        }else{
+            let meta = MetaValue::written(data_type, header, collection.encode());
+            self.kv_put(meta_key, meta.encode())?;
+        }
+        Ok(())
+    }
+
+    fn get_stream(&self, key: &[u8]) -> result::Result<Option<StreamMeta>, DBError>{
+        match self.get_meta(key){
+            Ok(ref meta) if meta.data_type != KeyType::Stream => Err(DBError::WrongType),
+            Ok(meta) => Ok(Some(StreamMeta::decode(&meta.payload)?)),
+            Err(DBError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn load_stream(&self, key: &[u8]) -> result::Result<Loaded<StreamMeta>, DBError>{
+        match self.load_meta(key)?{
+            Some(ref meta) if meta.data_type != KeyType::Stream => Err(DBError::WrongType),
+            Some(meta) => Ok(Some((StreamMeta::decode(&meta.payload)?, meta.header()))),
+            None => Ok(None),
+        }
+    }
+
+    // a stream keeps its meta without entries, for the last id
+    fn save_stream(&self, key: &[u8], stream: &StreamMeta, header: MetaHeader) -> result::Result<(), DBError>{
+        let meta = MetaValue::written(KeyType::Stream, header, stream.encode());
+        self.kv_put(codec::encode_meta_key(key), meta.encode())?;
+        Ok(())
+    }
+
+    // the stream holding the group with the last id of the group
+    fn get_group(&self, key: &[u8], group: &[u8]) -> result::Result<(StreamMeta, StreamId), DBError>{
+        let stream = self.get_stream(key)?.ok_or(DBError::NotFound)?;
+        match self.kv_get(stream.group_key(group))?{
+            Some(raw) => Ok((stream, codec::decode_stream_id(&raw)?)),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    // Like `get_group` for write paths, the stream meta gets locked. Group
+    // writes save it back with the header, counting the write.
+    fn load_group(&self, key: &[u8], group: &[u8]) -> result::Result<(StreamMeta, MetaHeader), DBError>{
+        let (stream, header) = self.load_stream(key)?.ok_or(DBError::NotFound)?;
+        match self.kv_get(stream.group_key(group))?{
+            Some(_) => Ok((stream, header)),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn decode_pending(kvs: Vec<(Key, Value)>) -> result::Result<Vec<PendingEntry>, DBError>{
+        kvs.into_iter().map(|(pending_key, raw)| Ok(codec::decode_pending(&pending_key, &raw)?)).collect()
+    }
+
+    fn decode_entries(kvs: Vec<(Key, Value)>) -> result::Result<Vec<StreamEntry>, DBError>{
+        kvs.into_iter().map(|(entry_key, raw)| {
+            Ok((codec::decode_stream_entry_key(&entry_key)?, codec::decode_stream_fields(&raw)?))
+        }).collect()
+    }
+
+    // Up to `count` elements of the collection `id` whose sub key is greater
+    // than `after`, with empty values for `key_only`.
+    fn scan_elements(&self, id: u64, after: Option<&[u8]>, count: usize, key_only: bool)
+        -> result::Result<Vec<FieldValue>, DBError>{
+        let (mut start, end) = codec::data_key_range(id);
+        if let Some(after) = after{
+            start = codec::encode_data_key(id, after);
+            start.push(0);
+        }
+        let kvs = self.scan_range(start, end, count, key_only, false)?;
+        Ok(kvs.into_iter().map(|(key, value)| (codec::decode_data_key(&key).to_vec(), value)).collect())
+    }
+
+    // Up to `count` records in `[start, end)`, read page by page so `count`
+    // may be unbounded. `reverse` reads from the end of the range down.
+    fn scan_range(&self, mut start: Key, mut end: Key, count: usize, key_only: bool, reverse: bool)
+        -> result::Result<Vec<(Key, Value)>, DBError>{
+        let mut kvs = Vec::new();
+        while kvs.len() < count{
+            let limit = (count - kvs.len()).min(SCAN_BATCH);
+            let page = if reverse{
+                self.kv_reverse_scan(start.clone(), end.clone(), limit as u32, key_only)?
+            }else{
+                self.kv_scan(start.clone(), end.clone(), limit as u32, key_only)?
+            };
+            let done = page.len() < limit;
+            if let Some((last, _)) = page.last(){
+                if reverse{
+                    end = last.clone();
+                }else{
+                    start = last.clone();
+                    start.push(0);
+                }
+            }
+            kvs.extend(page);
+            if done{
+                break;
+            }
+        }
+        Ok(kvs)
+    }
+
+    // members of a sorted set from its score index records
+    fn decode_scored(kvs: Vec<(Key, Value)>) -> result::Result<Vec<ScoredMember>, DBError>{
+        kvs.into_iter().map(|(score_key, _)| {
+            let (ordered, member) = codec::decode_zset_score_key(&score_key)?;
+            Ok((member, ordered_to_score(ordered)))
+        }).collect()
+    }
+
+    fn get_ordered_score(&self, id: u64, member: &[u8]) -> result::Result<Option<u64>, DBError>{
+        match self.kv_get(codec::zset_member_key(id, member))?{
+            Some(raw) => Ok(Some(codec::read_u64(&raw, 0)?)),
+            None => Ok(None),
+        }
+    }
+
+    // `start` and `stop` are inclusive offsets from the head. Element keys
+    // follow the list order, so this is a single range scan.
+    fn list_elements(&self, list: &ListMeta, start: u64, stop: u64) -> result::Result<Vec<Vec<u8>>, DBError>{
+        let first = list.element_key(list.head + start);
+        let end = list.element_key(list.head + stop + 1);
+        let count = (stop - start + 1) as usize;
+        let kvs = self.scan_range(first, end, count, false, false)?;
+        // every index between head and tail holds an element
+        if kvs.len() != count{
+            return Err(DBError::Other);
+        }
+        kvs.into_iter().map(|(_, value)| Ok(codec::decode_element_value(value)?)).collect()
+    }
+
+    // Writes `new`, the edited elements of `list`, over `old`. Only the
+    // indexes whose element changes are written, keeping in place whichever
+    // end of the list leaves less to move. Indexes left past the new ends
+    // are dropped after the meta.
+    fn splice_list(&self, key: &[u8], list: &ListMeta, header: MetaHeader, old: &[Vec<u8>], new: Vec<Vec<u8>>)
+        -> result::Result<(), DBError>{
+        let (head, tail, written) = splice_bounds(list.head, list.tail, old, &new);
+        let spliced = ListMeta{ id: list.id, head, tail };
+        for (offset, element) in new.into_iter().enumerate().take(written.end).skip(written.start){
+            self.kv_put(spliced.element_key(head + offset as u64), codec::encode_element_value(&element))?;
+        }
+        self.save_list(key, &spliced, header)?;
+        if spliced.is_empty(){
+            let (start, end) = codec::data_key_range(list.id);
+            self.kv_delete_range(start, end)?;
+            return Ok(());
+        }
+        if head > list.head{
+            self.kv_delete_range(list.element_key(list.head), list.element_key(head))?;
+        }
+        if tail < list.tail{
+            self.kv_delete_range(list.element_key(tail), list.element_key(list.tail))?;
+        }
+        Ok(())
+    }
+}
+
+// New head and tail of a list at `[head, tail)` holding `old` once it holds
+// `new`, with the offsets of `new` to write. Elements equal at the same
+// offset from the kept end are not written again.
+fn splice_bounds(head: u64, tail: u64, old: &[Vec<u8>], new: &[Vec<u8>]) -> (u64, u64, Range<usize>){
+    let same_front = old.iter().zip(new).take_while(|(old, new)| old == new).count();
+    let same_back = old.iter().rev().zip(new.iter().rev()).take_while(|(old, new)| old == new).count();
+    let len = new.len();
+    if len - same_front <= len - same_back{
+        (head, head + len as u64, same_front..len)
+    }else{
+        (tail - len as u64, tail, 0..len - same_back)
+    }
+}
+
 impl DB for TikvDB{
-    fn raw_put(&mut self, key: String, value: String) -> result::Result<String, DBError>{
-        if self.tikv_raw_put(key.into_bytes(), value.into_bytes(), None).is_ok(){
-            Ok("OK".to_string())
+    fn raw_put_with_expire(&mut self, key: Vec<u8>, value: Vec<u8>, expire_at: Option<u64>) -> result::Result<(), DBError>{
+        // SET replaces a value of any type, the elements of a collection go with it
+        let old = self.load_meta(&key)?;
+        let writes = old.as_ref().map_or(0, |old| old.writes);
+        let meta = MetaValue::written(KeyType::String, MetaHeader{ expire_at, writes }, value);
+        self.kv_put(codec::encode_meta_key(&key), meta.encode())?;
+        if let Some(old) = old{
+            self.drop_elements(&old)?;
+        }
+        Ok(())
+    }
+
+    fn raw_get(&self, key: Vec<u8>) -> result::Result<Vec<u8>, DBError>{
+        self.raw_get_with_expire(key).map(|(value, _)| value)
+    }
+
+    fn batch_get(&self, keys: Vec<Vec<u8>>) -> result::Result<Vec<Option<Vec<u8>>>, DBError>{
+        let meta_keys = keys.iter().map(|key| codec::encode_meta_key(key)).collect();
+        let now = now_millis();
+        self.kv_batch_get(meta_keys)?.into_iter().map(|raw| {
+            match raw{
+                Some(raw) =>{
+                    let meta = MetaValue::decode(raw)?;
+                    let live = meta.data_type == KeyType::String && !meta.is_expired(now);
+                    Ok(if live{ Some(meta.payload) }else{ None })
+                }
+                None => Ok(None),
+            }
+        }).collect()
+    }
+
+    // Reads the old metas and writes the new ones with one batch each, then
+    // drops the elements of replaced collections like SET does.
+    fn batch_put(&mut self, kvs: Vec<(Vec<u8>, Vec<u8>)>) -> result::Result<(), DBError>{
+        // a repeated key keeps its last value
+        let kvs: HashMap<Vec<u8>, Vec<u8>> = kvs.into_iter().collect();
+        let (meta_keys, values): (Vec<Key>, Vec<Value>) = kvs.into_iter()
+            .map(|(key, value)| (codec::encode_meta_key(&key), value))
+            .unzip();
+        let old = self.kv_batch_get(meta_keys.clone())?.into_iter()
+            .map(|raw| raw.map(MetaValue::decode).transpose())
+            .collect::<Result<Vec<Option<MetaValue>>>>()?;
+        let metas = meta_keys.into_iter().zip(values).zip(&old)
+            .map(|((meta_key, value), old)| {
+                let writes = old.as_ref().map_or(0, |old| old.writes);
+                (meta_key, MetaValue::written(KeyType::String, MetaHeader{ expire_at: None, writes }, value).encode())
+            })
+            .collect();
+        self.kv_batch_put(metas)?;
+        for old in old.into_iter().flatten(){
+            self.drop_elements(&old)?;
+        }
+        Ok(())
+    }
+
+    fn get_expire(&self, key: Vec<u8>) -> result::Result<Option<u64>, DBError>{
+        self.get_meta(&key).map(|meta| meta.expire_at)
+    }
+
+    fn raw_get_with_expire(&self, key: Vec<u8>) -> result::Result<(Vec<u8>, Option<u64>), DBError>{
+        let meta = self.get_meta(&key)?;
+        if meta.data_type != KeyType::String{
+            return Err(DBError::WrongType);
+        }
+        Ok((meta.payload, meta.expire_at))
+    }
+
+    fn raw_compare_and_swap(&mut self, key: Vec<u8>, expected: Option<(Vec<u8>, Option<u64>)>,
+                            value: Vec<u8>, expire_at: Option<u64>) -> result::Result<bool, DBError>{
+        // The swap compares the whole encoded meta value, write count
+        // included, which the caller did not see. So the record is read
+        // again and swapped only when it still holds `expected`.
+        let meta_key = codec::encode_meta_key(&key);
+        let current = self.kv_get(meta_key.clone())?;
+        let old = match current{
+            Some(ref raw) => Some(MetaValue::decode(raw.clone())?),
+            None => None,
+        };
+        // a dead key that was not reclaimed yet still counts as missing
+        let dead = old.as_ref().is_some_and(|old| old.is_expired(now_millis()));
+        let unchanged = match (old.as_ref().filter(|_| !dead), expected){
+            (None, None) => true,
+            (Some(old), Some((expected_value, expected_expire))) =>{
+                old.data_type == KeyType::String && old.payload == expected_value && old.expire_at == expected_expire
+            }
+            _ => false,
+        };
+        if !unchanged{
+            return Ok(false);
+        }
+        let writes = old.as_ref().map_or(0, |old| old.writes);
+        let new = MetaValue::written(KeyType::String, MetaHeader{ expire_at, writes }, value).encode();
+        let (succeed, _) = self.kv_compare_and_swap(meta_key, current, new)?;
+        if let Some(old) = old.filter(|_| succeed && dead){
+            self.drop_elements(&old)?;
+        }
+        Ok(succeed)
+    }
+
+    fn set_expire(&mut self, key: Vec<u8>, expire_at: Option<u64>) -> result::Result<bool, DBError>{
+        let meta = match self.get_meta(&key){
+            Ok(meta) => meta,
+            Err(DBError::NotFound) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let meta = MetaValue::written(meta.data_type, MetaHeader{ expire_at, writes: meta.writes }, meta.payload);
+        self.kv_put(codec::encode_meta_key(&key), meta.encode())
+            .map(|_| true)
+            .map_err(|_| DBError::Other)
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> result::Result<bool, DBError>{
+        let meta = match self.load_meta(&key)?{
+            Some(meta) => meta,
+            None => return Ok(false),
+        };
+        self.kv_delete(codec::encode_meta_key(&key))?;
+        self.drop_elements(&meta)?;
+        Ok(true)
+    }
+
+    fn exists(&self, key: Vec<u8>) -> result::Result<bool, DBError>{
+        match self.get_meta(&key){
+            Ok(_) => Ok(true),
+            Err(DBError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn key_type(&self, key: Vec<u8>) -> result::Result<KeyType, DBError>{
+        self.get_meta(&key).map(|meta| meta.data_type)
+    }
+
+    // Walks the meta records, which hold every key with its type and expiry.
+    // Expired ones are skipped, so the scan continues until `count` live keys
+    // are found or the range ends.
+    fn scan_keys(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, count: usize)
+        -> result::Result<Vec<(Vec<u8>, KeyType)>, DBError>{
+        let mut start_key = match start{
+            Bound::Included(key) => codec::encode_meta_key(&key),
+            Bound::Excluded(key) =>{
+                let mut start_key = codec::encode_meta_key(&key);
+                start_key.push(0);
+                start_key
+            }
+            Bound::Unbounded => vec![codec::META_PREFIX],
+        };
+        let end_key = match end{
+            Bound::Included(key) =>{
+                let mut end_key = codec::encode_meta_key(&key);
+                end_key.push(0);
+                end_key
+            }
+            Bound::Excluded(key) => codec::encode_meta_key(&key),
+            Bound::Unbounded => vec![codec::META_PREFIX + 1],
+        };
+        let now = now_millis();
+        let mut keys = Vec::new();
+        while keys.len() < count{
+            let wanted = count - keys.len();
+            let metas = self.scan_range(start_key.clone(), end_key.clone(), wanted, false, false)?;
+            let done = metas.len() < wanted;
+            if let Some((last, _)) = metas.last(){
+                start_key = last.clone();
+                start_key.push(0);
+            }
+            for (meta_key, raw) in metas{
+                let meta = MetaValue::decode(raw)?;
+                if !meta.is_expired(now){
+                    keys.push((codec::decode_meta_key(&meta_key).to_vec(), meta.data_type));
+                }
+            }
+            if done{
+                break;
+            }
+        }
+        Ok(keys)
+    }
+
+    fn rename(&mut self, key: Vec<u8>, new_key: Vec<u8>) -> result::Result<(), DBError>{
+        // collections keep their id, only the meta record moves
+        let meta = self.get_meta(&key)?;
+        let replaced = self.load_meta(&new_key)?;
+        // counted past the replaced key, whose WATCH sees a change
+        let writes = meta.writes.max(replaced.as_ref().map_or(0, |replaced| replaced.writes));
+        let moved = MetaValue::written(meta.data_type, MetaHeader{ expire_at: meta.expire_at, writes }, meta.payload);
+        self.kv_put(codec::encode_meta_key(&new_key), moved.encode())?;
+        self.kv_delete(codec::encode_meta_key(&key))?;
+        if let Some(replaced) = replaced{
+            self.drop_elements(&replaced)?;
+        }
+        Ok(())
+    }
+
+    fn list_push(&mut self, key: Vec<u8>, values: Vec<Vec<u8>>, left: bool, only_existing: bool) -> result::Result<usize, DBError>{
+        let (mut list, header) = match self.load_list(&key)?{
+            Some(list) => list,
+            None if only_existing => return Ok(0),
+            None => (ListMeta::new(self.alloc_id()?), MetaHeader::default()),
+        };
+        // elements first, the meta makes them visible
+        for value in values{
+            if left{
+                list.head -= 1;
+                self.kv_put(list.element_key(list.head), codec::encode_element_value(&value))?;
+            }else{
+                self.kv_put(list.element_key(list.tail), codec::encode_element_value(&value))?;
+                list.tail += 1;
+            }
+        }
+        self.save_list(&key, &list, header)?;
+        Ok(list.len() as usize)
+    }
+
+    fn list_pop(&mut self, key: Vec<u8>, left: bool, count: usize) -> result::Result<Vec<Vec<u8>>, DBError>{
+        let (mut list, header) = match self.load_list(&key)?{
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        let count = (count as u64).min(list.len());
+        if count == 0{
+            return Ok(Vec::new());
+        }
+        let (popped, start, end) = if left{
+            let popped = self.list_elements(&list, 0, count - 1)?;
+            let start = list.element_key(list.head);
+            list.head += count;
+            (popped, start, list.element_key(list.head))
         }else{
-            Err(DBError::Other)
+            let len = list.len();
+            let mut popped = self.list_elements(&list, len - count, len - 1)?;
+            popped.reverse();
+            let end = list.element_key(list.tail);
+            list.tail -= count;
+            (popped, list.element_key(list.tail), end)
+        };
+        self.save_list(&key, &list, header)?;
+        self.kv_delete_range(start, end)?;
+        Ok(popped)
+    }
+
+    fn list_len(&self, key: Vec<u8>) -> result::Result<usize, DBError>{
+        Ok(self.get_list(&key)?.map_or(0, |list| list.len() as usize))
+    }
+
+    fn list_range(&self, key: Vec<u8>, start: i64, stop: i64) -> result::Result<Vec<Vec<u8>>, DBError>{
+        let list = match self.get_list(&key)?{
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        match normalize_range(start, stop, list.len() as usize){
+            Some((start, stop)) => self.list_elements(&list, start as u64, stop as u64),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn list_index(&self, key: Vec<u8>, index: i64) -> result::Result<Option<Vec<u8>>, DBError>{
+        let list = match self.get_list(&key)?{
+            Some(list) => list,
+            None => return Ok(None),
+        };
+        match normalize_index(index, list.len() as usize){
+            Some(index) => {
+                let raw = self.kv_get(list.element_key(list.head + index as u64))?.ok_or(DBError::Other)?;
+                Ok(Some(codec::decode_element_value(raw)?))
+            },
+            None => Ok(None),
         }
     }
 
-    fn raw_get(&self, key: String) -> result::Result<String, DBError>{
-        if let Some(v) = self.tikv_raw_get(key.into_bytes(), None){
-            Ok(String::from_utf8(v).unwrap())
+    fn list_set(&mut self, key: Vec<u8>, index: i64, value: Vec<u8>) -> result::Result<(), DBError>{
+        let (list, header) = self.load_list(&key)?.ok_or(DBError::NotFound)?;
+        let index = normalize_index(index, list.len() as usize).ok_or(DBError::OutOfRange)?;
+        self.kv_put(list.element_key(list.head + index as u64), codec::encode_element_value(&value))?;
+        self.save_list(&key, &list, header)?;
+        Ok(())
+    }
+
+    fn list_trim(&mut self, key: Vec<u8>, start: i64, stop: i64) -> result::Result<(), DBError>{
+        let (mut list, header) = match self.load_list(&key)?{
+            Some(list) => list,
+            None => return Ok(()),
+        };
+        let (old_head, old_tail) = (list.head, list.tail);
+        match normalize_range(start, stop, list.len() as usize){
+            Some((start, stop)) =>{
+                list.tail = old_head + stop as u64 + 1;
+                list.head = old_head + start as u64;
+            },
+            None => list.tail = list.head,
+        }
+        self.save_list(&key, &list, header)?;
+        if list.is_empty(){
+            let (start, end) = codec::data_key_range(list.id);
+            self.kv_delete_range(start, end)?;
         }else{
-            Err(DBError::NotFound)
+            self.kv_delete_range(list.element_key(old_head), list.element_key(list.head))?;
+            self.kv_delete_range(list.element_key(list.tail), list.element_key(old_tail))?;
+        }
+        Ok(())
+    }
+
+    fn list_rem(&mut self, key: Vec<u8>, count: i64, value: Vec<u8>) -> result::Result<usize, DBError>{
+        let (list, header) = match self.load_list(&key)?{
+            Some(list) => list,
+            None => return Ok(0),
+        };
+        let old = self.list_elements(&list, 0, list.len() - 1)?;
+        let mut elements = old.clone();
+        let limit = if count == 0{ usize::MAX }else{ count.wrapping_abs() as u64 as usize };
+        let mut removed = 0;
+        if count < 0{
+            elements.reverse();
+        }
+        elements.retain(|element|{
+            if removed < limit && *element == value{
+                removed += 1;
+                false
+            }else{
+                true
+            }
+        });
+        if count < 0{
+            elements.reverse();
+        }
+        if removed > 0{
+            self.splice_list(&key, &list, header, &old, elements)?;
         }
+        Ok(removed)
+    }
+
+    fn list_insert(&mut self, key: Vec<u8>, before: bool, pivot: Vec<u8>, value: Vec<u8>) -> result::Result<Option<usize>, DBError>{
+        let (list, header) = match self.load_list(&key)?{
+            Some(list) => list,
+            None => return Ok(Some(0)),
+        };
+        let old = self.list_elements(&list, 0, list.len() - 1)?;
+        let pos = match old.iter().position(|element| *element == pivot){
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        let mut elements = old.clone();
+        elements.insert(if before{ pos }else{ pos + 1 }, value);
+        let len = elements.len();
+        self.splice_list(&key, &list, header, &old, elements)?;
+        Ok(Some(len))
+    }
+
+    fn hash_set(&mut self, key: Vec<u8>, fields: Vec<FieldValue>) -> result::Result<usize, DBError>{
+        let (mut hash, header) = match self.load_collection(&key, KeyType::Hash)?{
+            Some(hash) => hash,
+            None => (CollectionMeta::new(self.alloc_id()?), MetaHeader::default()),
+        };
+        let mut added = 0;
+        for (field, value) in fields{
+            let field_key = hash.element_key(&field);
+            if self.kv_get(field_key.clone())?.is_none(){
+                added += 1;
+            }
+            self.kv_put(field_key, codec::encode_element_value(&value))?;
+        }
+        hash.len += added as u64;
+        self.save_collection(&key, KeyType::Hash, &hash, header)?;
+        Ok(added)
+    }
+
+    fn hash_get(&self, key: Vec<u8>, field: Vec<u8>) -> result::Result<Option<Vec<u8>>, DBError>{
+        match self.get_collection(&key, KeyType::Hash)?{
+            Some(hash) => match self.kv_get(hash.element_key(&field))?{
+                Some(raw) => Ok(Some(codec::decode_element_value(raw)?)),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn hash_del(&mut self, key: Vec<u8>, fields: Vec<Vec<u8>>) -> result::Result<usize, DBError>{
+        let (mut hash, header) = match self.load_collection(&key, KeyType::Hash)?{
+            Some(hash) => hash,
+            None => return Ok(0),
+        };
+        let mut removed = 0;
+        for field in fields{
+            let field_key = hash.element_key(&field);
+            if self.kv_get(field_key.clone())?.is_some(){
+                self.kv_delete(field_key)?;
+                removed += 1;
+            }
+        }
+        if removed > 0{
+            hash.len = hash.len.saturating_sub(removed as u64);
+            self.save_collection(&key, KeyType::Hash, &hash, header)?;
+        }
+        Ok(removed)
+    }
+
+    fn hash_len(&self, key: Vec<u8>) -> result::Result<usize, DBError>{
+        Ok(self.get_collection(&key, KeyType::Hash)?.map_or(0, |hash| hash.len as usize))
+    }
+
+    fn hash_scan(&self, key: Vec<u8>, after: Option<Vec<u8>>, count: usize) -> result::Result<Vec<FieldValue>, DBError>{
+        match self.get_collection(&key, KeyType::Hash)?{
+            Some(hash) => self.scan_elements(hash.id, after.as_deref(), count, false)?.into_iter()
+                .map(|(field, raw)| Ok((field, codec::decode_element_value(raw)?))).collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn set_add(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> result::Result<usize, DBError>{
+        let (mut set, header) = match self.load_collection(&key, KeyType::Set)?{
+            Some(set) => set,
+            None => (CollectionMeta::new(self.alloc_id()?), MetaHeader::default()),
+        };
+        let mut added = 0;
+        for member in members{
+            let member_key = set.element_key(&member);
+            if self.kv_get(member_key.clone())?.is_none(){
+                self.kv_put(member_key, codec::MEMBER_VALUE.to_vec())?;
+                added += 1;
+            }
+        }
+        if added > 0{
+            set.len += added as u64;
+            self.save_collection(&key, KeyType::Set, &set, header)?;
+        }
+        Ok(added)
+    }
+
+    fn set_rem(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> result::Result<usize, DBError>{
+        let (mut set, header) = match self.load_collection(&key, KeyType::Set)?{
+            Some(set) => set,
+            None => return Ok(0),
+        };
+        let mut removed = 0;
+        for member in members{
+            let member_key = set.element_key(&member);
+            if self.kv_get(member_key.clone())?.is_some(){
+                self.kv_delete(member_key)?;
+                removed += 1;
+            }
+        }
+        if removed > 0{
+            set.len = set.len.saturating_sub(removed as u64);
+            self.save_collection(&key, KeyType::Set, &set, header)?;
+        }
+        Ok(removed)
+    }
+
+    fn set_members(&self, key: Vec<u8>) -> result::Result<Vec<Vec<u8>>, DBError>{
+        let set = match self.get_collection(&key, KeyType::Set)?{
+            Some(set) => set,
+            None => return Ok(Vec::new()),
+        };
+        let members = self.scan_elements(set.id, None, usize::MAX, true)?;
+        Ok(members.into_iter().map(|(member, _)| member).collect())
+    }
+
+    fn set_is_member(&self, key: Vec<u8>, member: Vec<u8>) -> result::Result<bool, DBError>{
+        match self.get_collection(&key, KeyType::Set)?{
+            Some(set) => Ok(self.kv_get(set.element_key(&member))?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    fn set_card(&self, key: Vec<u8>) -> result::Result<usize, DBError>{
+        Ok(self.get_collection(&key, KeyType::Set)?.map_or(0, |set| set.len as usize))
+    }
+
+    // Members have no position to draw from, so every one is read with a key
+    // only scan and sampled from there, each subset being equally likely.
+    fn set_random_members(&self, key: Vec<u8>, count: usize) -> result::Result<Vec<Vec<u8>>, DBError>{
+        Ok(random_sample(self.set_members(key)?.into_iter(), count))
+    }
+
+    fn zset_add(&mut self, key: Vec<u8>, members: Vec<ScoredMember>, nx: bool, xx: bool) -> result::Result<(usize, usize), DBError>{
+        let (mut zset, header) = match self.load_collection(&key, KeyType::ZSet)?{
+            Some(zset) => zset,
+            None if xx => return Ok((0, 0)),
+            None => (CollectionMeta::new(self.alloc_id()?), MetaHeader::default()),
+        };
+        let (mut added, mut changed) = (0, 0);
+        for (member, score) in members{
+            let ordered = score_to_ordered(score);
+            match self.get_ordered_score(zset.id, &member)?{
+                Some(_) if nx => continue,
+                Some(old) if old == ordered => continue,
+                Some(old) =>{
+                    self.kv_delete(codec::zset_score_key(zset.id, old, &member))?;
+                    changed += 1;
+                }
+                None if xx => continue,
+                None => added += 1,
+            }
+            // index first, the member record is what makes the member visible
+            self.kv_put(codec::zset_score_key(zset.id, ordered, &member), codec::MEMBER_VALUE.to_vec())?;
+            self.kv_put(codec::zset_member_key(zset.id, &member), ordered.to_be_bytes().to_vec())?;
+        }
+        if added > 0 || changed > 0{
+            zset.len += added as u64;
+            self.save_collection(&key, KeyType::ZSet, &zset, header)?;
+        }
+        Ok((added, changed))
+    }
+
+    fn zset_rem(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> result::Result<usize, DBError>{
+        let (mut zset, header) = match self.load_collection(&key, KeyType::ZSet)?{
+            Some(zset) => zset,
+            None => return Ok(0),
+        };
+        let mut removed = 0;
+        for member in members{
+            if let Some(ordered) = self.get_ordered_score(zset.id, &member)?{
+                self.kv_delete(codec::zset_member_key(zset.id, &member))?;
+                self.kv_delete(codec::zset_score_key(zset.id, ordered, &member))?;
+                removed += 1;
+            }
+        }
+        if removed > 0{
+            zset.len = zset.len.saturating_sub(removed as u64);
+            self.save_collection(&key, KeyType::ZSet, &zset, header)?;
+        }
+        Ok(removed)
+    }
+
+    fn zset_score(&self, key: Vec<u8>, member: Vec<u8>) -> result::Result<Option<f64>, DBError>{
+        match self.get_collection(&key, KeyType::ZSet)?{
+            Some(zset) => Ok(self.get_ordered_score(zset.id, &member)?.map(ordered_to_score)),
+            None => Ok(None),
+        }
+    }
+
+    fn zset_card(&self, key: Vec<u8>) -> result::Result<usize, DBError>{
+        Ok(self.get_collection(&key, KeyType::ZSet)?.map_or(0, |zset| zset.len as usize))
+    }
+
+    // counts the index records sorting before the member's one
+    fn zset_rank(&self, key: Vec<u8>, member: Vec<u8>) -> result::Result<Option<usize>, DBError>{
+        let zset = match self.get_collection(&key, KeyType::ZSet)?{
+            Some(zset) => zset,
+            None => return Ok(None),
+        };
+        let ordered = match self.get_ordered_score(zset.id, &member)?{
+            Some(ordered) => ordered,
+            None => return Ok(None),
+        };
+        let (start, _) = codec::zset_score_range(zset.id);
+        let end = codec::zset_score_key(zset.id, ordered, &member);
+        Ok(Some(self.scan_range(start, end, usize::MAX, true, false)?.len()))
+    }
+
+    fn zset_range(&self, key: Vec<u8>, start: i64, stop: i64) -> result::Result<Vec<ScoredMember>, DBError>{
+        let zset = match self.get_collection(&key, KeyType::ZSet)?{
+            Some(zset) => zset,
+            None => return Ok(Vec::new()),
+        };
+        let (start, stop) = match normalize_range(start, stop, zset.len as usize){
+            Some(range) => range,
+            None => return Ok(Vec::new()),
+        };
+        let len = zset.len as usize;
+        let (first, end) = codec::zset_score_range(zset.id);
+        // ranges nearer the tail are read backwards from the last member
+        if start > len - 1 - stop{
+            let mut kvs = self.scan_range(first, end, len - start, true, true)?;
+            let mut kvs = kvs.split_off((len - 1 - stop).min(kvs.len()));
+            kvs.reverse();
+            return Self::decode_scored(kvs);
+        }
+        let mut kvs = self.scan_range(first, end, stop + 1, true, false)?;
+        Self::decode_scored(kvs.split_off(start.min(kvs.len())))
+    }
+
+    fn zset_range_by_score(&self, key: Vec<u8>, min: Bound<f64>, max: Bound<f64>, offset: usize, count: usize)
+        -> result::Result<Vec<ScoredMember>, DBError>{
+        let zset = match self.get_collection(&key, KeyType::ZSet)?{
+            Some(zset) => zset,
+            None => return Ok(Vec::new()),
+        };
+        let (start, end) = match ordered_score_range(min, max){
+            Some(range) => range,
+            None => return Ok(Vec::new()),
+        };
+        let start_key = codec::zset_score_key(zset.id, start, b"");
+        let end_key = match end{
+            Some(end) => codec::zset_score_key(zset.id, end, b""),
+            None => codec::zset_score_range(zset.id).1,
+        };
+        let mut kvs = self.scan_range(start_key, end_key, offset.saturating_add(count), true, false)?;
+        Self::decode_scored(kvs.split_off(offset.min(kvs.len())))
+    }
+
+    fn stream_add(&mut self, key: Vec<u8>, id: NewStreamId, fields: Vec<FieldValue>)
+        -> result::Result<Option<StreamId>, DBError>{
+        let loaded = self.load_stream(&key)?;
+        let last_id = loaded.as_ref().map_or(StreamId::MIN, |(stream, _)| stream.last_id);
+        let entry_id = match id.resolve(last_id, now_millis()){
+            Some(entry_id) => entry_id,
+            None => return Ok(None),
+        };
+        let (mut stream, header) = match loaded{
+            Some(stream) => stream,
+            None => (StreamMeta::new(self.alloc_id()?), MetaHeader::default()),
+        };
+        self.kv_put(stream.entry_key(entry_id), codec::encode_stream_fields(&fields))?;
+        stream.len += 1;
+        stream.last_id = entry_id;
+        self.save_stream(&key, &stream, header)?;
+        Ok(Some(entry_id))
+    }
+
+    fn stream_len(&self, key: Vec<u8>) -> result::Result<usize, DBError>{
+        Ok(self.get_stream(&key)?.map_or(0, |stream| stream.len as usize))
+    }
+
+    fn stream_last_id(&self, key: Vec<u8>) -> result::Result<Option<StreamId>, DBError>{
+        Ok(self.get_stream(&key)?.map(|stream| stream.last_id))
+    }
+
+    fn stream_range(&self, key: Vec<u8>, start: StreamId, end: StreamId, count: usize, reverse: bool)
+        -> result::Result<Vec<StreamEntry>, DBError>{
+        let stream = match self.get_stream(&key)?{
+            Some(stream) => stream,
+            None => return Ok(Vec::new()),
+        };
+        if start > end{
+            return Ok(Vec::new());
+        }
+        let (start_key, end_key) = stream.entry_range(start, end);
+        let kvs = self.scan_range(start_key, end_key, count, false, reverse)?;
+        Self::decode_entries(kvs)
+    }
+
+    fn stream_trim(&mut self, key: Vec<u8>, trim: StreamTrim) -> result::Result<usize, DBError>{
+        let (mut stream, header) = match self.load_stream(&key)?{
+            Some(stream) => stream,
+            None => return Ok(0),
+        };
+        let (start, _) = stream.entry_range(StreamId::MIN, StreamId::MAX);
+        // the keys of the entries to drop, oldest first
+        let dropped = match trim{
+            StreamTrim::MaxLen(max_len) if stream.len as usize > max_len =>{
+                let (_, end) = stream.entry_range(StreamId::MIN, StreamId::MAX);
+                self.scan_range(start.clone(), end, stream.len as usize - max_len, true, false)?
+            }
+            StreamTrim::MaxLen(_) => return Ok(0),
+            StreamTrim::MinId(min_id) =>{
+                let end = stream.entry_key(min_id);
+                self.scan_range(start.clone(), end, usize::MAX, true, false)?
+            }
+        };
+        let mut end = match dropped.last(){
+            Some((last, _)) => last.clone(),
+            None => return Ok(0),
+        };
+        end.push(0);
+        stream.len -= dropped.len() as u64;
+        self.save_stream(&key, &stream, header)?;
+        self.kv_delete_range(start, end)?;
+        Ok(dropped.len())
+    }
+
+    fn stream_create_group(&mut self, key: Vec<u8>, group: Vec<u8>, last_id: Option<StreamId>, make_stream: bool)
+        -> result::Result<bool, DBError>{
+        let (stream, header) = match self.load_stream(&key)?{
+            Some(stream) => stream,
+            None if make_stream => (StreamMeta::new(self.alloc_id()?), MetaHeader::default()),
+            None => return Err(DBError::NotFound),
+        };
+        if self.kv_get(stream.group_key(&group))?.is_some(){
+            return Ok(false);
+        }
+        let last_id = last_id.unwrap_or(stream.last_id);
+        self.kv_put(stream.group_key(&group), codec::encode_stream_id(last_id))?;
+        self.save_stream(&key, &stream, header)?;
+        Ok(true)
+    }
+
+    fn stream_destroy_group(&mut self, key: Vec<u8>, group: Vec<u8>) -> result::Result<bool, DBError>{
+        let (stream, header) = match self.load_group(&key, &group){
+            Ok(loaded) => loaded,
+            Err(DBError::NotFound) if self.get_stream(&key)?.is_some() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        self.kv_delete(stream.group_key(&group))?;
+        let (start, end) = stream.consumer_range(&group);
+        self.kv_delete_range(start, end)?;
+        let (start, end) = stream.pending_range(&group, StreamId::MIN, StreamId::MAX);
+        self.kv_delete_range(start, end)?;
+        let (start, end) = stream.owned_group_range(&group);
+        self.kv_delete_range(start, end)?;
+        self.save_stream(&key, &stream, header)?;
+        Ok(true)
+    }
+
+    fn stream_group_last_id(&self, key: Vec<u8>, group: Vec<u8>) -> result::Result<StreamId, DBError>{
+        Ok(self.get_group(&key, &group)?.1)
+    }
+
+    fn stream_set_group_last_id(&mut self, key: Vec<u8>, group: Vec<u8>, last_id: StreamId) -> result::Result<(), DBError>{
+        let (stream, header) = self.load_group(&key, &group)?;
+        self.kv_put(stream.group_key(&group), codec::encode_stream_id(last_id))?;
+        self.save_stream(&key, &stream, header)?;
+        Ok(())
+    }
+
+    fn stream_create_consumer(&mut self, key: Vec<u8>, group: Vec<u8>, consumer: Vec<u8>) -> result::Result<bool, DBError>{
+        let (stream, header) = self.load_group(&key, &group)?;
+        let consumer_key = stream.consumer_key(&group, &consumer);
+        if self.kv_get(consumer_key.clone())?.is_some(){
+            return Ok(false);
+        }
+        self.kv_put(consumer_key, codec::MEMBER_VALUE.to_vec())?;
+        self.save_stream(&key, &stream, header)?;
+        Ok(true)
+    }
+
+    fn stream_delete_consumer(&mut self, key: Vec<u8>, group: Vec<u8>, consumer: Vec<u8>) -> result::Result<usize, DBError>{
+        let (stream, header) = self.load_group(&key, &group)?;
+        // only the index records of the consumer are read, not the whole PEL
+        let (start, end) = stream.owned_range(&group, &consumer, StreamId::MIN, StreamId::MAX);
+        let owned = self.scan_range(start.clone(), end.clone(), usize::MAX, true, false)?;
+        for (owned_key, _) in &owned{
+            self.kv_delete(stream.pending_key(&group, codec::decode_owned_key(owned_key)?))?;
+        }
+        self.kv_delete_range(start, end)?;
+        self.kv_delete(stream.consumer_key(&group, &consumer))?;
+        self.save_stream(&key, &stream, header)?;
+        Ok(owned.len())
+    }
+
+    fn stream_pending(&self, key: Vec<u8>, group: Vec<u8>, start: StreamId, end: StreamId, count: usize,
+                      consumer: Option<Vec<u8>>) -> result::Result<Vec<PendingEntry>, DBError>{
+        let (stream, _) = self.get_group(&key, &group)?;
+        if start > end{
+            return Ok(Vec::new());
+        }
+        let consumer = match consumer{
+            Some(consumer) => consumer,
+            None =>{
+                let (start_key, end_key) = stream.pending_range(&group, start, end);
+                return Self::decode_pending(self.scan_range(start_key, end_key, count, false, false)?);
+            }
+        };
+        // those of one consumer are found through its index records
+        let (start_key, end_key) = stream.owned_range(&group, &consumer, start, end);
+        let pending_keys = self.scan_range(start_key, end_key, count, true, false)?.into_iter()
+            .map(|(owned_key, _)| Ok(stream.pending_key(&group, codec::decode_owned_key(&owned_key)?)))
+            .collect::<result::Result<Vec<Key>, DBError>>()?;
+        let found = self.kv_batch_get(pending_keys.clone())?;
+        Self::decode_pending(pending_keys.into_iter().zip(found)
+            .filter_map(|(pending_key, raw)| raw.map(|raw| (pending_key, raw))).collect())
+    }
+
+    fn stream_set_pending(&mut self, key: Vec<u8>, group: Vec<u8>, entries: Vec<PendingEntry>) -> result::Result<(), DBError>{
+        let (stream, header) = self.load_group(&key, &group)?;
+        // a claimed entry leaves the index of its previous consumer
+        let pending_keys: Vec<Key> = entries.iter().map(|entry| stream.pending_key(&group, entry.id)).collect();
+        let found = self.kv_batch_get(pending_keys.clone())?;
+        let mut pairs = Vec::with_capacity(entries.len() * 3);
+        for ((entry, pending_key), raw) in entries.into_iter().zip(pending_keys).zip(found){
+            if let Some(raw) = raw{
+                let previous = codec::decode_pending(&pending_key, &raw)?;
+                if previous.consumer != entry.consumer{
+                    self.kv_delete(stream.owned_key(&group, &previous.consumer, entry.id))?;
+                }
+            }
+            pairs.push((stream.consumer_key(&group, &entry.consumer), codec::MEMBER_VALUE.to_vec()));
+            pairs.push((stream.owned_key(&group, &entry.consumer, entry.id), codec::MEMBER_VALUE.to_vec()));
+            pairs.push((pending_key, codec::encode_pending(&entry)));
+        }
+        self.kv_batch_put(pairs)?;
+        self.save_stream(&key, &stream, header)?;
+        Ok(())
+    }
+
+    fn stream_ack(&mut self, key: Vec<u8>, group: Vec<u8>, ids: Vec<StreamId>) -> result::Result<usize, DBError>{
+        let (stream, header) = self.load_group(&key, &group)?;
+        let pending_keys: Vec<Key> = ids.into_iter().map(|id| stream.pending_key(&group, id)).collect();
+        let found = self.kv_batch_get(pending_keys.clone())?;
+        let mut acked = 0;
+        for (pending_key, raw) in pending_keys.into_iter().zip(found){
+            if let Some(raw) = raw{
+                let entry = codec::decode_pending(&pending_key, &raw)?;
+                self.kv_delete(stream.owned_key(&group, &entry.consumer, entry.id))?;
+                self.kv_delete(pending_key)?;
+                acked += 1;
+            }
+        }
+        if acked > 0{
+            self.save_stream(&key, &stream, header)?;
+        }
+        Ok(acked)
+    }
+
+    fn begin_transaction(&self) -> result::Result<(), DBError>{
+        if self.mode == Mode::Txn{
+            let txn = Transaction::begin(self)?;
+            self.txns.lock().unwrap().insert(thread::current().id(), txn);
+        }
+        Ok(())
+    }
+
+    fn commit_transaction(&self) -> result::Result<bool, DBError>{
+        let txn = match self.txns.lock().unwrap().remove(&thread::current().id()){
+            Some(txn) => txn,
+            None => return Ok(true),
+        };
+        match txn.commit(self){
+            Ok(()) => Ok(true),
+            Err(ref err) if err.is_conflict() => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Versions are timestamps, EXEC compares the meta of a watched key in
+    // the snapshot at WATCH with its current one. RawKV keeps no history.
+    fn key_version(&self, _key: Vec<u8>) -> result::Result<u64, DBError>{
+        match self.mode{
+            Mode::Raw => Err(DBError::Storage("WATCH needs the txn mode of TiKV".to_string())),
+            Mode::Txn => Ok(self.timestamp()?),
+        }
+    }
+
+    // EXEC reads a fresh snapshot and fails when a watched meta differs from
+    // the one at its WATCH. The watched metas are locked, a write of one
+    // before the commit makes it conflict and the rerun of EXEC sees it.
+    fn begin_watched_transaction(&self, watched: Vec<(Vec<u8>, u64)>) -> result::Result<bool, DBError>{
+        if self.mode != Mode::Txn || watched.is_empty(){
+            self.begin_transaction()?;
+            return Ok(true);
+        }
+        let mut txn = Transaction::begin(self)?;
+        for (key, version) in watched{
+            let meta_key = codec::encode_meta_key(&key);
+            if Transaction::at(version).get(self, meta_key.clone())? != txn.get(self, meta_key.clone())?{
+                return Ok(false);
+            }
+            txn.lock(meta_key);
+        }
+        self.txns.lock().unwrap().insert(thread::current().id(), txn);
+        Ok(true)
+    }
+
+    fn isolates_transactions(&self) -> bool{
+        self.mode == Mode::Txn
     }
 }
 
 #[cfg(test)]
 mod test{
-    use crate::tikv::tikv_db::TikvDB;
-    use crate::redis_server::DB;
+    use crate::tikv::tikv_db::{TikvDB, Mode, Error, Result, splice_bounds};
+    use crate::redis_server::{DB, KeyType, NewStreamId, PendingEntry, StreamId, StreamTrim, now_millis};
+    use std::ops::Bound;
+
+    #[test]
+    fn test_splice_bounds(){
+        let list = |elements: &[&str]| elements.iter().map(|e| e.as_bytes().to_vec()).collect::<Vec<_>>();
+        // LREM of the second element keeps the head and moves the tail in
+        let old = list(&["a", "b", "c", "d", "e"]);
+        assert_eq!(splice_bounds(10, 15, &old, &list(&["a", "c", "d", "e"])), (11, 15, 0..1));
+        assert_eq!(splice_bounds(10, 15, &old, &list(&["a", "b", "c", "e"])), (10, 14, 3..4));
+        // LINSERT next to the tail only writes the new element and what follows it
+        assert_eq!(splice_bounds(10, 15, &old, &list(&["a", "b", "c", "d", "x", "e"])), (10, 16, 4..6));
+        assert_eq!(splice_bounds(10, 15, &old, &list(&["x", "a", "b", "c", "d", "e"])), (9, 15, 0..1));
+        // removing one of equal neighbours writes nothing
+        assert_eq!(splice_bounds(10, 12, &list(&["a", "a"]), &list(&["a"])), (10, 11, 1..1));
+        assert_eq!(splice_bounds(10, 12, &list(&["a", "a"]), &[]), (10, 10, 0..0));
+    }
 
     #[test]
     fn test_tikv_new_db(){
@@ -164,9 +1870,318 @@ mod test{
     fn test_tikv_basic_put_get(){
         let end_point = vec!["127.0.0.1:2379".to_string()];
         let mut tikv_db = TikvDB::connect(end_point).unwrap();
-        let key = "foo".to_string();
-        let value = "bar".to_string();
-        assert_eq!(tikv_db.raw_put(key.clone(), value).unwrap(), "OK".to_string());
-        assert_eq!(tikv_db.raw_get(key.clone()).unwrap(), "bar".to_string());
+        let key = b"foo".to_vec();
+        let value = b"bar".to_vec();
+        assert!(tikv_db.raw_put(key.clone(), value).is_ok());
+        assert_eq!(tikv_db.raw_get(key.clone()).unwrap(), b"bar".to_vec());
+    }
+
+    #[test]
+    fn test_tikv_empty_values(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        let key = b"empty_foo".to_vec();
+        assert!(tikv_db.raw_put(key.clone(), b"".to_vec()).is_ok());
+        assert_eq!(tikv_db.raw_get(key.clone()).unwrap(), b"".to_vec());
+
+        let raw_key = b"empty_raw_foo".to_vec();
+        tikv_db.tikv_raw_delete(raw_key.clone(), None).unwrap();
+        assert_eq!(tikv_db.tikv_raw_get(raw_key.clone(), None).unwrap(), None);
+        assert!(tikv_db.tikv_raw_put(raw_key.clone(), b"".to_vec(), None).is_err());
+        assert_eq!(tikv_db.tikv_raw_get(raw_key.clone(), None).unwrap(), None);
+
+        let hash = b"empty_hash_foo".to_vec();
+        tikv_db.delete(hash.clone()).unwrap();
+        assert_eq!(tikv_db.hash_set(hash.clone(), vec![(b"a".to_vec(), b"".to_vec())]).unwrap(), 1);
+        assert_eq!(tikv_db.hash_get(hash.clone(), b"a".to_vec()).unwrap(), Some(b"".to_vec()));
+        assert_eq!(tikv_db.hash_set(hash.clone(), vec![(b"a".to_vec(), b"1".to_vec())]).unwrap(), 0);
+
+        let list = b"empty_list_foo".to_vec();
+        tikv_db.delete(list.clone()).unwrap();
+        tikv_db.list_push(list.clone(), vec![b"".to_vec()], false, false).unwrap();
+        assert_eq!(tikv_db.list_index(list.clone(), 0).unwrap(), Some(b"".to_vec()));
+        tikv_db.list_push(list.clone(), vec![b"a".to_vec()], true, false).unwrap();
+        assert_eq!(tikv_db.list_range(list.clone(), 0, -1).unwrap(), vec![b"a".to_vec(), b"".to_vec()]);
+    }
+
+    #[test]
+    fn test_tikv_expire(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        let key = b"expire_foo".to_vec();
+        let expire_at = now_millis() + 100;
+        tikv_db.raw_put_with_expire(key.clone(), b"bar".to_vec(), Some(expire_at)).unwrap();
+        assert_eq!(tikv_db.get_expire(key.clone()).unwrap(), Some(expire_at));
+        assert!(tikv_db.set_expire(key.clone(), None).unwrap());
+        assert_eq!(tikv_db.get_expire(key.clone()).unwrap(), None);
+        assert!(tikv_db.set_expire(key.clone(), Some(1)).unwrap());
+        assert!(tikv_db.raw_get(key.clone()).is_err());
+    }
+
+    #[test]
+    fn test_tikv_delete_rename(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        tikv_db.raw_put(b"del_foo".to_vec(), b"bar".to_vec()).unwrap();
+        assert!(tikv_db.exists(b"del_foo".to_vec()).unwrap());
+        tikv_db.rename(b"del_foo".to_vec(), b"del_foo2".to_vec()).unwrap();
+        assert!(!tikv_db.exists(b"del_foo".to_vec()).unwrap());
+        assert!(tikv_db.delete(b"del_foo2".to_vec()).unwrap());
+        assert!(!tikv_db.delete(b"del_foo2".to_vec()).unwrap());
+    }
+
+    #[test]
+    fn test_tikv_compare_and_swap(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        let key = b"cas_foo".to_vec();
+        tikv_db.delete(key.clone()).unwrap();
+        assert!(tikv_db.raw_compare_and_swap(key.clone(), None, b"1".to_vec(), None).unwrap());
+        assert!(!tikv_db.raw_compare_and_swap(key.clone(), None, b"2".to_vec(), None).unwrap());
+        let current = tikv_db.raw_get_with_expire(key.clone()).unwrap();
+        assert!(tikv_db.raw_compare_and_swap(key.clone(), Some(current), b"2".to_vec(), None).unwrap());
+        assert_eq!(tikv_db.raw_get(key.clone()).unwrap(), b"2".to_vec());
+    }
+
+    #[test]
+    fn test_tikv_list(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        let key = b"list_foo".to_vec();
+        tikv_db.delete(key.clone()).unwrap();
+        let values = vec![b"b".to_vec(), b"c".to_vec()];
+        assert_eq!(tikv_db.list_push(key.clone(), values, false, false).unwrap(), 2);
+        assert_eq!(tikv_db.list_push(key.clone(), vec![b"a".to_vec()], true, false).unwrap(), 3);
+        assert_eq!(tikv_db.list_range(key.clone(), 0, -1).unwrap(), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(tikv_db.list_insert(key.clone(), true, b"c".to_vec(), b"b".to_vec()).unwrap(), Some(4));
+        assert_eq!(tikv_db.list_rem(key.clone(), 0, b"b".to_vec()).unwrap(), 2);
+        assert_eq!(tikv_db.list_pop(key.clone(), false, 5).unwrap(), vec![b"c".to_vec(), b"a".to_vec()]);
+        assert!(!tikv_db.exists(key.clone()).unwrap());
+    }
+
+    #[test]
+    fn test_tikv_hash(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        let key = b"hash_foo".to_vec();
+        tikv_db.delete(key.clone()).unwrap();
+        let fields = vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())];
+        assert_eq!(tikv_db.hash_set(key.clone(), fields).unwrap(), 2);
+        assert_eq!(tikv_db.hash_set(key.clone(), vec![(b"a".to_vec(), b"3".to_vec())]).unwrap(), 0);
+        assert_eq!(tikv_db.hash_get(key.clone(), b"a".to_vec()).unwrap(), Some(b"3".to_vec()));
+        assert_eq!(tikv_db.hash_len(key.clone()).unwrap(), 2);
+        assert_eq!(tikv_db.hash_scan(key.clone(), Some(b"a".to_vec()), 10).unwrap(), vec![(b"b".to_vec(), b"2".to_vec())]);
+        assert_eq!(tikv_db.hash_del(key.clone(), vec![b"a".to_vec(), b"b".to_vec()]).unwrap(), 2);
+        assert!(!tikv_db.exists(key.clone()).unwrap());
+    }
+
+    #[test]
+    fn test_tikv_set(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        let key = b"set_foo".to_vec();
+        tikv_db.delete(key.clone()).unwrap();
+        let members = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"a".to_vec()];
+        assert_eq!(tikv_db.set_add(key.clone(), members).unwrap(), 3);
+        assert!(tikv_db.set_is_member(key.clone(), b"b".to_vec()).unwrap());
+        assert_eq!(tikv_db.set_card(key.clone()).unwrap(), 3);
+        assert_eq!(tikv_db.set_random_members(key.clone(), 2).unwrap().len(), 2);
+        assert_eq!(tikv_db.set_rem(key.clone(), vec![b"b".to_vec(), b"z".to_vec()]).unwrap(), 1);
+        assert_eq!(tikv_db.set_members(key.clone()).unwrap(), vec![b"a".to_vec(), b"c".to_vec()]);
+        assert!(tikv_db.hash_len(key.clone()).is_err());
+    }
+
+    #[test]
+    fn test_tikv_zset(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        let key = b"zset_foo".to_vec();
+        tikv_db.delete(key.clone()).unwrap();
+        let members = vec![(b"a".to_vec(), 1.0), (b"b".to_vec(), -2.0), (b"c".to_vec(), 3.5)];
+        assert_eq!(tikv_db.zset_add(key.clone(), members, false, false).unwrap(), (3, 0));
+        assert_eq!(tikv_db.zset_add(key.clone(), vec![(b"a".to_vec(), 4.0)], false, false).unwrap(), (0, 1));
+        assert_eq!(tikv_db.zset_score(key.clone(), b"a".to_vec()).unwrap(), Some(4.0));
+        assert_eq!(tikv_db.zset_rank(key.clone(), b"a".to_vec()).unwrap(), Some(2));
+        assert_eq!(tikv_db.zset_range(key.clone(), 0, 0).unwrap(), vec![(b"b".to_vec(), -2.0)]);
+        assert_eq!(tikv_db.zset_range(key.clone(), -2, -1).unwrap(), vec![(b"c".to_vec(), 3.5), (b"a".to_vec(), 4.0)]);
+        let range = tikv_db.zset_range_by_score(key.clone(), Bound::Excluded(-2.0), Bound::Unbounded, 0, 10).unwrap();
+        assert_eq!(range, vec![(b"c".to_vec(), 3.5), (b"a".to_vec(), 4.0)]);
+        assert_eq!(tikv_db.zset_rem(key.clone(), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]).unwrap(), 3);
+        assert!(!tikv_db.exists(key.clone()).unwrap());
+    }
+
+    #[test]
+    fn test_tikv_stream(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        let key = b"test_stream".to_vec();
+        tikv_db.delete(key.clone()).unwrap();
+        let fields = vec![(b"f".to_vec(), b"v".to_vec())];
+        for seq in 1..4{
+            let id = tikv_db.stream_add(key.clone(), NewStreamId::AutoSeq(7), fields.clone()).unwrap();
+            assert_eq!(id, Some(StreamId::new(7, seq)));
+        }
+        assert_eq!(tikv_db.stream_add(key.clone(), NewStreamId::Id(StreamId::new(7, 2)), fields.clone()).unwrap(), None);
+        assert_eq!(tikv_db.key_type(key.clone()).unwrap(), KeyType::Stream);
+        assert_eq!(tikv_db.stream_len(key.clone()).unwrap(), 3);
+        let range = tikv_db.stream_range(key.clone(), StreamId::new(7, 2), StreamId::MAX, 10, false).unwrap();
+        assert_eq!(range, vec![(StreamId::new(7, 2), fields.clone()), (StreamId::new(7, 3), fields.clone())]);
+        let range = tikv_db.stream_range(key.clone(), StreamId::MIN, StreamId::MAX, 1, true).unwrap();
+        assert_eq!(range, vec![(StreamId::new(7, 3), fields.clone())]);
+        assert_eq!(tikv_db.stream_trim(key.clone(), StreamTrim::MaxLen(1)).unwrap(), 2);
+        assert_eq!(tikv_db.stream_trim(key.clone(), StreamTrim::MinId(StreamId::new(8, 0))).unwrap(), 1);
+        assert_eq!(tikv_db.stream_len(key.clone()).unwrap(), 0);
+        assert_eq!(tikv_db.stream_last_id(key.clone()).unwrap(), Some(StreamId::new(7, 3)));
+        assert!(tikv_db.delete(key).unwrap());
+    }
+
+    #[test]
+    fn test_tikv_stream_group(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        let (key, group) = (b"test_stream_group".to_vec(), b"workers".to_vec());
+        tikv_db.delete(key.clone()).unwrap();
+        assert!(tikv_db.stream_create_group(key.clone(), group.clone(), None, false).is_err());
+        assert!(tikv_db.stream_create_group(key.clone(), group.clone(), None, true).unwrap());
+        assert!(!tikv_db.stream_create_group(key.clone(), group.clone(), None, true).unwrap());
+        tikv_db.stream_set_group_last_id(key.clone(), group.clone(), StreamId::new(3, 0)).unwrap();
+        assert_eq!(tikv_db.stream_group_last_id(key.clone(), group.clone()).unwrap(), StreamId::new(3, 0));
+
+        let pending = |id, consumer: &[u8]| PendingEntry{
+            id: StreamId::new(id, 0),
+            consumer: consumer.to_vec(),
+            delivered_at: 10,
+            deliveries: 1,
+        };
+        tikv_db.stream_set_pending(key.clone(), group.clone(), vec![pending(1, b"a"), pending(2, b"b"), pending(3, b"a")]).unwrap();
+        assert!(!tikv_db.stream_create_consumer(key.clone(), group.clone(), b"b".to_vec()).unwrap());
+        let of_a = tikv_db.stream_pending(key.clone(), group.clone(), StreamId::MIN, StreamId::MAX, 10, Some(b"a".to_vec())).unwrap();
+        assert_eq!(of_a, vec![pending(1, b"a"), pending(3, b"a")]);
+        assert_eq!(tikv_db.stream_ack(key.clone(), group.clone(), vec![StreamId::new(1, 0), StreamId::new(9, 0)]).unwrap(), 1);
+        assert_eq!(tikv_db.stream_delete_consumer(key.clone(), group.clone(), b"a".to_vec()).unwrap(), 1);
+        assert!(tikv_db.stream_destroy_group(key.clone(), group.clone()).unwrap());
+        assert!(!tikv_db.stream_destroy_group(key.clone(), group.clone()).unwrap());
+        assert!(tikv_db.delete(key).unwrap());
+    }
+
+    #[test]
+    fn test_tikv_scan_keys(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        for key in &["scan_a", "scan_b", "scan_c"]{
+            tikv_db.raw_put(key.as_bytes().to_vec(), b"1".to_vec()).unwrap();
+        }
+        tikv_db.set_expire(b"scan_b".to_vec(), Some(1)).unwrap();
+        let keys = tikv_db.scan_keys(Bound::Included(b"scan_".to_vec()), Bound::Excluded(b"scan`".to_vec()), 10).unwrap();
+        let keys: Vec<_> = keys.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"scan_a".to_vec(), b"scan_c".to_vec()]);
+    }
+
+    #[test]
+    fn test_tikv_raw_scan(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let tikv_db = TikvDB::connect(end_point).unwrap();
+        for key in &["rscan_a", "rscan_b", "rscan_c"]{
+            tikv_db.tikv_raw_put(key.as_bytes().to_vec(), b"v".to_vec(), None).unwrap();
+        }
+        let (start, end) = (b"rscan_".to_vec(), b"rscan`".to_vec());
+        let kvs = tikv_db.tikv_raw_scan(start.clone(), end.clone(), 2, false, None).unwrap();
+        assert_eq!(kvs, vec![(b"rscan_a".to_vec(), b"v".to_vec()), (b"rscan_b".to_vec(), b"v".to_vec())]);
+        let kvs = tikv_db.tikv_raw_reverse_scan(start.clone(), end.clone(), 2, true, None).unwrap();
+        assert_eq!(kvs, vec![(b"rscan_c".to_vec(), Vec::new()), (b"rscan_b".to_vec(), Vec::new())]);
+        let kvs = tikv_db.tikv_raw_reverse_scan(start, b"rscan_b".to_vec(), 10, true, None).unwrap();
+        assert_eq!(kvs, vec![(b"rscan_a".to_vec(), Vec::new())]);
+    }
+
+    #[test]
+    fn test_tikv_batch(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect(end_point).unwrap();
+        tikv_db.delete(b"batch_b".to_vec()).unwrap();
+        tikv_db.set_add(b"batch_c".to_vec(), vec![b"x".to_vec()]).unwrap();
+        let kvs = vec![(b"batch_a".to_vec(), b"1".to_vec()), (b"batch_c".to_vec(), b"3".to_vec()), (b"batch_a".to_vec(), b"2".to_vec())];
+        tikv_db.batch_put(kvs).unwrap();
+        let keys = vec![b"batch_c".to_vec(), b"batch_b".to_vec(), b"batch_a".to_vec()];
+        assert_eq!(tikv_db.batch_get(keys).unwrap(), vec![Some(b"3".to_vec()), None, Some(b"2".to_vec())]);
+        assert_eq!(tikv_db.set_card(b"batch_c".to_vec()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_tikv_txn(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect_with_mode(end_point.clone(), Mode::Txn).unwrap();
+        let mut other = TikvDB::connect_with_mode(end_point, Mode::Txn).unwrap();
+        tikv_db.begin_transaction().unwrap();
+        tikv_db.batch_put(vec![(b"txn_a".to_vec(), b"1".to_vec()), (b"txn_b".to_vec(), b"1".to_vec())]).unwrap();
+        assert_eq!(tikv_db.raw_get(b"txn_a".to_vec()).unwrap(), b"1".to_vec());
+        assert!(tikv_db.commit_transaction().unwrap());
+        let keys = vec![b"txn_a".to_vec(), b"txn_b".to_vec()];
+        assert_eq!(other.batch_get(keys).unwrap(), vec![Some(b"1".to_vec()), Some(b"1".to_vec())]);
+
+        // a write committed after the snapshot fails the commit
+        tikv_db.begin_transaction().unwrap();
+        tikv_db.raw_put(b"txn_a".to_vec(), b"2".to_vec()).unwrap();
+        other.raw_put(b"txn_a".to_vec(), b"3".to_vec()).unwrap();
+        assert!(!tikv_db.commit_transaction().unwrap());
+        assert_eq!(other.raw_get(b"txn_a".to_vec()).unwrap(), b"3".to_vec());
+
+        // a step failing halfway rolls back what the command wrote before it
+        tikv_db.begin_transaction().unwrap();
+        tikv_db.raw_put(b"txn_c".to_vec(), b"1".to_vec()).unwrap();
+        assert!(tikv_db.in_txn(|_| -> Result<()> { Err(Error::Other) }).is_err());
+        assert!(tikv_db.commit_transaction().is_err());
+        assert!(other.raw_get(b"txn_c".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_tikv_watch(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect_with_mode(end_point.clone(), Mode::Txn).unwrap();
+        let mut other = TikvDB::connect_with_mode(end_point, Mode::Txn).unwrap();
+        tikv_db.raw_put(b"watch_a".to_vec(), b"1".to_vec()).unwrap();
+        let version = tikv_db.key_version(b"watch_a".to_vec()).unwrap();
+
+        // an unwatched key written since WATCH is read fresh and not a change
+        other.raw_put(b"watch_b".to_vec(), b"2".to_vec()).unwrap();
+        assert!(tikv_db.begin_watched_transaction(vec![(b"watch_a".to_vec(), version)]).unwrap());
+        assert_eq!(tikv_db.raw_get(b"watch_b".to_vec()).unwrap(), b"2".to_vec());
+        tikv_db.raw_put(b"watch_b".to_vec(), b"3".to_vec()).unwrap();
+        assert!(tikv_db.commit_transaction().unwrap());
+
+        other.raw_put(b"watch_a".to_vec(), b"2".to_vec()).unwrap();
+        assert!(!tikv_db.begin_watched_transaction(vec![(b"watch_a".to_vec(), version)]).unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_tikv_watch_rewrites(){
+        let end_point = vec!["127.0.0.1:2379".to_string()];
+        let mut tikv_db = TikvDB::connect_with_mode(end_point.clone(), Mode::Txn).unwrap();
+        let mut other = TikvDB::connect_with_mode(end_point, Mode::Txn).unwrap();
+        let (list, hash, zset) = (b"watch_list".to_vec(), b"watch_hash".to_vec(), b"watch_zset".to_vec());
+        for key in [&list, &hash, &zset]{
+            tikv_db.delete(key.clone()).unwrap();
+        }
+        tikv_db.list_push(list.clone(), vec![b"a".to_vec(), b"b".to_vec()], false, false).unwrap();
+        tikv_db.hash_set(hash.clone(), vec![(b"f".to_vec(), b"1".to_vec())]).unwrap();
+        tikv_db.zset_add(zset.clone(), vec![(b"m".to_vec(), 1.0)], false, false).unwrap();
+
+        // writes leaving the meta payload as it was still fail EXEC
+        let version = tikv_db.key_version(list.clone()).unwrap();
+        other.list_set(list.clone(), 0, b"c".to_vec()).unwrap();
+        assert!(!tikv_db.begin_watched_transaction(vec![(list.clone(), version)]).unwrap());
+
+        let version = tikv_db.key_version(hash.clone()).unwrap();
+        other.hash_set(hash.clone(), vec![(b"f".to_vec(), b"2".to_vec())]).unwrap();
+        assert!(!tikv_db.begin_watched_transaction(vec![(hash.clone(), version)]).unwrap());
+
+        let version = tikv_db.key_version(zset.clone()).unwrap();
+        assert_eq!(other.zset_add(zset.clone(), vec![(b"m".to_vec(), 2.0)], false, false).unwrap(), (0, 1));
+        assert!(!tikv_db.begin_watched_transaction(vec![(zset.clone(), version)]).unwrap());
+
+        // a ZADD changing nothing is no write
+        let version = tikv_db.key_version(zset.clone()).unwrap();
+        assert_eq!(other.zset_add(zset.clone(), vec![(b"m".to_vec(), 2.0)], false, false).unwrap(), (0, 0));
+        assert!(tikv_db.begin_watched_transaction(vec![(zset.clone(), version)]).unwrap());
+        assert!(tikv_db.commit_transaction().unwrap());
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+
+use kvproto::kvrpcpb;
+
+use super::tikv_db::{TikvDB, Key, Value, Result, Error};
+
+// How long the locks of a transaction outlive it when it stops between
+// prewrite and commit. Other transactions wait that long before they roll
+// it back.
+const LOCK_TTL_MS: u64 = 3000;
+// page size when a range is deleted key by key
+const DELETE_BATCH: u32 = 1024;
+
+// A Percolator transaction over the transactional API of TiKV. Reads see the
+// snapshot at `start_ts` merged with the writes of the transaction, which
+// stay buffered until `commit`. Committing prewrites, i.e. locks, every key
+// with the first one as primary, then commits the primary, which is the point
+// where the transaction becomes committed, and finally the others.
+pub struct Transaction {
+    start_ts: u64,
+    // buffered writes, `None` deletes the key
+    mutations: BTreeMap<Key, Option<Value>>,
+    // keys locked without a write, so that the commit fails when another
+    // transaction wrote them after `start_ts`
+    locks: BTreeSet<Key>,
+    // why a step of the transaction failed, it must not commit then
+    failure: Option<String>,
+}
+
+impl Transaction {
+    pub fn begin(db: &TikvDB) -> Result<Transaction> {
+        Ok(Transaction::at(db.timestamp()?))
+    }
+
+    // A transaction reading the snapshot at `start_ts`, taken earlier.
+    pub fn at(start_ts: u64) -> Transaction {
+        Transaction {
+            start_ts,
+            mutations: BTreeMap::new(),
+            locks: BTreeSet::new(),
+            failure: None,
+        }
+    }
+
+    pub fn get(&self, db: &TikvDB, key: Key) -> Result<Option<Value>> {
+        if let Some(value) = self.mutations.get(&key) {
+            return Ok(value.clone());
+        }
+        let start_ts = self.start_ts;
+        db.request(&key, &None, |context| context.client().kv_get(context, key.clone(), start_ts))
+    }
+
+    // values of `keys` in the same order, like `TikvDB::tikv_raw_batch_get`
+    pub fn batch_get(&self, db: &TikvDB, keys: Vec<Key>) -> Result<Vec<Option<Value>>> {
+        let unbuffered: Vec<Key> = keys.iter().filter(|key| !self.mutations.contains_key(*key)).cloned().collect();
+        let start_ts = self.start_ts;
+        let batches = db.batch_request(unbuffered, |key| key, None,
+            move |context, keys| context.client().kv_batch_get(context, keys, start_ts))?;
+        let found: HashMap<Key, Value> = batches.into_iter().flatten().collect();
+        Ok(keys.iter().map(|key| match self.mutations.get(key) {
+            Some(value) => value.clone(),
+            None => found.get(key).cloned(),
+        }).collect())
+    }
+
+    pub fn put(&mut self, key: Key, value: Value) {
+        self.mutations.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: Key) {
+        self.mutations.insert(key, None);
+    }
+
+    pub fn lock(&mut self, key: Key) {
+        self.locks.insert(key);
+    }
+
+    // Marks the transaction as failed after `err`, the writes buffered
+    // before it are thrown away instead of committed.
+    pub fn fail(&mut self, err: &Error) {
+        if self.failure.is_none() {
+            self.failure = Some(err.to_string());
+        }
+    }
+
+    // Same answer as `TikvDB::tikv_raw_compare_and_swap`. A write of the key
+    // by another transaction after the snapshot makes the commit fail, so
+    // the swap needs no more than a read.
+    pub fn compare_and_swap(&mut self, db: &TikvDB, key: Key, previous: Option<Value>, value: Value)
+        -> Result<(bool, Option<Value>)> {
+        let current = self.get(db, key.clone())?;
+        if current == previous {
+            self.put(key, value);
+            Ok((true, current))
+        } else {
+            Ok((false, current))
+        }
+    }
+
+    // Up to `limit` pairs of `[start_key, end_key)`, an empty end key meaning
+    // no upper bound, from the last one down when `reverse`.
+    pub fn scan(&self, db: &TikvDB, start_key: Key, end_key: Key, limit: u32, key_only: bool, reverse: bool)
+        -> Result<Vec<(Key, Value)>> {
+        let range = if end_key.is_empty() {
+            (Bound::Included(start_key.clone()), Bound::Unbounded)
+        } else {
+            (Bound::Included(start_key.clone()), Bound::Excluded(end_key.clone()))
+        };
+        // every buffered delete may hide a pair of the snapshot
+        let deletes = self.mutations.range(range.clone()).filter(|(_, value)| value.is_none()).count();
+        let fetch = limit.saturating_add(deletes as u32);
+        let start_ts = self.start_ts;
+        let snapshot = db.scan_regions(start_key, end_key, fetch, reverse, None, |context, start, end, limit| {
+            context.client().kv_scan(context, start, end, limit, start_ts, key_only, reverse)
+        })?;
+        // past the last pair fetched the snapshot is unknown, the buffered
+        // writes there are left to the next page
+        let last = if (snapshot.len() as u32) < fetch {
+            None
+        } else {
+            snapshot.last().map(|(key, _)| key.clone())
+        };
+        let mut merged: BTreeMap<Key, Value> = snapshot.into_iter().collect();
+        for (key, value) in self.mutations.range(range) {
+            let fetched = match last {
+                None => true,
+                Some(ref last) => if reverse { key >= last } else { key <= last },
+            };
+            if !fetched {
+                continue;
+            }
+            match value {
+                Some(value) => {
+                    let value = if key_only { Vec::new() } else { value.clone() };
+                    merged.insert(key.clone(), value);
+                }
+                None => {
+                    merged.remove(key);
+                }
+            }
+        }
+        let kvs: Vec<(Key, Value)> = if reverse {
+            merged.into_iter().rev().take(limit as usize).collect()
+        } else {
+            merged.into_iter().take(limit as usize).collect()
+        };
+        Ok(kvs)
+    }
+
+    // There is no range delete in transactions, every key seen in the
+    // range is deleted on its own.
+    pub fn delete_range(&mut self, db: &TikvDB, start_key: Key, end_key: Key) -> Result<()> {
+        let mut start_key = start_key;
+        while start_key < end_key {
+            let keys = self.scan(db, start_key, end_key.clone(), DELETE_BATCH, true, false)?;
+            let done = (keys.len() as u32) < DELETE_BATCH;
+            start_key = match keys.last() {
+                Some((last, _)) => {
+                    let mut next = last.clone();
+                    next.push(0);
+                    next
+                }
+                None => break,
+            };
+            for (key, _) in keys {
+                self.delete(key);
+            }
+            if done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn commit(self, db: &TikvDB) -> Result<()> {
+        // nothing reached TiKV yet, dropping the buffer rolls it back
+        if let Some(failure) = self.failure {
+            return Err(Error::OperationError(failure));
+        }
+        let written = &self.mutations;
+        let locks: Vec<Key> = self.locks.into_iter().filter(|key| !written.contains_key(key)).collect();
+        let mut mutations: Vec<(Key, kvrpcpb::Op, Value)> = self.mutations.into_iter().map(|(key, value)| match value {
+            Some(value) => (key, kvrpcpb::Op::Put, value),
+            None => (key, kvrpcpb::Op::Del, Vec::new()),
+        }).collect();
+        mutations.extend(locks.into_iter().map(|key| (key, kvrpcpb::Op::Lock, Vec::new())));
+        let primary = match mutations.first() {
+            Some((primary, _, _)) => primary.clone(),
+            // nothing to write for a read only transaction
+            None => return Ok(()),
+        };
+        let start_ts = self.start_ts;
+        let keys: Vec<Key> = mutations.iter().map(|(key, _, _)| key.clone()).collect();
+        let lock_primary = primary.clone();
+        let prewritten = db.batch_request(mutations, |(key, _, _)| key, None, move |context, mutations| {
+            context.client().kv_prewrite(context, mutations, lock_primary.clone(), start_ts, LOCK_TTL_MS)
+        });
+        let commit_ts = match prewritten.and_then(|_| db.timestamp()) {
+            Ok(commit_ts) => commit_ts,
+            Err(err) => {
+                abort(db, keys, start_ts);
+                return Err(err);
+            }
+        };
+        let committed = db.request(&primary, &None, |context| {
+            context.client().kv_commit(context, vec![primary.clone()], start_ts, commit_ts)
+        });
+        match committed {
+            Ok(()) => {
+                commit_secondaries(db, keys, &primary, start_ts, commit_ts);
+                Ok(())
+            }
+            // the primary lock is gone, another transaction rolled this one back
+            Err(err @ Error::KeyError(_)) => {
+                abort(db, keys, start_ts);
+                Err(err)
+            }
+            // without an answer the commit may have happened, readers settle
+            // the locks through the primary either way
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// Once the primary is committed the transaction is, and a reader finding a
+// secondary still locked commits it through the primary. So failing here
+// is only logged.
+fn commit_secondaries(db: &TikvDB, keys: Vec<Key>, primary: &[u8], start_ts: u64, commit_ts: u64) {
+    let secondaries: Vec<Key> = keys.into_iter().filter(|key| key.as_slice() != primary).collect();
+    let committed = db.batch_request(secondaries, |key| key, None,
+        move |context, keys| context.client().kv_commit(context, keys, start_ts, commit_ts));
+    if let Err(e) = committed {
+        println!("failed to commit secondary keys of transaction {}, {:?}", start_ts, e);
+    }
+}
+
+// Rolls back the locks written by a transaction that will not commit, so
+// readers do not wait for their TTL.
+fn abort(db: &TikvDB, keys: Vec<Key>, start_ts: u64) {
+    let rolled_back = db.batch_request(keys, |key| key, None,
+        move |context, keys| context.client().kv_batch_rollback(context, keys, start_ts));
+    if let Err(e) = rolled_back {
+        println!("failed to roll back transaction {}, {:?}", start_ts, e);
+    }
+}
+
+// Settles a lock left by another transaction. Its primary key tells whether
+// that transaction committed, then the lock is committed or rolled back the
+// same way. A lock still within its TTL is left alone, the reader backs off
+// and reads again.
+pub fn resolve_lock(db: &TikvDB, lock: &kvrpcpb::LockInfo) -> Result<()> {
+    let primary = lock.get_primary_lock().to_vec();
+    let lock_ts = lock.get_lock_version();
+    let current_ts = db.timestamp()?;
+    let commit_ts = db.request(&primary, &None, |context| {
+        // a live lock must not come back here to be resolved again
+        context.client().kv_cleanup(context, primary.clone(), lock_ts, current_ts).map_err(|err| match err {
+            Error::KeyError(ref key_err) if key_err.has_locked() =>
+                Error::TiKVError(format!("transaction {} is still alive", lock_ts)),
+            err => err,
+        })
+    })?;
+    let key = lock.get_key().to_vec();
+    db.request(&key, &None, |context| context.client().kv_resolve_lock(context, lock_ts, commit_ts))
+}
@@ -0,0 +1,106 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::Sender;
+
+// Clients blocked on list keys by BLPOP, BRPOP and BLMOVE, first come first
+// served on every key. A push wakes the first client waiting on the key,
+// which tries again and stays in front if someone else got the element.
+// Only the first client on a key tries it.
+#[derive(Default)]
+pub struct WaitQueues{
+    queues: HashMap<Vec<u8>, VecDeque<(u64, Sender<()>)>>,
+}
+
+impl WaitQueues{
+    pub fn new() -> Self{
+        Default::default()
+    }
+
+    // Queues the client on every key it is not waiting on yet.
+    pub fn wait(&mut self, keys: &[Vec<u8>], id: u64, wake: &Sender<()>){
+        for key in keys{
+            let queue = self.queues.entry(key.clone()).or_default();
+            if !queue.iter().any(|(waiter, _)| *waiter == id){
+                queue.push_back((id, wake.clone()));
+            }
+        }
+    }
+
+    // Takes the client off the keys. Where it was first, the next client is
+    // woken in its place, so a push it did not use is not lost.
+    pub fn leave(&mut self, keys: &[Vec<u8>], id: u64){
+        for key in keys{
+            let empty = match self.queues.get_mut(key){
+                Some(queue) =>{
+                    let was_first = queue.front().map_or(false, |(waiter, _)| *waiter == id);
+                    queue.retain(|(waiter, _)| *waiter != id);
+                    if was_first{
+                        if let Some((_, wake)) = queue.front(){
+                            let _ = wake.send(());
+                        }
+                    }
+                    queue.is_empty()
+                }
+                None => false,
+            };
+            if empty{
+                self.queues.remove(key);
+            }
+        }
+    }
+
+    // the keys among `keys` the client is first in line on
+    pub fn first_on(&self, keys: &[Vec<u8>], id: u64) -> Vec<Vec<u8>>{
+        keys.iter()
+            .filter(|key| {
+                self.queues.get(*key).and_then(|queue| queue.front()).is_some_and(|(waiter, _)| *waiter == id)
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Wakes the first client waiting on `key`, if any.
+    pub fn signal(&self, key: &[u8]){
+        if let Some((_, wake)) = self.queues.get(key).and_then(|queue| queue.front()){
+            let _ = wake.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::wait_queue::WaitQueues;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_wait_queues(){
+        let mut waits = WaitQueues::new();
+        let (first, first_rx) = channel();
+        let (second, second_rx) = channel();
+        let keys = vec![b"jobs".to_vec(), b"urgent".to_vec()];
+        waits.wait(&keys, 1, &first);
+        waits.wait(&keys[..1], 2, &second);
+        // waiting again keeps the place in the queue
+        waits.wait(&keys, 1, &first);
+
+        assert_eq!(waits.first_on(&keys, 1), keys);
+        assert!(waits.first_on(&keys, 2).is_empty());
+        waits.signal(b"jobs");
+        assert!(first_rx.try_recv().is_ok());
+        assert!(first_rx.try_recv().is_err());
+        assert!(second_rx.try_recv().is_err());
+        waits.signal(b"other");
+        assert!(first_rx.try_recv().is_err());
+
+        // the next client takes over once the first is served
+        waits.leave(&keys, 1);
+        assert!(second_rx.try_recv().is_ok());
+        assert_eq!(waits.first_on(&keys, 2), vec![b"jobs".to_vec()]);
+        waits.signal(b"urgent");
+        assert!(first_rx.try_recv().is_err());
+        waits.signal(b"jobs");
+        assert!(second_rx.try_recv().is_ok());
+
+        waits.leave(&keys[..1], 2);
+        assert!(waits.queues.is_empty());
+    }
+}